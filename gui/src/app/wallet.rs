@@ -126,7 +126,7 @@ impl Wallet {
             Err(e) => return Err(e.into()),
         };
 
-        let hot_signers = match HotSigner::from_datadir(datadir_path, network) {
+        let hot_signers = match HotSigner::from_datadir(datadir_path, network, None) {
             Ok(signers) => signers,
             Err(e) => match e {
                 liana::signer::SignerError::MnemonicStorage(e) => {
@@ -21,6 +21,13 @@ impl TryFrom<Context> for LianaConfig {
             data_dir: Some(ctx.data_dir),
             bitcoin_config: ctx.bitcoin_config,
             bitcoind_config: ctx.bitcoind_config,
+            electrum_config: None,
+            cbf_config: None,
+            hooks_config: None,
+            http_rpc_config: None,
+            rpc_auth: Vec::new(),
+            signer_config: None,
+            guardrails_config: None,
         })
     }
 }
@@ -44,6 +44,7 @@ impl Context {
             bitcoin_config: BitcoinConfig {
                 network,
                 poll_interval_secs: Duration::from_secs(30),
+                gap_limit: 200,
             },
             hws: Vec::new(),
             keys: Vec::new(),
@@ -96,6 +97,13 @@ impl Context {
             data_dir: Some(self.data_dir.clone()),
             bitcoin_config: self.bitcoin_config.clone(),
             bitcoind_config: self.bitcoind_config.clone(),
+            electrum_config: None,
+            cbf_config: None,
+            hooks_config: None,
+            http_rpc_config: None,
+            rpc_auth: Vec::new(),
+            signer_config: None,
+            guardrails_config: None,
         }
     }
 }
@@ -61,6 +61,6 @@ impl Signer {
         datadir_root: &std::path::Path,
         network: Network,
     ) -> Result<(), SignerError> {
-        self.key.store(datadir_root, network, &self.curve)
+        self.key.store(datadir_root, network, &self.curve, None)
     }
 }
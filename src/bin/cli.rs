@@ -1,9 +1,9 @@
 use liana::config::{config_folder_path, Config};
 
 use std::{
-    env,
-    io::{Read, Write},
-    path::PathBuf,
+    env, fs,
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
     process,
 };
 
@@ -15,11 +15,23 @@ use std::os::unix::net::UnixStream;
 fn show_usage() {
     eprintln!("Usage:");
     eprintln!(" liana-cli [--conf conf_path] [--raw] <command> [<param 1> <param 2> ...]");
+    eprintln!(
+        " liana-cli [--conf conf_path] --labels-file labels_path <exportlabels|importlabels>"
+    );
     process::exit(1);
 }
 
-// Returns (Maybe(special conf file), Raw, Method name, Maybe(List of parameters))
-fn parse_args(mut args: Vec<String>) -> (Option<PathBuf>, bool, String, Vec<String>) {
+struct Args {
+    conf_file: Option<PathBuf>,
+    raw: bool,
+    // A BIP329 file to write the labels to for 'exportlabels', or read them from for
+    // 'importlabels'.
+    labels_file: Option<PathBuf>,
+    method: String,
+    params: Vec<String>,
+}
+
+fn parse_args(mut args: Vec<String>) -> Args {
     if args.len() < 2 {
         eprintln!("Not enough arguments.");
         show_usage();
@@ -30,6 +42,7 @@ fn parse_args(mut args: Vec<String>) -> (Option<PathBuf>, bool, String, Vec<Stri
     let mut args = args.into_iter();
     let mut raw = false;
     let mut conf_file = None;
+    let mut labels_file = None;
 
     loop {
         match args.next().as_deref() {
@@ -48,7 +61,27 @@ fn parse_args(mut args: Vec<String>) -> (Option<PathBuf>, bool, String, Vec<Stri
                 }
                 raw = true;
             }
-            Some(method) => return (conf_file, raw, method.to_owned(), args.collect()),
+            Some("--labels-file") => {
+                if args.len() < 2 {
+                    eprintln!("Not enough arguments.");
+                    show_usage();
+                }
+
+                labels_file = Some(PathBuf::from(args.next().expect("Just checked")));
+            }
+            Some(method) => {
+                if labels_file.is_some() && method != "exportlabels" && method != "importlabels" {
+                    eprintln!("--labels-file is only valid with exportlabels and importlabels.");
+                    show_usage();
+                }
+                return Args {
+                    conf_file,
+                    raw,
+                    labels_file,
+                    method: method.to_owned(),
+                    params: args.collect(),
+                };
+            }
             None => {
                 // Should never happen...
                 eprintln!("Not enough arguments.");
@@ -100,6 +133,37 @@ fn socket_file(conf_file: Option<PathBuf>) -> PathBuf {
     .collect()
 }
 
+// Read BIP329 labels from a JSONL file, as a JSON array.
+fn read_labels_file(path: &Path) -> Result<Json, io::Error> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    let mut labels = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let label = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        labels.push(label);
+    }
+    Ok(Json::Array(labels))
+}
+
+// Write the BIP329 labels from an 'exportlabels' result to a JSONL file. Returns the number of
+// labels written.
+fn write_labels_file(path: &Path, result: &Json) -> Result<usize, io::Error> {
+    let labels = result
+        .get("labels")
+        .and_then(Json::as_array)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No labels in the result"))?;
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    for label in labels {
+        writeln!(file, "{}", label)?;
+    }
+    file.flush()?;
+    Ok(labels.len())
+}
+
 fn trimmed(mut vec: Vec<u8>, bytes_read: usize) -> Vec<u8> {
     vec.truncate(bytes_read);
 
@@ -116,8 +180,21 @@ fn trimmed(mut vec: Vec<u8>, bytes_read: usize) -> Vec<u8> {
 }
 
 fn main() {
-    let args = env::args().collect();
-    let (conf_file, raw, method, params) = parse_args(args);
+    let Args {
+        conf_file,
+        raw,
+        labels_file,
+        method,
+        mut params,
+    } = parse_args(env::args().collect());
+    let is_labels_export = method == "exportlabels";
+    if let (Some(path), "importlabels") = (&labels_file, method.as_str()) {
+        let labels = read_labels_file(path).unwrap_or_else(|e| {
+            eprintln!("Reading labels from {:?}: '{}'", path, e);
+            process::exit(1);
+        });
+        params.insert(0, labels.to_string());
+    }
     let request = rpc_request(method, params);
     let socket_file = socket_file(conf_file);
     let mut raw_response = vec![0; 256];
@@ -152,7 +229,18 @@ fn main() {
         match serde_json::from_slice::<Json>(&raw_response) {
             Ok(response) => {
                 if response.get("id") == request.get("id") {
-                    if raw {
+                    if let (Some(path), true, Some(r)) =
+                        (&labels_file, is_labels_export, response.get("result"))
+                    {
+                        let count = write_labels_file(path, r).unwrap_or_else(|e| {
+                            eprintln!("Writing labels to {:?}: '{}'", path, e);
+                            process::exit(1);
+                        });
+                        println!(
+                            "{:#}",
+                            serde_json::json!({ "result": { "exported": count } })
+                        );
+                    } else if raw {
                         print!("{}", response);
                     } else if let Some(r) = response.get("result") {
                         println!("{:#}", serde_json::json!({ "result": r }));
@@ -0,0 +1,1422 @@
+//! Implementation of the Bitcoin interface using compact block filters (BIP157/158).
+//!
+//! We connect to a configured peer over the P2P protocol, sync the block headers and download the
+//! filters of the blocks. We only fetch the blocks whose filter matches one of the scripts derived
+//! from our descriptors, so our peer doesn't learn which transactions are ours. The peer is
+//! trusted to serve us the correct filters.
+
+mod peer;
+use peer::{Peer, PeerSender};
+
+use crate::{
+    bitcoin::{
+        d::{utils::block_before_date, BlockStats, MempoolEntryFees},
+        Block, BlockChainTip, MempoolEntry, SyncProgress, UTxO, COINBASE_MATURITY,
+    },
+    config,
+    descriptors::{LianaDescriptor, SinglePathLianaDesc},
+};
+
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fmt, io, net,
+    sync::{self, atomic},
+    thread,
+    time::{Duration, Instant},
+};
+
+use miniscript::bitcoin::{
+    self, address,
+    bip158::BlockFilter,
+    block,
+    hashes::Hash,
+    network::{
+        message::NetworkMessage,
+        message_blockdata::{GetHeadersMessage, Inventory},
+        message_filter::GetCFilters,
+    },
+    pow, secp256k1,
+};
+
+// The maximum number of headers in a 'headers' message.
+const MAX_HEADERS_RESULTS: usize = 2_000;
+
+// How many filters we request at once. The protocol limit is 1000.
+const FILTERS_BATCH_SIZE: i32 = 500;
+
+// The type of the BIP158 basic filter.
+const BASIC_FILTER_TYPE: u8 = 0;
+
+// If we can't find the common ancestor of a former tip with the current chain, we assume it's
+// within this many blocks. This only happens if we didn't see the blocks of the former chain, for
+// instance after a restart.
+const MAX_REORG_DEPTH: i32 = 144;
+
+// How long we wait for the peer to answer one of our requests.
+const REQUEST_TIMEOUT: u64 = 120;
+
+// How often we ask the peer for new headers, in addition to its announcements.
+const HEADERS_POLL_INTERVAL: u64 = 30;
+
+// How long we wait before trying to reconnect to the peer after an error.
+const RECONNECTION_INTERVAL: u64 = 5;
+
+/// An error in the compact block filters interface.
+#[derive(Debug)]
+pub enum CbfError {
+    Io(io::Error),
+    Handshake(String),
+    /// The peer doesn't advertise serving compact block filters.
+    NoCompactFilters,
+    /// The peer is on a different network.
+    NetworkMismatch,
+    /// The peer sent us a message we could not make sense of, or didn't answer in time.
+    InvalidMessage(String),
+    /// We are not connected to the peer.
+    NotConnected,
+}
+
+impl fmt::Display for CbfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error when talking to the peer: {}", e),
+            Self::Handshake(e) => write!(f, "Error during handshake with the peer: {}", e),
+            Self::NoCompactFilters => write!(f, "The peer doesn't serve compact block filters."),
+            Self::NetworkMismatch => write!(f, "The peer is on a different network."),
+            Self::InvalidMessage(e) => write!(f, "Unexpected message from the peer: {}", e),
+            Self::NotConnected => write!(f, "Not connected to the peer."),
+        }
+    }
+}
+
+impl std::error::Error for CbfError {}
+
+impl From<io::Error> for CbfError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The information we keep about each header in the best chain.
+#[derive(Debug, Clone, Copy)]
+struct HeaderEntry {
+    hash: bitcoin::BlockHash,
+    time: u32,
+    bits: pow::CompactTarget,
+}
+
+impl From<&block::Header> for HeaderEntry {
+    fn from(header: &block::Header) -> HeaderEntry {
+        HeaderEntry {
+            hash: header.block_hash(),
+            time: header.time,
+            bits: header.bits,
+        }
+    }
+}
+
+fn chain_work(entries: &[HeaderEntry]) -> Option<pow::Work> {
+    entries
+        .iter()
+        .map(|e| pow::Target::from_compact(e.bits).to_work())
+        .reduce(|a, b| a + b)
+}
+
+/// A script derived from one of our descriptors.
+#[derive(Debug, Clone)]
+struct WatchedScript {
+    /// Index of the descriptor this script was derived from in the list of our descriptors.
+    desc_index: usize,
+    derivation_index: u32,
+    address: bitcoin::Address<address::NetworkUnchecked>,
+}
+
+/// A transaction relevant to our wallet.
+#[derive(Debug, Clone)]
+struct WalletTx {
+    tx: bitcoin::Transaction,
+    /// The height of the block it was confirmed in, `None` if it's one of ours which is yet to
+    /// be confirmed.
+    height: Option<i32>,
+}
+
+struct Wallet {
+    network: bitcoin::Network,
+    /// How many scripts we watch beyond the last one that was used, for each descriptor. This is
+    /// the same gap limit as for the addresses we store in database.
+    gap_limit: u32,
+    /// The receive and change descriptors of all the wallets we watch.
+    descs: Vec<SinglePathLianaDesc>,
+    secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    scripts: HashMap<bitcoin::ScriptBuf, WatchedScript>,
+    /// For each descriptor, the next derivation index to watch and the last used one.
+    next_index: Vec<u32>,
+    last_used: Vec<Option<u32>>,
+    txs: HashMap<bitcoin::Txid, WalletTx>,
+}
+
+impl Wallet {
+    // Add the receive and change descriptors of this descriptor to the ones we watch, unless
+    // they already are.
+    fn add_descriptor(&mut self, desc: &LianaDescriptor) {
+        for desc in [desc.receive_descriptor(), desc.change_descriptor()] {
+            if !self.descs.contains(desc) {
+                self.descs.push(desc.clone());
+                self.next_index.push(0);
+                self.last_used.push(None);
+            }
+        }
+        self.extend_watched();
+    }
+
+    // Watch the scripts derived from our descriptors up to the gap limit after the last used
+    // one.
+    fn extend_watched(&mut self) {
+        for (desc_index, desc) in self.descs.iter().enumerate() {
+            let target = self.last_used[desc_index]
+                .map(|i| i + 1)
+                .unwrap_or(0)
+                .checked_add(self.gap_limit)
+                .expect("Derivation index must not overflow");
+            while self.next_index[desc_index] < target {
+                let derivation_index = self.next_index[desc_index];
+                let derived = desc.derive(derivation_index.into(), &self.secp);
+                let address = derived.address(self.network);
+                self.scripts.insert(
+                    derived.script_pubkey(),
+                    WatchedScript {
+                        desc_index,
+                        derivation_index,
+                        address: bitcoin::Address::new(address.network, address.payload),
+                    },
+                );
+                self.next_index[desc_index] += 1;
+            }
+        }
+    }
+
+    // Whether this output of a wallet transaction pays to one of our scripts.
+    fn is_ours(&self, outpoint: &bitcoin::OutPoint) -> bool {
+        self.txs
+            .get(&outpoint.txid)
+            .and_then(|wtx| wtx.tx.output.get(outpoint.vout as usize))
+            .map(|txo| self.scripts.contains_key(&txo.script_pubkey))
+            .unwrap_or(false)
+    }
+
+    // Record the transactions from this block which pay to or spend from our scripts.
+    fn process_block(&mut self, block: &bitcoin::Block, height: i32) {
+        loop {
+            let last_used = self.last_used.clone();
+            for tx in &block.txdata {
+                let spends_ours = tx
+                    .input
+                    .iter()
+                    .any(|txin| self.is_ours(&txin.previous_output));
+                let mut pays_ours = false;
+                for txo in &tx.output {
+                    if let Some(script) = self.scripts.get(&txo.script_pubkey) {
+                        pays_ours = true;
+                        let last_used = &mut self.last_used[script.desc_index];
+                        if last_used
+                            .map(|i| i < script.derivation_index)
+                            .unwrap_or(true)
+                        {
+                            *last_used = Some(script.derivation_index);
+                        }
+                    }
+                }
+                if !spends_ours && !pays_ours {
+                    continue;
+                }
+
+                // Forget about unconfirmed transactions this one conflicts with.
+                let txid = tx.txid();
+                let spent: HashSet<_> = tx.input.iter().map(|txin| txin.previous_output).collect();
+                self.txs.retain(|id, wtx| {
+                    *id == txid
+                        || wtx.height.is_some()
+                        || !wtx
+                            .tx
+                            .input
+                            .iter()
+                            .any(|txin| spent.contains(&txin.previous_output))
+                });
+                self.txs.insert(
+                    txid,
+                    WalletTx {
+                        tx: tx.clone(),
+                        height: Some(height),
+                    },
+                );
+            }
+
+            // If we started to use new scripts, we may have missed transactions paying to the
+            // next ones in this very block.
+            if last_used == self.last_used {
+                break;
+            }
+            self.extend_watched();
+        }
+    }
+
+    // Forget about the transactions confirmed in blocks above this height.
+    fn rollback(&mut self, height: i32) {
+        self.txs
+            .retain(|_, wtx| wtx.height.map(|h| h <= height).unwrap_or(true));
+    }
+
+    // Map the outpoints spent by wallet transactions to the transactions spending them.
+    fn spenders(&self) -> HashMap<bitcoin::OutPoint, Vec<(bitcoin::Txid, Option<i32>)>> {
+        let mut spenders: HashMap<_, Vec<_>> = HashMap::new();
+        for (txid, wtx) in &self.txs {
+            for txin in &wtx.tx.input {
+                spenders
+                    .entry(txin.previous_output)
+                    .or_default()
+                    .push((*txid, wtx.height));
+            }
+        }
+        spenders
+    }
+
+    // The fees paid by this transaction, if we know all the coins it spends.
+    fn tx_fee(&self, tx: &bitcoin::Transaction) -> Option<bitcoin::Amount> {
+        let mut in_value: u64 = 0;
+        for txin in &tx.input {
+            let prev_tx = &self.txs.get(&txin.previous_output.txid)?.tx;
+            let prev_out = prev_tx.output.get(txin.previous_output.vout as usize)?;
+            in_value = in_value.checked_add(prev_out.value)?;
+        }
+        let out_value: u64 = tx.output.iter().map(|o| o.value).sum();
+        in_value
+            .checked_sub(out_value)
+            .map(bitcoin::Amount::from_sat)
+    }
+}
+
+/// A rescan of past blocks requested by the user.
+#[derive(Debug, Clone, Copy)]
+struct Rescan {
+    start: i32,
+    /// The last height scanned.
+    current: i32,
+    end: i32,
+}
+
+struct State {
+    /// The best chain of headers we know of. Indexed by height.
+    headers: Vec<HeaderEntry>,
+    /// The headers which were reorganized out of the best chain, mapped to their parent.
+    stale_headers: HashMap<bitcoin::BlockHash, (bitcoin::BlockHash, i32)>,
+    /// The height up to which we scanned the filters of the best chain.
+    scanned_height: i32,
+    rescan: Option<Rescan>,
+    /// The height of the best block of our peer at connection time.
+    peer_height: i32,
+    wallet: Wallet,
+    /// To send messages to our peer, if we are connected.
+    sender: Option<PeerSender>,
+}
+
+impl State {
+    fn tip_height(&self) -> i32 {
+        (self.headers.len() - 1) as i32
+    }
+
+    // The block up to which we've scanned the chain for our transactions.
+    fn scanned_tip(&self) -> BlockChainTip {
+        let height = cmp::min(self.scanned_height, self.tip_height());
+        BlockChainTip {
+            hash: self.headers[height as usize].hash,
+            height,
+        }
+    }
+
+    fn block_at(&self, height: i32) -> Option<Block> {
+        let entry = self.headers.get(height as usize)?;
+        Some(Block {
+            hash: entry.hash,
+            height,
+            time: entry.time,
+        })
+    }
+
+    // A list of hashes of our best chain for the peer to find the last header we have in common.
+    fn locator(&self) -> Vec<bitcoin::BlockHash> {
+        let mut locator = Vec::new();
+        let mut height = self.tip_height();
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.headers[height as usize].hash);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        locator.push(self.headers[0].hash);
+        locator
+    }
+
+    // Connect these headers to our chain. If they fork our best chain and have more work, switch
+    // to this new chain.
+    fn connect_headers(&mut self, headers: &[block::Header]) -> Result<(), CbfError> {
+        let first_parent = headers[0].prev_blockhash;
+        let fork_height = self
+            .headers
+            .iter()
+            .rposition(|e| e.hash == first_parent)
+            .ok_or_else(|| CbfError::InvalidMessage("Headers don't connect".to_string()))?;
+        let mut prev_hash = first_parent;
+        let mut entries = Vec::with_capacity(headers.len());
+        for header in headers {
+            // NOTE: we don't check the difficulty adjustments. We trust our peer not to send us
+            // low-work headers.
+            if header.prev_blockhash != prev_hash || header.validate_pow(header.target()).is_err() {
+                return Err(CbfError::InvalidMessage("Invalid header".to_string()));
+            }
+            prev_hash = header.block_hash();
+            entries.push(HeaderEntry::from(header));
+        }
+
+        if fork_height < self.headers.len() - 1 {
+            if chain_work(&entries) <= chain_work(&self.headers[fork_height + 1..]) {
+                log::debug!("Ignoring headers for a chain with less work than ours.");
+                return Ok(());
+            }
+            log::info!(
+                "Block chain reorganization at height {}. Switching to the new best chain.",
+                fork_height
+            );
+            for height in fork_height + 1..self.headers.len() {
+                self.stale_headers.insert(
+                    self.headers[height].hash,
+                    (self.headers[height - 1].hash, height as i32),
+                );
+            }
+            self.headers.truncate(fork_height + 1);
+            let fork_height = fork_height as i32;
+            self.wallet.rollback(fork_height);
+            self.scanned_height = cmp::min(self.scanned_height, fork_height);
+            if let Some(rescan) = self.rescan.as_mut() {
+                rescan.current = cmp::min(rescan.current, fork_height);
+                rescan.end = cmp::min(rescan.end, fork_height);
+            }
+        }
+        self.headers.extend(entries);
+
+        Ok(())
+    }
+}
+
+// Syncs our state with the peer in a background thread.
+struct Syncer {
+    addr: net::SocketAddr,
+    network: bitcoin::Network,
+    peer: Peer,
+    state: sync::Arc<sync::Mutex<State>>,
+    shutdown: sync::Arc<atomic::AtomicBool>,
+}
+
+impl Syncer {
+    // Read messages from the peer until we get the one we are looking for. Returns `None` if we
+    // were told to shut down.
+    fn wait_for<T>(
+        &mut self,
+        mut f: impl FnMut(NetworkMessage) -> Option<T>,
+    ) -> Result<Option<T>, CbfError> {
+        let start = Instant::now();
+        while !self.shutdown.load(atomic::Ordering::Relaxed) {
+            if start.elapsed() > Duration::from_secs(REQUEST_TIMEOUT) {
+                return Err(CbfError::InvalidMessage(
+                    "Timed out waiting for a response".to_string(),
+                ));
+            }
+            if let Some(res) = self.peer.recv()?.and_then(&mut f) {
+                return Ok(Some(res));
+            }
+        }
+        Ok(None)
+    }
+
+    fn sync_headers(&mut self) -> Result<(), CbfError> {
+        loop {
+            let locator = self.state.lock().unwrap().locator();
+            self.peer
+                .send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+                    locator,
+                    bitcoin::BlockHash::all_zeros(),
+                )))?;
+            let headers = match self.wait_for(|msg| match msg {
+                NetworkMessage::Headers(headers) => Some(headers),
+                _ => None,
+            })? {
+                Some(headers) => headers,
+                None => return Ok(()),
+            };
+            if headers.is_empty() {
+                return Ok(());
+            }
+            self.state.lock().unwrap().connect_headers(&headers)?;
+            if headers.len() < MAX_HEADERS_RESULTS {
+                return Ok(());
+            }
+        }
+    }
+
+    // Scan the filters of the blocks we haven't scanned yet, and fetch the blocks which contain
+    // transactions relevant to us. A rescan of past blocks takes precedence.
+    fn scan_filters(&mut self) -> Result<(), CbfError> {
+        loop {
+            let (start, stop, is_rescan) = {
+                let mut state = self.state.lock().unwrap();
+                let tip_height = state.tip_height();
+                match state.rescan {
+                    Some(rescan) if rescan.current < rescan.end => (
+                        rescan.current + 1,
+                        cmp::min(rescan.current + FILTERS_BATCH_SIZE, rescan.end),
+                        true,
+                    ),
+                    Some(_) => {
+                        log::info!("Rescan of past blocks completed.");
+                        state.rescan = None;
+                        continue;
+                    }
+                    None if state.scanned_height < tip_height => (
+                        state.scanned_height + 1,
+                        cmp::min(state.scanned_height + FILTERS_BATCH_SIZE, tip_height),
+                        false,
+                    ),
+                    None => return Ok(()),
+                }
+            };
+            let stop_hash = self.state.lock().unwrap().headers[stop as usize].hash;
+            self.peer.send(NetworkMessage::GetCFilters(GetCFilters {
+                filter_type: BASIC_FILTER_TYPE,
+                start_height: start as u32,
+                stop_hash,
+            }))?;
+
+            // Receive the whole batch of filters before requesting any block, so we don't miss
+            // filters sent in the meantime.
+            let mut filters = Vec::with_capacity((stop - start + 1) as usize);
+            for height in start..=stop {
+                let block_hash = self.state.lock().unwrap().headers[height as usize].hash;
+                match self.wait_for(|msg| match msg {
+                    NetworkMessage::CFilter(filter) if filter.block_hash == block_hash => {
+                        Some(filter)
+                    }
+                    _ => None,
+                })? {
+                    Some(filter) => filters.push((height, block_hash, filter.filter)),
+                    None => return Ok(()),
+                };
+            }
+
+            for (height, block_hash, filter) in filters {
+                let filter = BlockFilter::new(&filter);
+                let is_match = {
+                    let state = self.state.lock().unwrap();
+                    let scripts = state.wallet.scripts.keys().map(|s| s.as_bytes());
+                    filter
+                        .match_any(&block_hash, scripts)
+                        .map_err(|e| CbfError::InvalidMessage(e.to_string()))?
+                };
+
+                if is_match {
+                    log::debug!("Filter for block {} matched. Fetching it.", block_hash);
+                    self.peer
+                        .send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(
+                            block_hash,
+                        )]))?;
+                    let block = match self.wait_for(|msg| match msg {
+                        NetworkMessage::Block(block) if block.block_hash() == block_hash => {
+                            Some(block)
+                        }
+                        _ => None,
+                    })? {
+                        Some(block) => block,
+                        None => return Ok(()),
+                    };
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .wallet
+                        .process_block(&block, height);
+                }
+
+                let mut state = self.state.lock().unwrap();
+                match state.rescan.as_mut() {
+                    Some(rescan) if is_rescan => rescan.current = height,
+                    _ => state.scanned_height = height,
+                }
+            }
+        }
+    }
+
+    // Wait until the peer announces a new block, a rescan is requested or it's time to poll again.
+    fn wait_for_news(&mut self) -> Result<(), CbfError> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(HEADERS_POLL_INTERVAL)
+            && !self.shutdown.load(atomic::Ordering::Relaxed)
+        {
+            match self.peer.recv()? {
+                Some(NetworkMessage::Inv(inv))
+                    if inv.iter().any(|i| matches!(i, Inventory::Block(_))) =>
+                {
+                    return Ok(())
+                }
+                Some(NetworkMessage::Headers(_)) => return Ok(()),
+                _ => {}
+            }
+            if self.state.lock().unwrap().rescan.is_some() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), CbfError> {
+        self.sync_headers()?;
+        self.scan_filters()?;
+        self.wait_for_news()
+    }
+
+    fn run(mut self) {
+        while !self.shutdown.load(atomic::Ordering::Relaxed) {
+            if let Err(e) = self.sync() {
+                log::error!("Error syncing with our peer: '{}'. Reconnecting.", e);
+                self.state.lock().unwrap().sender = None;
+                loop {
+                    thread::sleep(Duration::from_secs(RECONNECTION_INTERVAL));
+                    if self.shutdown.load(atomic::Ordering::Relaxed) {
+                        return;
+                    }
+                    match Peer::connect(self.addr, self.network) {
+                        Ok(peer) => {
+                            self.peer = peer;
+                            let mut state = self.state.lock().unwrap();
+                            state.sender = Some(self.peer.sender());
+                            state.peer_height = self.peer.start_height;
+                            break;
+                        }
+                        Err(e) => log::error!("Error reconnecting to our peer: '{}'.", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Cbf {
+    state: sync::Arc<sync::Mutex<State>>,
+    shutdown: sync::Arc<atomic::AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Cbf {
+    /// Connect to our peer and start syncing with it in a background thread. We'll watch the
+    /// scripts of all these descriptors, up to `gap_limit` past the last used one.
+    pub fn new(
+        config: &config::CbfConfig,
+        network: bitcoin::Network,
+        gap_limit: u32,
+        main_descs: &[LianaDescriptor],
+    ) -> Result<Cbf, CbfError> {
+        log::info!("Connecting to our peer at '{}'.", config.peer);
+        let peer = Peer::connect(config.peer, network)?;
+        log::info!("Connected to our peer.");
+
+        let mut wallet = Wallet {
+            network,
+            gap_limit,
+            descs: Vec::new(),
+            secp: secp256k1::Secp256k1::verification_only(),
+            scripts: HashMap::new(),
+            next_index: Vec::new(),
+            last_used: Vec::new(),
+            txs: HashMap::new(),
+        };
+        for desc in main_descs {
+            wallet.add_descriptor(desc);
+        }
+        let genesis = bitcoin::blockdata::constants::genesis_block(network);
+        let state = sync::Arc::new(sync::Mutex::new(State {
+            headers: vec![HeaderEntry::from(&genesis.header)],
+            stale_headers: HashMap::new(),
+            // The blocks before the start height are assumed to not contain any transaction of
+            // ours. Note the genesis block can't.
+            scanned_height: cmp::max(config.start_height as i32 - 1, 0),
+            rescan: None,
+            peer_height: peer.start_height,
+            wallet,
+            sender: Some(peer.sender()),
+        }));
+
+        let shutdown = sync::Arc::new(atomic::AtomicBool::new(false));
+        let syncer = Syncer {
+            addr: config.peer,
+            network,
+            peer,
+            state: state.clone(),
+            shutdown: shutdown.clone(),
+        };
+        let handle = thread::Builder::new()
+            .name("Compact block filters sync".to_string())
+            .spawn(move || syncer.run())
+            .expect("Must not fail");
+
+        Ok(Cbf {
+            state,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn genesis_block(&self) -> BlockChainTip {
+        let state = self.state.lock().unwrap();
+        BlockChainTip {
+            hash: state.headers[0].hash,
+            height: 0,
+        }
+    }
+
+    pub fn sync_progress(&self) -> SyncProgress {
+        let state = self.state.lock().unwrap();
+        let headers = cmp::max(state.peer_height, state.tip_height()) as u64;
+        let blocks = state.scanned_tip().height as u64;
+        let percentage = if headers == 0 {
+            1.0
+        } else {
+            blocks as f64 / headers as f64
+        };
+        SyncProgress::new(percentage, headers, blocks)
+    }
+
+    /// The tip up to which we scanned the block chain, which may lag behind the best header.
+    pub fn chain_tip(&self) -> BlockChainTip {
+        self.state.lock().unwrap().scanned_tip()
+    }
+
+    pub fn tip_time(&self) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        Some(state.headers[state.scanned_tip().height as usize].time)
+    }
+
+    pub fn is_in_chain(&self, tip: &BlockChainTip) -> bool {
+        let state = self.state.lock().unwrap();
+        tip.height <= state.scanned_tip().height
+            && state.headers.get(tip.height as usize).map(|e| e.hash) == Some(tip.hash)
+    }
+
+    pub fn received_coins(&self, tip: &BlockChainTip, descs: &[SinglePathLianaDesc]) -> Vec<UTxO> {
+        let state = self.state.lock().unwrap();
+        let wallet = &state.wallet;
+        let tip_height = state.scanned_tip().height;
+
+        let mut received = Vec::new();
+        for (txid, wtx) in &wallet.txs {
+            // Only report coins created after the given tip, like bitcoind's `listsinceblock`.
+            if wtx.height.map(|h| h <= tip.height).unwrap_or(false) {
+                continue;
+            }
+            let is_immature = wtx.tx.is_coin_base()
+                && wtx
+                    .height
+                    .map(|h| tip_height - h + 1 < COINBASE_MATURITY)
+                    .unwrap_or(true);
+            for (vout, txout) in wtx.tx.output.iter().enumerate() {
+                let script = match wallet.scripts.get(&txout.script_pubkey) {
+                    Some(script) if descs.contains(&wallet.descs[script.desc_index]) => script,
+                    _ => continue,
+                };
+                received.push(UTxO {
+                    outpoint: bitcoin::OutPoint {
+                        txid: *txid,
+                        vout: vout
+                            .try_into()
+                            .expect("Can't have more than u32::MAX outputs"),
+                    },
+                    amount: bitcoin::Amount::from_sat(txout.value),
+                    block_height: wtx.height,
+                    address: script.address.clone(),
+                    is_immature,
+                });
+            }
+        }
+
+        received
+    }
+
+    pub fn confirmed_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> (Vec<(bitcoin::OutPoint, i32, u32)>, Vec<bitcoin::OutPoint>) {
+        let state = self.state.lock().unwrap();
+        let tip_height = state.scanned_tip().height;
+        let mut confirmed = Vec::with_capacity(outpoints.len());
+        let mut expired = Vec::new();
+
+        for op in outpoints {
+            // If we don't know about this transaction anymore, it was replaced or reorged out.
+            let wtx = match state.wallet.txs.get(&op.txid) {
+                Some(wtx) => wtx,
+                None => {
+                    expired.push(*op);
+                    continue;
+                }
+            };
+            let height = match wtx.height {
+                Some(height) => height,
+                None => continue,
+            };
+
+            // Do not mark immature coinbase deposits as confirmed until they become mature.
+            let confirmations = tip_height - height + 1;
+            if wtx.tx.is_coin_base() && confirmations < COINBASE_MATURITY {
+                log::debug!("Coin at '{}' comes from an immature coinbase transaction with {} confirmations. Not marking it as confirmed for now.", op, confirmations);
+                continue;
+            }
+            if let Some(block) = state.block_at(height) {
+                confirmed.push((*op, block.height, block.time));
+            }
+        }
+
+        (confirmed, expired)
+    }
+
+    pub fn spending_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid)> {
+        let state = self.state.lock().unwrap();
+        let spenders = state.wallet.spenders();
+
+        outpoints
+            .iter()
+            .filter_map(|op| {
+                // Prefer a confirmed spender if there is a conflict.
+                let (txid, _) = spenders.get(op)?.iter().max_by_key(|(_, h)| *h)?;
+                Some((*op, *txid))
+            })
+            .collect()
+    }
+
+    pub fn spent_coins(
+        &self,
+        outpoints: &[(bitcoin::OutPoint, bitcoin::Txid)],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid, Block)> {
+        let state = self.state.lock().unwrap();
+        let spenders = state.wallet.spenders();
+        let mut spent = Vec::with_capacity(outpoints.len());
+
+        for (op, txid) in outpoints {
+            // If the spending transaction was confirmed, mark it as such. If a conflicting
+            // transaction was confirmed instead, replace the txid of the spender for this coin.
+            let confirmed_spender = match state.wallet.txs.get(txid).and_then(|wtx| wtx.height) {
+                Some(height) => Some((*txid, height)),
+                None => spenders.get(op).and_then(|s| {
+                    s.iter()
+                        .find_map(|(txid, height)| height.map(|h| (*txid, h)))
+                }),
+            };
+            if let Some((spender, height)) = confirmed_spender {
+                if let Some(block) = state.block_at(height) {
+                    spent.push((*op, spender, block));
+                }
+            }
+        }
+
+        spent
+    }
+
+    pub fn common_ancestor(&self, tip: &BlockChainTip) -> Option<BlockChainTip> {
+        let state = self.state.lock().unwrap();
+        let scanned_tip = state.scanned_tip();
+
+        // Walk back the former chain until we find a block which is part of the best chain.
+        let (mut hash, mut height) = (tip.hash, tip.height);
+        loop {
+            if state.headers.get(height as usize).map(|e| e.hash) == Some(hash) {
+                return if height > scanned_tip.height {
+                    Some(scanned_tip)
+                } else {
+                    Some(BlockChainTip { hash, height })
+                };
+            }
+            match state.stale_headers.get(&hash) {
+                Some((prev_hash, stale_height)) => {
+                    hash = *prev_hash;
+                    height = stale_height - 1;
+                }
+                None => break,
+            }
+        }
+
+        let height = cmp::max(
+            cmp::min(tip.height, scanned_tip.height) - MAX_REORG_DEPTH,
+            0,
+        );
+        Some(BlockChainTip {
+            hash: state.headers[height as usize].hash,
+            height,
+        })
+    }
+
+    pub fn broadcast_tx(&self, tx: &bitcoin::Transaction) -> Result<(), CbfError> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .sender
+            .as_ref()
+            .ok_or(CbfError::NotConnected)?
+            .send(NetworkMessage::Tx(tx.clone()))?;
+        // Our peer won't tell us if it was accepted. Consider it as unconfirmed until we see it
+        // in a block.
+        state
+            .wallet
+            .txs
+            .entry(tx.txid())
+            .or_insert_with(|| WalletTx {
+                tx: tx.clone(),
+                height: None,
+            });
+        Ok(())
+    }
+
+    /// Scan again the blocks since the one before this date, once we are done with the current
+    /// ones. Start watching the scripts of this descriptor if we weren't already. There is nothing
+    /// to rescan if the date is after our tip.
+    pub fn start_rescan(&self, desc: &LianaDescriptor, timestamp: u32) -> Result<(), String> {
+        if self.tip_time().map(|t| timestamp >= t).unwrap_or(false) {
+            self.state.lock().unwrap().wallet.add_descriptor(desc);
+            return Ok(());
+        }
+        let start = self
+            .tip_before_timestamp(timestamp)
+            .ok_or_else(|| format!("No block before timestamp '{}'.", timestamp))?;
+        let mut state = self.state.lock().unwrap();
+        state.wallet.add_descriptor(desc);
+        let end = state.scanned_tip().height;
+        state.rescan = Some(Rescan {
+            start: start.height,
+            current: start.height - 1,
+            end,
+        });
+        Ok(())
+    }
+
+    pub fn rescan_progress(&self) -> Option<f64> {
+        let rescan = self.state.lock().unwrap().rescan?;
+        if rescan.end <= rescan.start {
+            return Some(1.0);
+        }
+        Some((rescan.current - rescan.start + 1) as f64 / (rescan.end - rescan.start + 1) as f64)
+    }
+
+    pub fn tip_before_timestamp(&self, timestamp: u32) -> Option<BlockChainTip> {
+        let state = self.state.lock().unwrap();
+        let tip = state.scanned_tip();
+        // Remember the height of the blocks we looked up to answer queries by hash.
+        let heights = RefCell::new(HashMap::new());
+        heights.borrow_mut().insert(tip.hash, tip.height);
+        block_before_date(
+            timestamp,
+            tip,
+            |height| {
+                let hash = state.headers.get(height as usize)?.hash;
+                heights.borrow_mut().insert(hash, height);
+                Some(hash)
+            },
+            |hash| {
+                let height = *heights.borrow().get(&hash)?;
+                let entry = state.headers.get(height as usize)?;
+                Some(BlockStats {
+                    confirmations: tip.height - height + 1,
+                    previous_blockhash: height
+                        .checked_sub(1)
+                        .and_then(|h| state.headers.get(h as usize))
+                        .map(|e| e.hash),
+                    blockhash: hash,
+                    height,
+                    time: entry.time,
+                    median_time_past: entry.time,
+                })
+            },
+        )
+    }
+
+    pub fn wallet_transaction(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Option<(bitcoin::Transaction, Option<Block>)> {
+        let state = self.state.lock().unwrap();
+        let wtx = state.wallet.txs.get(txid)?;
+        Some((wtx.tx.clone(), wtx.height.and_then(|h| state.block_at(h))))
+    }
+
+    /// We only know about the unconfirmed transactions we broadcast ourselves, and can only
+    /// account for their descendants which we broadcast too.
+    pub fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        let state = self.state.lock().unwrap();
+        let wallet = &state.wallet;
+        let wtx = wallet.txs.get(txid).filter(|wtx| wtx.height.is_none())?;
+        let base = wallet.tx_fee(&wtx.tx)?;
+
+        let spenders = wallet.spenders();
+        let mut descendants = HashSet::new();
+        let mut to_visit = vec![*txid];
+        while let Some(parent_txid) = to_visit.pop() {
+            let parent = &wallet.txs.get(&parent_txid)?.tx;
+            for vout in 0..parent.output.len() {
+                let op = bitcoin::OutPoint {
+                    txid: parent_txid,
+                    vout: vout as u32,
+                };
+                for (child_txid, height) in spenders.get(&op).into_iter().flatten() {
+                    if height.is_none() && descendants.insert(*child_txid) {
+                        to_visit.push(*child_txid);
+                    }
+                }
+            }
+        }
+        let mut descendant = base;
+        for child_txid in descendants {
+            descendant =
+                descendant.checked_add(wallet.tx_fee(&wallet.txs.get(&child_txid)?.tx)?)?;
+        }
+
+        Some(MempoolEntry {
+            vsize: wtx.tx.vsize() as u64,
+            fees: MempoolEntryFees { base, descendant },
+        })
+    }
+}
+
+impl Drop for Cbf {
+    fn drop(&mut self) {
+        self.shutdown.store(true, atomic::Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("The compact block filters sync thread panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, Write},
+        str::FromStr,
+    };
+
+    use miniscript::bitcoin::{
+        absolute,
+        blockdata::{constants::genesis_block, script},
+        consensus::encode,
+        network::{
+            constants::ServiceFlags, message::RawNetworkMessage, message_filter::CFilter,
+            message_network::VersionMessage,
+        },
+    };
+
+    // A stand-in for a regtest node serving compact block filters for this chain.
+    struct MockPeer {
+        blocks: Vec<bitcoin::Block>,
+        // The transactions broadcast to us.
+        broadcast: Vec<bitcoin::Transaction>,
+        // Bumped to announce the tip to the connected clients.
+        announcements: usize,
+    }
+
+    impl MockPeer {
+        fn height_of(&self, hash: &bitcoin::BlockHash) -> Option<usize> {
+            self.blocks.iter().position(|b| b.block_hash() == *hash)
+        }
+
+        fn filter(&self, block: &bitcoin::Block) -> Vec<u8> {
+            BlockFilter::new_script_filter(block, |op| {
+                self.blocks
+                    .iter()
+                    .flat_map(|b| b.txdata.iter())
+                    .find(|tx| tx.txid() == op.txid)
+                    .map(|tx| tx.output[op.vout as usize].script_pubkey.clone())
+                    .ok_or(bitcoin::bip158::Error::UtxoMissing(*op))
+            })
+            .unwrap()
+            .content
+        }
+
+        fn handle(&mut self, msg: NetworkMessage) -> Vec<NetworkMessage> {
+            match msg {
+                NetworkMessage::Version(version) => {
+                    let mut ours = VersionMessage::new(
+                        ServiceFlags::NETWORK
+                            | ServiceFlags::WITNESS
+                            | ServiceFlags::COMPACT_FILTERS,
+                        0,
+                        version.receiver.clone(),
+                        version.sender,
+                        0,
+                        "/MockPeer:0.1/".to_string(),
+                        (self.blocks.len() - 1) as i32,
+                    );
+                    ours.version = 70016;
+                    vec![NetworkMessage::Version(ours), NetworkMessage::Verack]
+                }
+                NetworkMessage::GetHeaders(req) => {
+                    let fork = req
+                        .locator_hashes
+                        .iter()
+                        .find_map(|h| self.height_of(h))
+                        .unwrap();
+                    vec![NetworkMessage::Headers(
+                        self.blocks[fork + 1..].iter().map(|b| b.header).collect(),
+                    )]
+                }
+                NetworkMessage::GetCFilters(req) => {
+                    let stop = self.height_of(&req.stop_hash).unwrap();
+                    self.blocks[req.start_height as usize..=stop]
+                        .iter()
+                        .map(|b| {
+                            NetworkMessage::CFilter(CFilter {
+                                filter_type: req.filter_type,
+                                block_hash: b.block_hash(),
+                                filter: self.filter(b),
+                            })
+                        })
+                        .collect()
+                }
+                NetworkMessage::GetData(inv) => inv
+                    .iter()
+                    .filter_map(|i| match i {
+                        Inventory::WitnessBlock(hash) => Some(NetworkMessage::Block(
+                            self.blocks[self.height_of(hash)?].clone(),
+                        )),
+                        _ => None,
+                    })
+                    .collect(),
+                NetworkMessage::Tx(tx) => {
+                    self.broadcast.push(tx);
+                    vec![]
+                }
+                _ => vec![],
+            }
+        }
+    }
+
+    fn start_peer(peer: sync::Arc<sync::Mutex<MockPeer>>) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let peer = peer.clone();
+                thread::spawn(move || {
+                    let mut writer = stream.unwrap();
+                    writer
+                        .set_read_timeout(Some(Duration::from_millis(100)))
+                        .unwrap();
+                    let mut reader = io::BufReader::new(writer.try_clone().unwrap());
+                    let mut send = |payload| {
+                        let msg = RawNetworkMessage {
+                            magic: bitcoin::Network::Regtest.magic(),
+                            payload,
+                        };
+                        writer.write_all(&encode::serialize(&msg)).is_ok()
+                    };
+                    let mut announced = peer.lock().unwrap().announcements;
+                    loop {
+                        let announcement = {
+                            let peer = peer.lock().unwrap();
+                            if peer.announcements > announced {
+                                announced = peer.announcements;
+                                let tip = peer.blocks.last().unwrap().block_hash();
+                                Some(NetworkMessage::Inv(vec![Inventory::Block(tip)]))
+                            } else {
+                                None
+                            }
+                        };
+                        if let Some(msg) = announcement {
+                            if !send(msg) {
+                                return;
+                            }
+                        }
+                        match reader.fill_buf() {
+                            Ok([]) => return,
+                            Ok(_) => {}
+                            Err(_) => continue,
+                        }
+                        let msg: RawNetworkMessage =
+                            match encode::Decodable::consensus_decode(&mut reader) {
+                                Ok(msg) => msg,
+                                Err(_) => return,
+                            };
+                        let responses = peer.lock().unwrap().handle(msg.payload);
+                        for resp in responses {
+                            if !send(resp) {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    // Mine a block on top of this one with a coinbase paying to an unrelated script.
+    fn mine(prev: &bitcoin::Block, height: i32, txs: Vec<bitcoin::Transaction>) -> bitcoin::Block {
+        let coinbase = bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn {
+                script_sig: script::Builder::new().push_int(height as i64).into_script(),
+                ..bitcoin::TxIn::default()
+            }],
+            output: vec![bitcoin::TxOut {
+                script_pubkey: foreign_spk(),
+                value: 5_000_000_000,
+            }],
+        };
+        let mut block = bitcoin::Block {
+            header: block::Header {
+                prev_blockhash: prev.block_hash(),
+                time: prev.header.time + 600,
+                ..prev.header
+            },
+            txdata: std::iter::once(coinbase).chain(txs).collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    fn foreign_spk() -> bitcoin::ScriptBuf {
+        script::Builder::new()
+            .push_opcode(bitcoin::opcodes::OP_TRUE)
+            .into_script()
+    }
+
+    fn dummy_tx(
+        inputs: &[bitcoin::OutPoint],
+        outputs: &[(bitcoin::ScriptBuf, u64)],
+    ) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|op| bitcoin::TxIn {
+                    previous_output: *op,
+                    ..bitcoin::TxIn::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(spk, value)| bitcoin::TxOut {
+                    script_pubkey: spk.clone(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(30), "Timed out");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn cbf_interface() {
+        let desc = LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs").unwrap();
+        let descs = [
+            desc.receive_descriptor().clone(),
+            desc.change_descriptor().clone(),
+        ];
+        let secp = secp256k1::Secp256k1::verification_only();
+
+        // A chain where a foreign transaction at height 4 pays to our receive addresses at index
+        // 150 and 250, beyond the initial look-ahead. The first coin is spent at height 5 with a
+        // change output.
+        let mut blocks = vec![genesis_block(bitcoin::Network::Regtest)];
+        for height in 1..4 {
+            let block = mine(blocks.last().unwrap(), height, vec![]);
+            blocks.push(block);
+        }
+        let spk_150 = descs[0].derive(150.into(), &secp).script_pubkey();
+        let spk_250 = descs[0].derive(250.into(), &secp).script_pubkey();
+        let deposit_tx = dummy_tx(
+            &[bitcoin::OutPoint::new(blocks[1].txdata[0].txid(), 0)],
+            &[(spk_150, 100_000), (spk_250, 200_000)],
+        );
+        let deposit_txid = deposit_tx.txid();
+        blocks.push(mine(&blocks[3], 4, vec![deposit_tx]));
+        let change_spk = descs[1].derive(0.into(), &secp).script_pubkey();
+        let spend_tx = dummy_tx(
+            &[bitcoin::OutPoint::new(deposit_txid, 0)],
+            &[(foreign_spk(), 50_000), (change_spk, 49_000)],
+        );
+        let spend_txid = spend_tx.txid();
+        blocks.push(mine(&blocks[4], 5, vec![spend_tx]));
+        for height in 6..11 {
+            let block = mine(blocks.last().unwrap(), height, vec![]);
+            blocks.push(block);
+        }
+        let peer = sync::Arc::new(sync::Mutex::new(MockPeer {
+            blocks: blocks.clone(),
+            broadcast: Vec::new(),
+            announcements: 0,
+        }));
+        let config = config::CbfConfig {
+            peer: start_peer(peer.clone()),
+            start_height: 0,
+        };
+
+        // The peer must be on the expected network.
+        assert!(matches!(
+            Cbf::new(
+                &config,
+                bitcoin::Network::Bitcoin,
+                200,
+                std::slice::from_ref(&desc)
+            ),
+            Err(CbfError::NetworkMismatch)
+        ));
+        let cbf = Cbf::new(
+            &config,
+            bitcoin::Network::Regtest,
+            200,
+            std::slice::from_ref(&desc),
+        )
+        .unwrap();
+        assert_eq!(cbf.genesis_block().hash, blocks[0].block_hash());
+        wait_until(|| cbf.chain_tip().height == 10);
+        let tip = cbf.chain_tip();
+        assert_eq!(tip.hash, blocks[10].block_hash());
+        assert!(cbf.is_in_chain(&tip));
+        assert_eq!(cbf.sync_progress().rounded_up_progress(), 1.0);
+
+        // We found both deposits and the change.
+        let genesis = cbf.genesis_block();
+        let mut received = cbf.received_coins(&genesis, &descs);
+        received.sort_by_key(|utxo| (utxo.block_height, utxo.outpoint));
+        assert_eq!(received.len(), 3);
+        assert_eq!(
+            received[0].outpoint,
+            bitcoin::OutPoint::new(deposit_txid, 0)
+        );
+        assert_eq!(received[0].amount.to_sat(), 100_000);
+        assert_eq!(received[0].block_height, Some(4));
+        assert_eq!(
+            received[1].address.clone().assume_checked(),
+            descs[0]
+                .derive(250.into(), &secp)
+                .address(bitcoin::Network::Regtest)
+        );
+        assert_eq!(received[2].outpoint, bitcoin::OutPoint::new(spend_txid, 1));
+        assert_eq!(cbf.received_coins(&genesis, &descs[1..]).len(), 1);
+        assert!(cbf.received_coins(&tip, &descs).is_empty());
+        let (confirmed, expired) = cbf.confirmed_coins(&[received[0].outpoint]);
+        assert_eq!(
+            confirmed,
+            vec![(received[0].outpoint, 4, blocks[4].header.time)]
+        );
+        assert!(expired.is_empty());
+        assert_eq!(
+            cbf.spending_coins(&[received[0].outpoint, received[1].outpoint]),
+            vec![(received[0].outpoint, spend_txid)]
+        );
+        let spent = cbf.spent_coins(&[(received[0].outpoint, spend_txid)]);
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[0].2.height, 5);
+        assert_eq!(
+            cbf.tip_before_timestamp(blocks[7].header.time + 1)
+                .unwrap()
+                .height,
+            7
+        );
+
+        // Broadcast a transaction spending the second coin. It's unconfirmed until we see it in
+        // a block.
+        let bump_tx = dummy_tx(&[received[1].outpoint], &[(foreign_spk(), 150_000)]);
+        let bump_txid = bump_tx.txid();
+        cbf.broadcast_tx(&bump_tx).unwrap();
+        wait_until(|| !peer.lock().unwrap().broadcast.is_empty());
+        assert_eq!(peer.lock().unwrap().broadcast[0], bump_tx);
+        assert_eq!(cbf.wallet_transaction(&bump_txid).unwrap().1, None);
+        let entry = cbf.mempool_entry(&bump_txid).unwrap();
+        assert_eq!(entry.fees.base.to_sat(), 50_000);
+        assert_eq!(entry.fees.descendant.to_sat(), 50_000);
+        assert_eq!(entry.vsize, bump_tx.vsize() as u64);
+
+        // A reorg replaces the blocks after height 4 with a longer chain, where the first
+        // spend never happened but our broadcast transaction is confirmed at height 6.
+        {
+            let mut peer = peer.lock().unwrap();
+            peer.blocks.truncate(5);
+            for height in 5..12 {
+                let txs = if height == 6 {
+                    vec![bump_tx.clone()]
+                } else {
+                    vec![]
+                };
+                let block = mine(peer.blocks.last().unwrap(), height, txs);
+                peer.blocks.push(block);
+            }
+            peer.announcements += 1;
+        }
+        wait_until(|| cbf.chain_tip().height == 11);
+        assert!(!cbf.is_in_chain(&tip));
+        assert_eq!(
+            cbf.common_ancestor(&tip),
+            Some(BlockChainTip {
+                height: 4,
+                hash: blocks[4].block_hash(),
+            })
+        );
+        assert!(cbf
+            .spent_coins(&[(received[0].outpoint, spend_txid)])
+            .is_empty());
+        assert!(cbf.spending_coins(&[received[0].outpoint]).is_empty());
+        let (_, expired) = cbf.confirmed_coins(&[received[2].outpoint]);
+        assert_eq!(expired, vec![received[2].outpoint]);
+        assert_eq!(
+            cbf.wallet_transaction(&bump_txid)
+                .unwrap()
+                .1
+                .unwrap()
+                .height,
+            6
+        );
+        assert!(cbf.mempool_entry(&bump_txid).is_none());
+        drop(cbf);
+
+        // If we start scanning after the deposit, we only find it upon rescanning.
+        let config = config::CbfConfig {
+            start_height: 5,
+            ..config
+        };
+        let cbf = Cbf::new(
+            &config,
+            bitcoin::Network::Regtest,
+            200,
+            std::slice::from_ref(&desc),
+        )
+        .unwrap();
+        wait_until(|| cbf.chain_tip().height == 11);
+        assert!(cbf.received_coins(&genesis, &descs).is_empty());
+        assert!(cbf.rescan_progress().is_none());
+        cbf.start_rescan(&desc, blocks[3].header.time + 1).unwrap();
+        wait_until(|| cbf.rescan_progress().is_none());
+        assert_eq!(cbf.received_coins(&genesis, &descs).len(), 2);
+    }
+}
@@ -0,0 +1,189 @@
+//! A connection to a Bitcoin P2P node serving compact block filters.
+
+use crate::bitcoin::cbf::CbfError;
+
+use std::{
+    convert::TryInto,
+    io::{self, BufRead, Write},
+    net, sync,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use miniscript::bitcoin::{
+    self,
+    consensus::encode,
+    network::{
+        address,
+        constants::{Magic, ServiceFlags},
+        message::{NetworkMessage, RawNetworkMessage},
+        message_network::VersionMessage,
+    },
+};
+
+// The protocol version we advertise. We need at least 70015 for BIP157.
+const PROTOCOL_VERSION: u32 = 70015;
+
+// How long to wait when establishing the connection to the peer.
+const CONNECTION_TIMEOUT: u64 = 10;
+
+// How long to block waiting for a message from the peer, so we can check for shutdown regularly.
+const READ_TIMEOUT: u64 = 1;
+
+// Once the peer started sending a message, how long we wait for it to complete.
+const MESSAGE_TIMEOUT: u64 = 120;
+
+// How long we wait for the peer to complete the handshake.
+const HANDSHAKE_TIMEOUT: u64 = 30;
+
+/// The sending half of a connection to a peer. It can be shared across threads.
+#[derive(Clone)]
+pub struct PeerSender {
+    magic: Magic,
+    stream: sync::Arc<sync::Mutex<net::TcpStream>>,
+}
+
+impl PeerSender {
+    pub fn send(&self, payload: NetworkMessage) -> Result<(), CbfError> {
+        log::trace!("Sending '{}' to peer.", payload.cmd());
+        let msg = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.stream
+            .lock()
+            .unwrap()
+            .write_all(&encode::serialize(&msg))?;
+        Ok(())
+    }
+}
+
+pub struct Peer {
+    addr: net::SocketAddr,
+    magic: Magic,
+    reader: io::BufReader<net::TcpStream>,
+    sender: PeerSender,
+    /// The height of the best block of the peer, as advertised during the handshake.
+    pub start_height: i32,
+}
+
+impl Peer {
+    /// Connect to this peer and perform the version handshake. The peer must serve compact block
+    /// filters.
+    pub fn connect(addr: net::SocketAddr, network: bitcoin::Network) -> Result<Peer, CbfError> {
+        let stream =
+            net::TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECTION_TIMEOUT))?;
+        stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(MESSAGE_TIMEOUT)))?;
+        let magic = network.magic();
+        let mut peer = Peer {
+            addr,
+            magic,
+            reader: io::BufReader::new(stream.try_clone()?),
+            sender: PeerSender {
+                magic,
+                stream: sync::Arc::new(sync::Mutex::new(stream)),
+            },
+            start_height: 0,
+        };
+        peer.handshake()?;
+        Ok(peer)
+    }
+
+    fn handshake(&mut self) -> Result<(), CbfError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock went backward the epoch")
+            .as_secs() as i64;
+        let nonce = u64::from_be_bytes(
+            crate::random::random_bytes().map_err(|e| CbfError::Handshake(e.to_string()))?[..8]
+                .try_into()
+                .expect("Slice of 8 bytes"),
+        );
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            address::Address::new(&self.addr, ServiceFlags::NONE),
+            address::Address::new(&self.addr, ServiceFlags::NONE),
+            nonce,
+            format!("/lianad:{}/", crate::VERSION),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        self.send(NetworkMessage::Version(version))?;
+
+        let (mut got_version, mut got_verack) = (false, false);
+        let start = SystemTime::now();
+        while !(got_version && got_verack) {
+            if start.elapsed().unwrap_or_default() > Duration::from_secs(HANDSHAKE_TIMEOUT) {
+                return Err(CbfError::Handshake("Timed out".to_string()));
+            }
+            match self.recv()? {
+                Some(NetworkMessage::Version(version)) => {
+                    if !version.services.has(ServiceFlags::COMPACT_FILTERS) {
+                        return Err(CbfError::NoCompactFilters);
+                    }
+                    self.start_height = version.start_height;
+                    self.send(NetworkMessage::Verack)?;
+                    got_version = true;
+                }
+                Some(NetworkMessage::Verack) => got_verack = true,
+                Some(msg) => log::debug!("Ignoring '{}' message during handshake.", msg.cmd()),
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get a handle to send messages to this peer.
+    pub fn sender(&self) -> PeerSender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, payload: NetworkMessage) -> Result<(), CbfError> {
+        self.sender.send(payload)
+    }
+
+    /// Read the next message from the peer. Returns `None` if none was received before the read
+    /// timeout. Answers pings on the fly.
+    pub fn recv(&mut self) -> Result<Option<NetworkMessage>, CbfError> {
+        // Only wait for a short while for a new message, but don't give up in the middle of one.
+        match self.reader.fill_buf() {
+            Ok([]) => return Err(CbfError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(CbfError::Io(e)),
+        }
+        let stream = self.reader.get_ref();
+        stream.set_read_timeout(Some(Duration::from_secs(MESSAGE_TIMEOUT)))?;
+        let res = encode::Decodable::consensus_decode(&mut self.reader);
+        self.reader
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+
+        let msg = match res {
+            Ok(RawNetworkMessage { magic, payload }) => {
+                if magic != self.magic {
+                    return Err(CbfError::NetworkMismatch);
+                }
+                payload
+            }
+            Err(encode::Error::Io(e)) => return Err(CbfError::Io(e)),
+            Err(e) => return Err(CbfError::InvalidMessage(e.to_string())),
+        };
+        log::trace!("Received '{}' from peer.", msg.cmd());
+
+        if let NetworkMessage::Ping(nonce) = msg {
+            self.send(NetworkMessage::Pong(nonce))?;
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+}
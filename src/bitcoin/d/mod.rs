@@ -2,11 +2,11 @@
 //!
 //! We use the RPC interface and a watchonly descriptor wallet.
 
-mod utils;
+pub mod utils;
 use crate::{
     bitcoin::{Block, BlockChainTip},
     config,
-    descriptors::LianaDescriptor,
+    descriptors::{LianaDescriptor, SinglePathLianaDesc},
 };
 use utils::{block_before_date, roundup_progress};
 
@@ -16,7 +16,7 @@ use std::{
     convert::TryInto,
     fs, io,
     str::FromStr,
-    thread,
+    sync, thread,
     time::Duration,
 };
 
@@ -37,6 +37,10 @@ use serde_json::Value as Json;
 // If bitcoind takes more than 3 minutes to answer one of our queries, fail.
 const RPC_SOCKET_TIMEOUT: u64 = 180;
 
+// The end of the range of the descriptors we import, unless the gap limit requires a larger one.
+// This is bitcoind's default.
+const DEFAULT_RANGE_END: u32 = 1_000;
+
 // Number of retries the client is allowed to do in case of timeout or i/o error
 // while communicating with the bitcoin daemon.
 // A retry happens every 1 second, this makes us give up after one minute.
@@ -209,6 +213,9 @@ pub struct BitcoinD {
     watchonly_wallet_path: String,
     /// How many times we'll retry upon failure to send a request.
     retries: usize,
+    /// The end of the range our descriptors are imported with on the watchonly wallet, once
+    /// known. The range never shrinks, so we don't need to query it at every poll.
+    watched_range_end: sync::Mutex<Option<u32>>,
 }
 
 macro_rules! params {
@@ -264,6 +271,7 @@ impl BitcoinD {
             watchonly_client: dummy_wo_client,
             watchonly_wallet_path: watchonly_wallet_path.clone(),
             retries: 0,
+            watched_range_end: sync::Mutex::new(None),
         };
         log::info!("Checking the connection to bitcoind.");
         dummy_bitcoind.check_connection()?;
@@ -300,6 +308,7 @@ impl BitcoinD {
             watchonly_client,
             watchonly_wallet_path,
             retries: BITCOIND_RETRY_LIMIT,
+            watched_range_end: sync::Mutex::new(None),
         })
     }
 
@@ -542,7 +551,7 @@ impl BitcoinD {
     }
 
     // Import the receive and change descriptors from the multipath descriptor to bitcoind.
-    fn import_descriptor(&self, desc: &LianaDescriptor) -> Option<String> {
+    fn import_descriptor(&self, desc: &LianaDescriptor, range_end: u32) -> Option<String> {
         let descriptors = [desc.receive_descriptor(), desc.change_descriptor()]
             .iter()
             .map(|desc| {
@@ -550,6 +559,7 @@ impl BitcoinD {
                     "desc": desc.to_string(),
                     "timestamp": "now",
                     "active": false,
+                    "range": range_end,
                 })
             })
             .collect();
@@ -620,10 +630,12 @@ impl BitcoinD {
         }
     }
 
-    /// Create the watchonly wallet on bitcoind, and import it the main descriptor.
+    /// Create the watchonly wallet on bitcoind, and import it the main descriptor. The range of
+    /// the imported descriptors covers at least the first `gap_limit` derivation indexes.
     pub fn create_watchonly_wallet(
         &self,
         main_descriptor: &LianaDescriptor,
+        gap_limit: u32,
     ) -> Result<(), BitcoindError> {
         // Remove any leftover. This can happen if we delete the watchonly wallet but don't restart
         // bitcoind.
@@ -635,7 +647,8 @@ impl BitcoinD {
                 BitcoindError::Wallet(self.watchonly_wallet_path.clone(), WalletError::Creating(e))
             })?;
         // TODO: make it return an error instead of an option.
-        if let Some(err) = self.import_descriptor(main_descriptor) {
+        let range_end = cmp::max(DEFAULT_RANGE_END, gap_limit);
+        if let Some(err) = self.import_descriptor(main_descriptor, range_end) {
             return Err(BitcoindError::Wallet(
                 self.watchonly_wallet_path.clone(),
                 WalletError::ImportingDescriptor(err),
@@ -1045,8 +1058,7 @@ impl BitcoinD {
         let max_range = self
             .list_descriptors()
             .into_iter()
-            // 1_000 is bitcoind's default and what we use at initial import.
-            .fold(1_000, |range, entry| {
+            .fold(DEFAULT_RANGE_END, |range, entry| {
                 cmp::max(range, entry.range.map(|r| r[1]).unwrap_or(0))
             });
         let desc_str = [
@@ -1098,6 +1110,76 @@ impl BitcoinD {
         }
     }
 
+    /// Make sure these descriptors are imported with a range covering all the derivation indexes
+    /// below `end_index`. If they aren't, re-import them with a larger range.
+    pub fn extend_range(&self, descs: &[SinglePathLianaDesc], end_index: u32) {
+        let desc_str: Vec<String> = descs.iter().map(|desc| desc.to_string()).collect();
+        let mut watched_range_end = self
+            .watched_range_end
+            .lock()
+            .expect("Watched range end lock must not be poisoned");
+        let curr_range_end = match *watched_range_end {
+            Some(range_end) => range_end,
+            None => match self
+                .list_descriptors()
+                .into_iter()
+                .filter(|entry| desc_str.contains(&entry.desc))
+                .filter_map(|entry| entry.range.map(|r| r[1]))
+                .min()
+            {
+                Some(range_end) => {
+                    *watched_range_end = Some(range_end);
+                    range_end
+                }
+                // Our descriptors are checked to be imported at startup.
+                None => return,
+            },
+        };
+        if curr_range_end >= end_index.saturating_sub(1) {
+            return;
+        }
+
+        // Importing descriptors would conflict with an ongoing rescan. We'll try again next time.
+        if self.rescan_progress().is_some() {
+            log::debug!("Not extending the range of the descriptors during a rescan.");
+            return;
+        }
+
+        // Double the range, to not have to re-import the descriptors every time a new address is
+        // used. The addresses past the former range were not watched until now, so there is no
+        // need to rescan the block chain for them.
+        let range_end = cmp::max(end_index - 1, curr_range_end.saturating_mul(2));
+        log::info!(
+            "Extending the range of the descriptors on the watchonly wallet to {}.",
+            range_end
+        );
+        let desc_json: Vec<Json> = desc_str
+            .iter()
+            .map(|desc_str| {
+                serde_json::json!({
+                    "desc": desc_str,
+                    "timestamp": "now",
+                    "active": false,
+                    "range": range_end,
+                })
+            })
+            .collect();
+        let res = self.make_wallet_request("importdescriptors", &params!(Json::Array(desc_json)));
+        let all_succeeded = res
+            .as_array()
+            .map(|results| {
+                results
+                    .iter()
+                    .all(|res| res.get("success").and_then(Json::as_bool).unwrap_or(false))
+            })
+            .unwrap_or(false);
+        if all_succeeded {
+            *watched_range_end = Some(range_end);
+        } else {
+            log::error!("Error extending the range of the descriptors: '{}'.", res);
+        }
+    }
+
     /// Get the progress of the ongoing rescan, if there is any.
     pub fn rescan_progress(&self) -> Option<f64> {
         self.make_wallet_request("getwalletinfo", &[])
@@ -1134,6 +1216,44 @@ impl BitcoinD {
         }
     }
 
+    /// Estimate the feerate, in sats/vb, for a transaction to be confirmed within this number of
+    /// blocks. Falls back to the minimum feerate for entering our mempool if bitcoind doesn't
+    /// have enough data to estimate it.
+    pub fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        let btc_kvb = self
+            .make_node_request(
+                "estimatesmartfee",
+                &params!(Json::Number(conf_target.into())),
+            )
+            .get("feerate")
+            .and_then(Json::as_f64)
+            .or_else(|| {
+                self.make_node_request("getmempoolinfo", &[])
+                    .get("mempoolminfee")
+                    .and_then(Json::as_f64)
+            })?;
+        let sat_kvb = bitcoin::Amount::from_btc(btc_kvb).ok()?.to_sat();
+        // Round up, and never return a feerate lower than the minimum relay feerate.
+        Some(cmp::max(sat_kvb.checked_add(999).unwrap() / 1_000, 1))
+    }
+
+    /// Get the fees and size of a transaction in the mempool. Returns `None` if it is not in the
+    /// mempool.
+    pub fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        match self
+            .make_fallible_node_request("getmempoolentry", &params!(Json::String(txid.to_string())))
+        {
+            Ok(json) => Some(json.into()),
+            Err(BitcoindError::Server(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+                code: -5,
+                ..
+            }))) => None,
+            Err(e) => {
+                panic!("Unexpected error returned by bitcoind {}", e);
+            }
+        }
+    }
+
     /// Stop bitcoind.
     pub fn stop(&self) {
         self.make_node_request("stop", &[]);
@@ -1355,6 +1475,48 @@ impl From<Json> for GetTxRes {
     }
 }
 
+/// Fees of a transaction in the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolEntryFees {
+    /// Fees of the transaction itself.
+    pub base: bitcoin::Amount,
+    /// Fees of the transaction and all its in-mempool descendants.
+    pub descendant: bitcoin::Amount,
+}
+
+/// A transaction in the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub vsize: u64,
+    pub fees: MempoolEntryFees,
+}
+
+impl From<Json> for MempoolEntry {
+    fn from(json: Json) -> MempoolEntry {
+        let vsize = json
+            .get("vsize")
+            .and_then(Json::as_u64)
+            .expect("Must be present in bitcoind response");
+        let fees = json
+            .get("fees")
+            .expect("Must be present in bitcoind response");
+        let fee = |name: &str| {
+            fees.get(name)
+                .and_then(Json::as_f64)
+                .and_then(|f| bitcoin::Amount::from_btc(f).ok())
+                .expect("Must be present and a valid amount in bitcoind response")
+        };
+
+        MempoolEntry {
+            vsize,
+            fees: MempoolEntryFees {
+                base: fee("base"),
+                descendant: fee("descendant"),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockStats {
     pub confirmations: i32,
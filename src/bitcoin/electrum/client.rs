@@ -0,0 +1,167 @@
+//! A minimal client for the Electrum protocol.
+//!
+//! Requests are newline-delimited JSONRPC 2.0 messages sent over a plain TCP connection. The
+//! server may interleave notifications for our subscriptions with the responses to our requests,
+//! we keep them around until they are processed.
+
+use crate::bitcoin::electrum::ElectrumError;
+
+use std::{
+    io::{self, BufRead, Write},
+    mem, net, thread,
+    time::Duration,
+};
+
+use serde_json::Value as Json;
+
+// If the Electrum server takes more than 3 minutes to answer one of our queries, fail.
+const RPC_SOCKET_TIMEOUT: u64 = 180;
+
+// How long to wait when establishing the connection to the server.
+const CONNECTION_TIMEOUT: u64 = 10;
+
+// Number of times we try to reconnect to the server upon an i/o error. We retry every second,
+// this makes us give up after a minute.
+const ELECTRUM_RETRY_LIMIT: usize = 60;
+
+// The version of the Electrum protocol we implement.
+const PROTOCOL_VERSION: &str = "1.4";
+
+pub struct Client {
+    addr: net::SocketAddr,
+    stream: io::BufReader<net::TcpStream>,
+    next_id: u64,
+    /// Notifications received while waiting for the response to a request.
+    notifications: Vec<Json>,
+    /// Whether we had to reconnect to the server, and therefore lost our subscriptions.
+    reconnected: bool,
+}
+
+impl Client {
+    /// Connect to the Electrum server at this address and negotiate the protocol version.
+    pub fn new(addr: net::SocketAddr) -> Result<Client, ElectrumError> {
+        let mut client = Client {
+            addr,
+            stream: Self::connect(&addr)?,
+            next_id: 0,
+            notifications: Vec::new(),
+            reconnected: false,
+        };
+        client.negotiate_version()?;
+        Ok(client)
+    }
+
+    fn connect(addr: &net::SocketAddr) -> Result<io::BufReader<net::TcpStream>, ElectrumError> {
+        let stream =
+            net::TcpStream::connect_timeout(addr, Duration::from_secs(CONNECTION_TIMEOUT))?;
+        stream.set_read_timeout(Some(Duration::from_secs(RPC_SOCKET_TIMEOUT)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(RPC_SOCKET_TIMEOUT)))?;
+        Ok(io::BufReader::new(stream))
+    }
+
+    fn negotiate_version(&mut self) -> Result<(), ElectrumError> {
+        let client_name = format!("lianad {}", crate::VERSION);
+        self.try_request(
+            "server.version",
+            &[Json::from(client_name), Json::from(PROTOCOL_VERSION)],
+        )?;
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<(), ElectrumError> {
+        self.stream = Self::connect(&self.addr)?;
+        self.notifications.clear();
+        self.reconnected = true;
+        self.negotiate_version()
+    }
+
+    fn try_request(&mut self, method: &str, params: &[Json]) -> Result<Json, ElectrumError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let req = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        let mut req = serde_json::to_vec(&req).expect("Serializing a JSON value must not fail");
+        req.push(b'\n');
+        log::trace!(
+            "Sending to Electrum server: {}",
+            String::from_utf8_lossy(&req)
+        );
+        self.stream.get_mut().write_all(&req)?;
+
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(ElectrumError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            log::trace!("Received from Electrum server: {}", line.trim_end());
+            let msg: Json = serde_json::from_str(&line)
+                .map_err(|e| ElectrumError::InvalidResponse(e.to_string()))?;
+
+            // Keep the notifications for later, and skip responses to requests we gave up on.
+            if msg.get("method").is_some() {
+                self.notifications.push(msg);
+                continue;
+            }
+            if msg.get("id").and_then(Json::as_u64) != Some(id) {
+                log::debug!("Ignoring unexpected message from Electrum server: {}", msg);
+                continue;
+            }
+
+            if let Some(error) = msg.get("error").filter(|e| !e.is_null()) {
+                let message = error
+                    .get("message")
+                    .and_then(Json::as_str)
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| error.to_string());
+                return Err(ElectrumError::Server(message));
+            }
+            return msg
+                .get("result")
+                .cloned()
+                .ok_or_else(|| ElectrumError::InvalidResponse(msg.to_string()));
+        }
+    }
+
+    /// Send a request to the server and wait for its response. Upon i/o error we try to reconnect
+    /// to the server and resend the request, up to a limit.
+    pub fn request(&mut self, method: &str, params: &[Json]) -> Result<Json, ElectrumError> {
+        let mut retries = 0;
+        loop {
+            match self.try_request(method, params) {
+                Err(ElectrumError::Io(e)) if retries < ELECTRUM_RETRY_LIMIT => {
+                    log::error!(
+                        "I/O error when sending '{}' to the Electrum server: '{}'. Retrying in a second.",
+                        method,
+                        e
+                    );
+                    thread::sleep(Duration::from_secs(1));
+                    retries += 1;
+                    if let Err(e) = self.reconnect() {
+                        log::error!("Error reconnecting to the Electrum server: '{}'.", e);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Get the notifications received from the server so far.
+    pub fn take_notifications(&mut self) -> Vec<Json> {
+        mem::take(&mut self.notifications)
+    }
+
+    /// Whether we reconnected to the server since the last call to this method.
+    pub fn take_reconnected(&mut self) -> bool {
+        mem::take(&mut self.reconnected)
+    }
+
+    /// Consider we reconnected, for the caller to subscribe again at the next call to
+    /// `take_reconnected`.
+    pub fn set_reconnected(&mut self) {
+        self.reconnected = true;
+    }
+}
@@ -0,0 +1,1224 @@
+//! Implementation of the Bitcoin interface using an Electrum server.
+//!
+//! The server doesn't hold a wallet for us. Instead we derive the scripts from our descriptors,
+//! subscribe to their scripthash, and keep track of the history of those whose status changed.
+//!
+//! The connection to the server is neither encrypted nor authenticated, and we can't verify what
+//! it tells us. Only use a server running locally or one you trust, over a network you trust.
+//! Errors from the server are logged and we keep on working with the last state we could sync.
+
+mod client;
+use client::Client;
+
+use crate::{
+    bitcoin::{
+        d::{utils::block_before_date, BlockStats, MempoolEntryFees},
+        Block, BlockChainTip, MempoolEntry, SyncProgress, UTxO, COINBASE_MATURITY,
+    },
+    config,
+    descriptors::{LianaDescriptor, SinglePathLianaDesc},
+};
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    fmt, io,
+    str::FromStr,
+    sync,
+};
+
+use miniscript::bitcoin::{
+    self, address, block,
+    consensus::encode,
+    hashes::{hex::FromHex, sha256, Hash},
+    secp256k1,
+};
+
+use serde_json::Value as Json;
+
+// If we can't find the common ancestor of a former tip with the current chain within this many
+// blocks, we assume it's there. This only happens if we didn't see the blocks of the former chain,
+// for instance after a restart.
+const MAX_REORG_DEPTH: i32 = 144;
+
+/// An error in the Electrum interface.
+#[derive(Debug)]
+pub enum ElectrumError {
+    Io(io::Error),
+    /// The server returned an error for one of our requests.
+    Server(String),
+    /// The server sent a message we could not make sense of.
+    InvalidResponse(String),
+    NetworkMismatch(
+        bitcoin::Network,
+        bitcoin::BlockHash, /* server genesis */
+    ),
+}
+
+impl fmt::Display for ElectrumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error when talking to the Electrum server: {}", e),
+            Self::Server(e) => write!(f, "Electrum server error: {}", e),
+            Self::InvalidResponse(e) => write!(f, "Invalid response from Electrum server: {}", e),
+            Self::NetworkMismatch(network, genesis) => write!(
+                f,
+                "Network mismatch. We are supposed to run on {} but the Electrum server's genesis block is {}",
+                network, genesis
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElectrumError {}
+
+impl From<io::Error> for ElectrumError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// The Electrum protocol identifies scripts by the reversed hex of their sha256.
+fn scripthash(script: &bitcoin::Script) -> String {
+    sha256::Hash::hash(script.as_bytes())
+        .as_byte_array()
+        .iter()
+        .rev()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_hex<T: encode::Decodable>(json: &Json) -> Result<T, ElectrumError> {
+    let invalid = || ElectrumError::InvalidResponse(json.to_string());
+    let hex = json.as_str().ok_or_else(invalid)?;
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|_| invalid())?;
+    encode::deserialize(&bytes).map_err(|_| invalid())
+}
+
+// Query the current tip of the server's best chain.
+fn query_tip(client: &mut Client) -> Result<(BlockChainTip, block::Header), ElectrumError> {
+    let res = client.request("blockchain.headers.subscribe", &[])?;
+    let invalid = || ElectrumError::InvalidResponse(res.to_string());
+    let header: block::Header = parse_hex(res.get("hex").ok_or_else(invalid)?)?;
+    let height: i32 = res
+        .get("height")
+        .and_then(Json::as_i64)
+        .and_then(|h| h.try_into().ok())
+        .ok_or_else(invalid)?;
+    let tip = BlockChainTip {
+        hash: header.block_hash(),
+        height,
+    };
+    Ok((tip, header))
+}
+
+// Parse the history of a script, as returned by 'blockchain.scripthash.get_history'.
+fn parse_history(res: &Json) -> Result<Vec<HistoryEntry>, ElectrumError> {
+    let invalid = || ElectrumError::InvalidResponse(res.to_string());
+    res.as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|entry| {
+            Ok(HistoryEntry {
+                txid: entry
+                    .get("tx_hash")
+                    .and_then(Json::as_str)
+                    .and_then(|s| bitcoin::Txid::from_str(s).ok())
+                    .ok_or_else(invalid)?,
+                height: entry
+                    .get("height")
+                    .and_then(Json::as_i64)
+                    .and_then(|h| h.try_into().ok())
+                    .ok_or_else(invalid)?,
+            })
+        })
+        .collect()
+}
+
+/// A script derived from one of our descriptors.
+#[derive(Debug, Clone)]
+struct WatchedScript {
+    /// Index of the descriptor this script was derived from in the list of our descriptors.
+    desc_index: usize,
+    derivation_index: u32,
+    address: bitcoin::Address<address::NetworkUnchecked>,
+}
+
+/// A transaction in the history of one of our scripts.
+#[derive(Debug, Clone, Copy)]
+struct HistoryEntry {
+    txid: bitcoin::Txid,
+    /// The height of the block it was confirmed in. 0 or -1 if it's unconfirmed.
+    height: i32,
+}
+
+struct State {
+    client: Client,
+    /// The scripts we are subscribed to, by scripthash.
+    scripts: HashMap<String, WatchedScript>,
+    /// The last status of each of our scripts. It's null if the script was never used.
+    statuses: HashMap<String, Json>,
+    /// The history of each of our scripts which were used.
+    histories: HashMap<String, Vec<HistoryEntry>>,
+    /// The scripts whose status changed and whose history we have yet to fetch.
+    dirty: HashSet<String>,
+    /// The receive and change descriptors of all the wallets we watch.
+    descs: Vec<SinglePathLianaDesc>,
+    /// For each descriptor, the next derivation index to watch and the last used one.
+    next_index: Vec<u32>,
+    last_used: Vec<Option<u32>>,
+    /// All the transactions we fetched, either ours or the ones they spend.
+    txs: HashMap<bitcoin::Txid, bitcoin::Transaction>,
+    /// The chain tips we've seen, to be able to find the common ancestor after a reorg.
+    seen_tips: BTreeMap<i32, bitcoin::BlockHash>,
+    /// The last chain tip at which we could sync the history of all our scripts.
+    tip: (BlockChainTip, block::Header),
+}
+
+impl State {
+    // Add the receive and change descriptors of this descriptor to the ones we watch, unless
+    // they already are.
+    fn add_descriptor(&mut self, desc: &LianaDescriptor) {
+        for desc in [desc.receive_descriptor(), desc.change_descriptor()] {
+            if !self.descs.contains(desc) {
+                self.descs.push(desc.clone());
+                self.next_index.push(0);
+                self.last_used.push(None);
+            }
+        }
+    }
+
+    // Remember this tip of the best chain. Don't overwrite a tip we've seen at this height, it's
+    // how we'll find the common ancestor in case of reorg.
+    fn record_tip(&mut self, tip: &BlockChainTip) {
+        self.seen_tips.entry(tip.height).or_insert(tip.hash);
+        self.seen_tips = self
+            .seen_tips
+            .split_off(&tip.height.saturating_sub(MAX_REORG_DEPTH));
+    }
+}
+
+pub struct Electrum {
+    network: bitcoin::Network,
+    /// How many scripts we watch beyond the last one that was used, for each descriptor. This is
+    /// the same gap limit as for the addresses we store in database.
+    gap_limit: u32,
+    secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    state: sync::Mutex<State>,
+}
+
+impl Electrum {
+    /// Connect to the Electrum server and check it's operating on the expected network. We'll
+    /// watch the scripts of all these descriptors, up to `gap_limit` past the last used one.
+    pub fn new(
+        config: &config::ElectrumConfig,
+        network: bitcoin::Network,
+        gap_limit: u32,
+        main_descs: &[LianaDescriptor],
+    ) -> Result<Electrum, ElectrumError> {
+        log::info!("Checking the connection to the Electrum server.");
+        if !config.addr.ip().is_loopback() {
+            log::warn!(
+                "The connection to the Electrum server at '{}' is not encrypted. Make sure you trust the server and the network to it.",
+                config.addr
+            );
+        }
+        let mut client = Client::new(config.addr)?;
+        let genesis: block::Header =
+            parse_hex(&client.request("blockchain.block.header", &[Json::from(0)])?)?;
+        if genesis.block_hash()
+            != bitcoin::blockdata::constants::genesis_block(network).block_hash()
+        {
+            return Err(ElectrumError::NetworkMismatch(
+                network,
+                genesis.block_hash(),
+            ));
+        }
+        log::info!("Connection to the Electrum server checked.");
+
+        let tip = query_tip(&mut client)?;
+        let mut state = State {
+            client,
+            scripts: HashMap::new(),
+            statuses: HashMap::new(),
+            histories: HashMap::new(),
+            dirty: HashSet::new(),
+            descs: Vec::new(),
+            next_index: Vec::new(),
+            last_used: Vec::new(),
+            txs: HashMap::new(),
+            seen_tips: BTreeMap::new(),
+            tip,
+        };
+        state.record_tip(&tip.0);
+        for desc in main_descs {
+            state.add_descriptor(desc);
+        }
+        Ok(Electrum {
+            network,
+            gap_limit,
+            secp: secp256k1::Secp256k1::verification_only(),
+            state: sync::Mutex::new(state),
+        })
+    }
+
+    /// Start watching the scripts of this descriptor too. The Electrum server indexes the whole
+    /// block chain so the history of its scripts will be available from the next sync.
+    pub fn watch_descriptor(&self, desc: &LianaDescriptor) {
+        self.state.lock().unwrap().add_descriptor(desc);
+    }
+
+    fn header_at(
+        &self,
+        state: &mut State,
+        height: i32,
+    ) -> Result<Option<block::Header>, ElectrumError> {
+        match state
+            .client
+            .request("blockchain.block.header", &[Json::from(height)])
+        {
+            Ok(res) => parse_hex(&res).map(Some),
+            Err(ElectrumError::Server(e)) => {
+                log::debug!("Could not get header at height {}: '{}'.", height, e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn block_at(&self, state: &mut State, height: i32) -> Result<Option<Block>, ElectrumError> {
+        Ok(self.header_at(state, height)?.map(|header| Block {
+            hash: header.block_hash(),
+            height,
+            time: header.time,
+        }))
+    }
+
+    // Query the tip of the server's best chain and sync the history of our scripts. Only once the
+    // latter succeeded do we move our own tip forward, so the coins we report are always
+    // consistent with it. If the server fails us we keep the last tip we could sync at.
+    fn sync_tip(&self, state: &mut State) -> (BlockChainTip, block::Header) {
+        let res = query_tip(&mut state.client).and_then(|tip| {
+            self.sync_wallet(state)?;
+            Ok(tip)
+        });
+        match res {
+            Ok(tip) => {
+                state.record_tip(&tip.0);
+                state.tip = tip;
+            }
+            Err(e) => log::error!("Error syncing with the Electrum server: '{}'.", e),
+        }
+        state.tip
+    }
+
+    fn get_tx(
+        &self,
+        state: &mut State,
+        txid: &bitcoin::Txid,
+    ) -> Result<Option<bitcoin::Transaction>, ElectrumError> {
+        if let Some(tx) = state.txs.get(txid) {
+            return Ok(Some(tx.clone()));
+        }
+        match state.client.request(
+            "blockchain.transaction.get",
+            &[Json::from(txid.to_string())],
+        ) {
+            Ok(res) => {
+                let tx: bitcoin::Transaction = parse_hex(&res)?;
+                state.txs.insert(*txid, tx.clone());
+                Ok(Some(tx))
+            }
+            Err(ElectrumError::Server(e)) => {
+                log::debug!("Could not get transaction '{}': '{}'.", txid, e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Subscribe to the scripts derived from our descriptors up to the gap limit after the last
+    // used one. Scripts which were already used are marked as dirty.
+    fn extend_watched(&self, state: &mut State) -> Result<(), ElectrumError> {
+        for desc_index in 0..state.descs.len() {
+            let desc = state.descs[desc_index].clone();
+            let target = state.last_used[desc_index]
+                .map(|i| i + 1)
+                .unwrap_or(0)
+                .checked_add(self.gap_limit)
+                .expect("Derivation index must not overflow");
+            while state.next_index[desc_index] < target {
+                let derivation_index = state.next_index[desc_index];
+                let derived = desc.derive(derivation_index.into(), &self.secp);
+                let sh = scripthash(&derived.script_pubkey());
+                let status = state
+                    .client
+                    .request("blockchain.scripthash.subscribe", &[Json::from(sh.clone())])?;
+                if !status.is_null() {
+                    state.dirty.insert(sh.clone());
+                }
+                let address = derived.address(self.network);
+                state.statuses.insert(sh.clone(), status);
+                state.scripts.insert(
+                    sh,
+                    WatchedScript {
+                        desc_index,
+                        derivation_index,
+                        address: bitcoin::Address::new(address.network, address.payload),
+                    },
+                );
+                state.next_index[desc_index] += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Update our view of the wallet: query the history of the scripts whose status changed and
+    // the transactions therein. The scripts stay marked as dirty until we could fetch their
+    // history, so if we fail we'll pick up where we left off at the next sync.
+    fn sync_wallet(&self, state: &mut State) -> Result<(), ElectrumError> {
+        // If we had to reconnect to the server we lost our subscriptions. Subscribe anew.
+        if state.client.take_reconnected() {
+            let scripthashes: Vec<String> = state.scripts.keys().cloned().collect();
+            for sh in scripthashes {
+                let status = state
+                    .client
+                    .request("blockchain.scripthash.subscribe", &[Json::from(sh.clone())]);
+                let status = match status {
+                    Ok(status) => status,
+                    Err(e) => {
+                        // Make sure we'll subscribe again to all of them next time.
+                        state.client.set_reconnected();
+                        return Err(e);
+                    }
+                };
+                if state.statuses.get(&sh) != Some(&status) {
+                    state.dirty.insert(sh.clone());
+                    state.statuses.insert(sh, status);
+                }
+            }
+        }
+        self.extend_watched(state)?;
+
+        // Any notification for our subscriptions sent before the response to this request would
+        // be received in the meantime.
+        state.client.request("server.ping", &[])?;
+        for notif in state.client.take_notifications() {
+            if notif.get("method").and_then(Json::as_str) != Some("blockchain.scripthash.subscribe")
+            {
+                continue;
+            }
+            let params = notif.get("params").and_then(Json::as_array);
+            if let Some([Json::String(sh), status]) = params.map(|p| p.as_slice()) {
+                if state.scripts.contains_key(sh) && state.statuses.get(sh) != Some(status) {
+                    state.dirty.insert(sh.clone());
+                    state.statuses.insert(sh.clone(), status.clone());
+                }
+            } else {
+                log::debug!(
+                    "Ignoring invalid notification from Electrum server: {}",
+                    notif
+                );
+            }
+        }
+
+        // Now fetch the history of the scripts whose status changed. Once a script is used, we
+        // need to extend the range of scripts we are watching, which may uncover more used ones.
+        while !state.dirty.is_empty() {
+            for sh in state.dirty.iter().cloned().collect::<Vec<_>>() {
+                let res = state.client.request(
+                    "blockchain.scripthash.get_history",
+                    &[Json::from(sh.clone())],
+                )?;
+                let history = parse_history(&res)?;
+                for entry in &history {
+                    if self.get_tx(state, &entry.txid)?.is_none() {
+                        log::error!("Could not get wallet transaction '{}'.", entry.txid);
+                    }
+                }
+
+                let script = &state.scripts[&sh];
+                if !history.is_empty() {
+                    let last_used = &mut state.last_used[script.desc_index];
+                    if last_used
+                        .map(|i| i < script.derivation_index)
+                        .unwrap_or(true)
+                    {
+                        *last_used = Some(script.derivation_index);
+                    }
+                }
+                state.histories.insert(sh.clone(), history);
+                state.dirty.remove(&sh);
+            }
+            self.extend_watched(state)?;
+        }
+
+        Ok(())
+    }
+
+    // Sync the history of our scripts, for the commands which need an up to date view of our
+    // transactions. If the server fails us we go with what we have.
+    fn try_sync_wallet(&self, state: &mut State) {
+        if let Err(e) = self.sync_wallet(state) {
+            log::error!("Error syncing with the Electrum server: '{}'.", e);
+        }
+    }
+
+    // All the transactions in the history of our scripts, along with their confirmation height.
+    fn wallet_txs(&self, state: &State) -> HashMap<bitcoin::Txid, i32> {
+        state
+            .histories
+            .values()
+            .flatten()
+            .map(|entry| (entry.txid, entry.height))
+            .collect()
+    }
+
+    // Map the outpoints spent by wallet transactions to the transactions spending them.
+    fn spenders(
+        &self,
+        state: &State,
+        wallet_txs: &HashMap<bitcoin::Txid, i32>,
+    ) -> HashMap<bitcoin::OutPoint, Vec<(bitcoin::Txid, i32)>> {
+        let mut spenders: HashMap<bitcoin::OutPoint, Vec<(bitcoin::Txid, i32)>> = HashMap::new();
+        for (txid, height) in wallet_txs {
+            if let Some(tx) = state.txs.get(txid) {
+                for txin in &tx.input {
+                    spenders
+                        .entry(txin.previous_output)
+                        .or_default()
+                        .push((*txid, *height));
+                }
+            }
+        }
+        spenders
+    }
+
+    /// We checked the server is on our network at startup.
+    pub fn genesis_block(&self) -> BlockChainTip {
+        let hash = bitcoin::blockdata::constants::genesis_block(self.network).block_hash();
+        BlockChainTip { hash, height: 0 }
+    }
+
+    /// The Electrum server only serves requests once it's synced.
+    pub fn sync_progress(&self) -> SyncProgress {
+        let height = self.chain_tip().height as u64;
+        SyncProgress::new(1.0, height, height)
+    }
+
+    pub fn chain_tip(&self) -> BlockChainTip {
+        let mut state = self.state.lock().unwrap();
+        self.sync_tip(&mut state).0
+    }
+
+    pub fn tip_time(&self) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        Some(self.sync_tip(&mut state).1.time)
+    }
+
+    /// If the server fails us, we go by the tips we've seen.
+    pub fn is_in_chain(&self, tip: &BlockChainTip) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match self.header_at(&mut state, tip.height) {
+            Ok(header) => header.map(|h| h.block_hash() == tip.hash).unwrap_or(false),
+            Err(e) => {
+                log::error!(
+                    "Error when querying block header from the Electrum server: '{}'.",
+                    e
+                );
+                state.seen_tips.get(&tip.height) == Some(&tip.hash)
+            }
+        }
+    }
+
+    pub fn received_coins(&self, tip: &BlockChainTip, descs: &[SinglePathLianaDesc]) -> Vec<UTxO> {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let tip_height = state.tip.0.height;
+        let desc_indexes: Vec<usize> = state
+            .descs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, d)| if descs.contains(d) { Some(i) } else { None })
+            .collect();
+
+        let mut received = Vec::new();
+        for (txid, height) in self.wallet_txs(&state) {
+            // Only report coins created after the given tip, like bitcoind's `listsinceblock`.
+            if height > 0 && height <= tip.height {
+                continue;
+            }
+            let tx = match state.txs.get(&txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            let is_immature =
+                tx.is_coin_base() && (height <= 0 || tip_height - height + 1 < COINBASE_MATURITY);
+            for (vout, txout) in tx.output.iter().enumerate() {
+                let script = match state.scripts.get(&scripthash(&txout.script_pubkey)) {
+                    Some(script) if desc_indexes.contains(&script.desc_index) => script,
+                    _ => continue,
+                };
+                received.push(UTxO {
+                    outpoint: bitcoin::OutPoint {
+                        txid,
+                        vout: vout
+                            .try_into()
+                            .expect("Can't have more than u32::MAX outputs"),
+                    },
+                    amount: bitcoin::Amount::from_sat(txout.value),
+                    block_height: if height > 0 { Some(height) } else { None },
+                    address: script.address.clone(),
+                    is_immature,
+                });
+            }
+        }
+
+        received
+    }
+
+    pub fn confirmed_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> (Vec<(bitcoin::OutPoint, i32, u32)>, Vec<bitcoin::OutPoint>) {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let tip_height = state.tip.0.height;
+        let wallet_txs = self.wallet_txs(&state);
+        let mut confirmed = Vec::with_capacity(outpoints.len());
+        let mut expired = Vec::new();
+
+        for op in outpoints {
+            // If the transaction isn't in the history of our scripts anymore, it was dropped from
+            // the mempool or replaced.
+            let height = match wallet_txs.get(&op.txid) {
+                Some(height) => *height,
+                None => {
+                    expired.push(*op);
+                    continue;
+                }
+            };
+            if height <= 0 {
+                continue;
+            }
+
+            // Do not mark immature coinbase deposits as confirmed until they become mature.
+            let is_coinbase = state
+                .txs
+                .get(&op.txid)
+                .map(|tx| tx.is_coin_base())
+                .unwrap_or(false);
+            let confirmations = tip_height - height + 1;
+            if is_coinbase && confirmations < COINBASE_MATURITY {
+                log::debug!("Coin at '{}' comes from an immature coinbase transaction with {} confirmations. Not marking it as confirmed for now.", op, confirmations);
+                continue;
+            }
+            match self.block_at(&mut state, height) {
+                Ok(Some(block)) => confirmed.push((*op, block.height, block.time)),
+                Ok(None) => log::error!("Could not get block at height {}.", height),
+                Err(e) => log::error!("Error getting block at height {}: '{}'.", height, e),
+            }
+        }
+
+        (confirmed, expired)
+    }
+
+    pub fn spending_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid)> {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let wallet_txs = self.wallet_txs(&state);
+        let spenders = self.spenders(&state, &wallet_txs);
+
+        outpoints
+            .iter()
+            .filter_map(|op| {
+                // Prefer a confirmed spender if there is a conflict.
+                let (txid, _) = spenders.get(op)?.iter().max_by_key(|(_, h)| *h)?;
+                Some((*op, *txid))
+            })
+            .collect()
+    }
+
+    pub fn spent_coins(
+        &self,
+        outpoints: &[(bitcoin::OutPoint, bitcoin::Txid)],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid, Block)> {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let wallet_txs = self.wallet_txs(&state);
+        let spenders = self.spenders(&state, &wallet_txs);
+        let mut spent = Vec::with_capacity(outpoints.len());
+
+        for (op, txid) in outpoints {
+            // If the spending transaction was confirmed, mark it as such. If a conflicting
+            // transaction was confirmed instead, replace the txid of the spender for this coin.
+            let confirmed_spender = match wallet_txs.get(txid) {
+                Some(height) if *height > 0 => Some((*txid, *height)),
+                _ => spenders
+                    .get(op)
+                    .and_then(|s| s.iter().find(|(_, height)| *height > 0))
+                    .copied(),
+            };
+            if let Some((spender, height)) = confirmed_spender {
+                match self.block_at(&mut state, height) {
+                    Ok(Some(block)) => spent.push((*op, spender, block)),
+                    Ok(None) => log::error!("Could not get block at height {}.", height),
+                    Err(e) => log::error!("Error getting block at height {}: '{}'.", height, e),
+                }
+            }
+        }
+
+        spent
+    }
+
+    /// If the server fails us, we fall back to the last tip we've seen below this one, or to the
+    /// genesis block.
+    pub fn common_ancestor(&self, tip: &BlockChainTip) -> Option<BlockChainTip> {
+        let mut state = self.state.lock().unwrap();
+        let current_height = state.tip.0.height;
+        let mut height = std::cmp::min(tip.height, current_height);
+
+        let ancestor = loop {
+            let hash = match self.header_at(&mut state, height) {
+                Ok(header) => header?.block_hash(),
+                Err(e) => {
+                    log::error!(
+                        "Error when querying block header from the Electrum server: '{}'.",
+                        e
+                    );
+                    break state
+                        .seen_tips
+                        .range(..tip.height)
+                        .next_back()
+                        .map(|(height, hash)| BlockChainTip {
+                            hash: *hash,
+                            height: *height,
+                        })
+                        .unwrap_or_else(|| self.genesis_block());
+                }
+            };
+            let former_hash = if height == tip.height {
+                Some(tip.hash)
+            } else {
+                state.seen_tips.get(&height).copied()
+            };
+            if former_hash == Some(hash) || height == 0 || height <= tip.height - MAX_REORG_DEPTH {
+                break BlockChainTip { hash, height };
+            }
+            height -= 1;
+        };
+
+        // The tips we've seen above the common ancestor aren't part of the best chain anymore.
+        state.seen_tips.split_off(&(ancestor.height + 1));
+        Some(ancestor)
+    }
+
+    pub fn broadcast_tx(&self, tx: &bitcoin::Transaction) -> Result<(), ElectrumError> {
+        let mut state = self.state.lock().unwrap();
+        // The server returns the txid of the transaction it accepted.
+        let res = state.client.request(
+            "blockchain.transaction.broadcast",
+            &[Json::from(encode::serialize_hex(tx))],
+        )?;
+        if res.as_str() != Some(&tx.txid().to_string()) {
+            return Err(ElectrumError::InvalidResponse(res.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn tip_before_timestamp(&self, timestamp: u32) -> Option<BlockChainTip> {
+        let state = self.state.lock().unwrap();
+        let (tip, tip_header) = state.tip;
+        // We can only query headers by height, so remember those we fetched to answer queries by
+        // hash.
+        let mut headers = HashMap::new();
+        headers.insert(tip.hash, (tip.height, tip_header));
+        let headers = RefCell::new(headers);
+        let state = RefCell::new(state);
+        block_before_date(
+            timestamp,
+            tip,
+            |height| {
+                let header = self
+                    .header_at(&mut state.borrow_mut(), height)
+                    .unwrap_or_else(|e| {
+                        log::error!("Error getting block at height {}: '{}'.", height, e);
+                        None
+                    })?;
+                let hash = header.block_hash();
+                headers.borrow_mut().insert(hash, (height, header));
+                Some(hash)
+            },
+            |hash| {
+                let (height, header) = *headers.borrow().get(&hash)?;
+                Some(BlockStats {
+                    confirmations: tip.height - height + 1,
+                    previous_blockhash: Some(header.prev_blockhash),
+                    blockhash: hash,
+                    height,
+                    time: header.time,
+                    median_time_past: header.time,
+                })
+            },
+        )
+    }
+
+    pub fn wallet_transaction(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Option<(bitcoin::Transaction, Option<Block>)> {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let height = *self.wallet_txs(&state).get(txid)?;
+        let tx = state.txs.get(txid)?.clone();
+        let block = if height > 0 {
+            // Don't report a confirmed transaction as unconfirmed.
+            match self.block_at(&mut state, height) {
+                Ok(block) => Some(block?),
+                Err(e) => {
+                    log::error!("Error getting block at height {}: '{}'.", height, e);
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+        Some((tx, block))
+    }
+
+    // The fees paid by this transaction, fetching the transactions it spends if necessary.
+    fn tx_fee(&self, state: &mut State, tx: &bitcoin::Transaction) -> Option<bitcoin::Amount> {
+        let mut in_value: u64 = 0;
+        for txin in &tx.input {
+            let prev_tx = self
+                .get_tx(state, &txin.previous_output.txid)
+                .unwrap_or_else(|e| {
+                    log::error!(
+                        "Error getting transaction from the Electrum server: '{}'.",
+                        e
+                    );
+                    None
+                })?;
+            let prev_out = prev_tx.output.get(txin.previous_output.vout as usize)?;
+            in_value = in_value.checked_add(prev_out.value)?;
+        }
+        let out_value: u64 = tx.output.iter().map(|o| o.value).sum();
+        in_value
+            .checked_sub(out_value)
+            .map(bitcoin::Amount::from_sat)
+    }
+
+    /// Note we can only account for the descendants of this transaction which are part of our
+    /// wallet.
+    pub fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        let mut state = self.state.lock().unwrap();
+        self.try_sync_wallet(&mut state);
+        let wallet_txs = self.wallet_txs(&state);
+        if wallet_txs.get(txid).map(|h| *h > 0).unwrap_or(true) {
+            return None;
+        }
+        let tx = state.txs.get(txid)?.clone();
+        let vsize = tx.vsize() as u64;
+        let base = self.tx_fee(&mut state, &tx)?;
+
+        // Gather the unconfirmed wallet transactions descending from this one.
+        let spenders = self.spenders(&state, &wallet_txs);
+        let mut descendants = HashSet::new();
+        let mut to_visit = vec![tx];
+        while let Some(tx) = to_visit.pop() {
+            let parent_txid = tx.txid();
+            for vout in 0..tx.output.len() {
+                let op = bitcoin::OutPoint {
+                    txid: parent_txid,
+                    vout: vout as u32,
+                };
+                for (child_txid, height) in spenders.get(&op).into_iter().flatten() {
+                    if *height <= 0 && descendants.insert(*child_txid) {
+                        to_visit.extend(state.txs.get(child_txid).cloned());
+                    }
+                }
+            }
+        }
+        let mut descendant = base;
+        for child_txid in descendants {
+            let child = state.txs.get(&child_txid)?.clone();
+            descendant = descendant.checked_add(self.tx_fee(&mut state, &child)?)?;
+        }
+
+        Some(MempoolEntry {
+            vsize,
+            fees: MempoolEntryFees { base, descendant },
+        })
+    }
+
+    pub fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        // The server returns a feerate in BTC/kvB, or -1 if it doesn't have enough data.
+        let res = state
+            .client
+            .request("blockchain.estimatefee", &[Json::from(conf_target)])
+            .and_then(|estimate| match estimate.as_f64().filter(|f| *f > 0.0) {
+                Some(btc_kvb) => Ok(Json::from(btc_kvb)),
+                None => state.client.request("blockchain.relayfee", &[]),
+            });
+        let btc_kvb = match res {
+            Ok(btc_kvb) => btc_kvb.as_f64()?,
+            Err(e) => {
+                log::error!(
+                    "Error estimating the feerate with the Electrum server: '{}'.",
+                    e
+                );
+                return None;
+            }
+        };
+        let sat_vb = (btc_kvb * 100_000.0).ceil() as u64;
+        Some(std::cmp::max(sat_vb, 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniscript::bitcoin::{
+        absolute, blockdata::constants::genesis_block, hash_types::TxMerkleNode,
+    };
+    use std::{
+        io::{BufRead, Write},
+        net, thread,
+    };
+
+    // A stand-in for an Electrum server on regtest, serving a chain of `tip_height` blocks and
+    // the given histories.
+    #[derive(Default)]
+    struct MockServer {
+        tip_height: i32,
+        histories: HashMap<String, Vec<(bitcoin::Txid, i32)>>,
+        txs: HashMap<bitcoin::Txid, bitcoin::Transaction>,
+        // Notifications to send before the next response.
+        notifications: Vec<Json>,
+        // Whether to respond garbage to all our requests.
+        broken: bool,
+    }
+
+    fn header(height: i32) -> block::Header {
+        let genesis = genesis_block(bitcoin::Network::Regtest).header;
+        if height == 0 {
+            return genesis;
+        }
+        block::Header {
+            prev_blockhash: header(height - 1).block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: genesis.time + height as u32 * 600,
+            ..genesis
+        }
+    }
+
+    impl MockServer {
+        fn handle(&mut self, method: &str, params: &[Json]) -> Result<Json, String> {
+            if self.broken {
+                return Ok(Json::from("garbage"));
+            }
+            Ok(match method {
+                "server.version" => serde_json::json!(["MockServer 0.1", "1.4"]),
+                "server.ping" => Json::Null,
+                "blockchain.block.header" => {
+                    let height = params[0].as_i64().unwrap() as i32;
+                    if height > self.tip_height {
+                        return Err("Height out of range".to_string());
+                    }
+                    Json::from(encode::serialize_hex(&header(height)))
+                }
+                "blockchain.headers.subscribe" => serde_json::json!({
+                    "height": self.tip_height,
+                    "hex": encode::serialize_hex(&header(self.tip_height)),
+                }),
+                "blockchain.scripthash.subscribe" => self
+                    .histories
+                    .get(params[0].as_str().unwrap())
+                    .map(|h| Json::from(format!("{:?}", h)))
+                    .unwrap_or(Json::Null),
+                "blockchain.scripthash.get_history" => self
+                    .histories
+                    .get(params[0].as_str().unwrap())
+                    .into_iter()
+                    .flatten()
+                    .map(|(txid, height)| {
+                        serde_json::json!({"tx_hash": txid.to_string(), "height": height})
+                    })
+                    .collect(),
+                "blockchain.transaction.get" => {
+                    let txid = bitcoin::Txid::from_str(params[0].as_str().unwrap()).unwrap();
+                    let tx = self.txs.get(&txid).ok_or("Unknown transaction")?;
+                    Json::from(encode::serialize_hex(tx))
+                }
+                "blockchain.transaction.broadcast" => return Err("Bad tx".to_string()),
+                "blockchain.estimatefee" => Json::from(-1),
+                "blockchain.relayfee" => Json::from(0.00001),
+                _ => return Err(format!("Unknown method '{}'", method)),
+            })
+        }
+
+        // Add a transaction to the history of the script it pays to, and of the scripts of the
+        // coins it spends.
+        fn add_tx(&mut self, tx: bitcoin::Transaction, height: i32) {
+            let txid = tx.txid();
+            let mut scripts: Vec<bitcoin::ScriptBuf> =
+                tx.output.iter().map(|o| o.script_pubkey.clone()).collect();
+            for txin in &tx.input {
+                if let Some(prev) = self.txs.get(&txin.previous_output.txid) {
+                    scripts.push(
+                        prev.output[txin.previous_output.vout as usize]
+                            .script_pubkey
+                            .clone(),
+                    );
+                }
+            }
+            for script in scripts {
+                let sh = scripthash(&script);
+                self.histories
+                    .entry(sh.clone())
+                    .or_default()
+                    .push((txid, height));
+                let status = Json::from(format!("{:?}", self.histories[&sh]));
+                self.notifications.push(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [sh, status],
+                }));
+            }
+            self.txs.insert(txid, tx);
+        }
+    }
+
+    fn start_server(server: sync::Arc<sync::Mutex<MockServer>>) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut writer = stream.unwrap();
+                let mut reader = io::BufReader::new(writer.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let req: Json = serde_json::from_str(&line).unwrap();
+                    let mut server = server.lock().unwrap();
+                    let method = req["method"].as_str().unwrap();
+                    let res = match server.handle(method, req["params"].as_array().unwrap()) {
+                        Ok(result) => serde_json::json!({"id": req["id"], "result": result}),
+                        Err(e) => {
+                            serde_json::json!({"id": req["id"], "error": {"code": 1, "message": e}})
+                        }
+                    };
+                    for msg in server.notifications.drain(..).chain(std::iter::once(res)) {
+                        writer.write_all(format!("{}\n", msg).as_bytes()).unwrap();
+                    }
+                    line.clear();
+                }
+            }
+        });
+        addr
+    }
+
+    fn dummy_tx(
+        inputs: &[bitcoin::OutPoint],
+        outputs: &[(bitcoin::ScriptBuf, u64)],
+    ) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|op| bitcoin::TxIn {
+                    previous_output: *op,
+                    ..bitcoin::TxIn::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(spk, value)| bitcoin::TxOut {
+                    script_pubkey: spk.clone(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn electrum_interface() {
+        let desc = LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs").unwrap();
+        let descs = [
+            desc.receive_descriptor().clone(),
+            desc.change_descriptor().clone(),
+        ];
+        let secp = secp256k1::Secp256k1::verification_only();
+        let server = sync::Arc::new(sync::Mutex::new(MockServer {
+            tip_height: 110,
+            ..MockServer::default()
+        }));
+        let config = config::ElectrumConfig {
+            addr: start_server(server.clone()),
+        };
+
+        // The server must be on the expected network.
+        match Electrum::new(
+            &config,
+            bitcoin::Network::Bitcoin,
+            200,
+            std::slice::from_ref(&desc),
+        ) {
+            Err(ElectrumError::NetworkMismatch(..)) => {}
+            _ => panic!("Must fail with a network mismatch"),
+        }
+        let electrum = Electrum::new(
+            &config,
+            bitcoin::Network::Regtest,
+            200,
+            std::slice::from_ref(&desc),
+        )
+        .unwrap();
+        assert_eq!(electrum.genesis_block().hash, header(0).block_hash());
+        let tip = electrum.chain_tip();
+        assert_eq!(tip.height, 110);
+        assert!(electrum.is_in_chain(&tip));
+        assert!(electrum.received_coins(&tip, &descs).is_empty());
+
+        // A foreign transaction pays to our receive address at index 250, beyond the initial
+        // look-ahead, and to the one at index 150. We get both coins.
+        let foreign_tx = dummy_tx(&[], &[(bitcoin::ScriptBuf::new(), 1_000_000)]);
+        let spk_150 = descs[0].derive(150.into(), &secp).script_pubkey();
+        let spk_250 = descs[0].derive(250.into(), &secp).script_pubkey();
+        let deposit_tx = dummy_tx(
+            &[bitcoin::OutPoint::new(foreign_tx.txid(), 0)],
+            &[
+                (spk_150, 100_000),
+                (spk_250, 200_000),
+                (bitcoin::ScriptBuf::new(), 600_000),
+            ],
+        );
+        let deposit_txid = deposit_tx.txid();
+        {
+            let mut server = server.lock().unwrap();
+            server.txs.insert(foreign_tx.txid(), foreign_tx);
+            server.add_tx(deposit_tx, 105);
+        }
+        let genesis = electrum.genesis_block();
+        let mut received = electrum.received_coins(&genesis, &descs);
+        received.sort_by_key(|utxo| utxo.outpoint);
+        assert_eq!(received.len(), 2);
+        assert_eq!(
+            received[0].outpoint,
+            bitcoin::OutPoint::new(deposit_txid, 0)
+        );
+        assert_eq!(received[0].amount.to_sat(), 100_000);
+        assert_eq!(received[0].block_height, Some(105));
+        assert_eq!(
+            received[1].address.clone().assume_checked(),
+            descs[0]
+                .derive(250.into(), &secp)
+                .address(bitcoin::Network::Regtest)
+        );
+        assert!(electrum.received_coins(&tip, &descs).is_empty());
+        assert!(electrum.received_coins(&tip, &descs[1..]).is_empty());
+        assert_eq!(electrum.state.lock().unwrap().next_index, vec![451, 200]);
+        let (confirmed, expired) = electrum.confirmed_coins(&[received[0].outpoint]);
+        assert_eq!(
+            confirmed,
+            vec![(received[0].outpoint, 105, header(105).time)]
+        );
+        assert!(expired.is_empty());
+
+        // Now spend the first coin in an unconfirmed transaction with a change output.
+        let change_spk = descs[1].derive(0.into(), &secp).script_pubkey();
+        let spend_tx = dummy_tx(
+            &[received[0].outpoint],
+            &[(bitcoin::ScriptBuf::new(), 50_000), (change_spk, 49_000)],
+        );
+        let spend_txid = spend_tx.txid();
+        server.lock().unwrap().add_tx(spend_tx.clone(), 0);
+        assert_eq!(
+            electrum.spending_coins(&[received[0].outpoint, received[1].outpoint]),
+            vec![(received[0].outpoint, spend_txid)]
+        );
+        assert!(electrum
+            .spent_coins(&[(received[0].outpoint, spend_txid)])
+            .is_empty());
+        let change_coins = electrum.received_coins(&tip, &descs);
+        assert_eq!(change_coins.len(), 1);
+        assert_eq!(change_coins[0].block_height, None);
+        let entry = electrum.mempool_entry(&spend_txid).unwrap();
+        assert_eq!(entry.fees.base.to_sat(), 1_000);
+        assert_eq!(entry.fees.descendant.to_sat(), 1_000);
+        assert_eq!(entry.vsize, spend_tx.vsize() as u64);
+        assert_eq!(electrum.wallet_transaction(&spend_txid).unwrap().1, None);
+
+        // Once it's confirmed, the coin is spent.
+        {
+            let mut server = server.lock().unwrap();
+            server.tip_height = 111;
+            for history in server.histories.values_mut() {
+                for entry in history.iter_mut().filter(|e| e.0 == spend_txid) {
+                    entry.1 = 111;
+                }
+            }
+            let shs: Vec<String> = server.histories.keys().cloned().collect();
+            for sh in shs {
+                let status = Json::from(format!("{:?}", server.histories[&sh]));
+                server.notifications.push(serde_json::json!({
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [sh, status],
+                }));
+            }
+        }
+        let spent = electrum.spent_coins(&[(received[0].outpoint, spend_txid)]);
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent[0].2.height, 111);
+        assert!(electrum.mempool_entry(&spend_txid).is_none());
+
+        // Block lookups by date, broadcast errors and the feerate estimation fallback.
+        assert_eq!(
+            electrum
+                .tip_before_timestamp(header(50).time + 1)
+                .unwrap()
+                .height,
+            50
+        );
+        assert!(matches!(
+            electrum.broadcast_tx(&spend_tx),
+            Err(ElectrumError::Server(_))
+        ));
+        assert_eq!(electrum.feerate_estimate(2), Some(1));
+
+        // After a reorg, we find the common ancestor from the tips we've seen.
+        let former_tip = electrum.chain_tip();
+        let stale_tip = BlockChainTip {
+            hash: bitcoin::BlockHash::all_zeros(),
+            height: former_tip.height + 1,
+        };
+        assert_eq!(electrum.common_ancestor(&stale_tip), Some(former_tip));
+
+        // If the server starts sending garbage, we don't crash and keep the last state we could
+        // sync.
+        {
+            let mut server = server.lock().unwrap();
+            server.broken = true;
+            server.tip_height = 112;
+        }
+        assert_eq!(electrum.chain_tip(), former_tip);
+        assert!(electrum.is_in_chain(&former_tip));
+        assert_eq!(electrum.common_ancestor(&stale_tip), Some(former_tip));
+        assert_eq!(electrum.received_coins(&genesis, &descs).len(), 3);
+        assert_eq!(
+            electrum.spent_coins(&[(received[0].outpoint, spend_txid)]),
+            Vec::new()
+        );
+        assert!(matches!(
+            electrum.broadcast_tx(&spend_tx),
+            Err(ElectrumError::InvalidResponse(_))
+        ));
+        assert_eq!(electrum.feerate_estimate(2), None);
+    }
+}
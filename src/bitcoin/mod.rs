@@ -2,14 +2,16 @@
 //!
 //! Broadcast transactions, poll for new unspent coins, gather fee estimates.
 
+pub mod cbf;
 pub mod d;
+pub mod electrum;
 pub mod poller;
 
 use crate::{
     bitcoin::d::{BitcoindError, CachedTxGetter, LSBlockEntry},
     descriptors,
 };
-pub use d::SyncProgress;
+pub use d::{MempoolEntry, SyncProgress};
 
 use std::{fmt, sync};
 
@@ -99,6 +101,10 @@ pub trait BitcoinInterface: Send {
     /// Rescan progress percentage. Between 0 and 1.
     fn rescan_progress(&self) -> Option<f64>;
 
+    /// Make sure the scripts derived from these descriptors are watched for all the derivation
+    /// indexes below `end_index`.
+    fn extend_watched_range(&self, descs: &[descriptors::SinglePathLianaDesc], end_index: u32);
+
     /// Get the last block chain tip with a timestamp below this. Timestamp must be a valid block
     /// timestamp.
     fn block_before_date(&self, timestamp: u32) -> Option<BlockChainTip>;
@@ -108,6 +114,14 @@ pub trait BitcoinInterface: Send {
         &self,
         txid: &bitcoin::Txid,
     ) -> Option<(bitcoin::Transaction, Option<Block>)>;
+
+    /// Get the size and fees of this unconfirmed transaction. Returns `None` if it is not in
+    /// our mempool.
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry>;
+
+    /// Estimate the feerate, in sats/vb, for a transaction to confirm within this number of
+    /// blocks.
+    fn feerate_estimate(&self, conf_target: u16) -> Option<u64>;
 }
 
 impl BitcoinInterface for d::BitcoinD {
@@ -312,6 +326,10 @@ impl BitcoinInterface for d::BitcoinD {
         self.rescan_progress()
     }
 
+    fn extend_watched_range(&self, descs: &[descriptors::SinglePathLianaDesc], end_index: u32) {
+        self.extend_range(descs, end_index)
+    }
+
     fn block_before_date(&self, timestamp: u32) -> Option<BlockChainTip> {
         self.tip_before_timestamp(timestamp)
     }
@@ -327,6 +345,209 @@ impl BitcoinInterface for d::BitcoinD {
     ) -> Option<(bitcoin::Transaction, Option<Block>)> {
         self.get_transaction(txid).map(|res| (res.tx, res.block))
     }
+
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        self.mempool_entry(txid)
+    }
+
+    fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        self.feerate_estimate(conf_target)
+    }
+}
+
+impl BitcoinInterface for electrum::Electrum {
+    fn genesis_block(&self) -> BlockChainTip {
+        self.genesis_block()
+    }
+
+    fn sync_progress(&self) -> SyncProgress {
+        self.sync_progress()
+    }
+
+    fn chain_tip(&self) -> BlockChainTip {
+        self.chain_tip()
+    }
+
+    fn is_in_chain(&self, tip: &BlockChainTip) -> bool {
+        self.is_in_chain(tip)
+    }
+
+    fn received_coins(
+        &self,
+        tip: &BlockChainTip,
+        descs: &[descriptors::SinglePathLianaDesc],
+    ) -> Vec<UTxO> {
+        self.received_coins(tip, descs)
+    }
+
+    fn confirmed_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> (Vec<(bitcoin::OutPoint, i32, u32)>, Vec<bitcoin::OutPoint>) {
+        self.confirmed_coins(outpoints)
+    }
+
+    fn spending_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid)> {
+        self.spending_coins(outpoints)
+    }
+
+    fn spent_coins(
+        &self,
+        outpoints: &[(bitcoin::OutPoint, bitcoin::Txid)],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid, Block)> {
+        self.spent_coins(outpoints)
+    }
+
+    fn common_ancestor(&self, tip: &BlockChainTip) -> Option<BlockChainTip> {
+        self.common_ancestor(tip)
+    }
+
+    fn broadcast_tx(&self, tx: &bitcoin::Transaction) -> Result<(), String> {
+        // The server is remote, don't crash if it fails us.
+        self.broadcast_tx(tx).map_err(|e| e.to_string())
+    }
+
+    fn start_rescan(
+        &self,
+        desc: &descriptors::LianaDescriptor,
+        _timestamp: u32,
+    ) -> Result<(), String> {
+        // The Electrum server indexes the whole block chain and we keep the history of our
+        // scripts up to date. There is nothing to rescan, but the descriptor may be a new one.
+        self.watch_descriptor(desc);
+        Ok(())
+    }
+
+    fn rescan_progress(&self) -> Option<f64> {
+        None
+    }
+
+    fn extend_watched_range(&self, _: &[descriptors::SinglePathLianaDesc], _: u32) {
+        // We always watch the scripts up to the gap limit past the last used one.
+    }
+
+    fn block_before_date(&self, timestamp: u32) -> Option<BlockChainTip> {
+        self.tip_before_timestamp(timestamp)
+    }
+
+    fn tip_time(&self) -> Option<u32> {
+        self.tip_time()
+    }
+
+    fn wallet_transaction(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Option<(bitcoin::Transaction, Option<Block>)> {
+        self.wallet_transaction(txid)
+    }
+
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        self.mempool_entry(txid)
+    }
+
+    fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        self.feerate_estimate(conf_target)
+    }
+}
+
+impl BitcoinInterface for cbf::Cbf {
+    fn genesis_block(&self) -> BlockChainTip {
+        self.genesis_block()
+    }
+
+    fn sync_progress(&self) -> SyncProgress {
+        self.sync_progress()
+    }
+
+    fn chain_tip(&self) -> BlockChainTip {
+        self.chain_tip()
+    }
+
+    fn is_in_chain(&self, tip: &BlockChainTip) -> bool {
+        self.is_in_chain(tip)
+    }
+
+    fn received_coins(
+        &self,
+        tip: &BlockChainTip,
+        descs: &[descriptors::SinglePathLianaDesc],
+    ) -> Vec<UTxO> {
+        self.received_coins(tip, descs)
+    }
+
+    fn confirmed_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> (Vec<(bitcoin::OutPoint, i32, u32)>, Vec<bitcoin::OutPoint>) {
+        self.confirmed_coins(outpoints)
+    }
+
+    fn spending_coins(
+        &self,
+        outpoints: &[bitcoin::OutPoint],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid)> {
+        self.spending_coins(outpoints)
+    }
+
+    fn spent_coins(
+        &self,
+        outpoints: &[(bitcoin::OutPoint, bitcoin::Txid)],
+    ) -> Vec<(bitcoin::OutPoint, bitcoin::Txid, Block)> {
+        self.spent_coins(outpoints)
+    }
+
+    fn common_ancestor(&self, tip: &BlockChainTip) -> Option<BlockChainTip> {
+        self.common_ancestor(tip)
+    }
+
+    fn broadcast_tx(&self, tx: &bitcoin::Transaction) -> Result<(), String> {
+        // The connection to our peer may be temporarily down, don't crash.
+        self.broadcast_tx(tx).map_err(|e| e.to_string())
+    }
+
+    fn start_rescan(
+        &self,
+        desc: &descriptors::LianaDescriptor,
+        timestamp: u32,
+    ) -> Result<(), String> {
+        self.start_rescan(desc, timestamp)
+    }
+
+    fn rescan_progress(&self) -> Option<f64> {
+        self.rescan_progress()
+    }
+
+    fn extend_watched_range(&self, _: &[descriptors::SinglePathLianaDesc], _: u32) {
+        // We always watch the scripts up to the gap limit past the last used one.
+    }
+
+    fn block_before_date(&self, timestamp: u32) -> Option<BlockChainTip> {
+        self.tip_before_timestamp(timestamp)
+    }
+
+    fn tip_time(&self) -> Option<u32> {
+        self.tip_time()
+    }
+
+    fn wallet_transaction(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Option<(bitcoin::Transaction, Option<Block>)> {
+        self.wallet_transaction(txid)
+    }
+
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        self.mempool_entry(txid)
+    }
+
+    fn feerate_estimate(&self, _conf_target: u16) -> Option<u64> {
+        // We don't see the mempool and the block filters don't tell about the fees paid. Estimating
+        // the feerate is therefore not supported with this backend: the caller must provide one.
+        None
+    }
 }
 
 // FIXME: do we need to repeat the entire trait implemenation? Isn't there a nicer way?
@@ -396,6 +617,10 @@ impl BitcoinInterface for sync::Arc<sync::Mutex<dyn BitcoinInterface + 'static>>
         self.lock().unwrap().rescan_progress()
     }
 
+    fn extend_watched_range(&self, descs: &[descriptors::SinglePathLianaDesc], end_index: u32) {
+        self.lock().unwrap().extend_watched_range(descs, end_index)
+    }
+
     fn block_before_date(&self, timestamp: u32) -> Option<BlockChainTip> {
         self.lock().unwrap().block_before_date(timestamp)
     }
@@ -410,6 +635,14 @@ impl BitcoinInterface for sync::Arc<sync::Mutex<dyn BitcoinInterface + 'static>>
     ) -> Option<(bitcoin::Transaction, Option<Block>)> {
         self.lock().unwrap().wallet_transaction(txid)
     }
+
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        self.lock().unwrap().mempool_entry(txid)
+    }
+
+    fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        self.lock().unwrap().feerate_estimate(conf_target)
+    }
 }
 
 // FIXME: We could avoid this type (and all the conversions entailing allocations) if bitcoind
@@ -0,0 +1,211 @@
+//! Event hooks
+//!
+//! Shell commands run by the poller upon events concerning our wallets. The event is passed to
+//! the command as JSON on its standard input.
+//!
+//! The commands are run one at a time by a dedicated thread, each within a time limit.
+
+use crate::{config::HooksConfig, events::Event};
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread, time,
+};
+
+// How long a command may run before we kill it.
+const HOOK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+// How many events may be waiting for their command to be run. Past this, events are dropped.
+const MAX_PENDING_HOOKS: usize = 1_000;
+
+/// The commands to run upon events.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    config: HooksConfig,
+    /// The commands to run, along with the event to pass them, are sent to the worker thread.
+    sender: mpsc::SyncSender<(String, Vec<u8>)>,
+}
+
+impl Hooks {
+    /// Start the thread running the commands. It stops once all the copies of the returned
+    /// `Hooks` were dropped.
+    pub fn new(config: HooksConfig) -> Hooks {
+        let (sender, receiver) = mpsc::sync_channel::<(String, Vec<u8>)>(MAX_PENDING_HOOKS);
+        let res = thread::Builder::new()
+            .name("Event hooks".to_string())
+            .spawn(move || {
+                for (command, event_json) in receiver {
+                    if let Err(e) = run_command(&command, &event_json, HOOK_TIMEOUT) {
+                        log::error!("Error running event hook '{}': '{}'.", command, e);
+                    }
+                }
+            });
+        if let Err(e) = res {
+            log::error!("Error spawning the thread to run event hooks: '{}'.", e);
+        }
+        Hooks { config, sender }
+    }
+
+    // The command to run for this event, if any.
+    fn command(&self, event: &Event) -> Option<&str> {
+        match event {
+            Event::CoinReceived { .. } => self.config.coin_received.as_deref(),
+            Event::CoinConfirmed { .. } => self.config.coin_confirmed.as_deref(),
+            Event::CoinSpent { .. } => self.config.coin_spent.as_deref(),
+            Event::RecoveryAvailable { .. } => self.config.recovery_available.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Queue the command configured for this event, if any, to be run. This does not wait for the
+    /// command to complete.
+    pub fn run(&self, event: &Event) {
+        let command = match self.command(event) {
+            Some(command) => command.to_string(),
+            None => return,
+        };
+        let event_json = serde_json::to_vec(event).expect("Serializing an event must not fail");
+
+        // Don't block the poller on slow commands.
+        match self.sender.try_send((command, event_json)) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full((command, _))) => log::error!(
+                "Too many event hooks pending. Not running '{}' for event {:?}.",
+                command,
+                event
+            ),
+            Err(mpsc::TrySendError::Disconnected((command, _))) => log::error!(
+                "Event hooks thread is gone. Not running '{}' for event {:?}.",
+                command,
+                event
+            ),
+        }
+    }
+}
+
+// Run this command through the system shell, feeding it the given bytes on stdin. Wait for it to
+// complete, killing it if it takes longer than `timeout`.
+fn run_command(command: &str, stdin: &[u8], timeout: time::Duration) -> Result<(), std::io::Error> {
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+
+    log::debug!("Running event hook '{}'.", command);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut child_stdin) = child.stdin.take() {
+        // The command may not read its stdin. Don't treat it as an error.
+        if let Err(e) = child_stdin.write_all(stdin) {
+            log::debug!("Error writing the event to the hook's stdin: '{}'.", e);
+        }
+    }
+
+    let start = time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                log::error!("Event hook '{}' failed: {}.", command, status);
+            }
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            log::error!(
+                "Event hook '{}' did not complete within {} seconds. Killing it.",
+                command,
+                timeout.as_secs()
+            );
+            child.kill()?;
+            // Reap it.
+            child.wait()?;
+            return Ok(());
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testutils::tmp_dir;
+
+    use std::{fs, str::FromStr, time};
+
+    use miniscript::bitcoin;
+
+    #[test]
+    fn hooks_run() {
+        let tmp_dir = tmp_dir();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let received_path = tmp_dir.join("received");
+        let hooks = Hooks::new(HooksConfig {
+            coin_received: Some(format!("cat > '{}'", received_path.display())),
+            coin_confirmed: None,
+            coin_spent: None,
+            recovery_available: Some("exit 1".to_string()),
+        });
+
+        // Nothing configured for this event.
+        let outpoint = bitcoin::OutPoint::from_str(
+            "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:1",
+        )
+        .unwrap();
+        let confirmed = Event::CoinConfirmed {
+            wallet: "main".to_string(),
+            outpoint,
+            height: 10,
+            time: 1_000,
+        };
+        assert!(hooks.command(&confirmed).is_none());
+        hooks.run(&confirmed);
+
+        // A failing command is not an issue.
+        hooks.run(&Event::RecoveryAvailable {
+            wallet: "main".to_string(),
+            outpoint,
+            timelock: 144,
+        });
+
+        // The event is passed as JSON on stdin.
+        let received = Event::CoinReceived {
+            wallet: "main".to_string(),
+            outpoint,
+            amount: bitcoin::Amount::from_sat(42_000),
+            derivation_index: 0,
+            is_change: false,
+        };
+        hooks.run(&received);
+        let start = time::Instant::now();
+        loop {
+            let content = fs::read(&received_path).unwrap_or_default();
+            if !content.is_empty() {
+                if let Ok(event) = serde_json::from_slice::<Event>(&content) {
+                    assert_eq!(event, received);
+                    break;
+                }
+            }
+            assert!(start.elapsed() < time::Duration::from_secs(10));
+            thread::sleep(time::Duration::from_millis(50));
+        }
+
+        // Commands are killed if they take too long.
+        let start = time::Instant::now();
+        run_command("sleep 10", b"", time::Duration::from_millis(200)).unwrap();
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+}
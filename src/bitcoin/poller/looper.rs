@@ -1,10 +1,13 @@
 use crate::{
+    bitcoin::poller::hooks::Hooks,
     bitcoin::{BitcoinInterface, BlockChainTip, UTxO},
-    database::{Coin, DatabaseConnection, DatabaseInterface},
+    database::{Coin, CoinStatus, DatabaseConnection, DatabaseInterface, Wallet},
     descriptors,
+    events::{Event, EventNotifier},
 };
 
 use std::{
+    cmp,
     sync::{self, atomic},
     thread, time,
 };
@@ -20,6 +23,57 @@ struct UpdatedCoins {
     pub spent: Vec<(bitcoin::OutPoint, bitcoin::Txid, i32, u32)>,
 }
 
+impl UpdatedCoins {
+    /// The events corresponding to these updates of the coins of the given wallet.
+    pub fn events(&self, wallet: &str) -> Vec<Event> {
+        let received = self.received.iter().map(|coin| Event::CoinReceived {
+            wallet: wallet.to_string(),
+            outpoint: coin.outpoint,
+            amount: coin.amount,
+            derivation_index: coin.derivation_index.into(),
+            is_change: coin.is_change,
+        });
+        let confirmed =
+            self.confirmed
+                .iter()
+                .map(|(outpoint, height, time)| Event::CoinConfirmed {
+                    wallet: wallet.to_string(),
+                    outpoint: *outpoint,
+                    height: *height,
+                    time: *time,
+                });
+        let expired = self.expired.iter().map(|outpoint| Event::CoinExpired {
+            wallet: wallet.to_string(),
+            outpoint: *outpoint,
+        });
+        let spending = self
+            .spending
+            .iter()
+            .map(|(outpoint, spend_txid)| Event::CoinSpending {
+                wallet: wallet.to_string(),
+                outpoint: *outpoint,
+                spend_txid: *spend_txid,
+            });
+        let spent = self
+            .spent
+            .iter()
+            .map(|(outpoint, spend_txid, height, time)| Event::CoinSpent {
+                wallet: wallet.to_string(),
+                outpoint: *outpoint,
+                spend_txid: *spend_txid,
+                height: *height,
+                time: *time,
+            });
+
+        received
+            .chain(confirmed)
+            .chain(expired)
+            .chain(spending)
+            .chain(spent)
+            .collect()
+    }
+}
+
 // Update the state of our coins. There may be new unspent, and existing ones may become confirmed
 // or spent.
 // NOTE: A coin may be updated multiple times at once. That is, a coin may be received, confirmed,
@@ -31,6 +85,7 @@ fn update_coins(
     db_conn: &mut Box<dyn DatabaseConnection>,
     previous_tip: &BlockChainTip,
     descs: &[descriptors::SinglePathLianaDesc],
+    gap_limit: u32,
     secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
 ) -> UpdatedCoins {
     let network = db_conn.network();
@@ -56,13 +111,17 @@ fn update_coins(
             }
         };
         if let Some((derivation_index, is_change)) = db_conn.derivation_index_by_address(&address) {
-            // First of if we are receiving coins that are beyond our next derivation index,
-            // adjust it.
-            if derivation_index > db_conn.receive_index() {
-                db_conn.set_receive_index(derivation_index, secp);
+            // First of if we are receiving coins that are at or beyond our next derivation
+            // index, adjust it past the used one. This extends the addresses we store up to the
+            // gap limit past it.
+            let next_index = derivation_index
+                .increment()
+                .expect("Can't get into hardened territory");
+            if next_index > db_conn.receive_index() {
+                db_conn.set_receive_index(next_index, secp);
             }
-            if derivation_index > db_conn.change_index() {
-                db_conn.set_change_index(derivation_index, secp);
+            if next_index > db_conn.change_index() {
+                db_conn.set_change_index(next_index, secp);
             }
 
             // Now record this coin as a newly received one.
@@ -90,6 +149,14 @@ fn update_coins(
     }
     log::debug!("Newly received coins: {:?}", received);
 
+    // The next derivation indexes may have moved, either above or by handing out new addresses.
+    // Make sure the Bitcoin backend watches as many addresses as we store past them.
+    let next_index = cmp::max(db_conn.receive_index(), db_conn.change_index());
+    let end_index = u32::from(next_index)
+        .checked_add(gap_limit)
+        .expect("Derivation index must not overflow");
+    bit.extend_watched_range(descs, end_index);
+
     // We need to take the newly received ones into account as well, as they may have been
     // confirmed within the previous tip and the current one, and we may not poll this chunk of the
     // chain anymore.
@@ -152,6 +219,42 @@ fn update_coins(
     }
 }
 
+// The events for the coins of this wallet for which a recovery path became available as we moved
+// from the previous tip to the new one. A recovery path is available for a coin once a transaction
+// using it could be included in the next block.
+fn recovery_events(
+    wallet: &Wallet,
+    db_conn: &mut Box<dyn DatabaseConnection>,
+    previous_tip: &BlockChainTip,
+    new_tip: &BlockChainTip,
+) -> Vec<Event> {
+    let timelocks: Vec<u16> = wallet
+        .main_descriptor
+        .policy()
+        .recovery_paths()
+        .keys()
+        .copied()
+        .collect();
+    db_conn
+        .coins(&[CoinStatus::Confirmed], &[])
+        .into_values()
+        .flat_map(|coin| {
+            let height = coin.block_info.expect("Coin is confirmed").height;
+            timelocks
+                .iter()
+                .filter(move |timelock| {
+                    let available_at = height + i32::from(**timelock);
+                    available_at > previous_tip.height + 1 && available_at <= new_tip.height + 1
+                })
+                .map(move |timelock| Event::RecoveryAvailable {
+                    wallet: wallet.name.clone(),
+                    outpoint: coin.outpoint,
+                    timelock: *timelock,
+                })
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone, Copy)]
 enum TipUpdate {
     // The best block is still the same as in the previous poll.
@@ -202,7 +305,8 @@ fn new_tip(bit: &impl BitcoinInterface, current_tip: &BlockChainTip) -> TipUpdat
 fn updates(
     bit: &impl BitcoinInterface,
     db: &impl DatabaseInterface,
-    descs: &[descriptors::SinglePathLianaDesc],
+    events: &EventNotifier,
+    gap_limit: u32,
     secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
 ) {
     let mut db_conn = db.connection();
@@ -217,41 +321,97 @@ fn updates(
             // between our former chain and the new one, then restart fresh.
             db_conn.rollback_tip(&new_tip);
             log::info!("Tip was rolled back to '{}'.", new_tip);
-            return updates(bit, db, descs, secp);
+            return updates(bit, db, events, gap_limit, secp);
         }
     };
 
-    // Then check the state of our coins. Do it even if the tip did not change since last poll, as
-    // we may have unconfirmed transactions.
-    let updated_coins = update_coins(bit, &mut db_conn, &current_tip, descs, secp);
+    // Then check the state of the coins of each of our wallets. Do it even if the tip did not
+    // change since last poll, as we may have unconfirmed transactions.
+    let wallets_updates: Vec<(Wallet, Box<dyn DatabaseConnection>, UpdatedCoins)> = db_conn
+        .wallets()
+        .into_iter()
+        .map(|wallet| {
+            let mut wallet_conn = db.wallet_connection(wallet.id);
+            let descs = [
+                wallet.main_descriptor.receive_descriptor().clone(),
+                wallet.main_descriptor.change_descriptor().clone(),
+            ];
+            let updated_coins =
+                update_coins(bit, &mut wallet_conn, &current_tip, &descs, gap_limit, secp);
+            (wallet, wallet_conn, updated_coins)
+        })
+        .collect();
 
     // If the tip changed while we were polling our Bitcoin interface, start over.
     if bit.chain_tip() != latest_tip {
         log::info!("Chain tip changed while we were updating our state. Starting over.");
-        return updates(bit, db, descs, secp);
+        return updates(bit, db, events, gap_limit, secp);
     }
 
     // The chain tip did not change since we started our updates. Record them and the latest tip.
     // Having the tip in database means that, as far as the chain is concerned, we've got all
     // updates up to this block. But not more.
-    db_conn.new_unspent_coins(&updated_coins.received);
-    db_conn.remove_coins(&updated_coins.expired);
-    db_conn.confirm_coins(&updated_coins.confirmed);
-    db_conn.spend_coins(&updated_coins.spending);
-    db_conn.confirm_spend(&updated_coins.spent);
+    for (wallet, mut wallet_conn, updated_coins) in wallets_updates {
+        wallet_conn.new_unspent_coins(&updated_coins.received);
+        wallet_conn.remove_coins(&updated_coins.expired);
+        wallet_conn.confirm_coins(&updated_coins.confirmed);
+        wallet_conn.spend_coins(&updated_coins.spending);
+        wallet_conn.confirm_spend(&updated_coins.spent);
+        update_spends_status(&mut wallet_conn);
+        for event in updated_coins.events(&wallet.name) {
+            events.notify(event);
+        }
+        if latest_tip.height > current_tip.height {
+            for event in recovery_events(&wallet, &mut wallet_conn, &current_tip, &latest_tip) {
+                events.notify(event);
+            }
+        }
+    }
     if latest_tip != current_tip {
         db_conn.update_tip(&latest_tip);
         log::debug!("New tip: '{}'", latest_tip);
+        events.notify(Event::NewTip {
+            height: latest_tip.height,
+            hash: latest_tip.hash,
+        });
     }
 
     log::debug!("Updates done.");
 }
 
+// Update the status of the stored Spend transactions according to the current state of the coins
+// they spend. Notably, a Spend transaction is invalidated as soon as one of its coins gets spent by
+// another transaction.
+fn update_spends_status(db_conn: &mut Box<dyn DatabaseConnection>) {
+    for spend in db_conn.list_spend() {
+        let prevouts: Vec<bitcoin::OutPoint> = spend
+            .psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+        let coins = db_conn.coins_by_outpoints(&prevouts);
+        let status = spend.status.updated(&spend.psbt, &coins);
+        if status != spend.status {
+            let txid = spend.psbt.unsigned_tx.txid();
+            log::info!(
+                "Status of Spend transaction '{}' changed from {:?} to {:?}.",
+                txid,
+                spend.status,
+                status
+            );
+            db_conn.set_spend_status(&txid, status);
+        }
+    }
+}
+
 // Check if there is any rescan of the backend ongoing or one that just finished.
 fn rescan_check(
     bit: &impl BitcoinInterface,
     db: &impl DatabaseInterface,
-    descs: &[descriptors::SinglePathLianaDesc],
+    events: &EventNotifier,
+    gap_limit: u32,
     secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
 ) {
     log::debug!("Checking the state of an ongoing rescan if there is any");
@@ -261,9 +421,20 @@ fn rescan_check(
     // the backend, we treat it as completed.
     // Upon completion of the rescan from the given timestamp on the backend, we rollback our state
     // down to the height before this timestamp to rescan everything that happened since then.
-    let rescan_timestamp = db_conn.rescan_timestamp();
+    // The backend rescans for all wallets at once, so we rollback down to the earliest of the
+    // timestamps of the wallets we asked a rescan for.
+    let rescanning_wallets: Vec<Wallet> = db_conn
+        .wallets()
+        .into_iter()
+        .filter(|wallet| wallet.rescan_timestamp.is_some())
+        .collect();
+    let rescan_timestamp = rescanning_wallets
+        .iter()
+        .filter_map(|wallet| wallet.rescan_timestamp)
+        .min();
     if let Some(progress) = bit.rescan_progress() {
         log::info!("Rescan progress: {:.2}%.", progress * 100.0);
+        events.notify(Event::RescanProgress { progress });
         if rescan_timestamp.is_none() {
             log::warn!("Backend is rescanning but we didn't ask for it.");
         }
@@ -285,12 +456,15 @@ fn rescan_check(
             }
         };
         db_conn.rollback_tip(&rescan_tip);
-        db_conn.complete_rescan();
+        for wallet in rescanning_wallets {
+            db.wallet_connection(wallet.id).complete_rescan();
+        }
         log::info!(
             "Rolling back our internal tip to '{}' to update our internal state with past transactions.",
             rescan_tip
         );
-        updates(bit, db, descs, secp)
+        events.notify(Event::RescanCompleted {});
+        updates(bit, db, events, gap_limit, secp)
     } else {
         log::debug!("No ongoing rescan.");
     }
@@ -321,17 +495,17 @@ fn sync_poll_interval() -> time::Duration {
 pub fn looper(
     bit: sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
     db: sync::Arc<sync::Mutex<dyn DatabaseInterface>>,
+    events: EventNotifier,
+    hooks: Option<Hooks>,
     shutdown: sync::Arc<atomic::AtomicBool>,
     poll_interval: time::Duration,
-    desc: descriptors::LianaDescriptor,
+    gap_limit: u32,
 ) {
     let mut last_poll = None;
     let mut synced = false;
-    let descs = [
-        desc.receive_descriptor().clone(),
-        desc.change_descriptor().clone(),
-    ];
     let secp = secp256k1::Secp256k1::verification_only();
+    // The hooks are run for the events emitted while polling.
+    let hooks = hooks.map(|hooks| (hooks, events.subscribe()));
 
     maybe_initialize_tip(&bit, &db);
 
@@ -369,7 +543,12 @@ pub fn looper(
             }
         }
 
-        updates(&bit, &db, &descs, &secp);
-        rescan_check(&bit, &db, &descs, &secp);
+        updates(&bit, &db, &events, gap_limit, &secp);
+        rescan_check(&bit, &db, &events, gap_limit, &secp);
+        if let Some((hooks, hooks_events)) = &hooks {
+            for event in hooks_events.try_iter() {
+                hooks.run(&event);
+            }
+        }
     }
 }
@@ -1,9 +1,12 @@
+mod hooks;
 mod looper;
 
+pub use hooks::Hooks;
+
 use crate::{
     bitcoin::{poller::looper::looper, BitcoinInterface},
     database::DatabaseInterface,
-    descriptors,
+    events::EventNotifier,
 };
 
 use std::{
@@ -21,15 +24,17 @@ impl Poller {
     pub fn start(
         bit: sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
         db: sync::Arc<sync::Mutex<dyn DatabaseInterface>>,
+        events: EventNotifier,
+        hooks: Option<Hooks>,
         poll_interval: time::Duration,
-        desc: descriptors::LianaDescriptor,
+        gap_limit: u32,
     ) -> Poller {
         let shutdown = sync::Arc::from(atomic::AtomicBool::from(false));
         let handle = thread::Builder::new()
             .name("Bitcoin poller".to_string())
             .spawn({
                 let shutdown = shutdown.clone();
-                move || looper(bit, db, shutdown, poll_interval, desc)
+                move || looper(bit, db, events, hooks, shutdown, poll_interval, gap_limit)
             })
             .expect("Must not fail");
 
@@ -0,0 +1,326 @@
+//! Coin selection
+//!
+//! Pick the coins to spend when the caller of `createspend` did not provide any, or when the inputs
+//! of a transaction being replaced are not sufficient. We first try to find a changeless solution
+//! using branch-and-bound, and fall back to a single random draw.
+
+use crate::random;
+
+use miniscript::bitcoin::{
+    self,
+    hashes::{sha256, Hash, HashEngine},
+};
+
+// Bound the number of branches explored by the branch-and-bound search.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// A coin that may be selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub outpoint: bitcoin::OutPoint,
+    pub amount: bitcoin::Amount,
+}
+
+/// The parameters of the transaction we are selecting coins for.
+#[derive(Debug, Clone, Copy)]
+pub struct SelectionParams {
+    /// The sum of the values of the outputs, in sats.
+    pub out_value: u64,
+    /// The size of the transaction without any input, in virtual bytes.
+    pub base_vb: u64,
+    /// The size of a single input spending one of our coins, in virtual bytes.
+    pub input_vb: u64,
+    /// The size of a change output, in virtual bytes.
+    pub change_vb: u64,
+    /// The target feerate, in sats per virtual byte.
+    pub feerate_vb: u64,
+    /// The minimum value of a change output for it to be worth creating, in sats.
+    pub min_change: u64,
+}
+
+impl SelectionParams {
+    // The value the effective values of the selected coins must add up to.
+    fn target(&self) -> u64 {
+        self.out_value
+            .checked_add(self.base_vb.checked_mul(self.feerate_vb).unwrap())
+            .unwrap()
+    }
+
+    // The value of a coin minus the fee for spending it.
+    fn effective_value(&self, amount: bitcoin::Amount) -> Option<u64> {
+        amount
+            .to_sat()
+            .checked_sub(self.input_vb.checked_mul(self.feerate_vb).unwrap())
+            .filter(|v| *v > 0)
+    }
+
+    // How much it costs to create a change output now and to spend it later. Any changeless
+    // solution wasting less than this is preferable to creating a change output.
+    fn cost_of_change(&self) -> u64 {
+        self.change_vb
+            .checked_add(self.input_vb)
+            .unwrap()
+            .checked_mul(self.feerate_vb)
+            .unwrap()
+    }
+}
+
+/// Select coins among the candidates to fund a transaction with the given parameters, in addition
+/// to the coins which must be spent. Returns `None` if the candidates are not sufficient.
+pub fn select_coins(
+    must_select: &[Candidate],
+    candidates: &[Candidate],
+    params: &SelectionParams,
+) -> Option<Vec<bitcoin::OutPoint>> {
+    let mut selection: Vec<bitcoin::OutPoint> = must_select.iter().map(|c| c.outpoint).collect();
+
+    // The coins which must be spent reduce the value we need from the other candidates. Don't
+    // select any more coin if they are sufficient.
+    let must_value: u64 = must_select.iter().map(|c| c.amount.to_sat()).sum();
+    let must_fee = (must_select.len() as u64)
+        .checked_mul(params.input_vb)
+        .unwrap()
+        .checked_mul(params.feerate_vb)
+        .unwrap();
+    let target = match params
+        .target()
+        .checked_add(must_fee)
+        .unwrap()
+        .checked_sub(must_value)
+    {
+        Some(target) if target > 0 => target,
+        _ => return Some(selection),
+    };
+
+    // Coins that cost more to spend than they are worth are never selected.
+    let mut utxos: Vec<(bitcoin::OutPoint, u64)> = candidates
+        .iter()
+        .filter(|c| !selection.contains(&c.outpoint))
+        .filter_map(|c| Some((c.outpoint, params.effective_value(c.amount)?)))
+        .collect();
+
+    // Try to find a changeless solution first.
+    utxos.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let values: Vec<u64> = utxos.iter().map(|u| u.1).collect();
+    let upper_bound = target.checked_add(params.cost_of_change()).unwrap();
+    if let Some(indexes) = branch_and_bound(&values, target, upper_bound) {
+        selection.extend(indexes.into_iter().map(|i| utxos[i].0));
+        return Some(selection);
+    }
+
+    // Otherwise draw coins randomly until we have enough for a change output.
+    shuffle(&mut utxos);
+    let with_change_target = target
+        .checked_add(params.change_vb.checked_mul(params.feerate_vb).unwrap())
+        .unwrap()
+        .checked_add(params.min_change)
+        .unwrap();
+    let mut selected_value: u64 = 0;
+    for (outpoint, value) in utxos {
+        selection.push(outpoint);
+        selected_value = selected_value.checked_add(value).unwrap();
+        if selected_value >= with_change_target {
+            return Some(selection);
+        }
+    }
+
+    // We can't afford a change output, but may still have enough for a changeless transaction.
+    if selected_value >= target {
+        Some(selection)
+    } else {
+        None
+    }
+}
+
+// Search for a subset of the values whose sum is within [target, upper_bound], minimizing the
+// excess. The values must be sorted in decreasing order. Returns the indexes of the values.
+fn branch_and_bound(values: &[u64], target: u64, upper_bound: u64) -> Option<Vec<usize>> {
+    // The sum of all the values from a given index, to prune branches that can't reach the target.
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i];
+    }
+    if remaining[0] < target {
+        return None;
+    }
+
+    let mut best: Option<(u64, Vec<usize>)> = None;
+    let mut tries = 0;
+    bnb_search(
+        values,
+        &remaining,
+        (target, upper_bound),
+        0,
+        0,
+        &mut Vec::new(),
+        &mut best,
+        &mut tries,
+    );
+    best.map(|(_, selection)| selection)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn bnb_search(
+    values: &[u64],
+    remaining: &[u64],
+    (target, upper_bound): (u64, u64),
+    index: usize,
+    sum: u64,
+    selection: &mut Vec<usize>,
+    best: &mut Option<(u64, Vec<usize>)>,
+    tries: &mut usize,
+) {
+    *tries += 1;
+    if *tries > BNB_MAX_TRIES || sum > upper_bound {
+        return;
+    }
+    if sum >= target {
+        let excess = sum - target;
+        if best.as_ref().map(|(e, _)| excess < *e).unwrap_or(true) {
+            *best = Some((excess, selection.clone()));
+        }
+        return;
+    }
+    if index == values.len() || sum + remaining[index] < target {
+        return;
+    }
+
+    // Explore the inclusion branch first, then the omission branch. When omitting a value we
+    // can skip the following ones equal to it, as they would lead to equivalent selections.
+    selection.push(index);
+    bnb_search(
+        values,
+        remaining,
+        (target, upper_bound),
+        index + 1,
+        sum + values[index],
+        selection,
+        best,
+        tries,
+    );
+    selection.pop();
+    let mut next = index + 1;
+    while next < values.len() && values[next] == values[index] {
+        next += 1;
+    }
+    bnb_search(
+        values,
+        remaining,
+        (target, upper_bound),
+        next,
+        sum,
+        selection,
+        best,
+        tries,
+    );
+}
+
+// Shuffle the coins by sorting them according to a keyed hash of their outpoint. If we can't get
+// randomness, keep the largest-first order.
+fn shuffle(utxos: &mut [(bitcoin::OutPoint, u64)]) {
+    let seed = match random::random_bytes() {
+        Ok(seed) => seed,
+        Err(e) => {
+            log::error!("Could not get randomness for coin selection: '{}'.", e);
+            return;
+        }
+    };
+    utxos.sort_by_cached_key(|(op, _)| {
+        let mut engine = sha256::HashEngine::default();
+        engine.input(&seed);
+        engine.input(op.txid.as_ref());
+        engine.input(&op.vout.to_be_bytes());
+        sha256::Hash::from_engine(engine)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn candidate(vout: u32, amount: u64) -> Candidate {
+        Candidate {
+            outpoint: bitcoin::OutPoint::from_str(&format!(
+                "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:{}",
+                vout
+            ))
+            .unwrap(),
+            amount: bitcoin::Amount::from_sat(amount),
+        }
+    }
+
+    fn params(out_value: u64, feerate_vb: u64) -> SelectionParams {
+        SelectionParams {
+            out_value,
+            base_vb: 10,
+            input_vb: 100,
+            change_vb: 43,
+            feerate_vb,
+            min_change: 5_000,
+        }
+    }
+
+    #[test]
+    fn bnb_exact_match() {
+        let values = [10, 8, 5, 3, 1];
+        assert_eq!(branch_and_bound(&values, 11, 11), Some(vec![0, 4]));
+        assert_eq!(branch_and_bound(&values, 27, 27), Some(vec![0, 1, 2, 3, 4]));
+        assert_eq!(branch_and_bound(&values, 28, 30), None);
+        // Picks the solution with the least excess within the window.
+        assert_eq!(branch_and_bound(&values, 17, 20), Some(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn changeless_selection() {
+        // With a 1sat/vb feerate, each coin has an effective value of its amount minus 100 sats.
+        // The base transaction costs 10 sats, hence we need 1_010 sats of effective value. The
+        // 1_110 sats coin is a perfect match.
+        let candidates = [
+            candidate(0, 100_000),
+            candidate(1, 1_110),
+            candidate(2, 50_000),
+        ];
+        assert_eq!(
+            select_coins(&[], &candidates, &params(1_000, 1)),
+            Some(vec![candidates[1].outpoint])
+        );
+
+        // Coins not worth their spending cost are never selected.
+        let candidates = [candidate(0, 90), candidate(1, 600), candidate(2, 610)];
+        let selection = select_coins(&[], &candidates, &params(1_000, 1)).unwrap();
+        assert_eq!(selection.len(), 2);
+        assert!(!selection.contains(&candidates[0].outpoint));
+    }
+
+    #[test]
+    fn fallback_selection() {
+        // No changeless solution: we select enough coins for a change output.
+        let candidates = [
+            candidate(0, 100_000),
+            candidate(1, 200_000),
+            candidate(2, 300_000),
+        ];
+        let p = params(150_000, 2);
+        let selection = select_coins(&[], &candidates, &p).unwrap();
+        let selected_value: u64 = candidates
+            .iter()
+            .filter(|c| selection.contains(&c.outpoint))
+            .map(|c| p.effective_value(c.amount).unwrap())
+            .sum();
+        assert!(selected_value >= p.target() + p.change_vb * p.feerate_vb + p.min_change);
+
+        // Coins which must be selected are always part of the selection, and others are only
+        // added if they are not sufficient.
+        let must_select = [candidate(3, 200_000)];
+        let selection = select_coins(&must_select, &candidates, &params(150_000, 2)).unwrap();
+        assert_eq!(selection, vec![must_select[0].outpoint]);
+        let selection = select_coins(&must_select, &candidates, &params(250_000, 2)).unwrap();
+        assert_eq!(selection[0], must_select[0].outpoint);
+        assert!(selection.len() > 1);
+
+        // Not enough funds.
+        assert_eq!(select_coins(&[], &candidates, &params(600_000, 1)), None);
+        assert_eq!(select_coins(&[], &[], &params(1_000, 1)), None);
+    }
+}
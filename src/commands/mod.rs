@@ -2,15 +2,19 @@
 //!
 //! External interface to the Liana daemon.
 
+mod coin_selection;
 mod utils;
 
 use crate::{
     bitcoin::BitcoinInterface,
-    database::{Coin, DatabaseInterface},
-    descriptors, DaemonControl, VERSION,
+    config::GuardrailsConfig,
+    database::{Coin, DatabaseConnection, DatabaseInterface, SpendTx},
+    descriptors,
+    events::Event,
+    random, DaemonControl, VERSION,
 };
 
-pub use crate::database::{CoinStatus, LabelItem};
+pub use crate::database::{CoinStatus, LabelItem, SpendStatus};
 
 use utils::{
     deser_addr_assume_checked, deser_amount_from_sats, deser_fromstr, deser_hex, ser_amount,
@@ -18,14 +22,19 @@ use utils::{
 };
 
 use std::{
+    cmp,
     collections::{hash_map, BTreeMap, HashMap, HashSet},
     convert::TryInto,
-    fmt,
+    fmt, fs, path,
+    str::FromStr,
+    sync::mpsc,
+    time,
 };
 
 use miniscript::{
     bitcoin::{
-        self, address,
+        self, address, bip32,
+        hashes::{sha256, Hash, HashEngine},
         locktime::absolute,
         psbt::{Input as PsbtIn, Output as PsbtOut, PartiallySignedTransaction as Psbt},
     },
@@ -43,6 +52,22 @@ const MAX_FEE: u64 = bitcoin::blockdata::constants::COIN_VALUE;
 // Assume that paying more than 1000sat/vb in feerate is a bug.
 const MAX_FEERATE: u64 = 1_000;
 
+// The feerate increment, in sats/vb, a replacement transaction must pay for its own size on top of
+// the fees of the transactions it replaces. That's the default of Bitcoin Core.
+const INCREMENTAL_RELAY_FEERATE: u64 = 1;
+
+// The confirmation targets, in blocks, for which we provide feerate estimates.
+const FEE_ESTIMATES_CONF_TARGETS: [u16; 7] = [1, 3, 6, 12, 24, 144, 1008];
+
+// The maximum confirmation target bitcoind can estimate a feerate for.
+const MAX_CONF_TARGET: u16 = 1008;
+
+// The maximum length of a label, in bytes.
+const MAX_LABEL_LEN: usize = 100;
+
+// The window over which the outflow of the wallet is limited by the guardrails, in seconds.
+const OUTFLOW_WINDOW_SECS: u32 = 24 * 60 * 60;
+
 // Timestamp in the header of the genesis block. Used for sanity checks.
 const MAINNET_GENESIS_TIME: u32 = 1231006505;
 
@@ -72,6 +97,39 @@ pub enum CommandError {
     /// An error that might occur in the racy rescan triggering logic.
     RescanTrigger(String),
     RecoveryNotAvailable,
+    RbfError(RbfErrorInfo),
+    CpfpError(CpfpErrorInfo),
+    InvalidConfTarget(u16),
+    FeerateEstimation(/* conf target */ u16),
+    UnknownWallet(/* name */ String),
+    WalletNameTaken(String),
+    DescriptorAlreadyTracked(/* name of the wallet tracking it */ String),
+    DescriptorNetworkMismatch(/* expected network */ bitcoin::Network),
+    InvalidDerivationIndex(u32),
+    UnwatchedDerivationIndex(u32),
+    InvalidBackupName(String),
+    Backup(String),
+    WalletExportMismatch(
+        /* descriptor of the exported wallet */ Box<descriptors::LianaDescriptor>,
+    ),
+    InvalidLabelItem(String),
+    NoHotSigner,
+    Signing(String),
+    TxAmountAboveLimit(
+        /* sent */ bitcoin::Amount,
+        /* limit */ bitcoin::Amount,
+    ),
+    DailyOutflowAboveLimit(
+        /* outflow */ bitcoin::Amount,
+        /* limit */ bitcoin::Amount,
+    ),
+    FeeAboveLimit(
+        /* fee */ bitcoin::Amount,
+        /* limit */ bitcoin::Amount,
+    ),
+    FeerateAboveLimit(/* sats/vb */ u64, /* limit */ u64),
+    DestinationNotAllowed(/* address or script */ String),
+    InvalidPsbt(String),
 }
 
 impl fmt::Display for CommandError {
@@ -136,6 +194,83 @@ impl fmt::Display for CommandError {
                 f,
                 "No coin currently spendable through this timelocked recovery path."
            ),
+            Self::RbfError(info) => write!(f, "Cannot replace transaction: {}", info),
+            Self::CpfpError(info) => write!(f, "Cannot create a CPFP transaction: {}", info),
+            Self::InvalidConfTarget(t) => write!(
+                f,
+                "Invalid confirmation target: {}. Must be between 1 and {} blocks.",
+                t, MAX_CONF_TARGET
+            ),
+            Self::FeerateEstimation(t) => write!(
+                f,
+                "Could not estimate a feerate for a confirmation within {} blocks.",
+                t
+            ),
+            Self::UnknownWallet(name) => write!(f, "Unknown wallet '{}'.", name),
+            Self::WalletNameTaken(name) => {
+                write!(f, "There is already a wallet named '{}'.", name)
+            }
+            Self::DescriptorAlreadyTracked(name) => {
+                write!(f, "This descriptor is already tracked by wallet '{}'.", name)
+            }
+            Self::DescriptorNetworkMismatch(net) => write!(
+                f,
+                "The descriptor's extended keys are not for network '{}'.",
+                net
+            ),
+            Self::InvalidDerivationIndex(index) => {
+                write!(f, "Invalid derivation index '{}': must not be hardened.", index)
+            }
+            Self::UnwatchedDerivationIndex(index) => write!(
+                f,
+                "Derivation index '{}' is beyond the addresses watched by the wallet. Coins sent to it would not be detected.",
+                index
+            ),
+            Self::InvalidBackupName(name) => write!(
+                f,
+                "Invalid backup file name '{}': must be a plain file name, not already used in the backups directory.",
+                name
+            ),
+            Self::Backup(e) => write!(f, "Error when backing up the database: '{}'.", e),
+            Self::WalletExportMismatch(desc) => write!(
+                f,
+                "The exported wallet is for descriptor '{}', not ours.",
+                desc
+            ),
+            Self::InvalidLabelItem(item) => write!(
+                f,
+                "Invalid label item '{}': must be an address, a txid or an outpoint.",
+                item
+            ),
+            Self::NoHotSigner => write!(f, "No hot signer is configured."),
+            Self::Signing(e) => write!(f, "Error signing the Spend transaction: '{}'.", e),
+            Self::TxAmountAboveLimit(sent, limit) => write!(
+                f,
+                "The transaction sends {} out of the wallet, more than the configured limit of {}.",
+                sent, limit
+            ),
+            Self::DailyOutflowAboveLimit(outflow, limit) => write!(
+                f,
+                "Broadcasting this transaction would bring the outflow of the last 24 hours to {}, \
+                more than the configured limit of {}.",
+                outflow, limit
+            ),
+            Self::FeeAboveLimit(fee, limit) => write!(
+                f,
+                "The transaction pays {} in fees, more than the configured limit of {}.",
+                fee, limit
+            ),
+            Self::FeerateAboveLimit(feerate, limit) => write!(
+                f,
+                "The transaction has a feerate of {} sats/vb, more than the configured limit of {} sats/vb.",
+                feerate, limit
+            ),
+            Self::DestinationNotAllowed(dest) => write!(
+                f,
+                "Destination '{}' is not among the configured allowed destinations.",
+                dest
+            ),
+            Self::InvalidPsbt(e) => write!(f, "Cannot analyze the PSBT: '{}'.", e),
         }
     }
 }
@@ -162,11 +297,73 @@ pub enum InsaneFeeInfo {
     TooHighFeerate(u64),
 }
 
-// Apply some sanity checks on a created transaction's PSBT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RbfErrorInfo {
+    NotInMempool,
+    NotSignaling,
+    TooLowFeerate(/* minimum sats/vb */ u64),
+}
+
+impl fmt::Display for RbfErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotInMempool => write!(f, "the transaction is not in the mempool."),
+            Self::NotSignaling => write!(f, "the transaction does not signal for replacement."),
+            Self::TooLowFeerate(r) => {
+                write!(f, "the feerate must be at least {} sats/vb.", r)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpfpErrorInfo {
+    UnknownTransaction,
+    NotInMempool,
+    NoSpendableOutput,
+    AlreadyAboveFeerate(/* parent sats/vb */ u64),
+}
+
+impl fmt::Display for CpfpErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownTransaction => write!(f, "the transaction is not in our wallet."),
+            Self::NotInMempool => write!(f, "the transaction is not in the mempool."),
+            Self::NoSpendableOutput => {
+                write!(f, "the transaction has no unspent output of ours.")
+            }
+            Self::AlreadyAboveFeerate(r) => {
+                write!(
+                    f,
+                    "the transaction already pays a feerate of {} sats/vb.",
+                    r
+                )
+            }
+        }
+    }
+}
+
+// A transaction to be replaced by the one we are creating.
+struct ReplacedTx {
+    // The derivation index of the change output to use, if any. When bumping the fees this is the
+    // one of the replaced transaction's change output, which is reused.
+    change_index: Option<bip32::ChildNumber>,
+}
+
+// Whether this PSBT output was marked as paying to one of our addresses. Those are not considered
+// as leaving the wallet. This is only to be trusted for the PSBTs we just created.
+fn is_our_psbt_out(psbt_out: &PsbtOut) -> bool {
+    !psbt_out.bip32_derivation.is_empty() || !psbt_out.tap_key_origins.is_empty()
+}
+
+// Apply some sanity checks on a created transaction's PSBT. If guardrails are configured, also
+// check the transaction abides by them.
 // TODO: add more sanity checks from revault_tx
 fn sanity_check_psbt(
     spent_desc: &descriptors::LianaDescriptor,
     psbt: &Psbt,
+    guardrails: Option<&GuardrailsConfig>,
+    network: bitcoin::Network,
 ) -> Result<(), CommandError> {
     let tx = &psbt.unsigned_tx;
 
@@ -182,7 +379,7 @@ fn sanity_check_psbt(
     // index set for signing devices to recognize them as ours.
     let mut value_in = 0;
     for psbtin in psbt.inputs.iter() {
-        if psbtin.bip32_derivation.is_empty() {
+        if psbtin.bip32_derivation.is_empty() && psbtin.tap_key_origins.is_empty() {
             return Err(CommandError::SanityCheckFailure(psbt.clone()));
         }
         value_in += psbtin
@@ -202,14 +399,7 @@ fn sanity_check_psbt(
     }
 
     // Check the feerate isn't insane.
-    // Add weights together before converting to vbytes to avoid rounding up multiple times
-    // and increasing the result, which could lead to the feerate in sats/vb falling below 1.
-    let tx_wu = tx.weight().to_wu() + (spent_desc.max_sat_weight() * tx.input.len()) as u64;
-    let tx_vb = tx_wu
-        .checked_add(descriptors::WITNESS_FACTOR as u64 - 1)
-        .unwrap()
-        .checked_div(descriptors::WITNESS_FACTOR as u64)
-        .unwrap();
+    let tx_vb = max_vsize(spent_desc, tx);
     let feerate_sats_vb = abs_fee
         .checked_div(tx_vb)
         .ok_or(CommandError::InsaneFees(InsaneFeeInfo::InvalidFeerate))?;
@@ -226,25 +416,322 @@ fn sanity_check_psbt(
         }
     }
 
+    if let Some(guardrails) = guardrails {
+        let payments: Vec<&bitcoin::TxOut> = tx
+            .output
+            .iter()
+            .zip(psbt.outputs.iter())
+            .filter_map(|(txo, psbt_out)| {
+                if is_our_psbt_out(psbt_out) {
+                    None
+                } else {
+                    Some(txo)
+                }
+            })
+            .collect();
+        check_guardrails(guardrails, &payments, abs_fee, feerate_sats_vb, network)?;
+    }
+
+    Ok(())
+}
+
+// Check a transaction against the limits configured by the user, given the outputs which send
+// coins out of the wallet.
+fn check_guardrails(
+    guardrails: &GuardrailsConfig,
+    payments: &[&bitcoin::TxOut],
+    abs_fee: u64,
+    feerate_sats_vb: u64,
+    network: bitcoin::Network,
+) -> Result<(), CommandError> {
+    if let Some(max_fee) = guardrails.max_fee {
+        if abs_fee > max_fee {
+            return Err(CommandError::FeeAboveLimit(
+                bitcoin::Amount::from_sat(abs_fee),
+                bitcoin::Amount::from_sat(max_fee),
+            ));
+        }
+    }
+    if let Some(max_feerate) = guardrails.max_feerate {
+        if feerate_sats_vb > max_feerate {
+            return Err(CommandError::FeerateAboveLimit(
+                feerate_sats_vb,
+                max_feerate,
+            ));
+        }
+    }
+
+    let mut sent = 0;
+    for txo in payments {
+        sent += txo.value;
+        if !guardrails.allowed_destinations.is_empty()
+            && !guardrails
+                .allowed_destinations
+                .iter()
+                .any(|addr| addr.payload.matches_script_pubkey(&txo.script_pubkey))
+        {
+            let dest = bitcoin::Address::from_script(&txo.script_pubkey, network)
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| txo.script_pubkey.to_hex_string());
+            return Err(CommandError::DestinationNotAllowed(dest));
+        }
+    }
+    if let Some(max_tx_amount) = guardrails.max_tx_amount {
+        if sent > max_tx_amount {
+            return Err(CommandError::TxAmountAboveLimit(
+                bitcoin::Amount::from_sat(sent),
+                bitcoin::Amount::from_sat(max_tx_amount),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Get the maximum size of this transaction once all its inputs are satisfied, in virtual bytes.
+fn max_vsize(spent_desc: &descriptors::LianaDescriptor, tx: &bitcoin::Transaction) -> u64 {
+    // Add weights together before converting to vbytes to avoid rounding up multiple times
+    // and increasing the result, which could lead to the feerate in sats/vb falling below 1.
+    let tx_wu = tx.weight().to_wu() + (spent_desc.max_sat_weight() * tx.input.len()) as u64;
+    tx_wu
+        .checked_add(descriptors::WITNESS_FACTOR as u64 - 1)
+        .unwrap()
+        .checked_div(descriptors::WITNESS_FACTOR as u64)
+        .unwrap()
+}
+
+// Get the height to set as nLockTime given this random data, to discourage fee sniping. Like
+// Bitcoin Core, use the current tip height but sometimes (10% of the time) go back up to 100
+// blocks so that transactions which were delayed after being signed don't stand out.
+fn anti_fee_sniping_height(tip_height: u32, random: &[u8; 32]) -> u32 {
+    let draw = u32::from_be_bytes(random[0..4].try_into().unwrap());
+    if draw % 10 == 0 {
+        let delta = u32::from_be_bytes(random[4..8].try_into().unwrap()) % 100;
+        tip_height.saturating_sub(delta)
+    } else {
+        tip_height
+    }
+}
+
+// Shuffle the outputs of a transaction along with the corresponding PSBT outputs, so the position
+// of the change output doesn't reveal it. Like for coin selection, sort them according to a keyed
+// hash of their position. If we can't get randomness, keep the outputs in order.
+fn shuffle_outputs(txouts: &mut Vec<bitcoin::TxOut>, psbt_outs: &mut Vec<PsbtOut>) {
+    assert_eq!(txouts.len(), psbt_outs.len());
+    let seed = match random::random_bytes() {
+        Ok(seed) => seed,
+        Err(e) => {
+            log::error!("Could not get randomness to shuffle the outputs: '{}'.", e);
+            return;
+        }
+    };
+    let mut outputs: Vec<(u64, bitcoin::TxOut, PsbtOut)> = txouts
+        .drain(..)
+        .zip(psbt_outs.drain(..))
+        .enumerate()
+        .map(|(i, (txout, psbt_out))| (i as u64, txout, psbt_out))
+        .collect();
+    outputs.sort_by_cached_key(|(i, _, _)| {
+        let mut engine = sha256::HashEngine::default();
+        engine.input(&seed);
+        engine.input(&i.to_be_bytes());
+        sha256::Hash::from_engine(engine)
+    });
+    for (_, txout, psbt_out) in outputs {
+        txouts.push(txout);
+        psbt_outs.push(psbt_out);
+    }
+}
+
+// The status of a spending path given the signatures for it present in a PSBT, if any.
+fn psbt_path_info(
+    path: &descriptors::PathInfo,
+    spend_info: Option<&descriptors::PathSpendInfo>,
+) -> PsbtPathInfo {
+    let (threshold, origins) = path.thresh_origins();
+    let mut signed_fingerprints: Vec<bip32::Fingerprint> = spend_info
+        .map(|info| info.signed_pubkeys.keys().cloned().collect())
+        .unwrap_or_default();
+    signed_fingerprints.sort();
+    let sigs_count = spend_info.map(|info| info.sigs_count).unwrap_or(0);
+    let is_satisfied = sigs_count >= threshold;
+    let mut missing_fingerprints: Vec<bip32::Fingerprint> = if is_satisfied {
+        Vec::new()
+    } else {
+        origins
+            .into_keys()
+            .filter(|fg| !signed_fingerprints.contains(fg))
+            .collect()
+    };
+    missing_fingerprints.sort();
+
+    PsbtPathInfo {
+        threshold,
+        sigs_count,
+        is_satisfied,
+        signed_fingerprints,
+        missing_fingerprints,
+    }
+}
+
+// The value which left the wallet, fees included, through the transactions which are either
+// unconfirmed or were confirmed within the guardrails' outflow window. Transactions conflicting
+// with the given one are ignored, as it would replace them.
+fn recent_outflow(
+    db_conn: &mut dyn DatabaseConnection,
+    tx: &bitcoin::Transaction,
+) -> bitcoin::Amount {
+    let txid = tx.txid();
+    let window_start = curr_timestamp().saturating_sub(OUTFLOW_WINDOW_SECS);
+    let coins = db_conn.coins(&[], &[]);
+
+    let mut ignored: HashSet<bitcoin::Txid> = tx
+        .input
+        .iter()
+        .filter_map(|txin| coins.get(&txin.previous_output)?.spend_txid)
+        .collect();
+    ignored.insert(txid);
+
+    // The value spent by each recent transaction, minus the value it sent back to us.
+    let mut outflows: HashMap<bitcoin::Txid, i64> = HashMap::new();
+    for coin in coins.values() {
+        if let Some(spend_txid) = coin.spend_txid {
+            let is_recent = coin
+                .spend_block
+                .map(|block| block.time >= window_start)
+                .unwrap_or(true);
+            if is_recent && !ignored.contains(&spend_txid) {
+                *outflows.entry(spend_txid).or_insert(0) += coin.amount.to_sat() as i64;
+            }
+        }
+    }
+    for coin in coins.values() {
+        if let Some(outflow) = outflows.get_mut(&coin.outpoint.txid) {
+            *outflow -= coin.amount.to_sat() as i64;
+        }
+    }
+
+    bitcoin::Amount::from_sat(outflows.values().map(|o| cmp::max(*o, 0) as u64).sum())
+}
+
+// Check a transaction about to be broadcast abides by the guardrails, including the outflow limit.
+// It must only spend these coins of ours, which give its input value. Its outputs are only
+// considered ours if they pay to one of our addresses, whatever a PSBT may claim.
+fn check_broadcast_guardrails(
+    db_conn: &mut dyn DatabaseConnection,
+    guardrails: &GuardrailsConfig,
+    tx: &bitcoin::Transaction,
+    coins: &HashMap<bitcoin::OutPoint, Coin>,
+    tx_vb: u64,
+    network: bitcoin::Network,
+) -> Result<(), CommandError> {
+    let value_in: u64 = coins.values().map(|c| c.amount.to_sat()).sum();
+    let value_out: u64 = tx.output.iter().map(|txo| txo.value).sum();
+    let abs_fee = value_in
+        .checked_sub(value_out)
+        .ok_or(CommandError::InsaneFees(InsaneFeeInfo::NegativeFee))?;
+    let feerate_sats_vb = abs_fee / tx_vb;
+    let mut payments = Vec::with_capacity(tx.output.len());
+    for txo in tx.output.iter() {
+        let is_ours = bitcoin::Address::from_script(&txo.script_pubkey, network)
+            .ok()
+            .and_then(|addr| db_conn.derivation_index_by_address(&addr))
+            .is_some();
+        if !is_ours {
+            payments.push(txo);
+        }
+    }
+    check_guardrails(guardrails, &payments, abs_fee, feerate_sats_vb, network)?;
+    let sent: u64 = payments.iter().map(|txo| txo.value).sum();
+    check_daily_outflow(
+        db_conn,
+        guardrails,
+        tx,
+        bitcoin::Amount::from_sat(sent + abs_fee),
+    )
+}
+
+// Check broadcasting this transaction, which takes `tx_outflow` out of the wallet, would not bring
+// the outflow over the last 24 hours above the limit configured in the guardrails.
+fn check_daily_outflow(
+    db_conn: &mut dyn DatabaseConnection,
+    guardrails: &GuardrailsConfig,
+    tx: &bitcoin::Transaction,
+    tx_outflow: bitcoin::Amount,
+) -> Result<(), CommandError> {
+    if let Some(max_daily_outflow) = guardrails.max_daily_outflow {
+        let outflow = recent_outflow(db_conn, tx) + tx_outflow;
+        let max_daily_outflow = bitcoin::Amount::from_sat(max_daily_outflow);
+        if outflow > max_daily_outflow {
+            return Err(CommandError::DailyOutflowAboveLimit(
+                outflow,
+                max_daily_outflow,
+            ));
+        }
+    }
+
     Ok(())
 }
 
+// The current time as the number of seconds since the UNIX epoch.
+fn curr_timestamp() -> u32 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("System clock went backward the epoch?")
+        .as_secs()
+        .try_into()
+        .expect("Is this the year 2106 yet? Misconfigured system clock.")
+}
+
 // Get the size of a type that can be serialized (txos, transactions, ..)
 fn serializable_size<T: bitcoin::consensus::Encodable + ?Sized>(t: &T) -> u64 {
     bitcoin::consensus::serialize(t).len().try_into().unwrap()
 }
 
+// Truncate this label to the maximum length we support, on a character boundary.
+fn truncated_label(label: &str) -> String {
+    let mut end = cmp::min(label.len(), MAX_LABEL_LEN);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].to_string()
+}
+
 impl DaemonControl {
+    // Get a connection to the database scoped to the wallet the commands are run against.
+    fn wallet_conn(&self) -> Box<dyn DatabaseConnection> {
+        match self.wallet_id {
+            Some(id) => self.db.wallet_connection(id),
+            None => self.db.connection(),
+        }
+    }
+
     // Get the derived descriptor for this coin
     fn derived_desc(&self, coin: &Coin) -> descriptors::DerivedSinglePathLianaDesc {
         let desc = if coin.is_change {
-            self.config.main_descriptor.change_descriptor()
+            self.main_descriptor.change_descriptor()
         } else {
-            self.config.main_descriptor.receive_descriptor()
+            self.main_descriptor.receive_descriptor()
         };
         desc.derive(coin.derivation_index, &self.secp)
     }
 
+    // Get the nLockTime to set on a transaction we create, to discourage fee sniping.
+    fn anti_fee_sniping_locktime(&self) -> absolute::LockTime {
+        let tip_height: u32 = self
+            .bitcoin
+            .chain_tip()
+            .height
+            .try_into()
+            .expect("Must not be negative");
+        let random = random::random_bytes().unwrap_or_else(|e| {
+            log::error!("Could not get randomness for the nLockTime: '{}'.", e);
+            [0; 32]
+        });
+        absolute::LockTime::from_height(anti_fee_sniping_height(tip_height, &random))
+            .expect("Must be a valid block height")
+    }
+
     // Check whether this address is valid for the network we are operating on.
     fn validate_address(
         &self,
@@ -255,12 +742,91 @@ impl DaemonControl {
         addr.require_network(self.config.bitcoin_config.network)
             .map_err(CommandError::Address)
     }
+
+    // Select coins to fund a transaction with these outputs at this feerate, in addition to the
+    // coins which must be spent. Confirmed coins are preferred, unconfirmed ones are only used if
+    // the confirmed ones are not sufficient and if allowed.
+    fn select_coins(
+        &self,
+        db_conn: &mut Box<dyn DatabaseConnection>,
+        txouts: &[bitcoin::TxOut],
+        feerate_vb: u64,
+        must_spend: &[Coin],
+        allow_unconfirmed: bool,
+    ) -> Result<Vec<bitcoin::OutPoint>, CommandError> {
+        let out_value: u64 = txouts.iter().map(|txo| txo.value).sum();
+        // The transaction without any input: nVersion, input count, outputs and nLockTime. Plus 1
+        // for the Segwit marker and flag size, rounded up.
+        let base_vb = bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+            input: vec![],
+            output: txouts.to_vec(),
+        }
+        .vsize() as u64
+            + 1;
+        let change_txo = bitcoin::TxOut {
+            value: u64::MAX,
+            script_pubkey: self
+                .main_descriptor
+                .change_descriptor()
+                .derive(db_conn.change_index(), &self.secp)
+                .script_pubkey(),
+        };
+        let params = coin_selection::SelectionParams {
+            out_value,
+            base_vb,
+            input_vb: self.main_descriptor.spender_input_size() as u64,
+            change_vb: serializable_size(&change_txo),
+            feerate_vb,
+            min_change: DUST_OUTPUT_SATS,
+        };
+
+        let to_candidate = |coin: &Coin| coin_selection::Candidate {
+            outpoint: coin.outpoint,
+            amount: coin.amount,
+        };
+        let must_select: Vec<_> = must_spend.iter().map(to_candidate).collect();
+        let mut in_value: bitcoin::Amount = must_select.iter().map(|c| c.amount).sum();
+        let statuses: &[&[CoinStatus]] = if allow_unconfirmed {
+            &[
+                &[CoinStatus::Confirmed],
+                &[CoinStatus::Confirmed, CoinStatus::Unconfirmed],
+            ]
+        } else {
+            &[&[CoinStatus::Confirmed]]
+        };
+        for statuses in statuses {
+            let candidates: Vec<_> = db_conn
+                .coins(statuses, &[])
+                .values()
+                .filter(|coin| !coin.is_immature)
+                .map(to_candidate)
+                .collect();
+            if let Some(selection) =
+                coin_selection::select_coins(&must_select, &candidates, &params)
+            {
+                return Ok(selection);
+            }
+            in_value = must_select
+                .iter()
+                .chain(candidates.iter())
+                .map(|c| c.amount)
+                .sum();
+        }
+
+        Err(CommandError::InsufficientFunds(
+            in_value,
+            Some(bitcoin::Amount::from_sat(out_value)),
+            feerate_vb,
+        ))
+    }
 }
 
 impl DaemonControl {
     /// Get information about the current state of the daemon
     pub fn get_info(&self) -> GetInfoResult {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
 
         let block_height = db_conn.chain_tip().map(|tip| tip.height).unwrap_or(0);
         let rescan_progress = db_conn
@@ -272,7 +838,7 @@ impl DaemonControl {
             block_height,
             sync: self.bitcoin.sync_progress().rounded_up_progress(),
             descriptors: GetInfoDescriptors {
-                main: self.config.main_descriptor.clone(),
+                main: self.main_descriptor.clone(),
             },
             rescan_progress,
         }
@@ -281,14 +847,13 @@ impl DaemonControl {
     /// Get a new deposit address. This will always generate a new deposit address, regardless of
     /// whether it was actually used.
     pub fn get_new_address(&self) -> GetAddressResult {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         let index = db_conn.receive_index();
         let new_index = index
             .increment()
             .expect("Can't get into hardened territory");
         db_conn.set_receive_index(new_index, &self.secp);
         let address = self
-            .config
             .main_descriptor
             .receive_descriptor()
             .derive(index, &self.secp)
@@ -296,13 +861,166 @@ impl DaemonControl {
         GetAddressResult::new(address)
     }
 
+    /// Get the receive or change address at this derivation index. Unlike `get_new_address`, this
+    /// doesn't update the index of the next address to be handed out. The address must be within
+    /// the range of addresses watched by the wallet.
+    pub fn get_address(
+        &self,
+        index: u32,
+        is_change: bool,
+    ) -> Result<GetAddressResult, CommandError> {
+        let child = bip32::ChildNumber::from_normal_idx(index)
+            .map_err(|_| CommandError::InvalidDerivationIndex(index))?;
+        let desc = if is_change {
+            self.main_descriptor.change_descriptor()
+        } else {
+            self.main_descriptor.receive_descriptor()
+        };
+        let address = desc
+            .derive(child, &self.secp)
+            .address(self.config.bitcoin_config.network);
+
+        // We only know about the addresses we are watching for.
+        if self
+            .wallet_conn()
+            .derivation_index_by_address(&address)
+            .is_none()
+        {
+            return Err(CommandError::UnwatchedDerivationIndex(index));
+        }
+
+        Ok(GetAddressResult::new(address))
+    }
+
+    /// List our receive and change addresses for a range of derivation indexes, along with their
+    /// label and the coins they received. By default, list the addresses at all the indexes
+    /// below the next receive or change index.
+    pub fn list_addresses(
+        &self,
+        start_index: u32,
+        count: Option<u32>,
+    ) -> Result<ListAddressesResult, CommandError> {
+        let mut db_conn = self.wallet_conn();
+        let count = count.unwrap_or_else(|| {
+            let next_index: u32 = cmp::max(db_conn.receive_index(), db_conn.change_index()).into();
+            next_index.saturating_sub(start_index)
+        });
+        let end_index = start_index
+            .checked_add(count)
+            .filter(|end| *end <= bip32::ChildNumber::Hardened { index: 0 }.into())
+            .ok_or(CommandError::InvalidDerivationIndex(
+                start_index.saturating_add(count),
+            ))?;
+
+        // Gather the number of coins and the value received at each address.
+        let mut received: HashMap<(bip32::ChildNumber, bool), (usize, bitcoin::Amount)> =
+            HashMap::new();
+        for coin in db_conn.coins(&[], &[]).into_values() {
+            let entry = received
+                .entry((coin.derivation_index, coin.is_change))
+                .or_insert((0, bitcoin::Amount::ZERO));
+            entry.0 += 1;
+            entry.1 += coin.amount;
+        }
+
+        let network = self.config.bitcoin_config.network;
+        let receive_desc = self.main_descriptor.receive_descriptor();
+        let change_desc = self.main_descriptor.change_descriptor();
+        let derived: Vec<_> = (start_index..end_index)
+            .map(|index| {
+                let child =
+                    bip32::ChildNumber::from_normal_idx(index).expect("Checked it's not hardened");
+                let receive = receive_desc.derive(child, &self.secp).address(network);
+                let change = change_desc.derive(child, &self.secp).address(network);
+                (child, receive, change)
+            })
+            .collect();
+
+        let label_items: HashSet<LabelItem> = derived
+            .iter()
+            .flat_map(|(_, receive, change)| {
+                [
+                    LabelItem::from(receive.clone()),
+                    LabelItem::from(change.clone()),
+                ]
+            })
+            .collect();
+        let labels = if label_items.is_empty() {
+            HashMap::new()
+        } else {
+            db_conn.labels(&label_items)
+        };
+
+        let entry = |address: bitcoin::Address, index, is_change| {
+            let (coins_count, received) = received
+                .get(&(index, is_change))
+                .cloned()
+                .unwrap_or((0, bitcoin::Amount::ZERO));
+            AddressEntry {
+                label: labels.get(&address.to_string()).cloned(),
+                address,
+                coins_count,
+                received,
+            }
+        };
+        let addresses = derived
+            .into_iter()
+            .map(|(index, receive, change)| ListAddressesEntry {
+                index: index.into(),
+                receive: entry(receive, index, false),
+                change: entry(change, index, true),
+            })
+            .collect();
+
+        Ok(ListAddressesResult { addresses })
+    }
+
+    /// Get information about this address, and how it was derived if it is ours.
+    pub fn get_address_info(
+        &self,
+        address: bitcoin::Address<address::NetworkUnchecked>,
+    ) -> Result<GetAddressInfoResult, CommandError> {
+        let address = self.validate_address(address)?;
+        let mut db_conn = self.wallet_conn();
+        let mut label_items = HashSet::new();
+        label_items.insert(LabelItem::from(address.clone()));
+        let label = db_conn.labels(&label_items).remove(&address.to_string());
+
+        let derivation = db_conn.derivation_index_by_address(&address);
+        let mut info = GetAddressInfoResult {
+            address,
+            is_mine: derivation.is_some(),
+            is_change: None,
+            derivation_index: None,
+            descriptor: None,
+            witness_script: None,
+            label,
+        };
+        if let Some((index, is_change)) = derivation {
+            let desc = if is_change {
+                self.main_descriptor.change_descriptor()
+            } else {
+                self.main_descriptor.receive_descriptor()
+            }
+            .derive(index, &self.secp);
+            info.is_change = Some(is_change);
+            info.derivation_index = Some(index.into());
+            if !desc.is_taproot() {
+                info.witness_script = Some(desc.witness_script());
+            }
+            info.descriptor = Some(desc);
+        }
+
+        Ok(info)
+    }
+
     /// Get a list of all known coins, optionally by status and/or outpoint.
     pub fn list_coins(
         &self,
         statuses: &[CoinStatus],
         outpoints: &[bitcoin::OutPoint],
     ) -> ListCoinsResult {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         let coins: Vec<ListCoinsEntry> = db_conn
             .coins(statuses, outpoints)
             .into_values()
@@ -337,6 +1055,29 @@ impl DaemonControl {
         ListCoinsResult { coins }
     }
 
+    /// Estimate the feerate, in sats/vb, for a transaction to be confirmed within this number of
+    /// blocks.
+    pub fn estimate_feerate(&self, conf_target: u16) -> Result<u64, CommandError> {
+        if !(1..=MAX_CONF_TARGET).contains(&conf_target) {
+            return Err(CommandError::InvalidConfTarget(conf_target));
+        }
+        self.bitcoin
+            .feerate_estimate(conf_target)
+            .ok_or(CommandError::FeerateEstimation(conf_target))
+    }
+
+    /// Get feerate estimates for a range of confirmation targets.
+    pub fn get_fee_estimates(&self) -> GetFeeEstimatesResult {
+        let estimates = FEE_ESTIMATES_CONF_TARGETS
+            .iter()
+            .map(|conf_target| FeeEstimate {
+                conf_target: *conf_target,
+                feerate: self.bitcoin.feerate_estimate(*conf_target),
+            })
+            .collect();
+        GetFeeEstimatesResult { estimates }
+    }
+
     pub fn create_spend(
         &self,
         destinations: &HashMap<bitcoin::Address<bitcoin::address::NetworkUnchecked>, u64>,
@@ -344,33 +1085,115 @@ impl DaemonControl {
         feerate_vb: u64,
     ) -> Result<CreateSpendResult, CommandError> {
         let is_self_send = destinations.is_empty();
-        if coins_outpoints.is_empty() {
+        if is_self_send && coins_outpoints.is_empty() {
             return Err(CommandError::NoOutpoint);
         }
         if feerate_vb < 1 {
             return Err(CommandError::InvalidFeerate(feerate_vb));
         }
-        let mut db_conn = self.db.connection();
+        let mut txouts = Vec::with_capacity(destinations.len());
+        for (address, value_sat) in destinations {
+            let address = self.validate_address(address.clone())?;
+            let amount = bitcoin::Amount::from_sat(*value_sat);
+            check_output_value(amount)?;
+            txouts.push(bitcoin::TxOut {
+                value: amount.to_sat(),
+                script_pubkey: address.script_pubkey(),
+            });
+        }
 
-        // Iterate through given outpoints to fetch the coins (hence checking their existence
-        // at the same time). We checked there is at least one, therefore after this loop the
-        // list of coins is not empty.
-        // While doing so, we record the total input value of the transaction to later compute
-        // fees, and add necessary information to the PSBT inputs.
-        let mut in_value = bitcoin::Amount::from_sat(0);
-        let txin_sat_vb = self.config.main_descriptor.max_sat_vbytes();
-        let mut sat_vb = 0;
-        let mut txins = Vec::with_capacity(coins_outpoints.len());
-        let mut psbt_ins = Vec::with_capacity(coins_outpoints.len());
-        let mut spent_txs = HashMap::with_capacity(coins_outpoints.len());
-        let coins = db_conn.coins_by_outpoints(coins_outpoints);
-        for op in coins_outpoints {
-            // Get the coin from our in-DB unspent txos
-            let coin = coins.get(op).ok_or(CommandError::UnknownOutpoint(*op))?;
-            if coin.is_spent() {
-                return Err(CommandError::AlreadySpent(*op));
-            }
-            if coin.is_immature {
+        let mut db_conn = self.wallet_conn();
+        self.create_spend_internal(&mut db_conn, &txouts, coins_outpoints, feerate_vb, None)
+    }
+
+    // Create a transaction spending these coins to these destinations outputs, at this feerate.
+    // The order of the outputs, including the change one if any, is randomized. If no coin is given, or if we are replacing a transaction whose coins are not
+    // sufficient, coins are selected automatically.
+    fn create_spend_internal(
+        &self,
+        db_conn: &mut Box<dyn DatabaseConnection>,
+        destinations: &[bitcoin::TxOut],
+        coins_outpoints: &[bitcoin::OutPoint],
+        feerate_vb: u64,
+        replaced: Option<&ReplacedTx>,
+    ) -> Result<CreateSpendResult, CommandError> {
+        let is_self_send = destinations.is_empty();
+
+        // Add the destinations outputs to the transaction and PSBT. At the same time record the
+        // total output value to later compute fees.
+        let mut out_value = bitcoin::Amount::from_sat(0);
+        let txouts = destinations.to_vec();
+        let mut psbt_outs = Vec::with_capacity(destinations.len());
+        for txo in destinations {
+            out_value = out_value
+                .checked_add(bitcoin::Amount::from_sat(txo.value))
+                .unwrap();
+
+            // If it's an address of ours, signal it as change to signing devices by adding the
+            // BIP32 derivation path to the PSBT output.
+            let mut psbt_out = PsbtOut::default();
+            let derivation_index = bitcoin::Address::from_script(
+                &txo.script_pubkey,
+                self.config.bitcoin_config.network,
+            )
+            .ok()
+            .and_then(|address| db_conn.derivation_index_by_address(&address));
+            if let Some((index, is_change)) = derivation_index {
+                let desc = if is_change {
+                    self.main_descriptor.change_descriptor()
+                } else {
+                    self.main_descriptor.receive_descriptor()
+                };
+                desc.derive(index, &self.secp)
+                    .update_psbt_out(&mut psbt_out);
+            }
+            psbt_outs.push(psbt_out);
+        }
+        assert_eq!(txouts.is_empty(), is_self_send);
+
+        // If no coins were provided, select them among our unspent coins. When replacing a
+        // transaction, its coins must be spent and only confirmed coins may be added (BIP125).
+        let selected_outpoints;
+        let coins_outpoints = if coins_outpoints.is_empty() || replaced.is_some() {
+            let coins = db_conn.coins_by_outpoints(coins_outpoints);
+            let must_spend: Vec<Coin> = coins_outpoints
+                .iter()
+                .filter_map(|op| coins.get(op).cloned())
+                .collect();
+            selected_outpoints = self.select_coins(
+                db_conn,
+                &txouts,
+                feerate_vb,
+                &must_spend,
+                replaced.is_none(),
+            )?;
+            &selected_outpoints[..]
+        } else {
+            coins_outpoints
+        };
+
+        // Iterate through given outpoints to fetch the coins (hence checking their existence
+        // at the same time). There is at least one, therefore after this loop the list of coins
+        // is not empty.
+        // While doing so, we record the total input value of the transaction to later compute
+        // fees, and add necessary information to the PSBT inputs.
+        let mut in_value = bitcoin::Amount::from_sat(0);
+        let txin_sat_vb = self.main_descriptor.max_sat_vbytes();
+        let mut sat_vb = 0;
+        let mut txins = Vec::with_capacity(coins_outpoints.len());
+        let mut psbt_ins = Vec::with_capacity(coins_outpoints.len());
+        let mut spent_txs = HashMap::with_capacity(coins_outpoints.len());
+        let coins = db_conn.coins_by_outpoints(coins_outpoints);
+        for op in coins_outpoints {
+            // Get the coin from our in-DB unspent txos
+            let coin = coins.get(op).ok_or(CommandError::UnknownOutpoint(*op))?;
+            // The coins of a transaction we are replacing are spent by this very transaction (or
+            // by one it replaced and that we didn't notice yet) which is still unconfirmed.
+            let is_replaced_spend = replaced.is_some() && coin.spend_block.is_none();
+            if coin.is_spent() && !is_replaced_spend {
+                return Err(CommandError::AlreadySpent(*op));
+            }
+            if coin.is_immature {
                 return Err(CommandError::ImmatureCoinbase(*op));
             }
 
@@ -394,63 +1217,25 @@ impl DaemonControl {
             // Populate the PSBT input with the information needed by signers.
             let coin_desc = self.derived_desc(coin);
             sat_vb += txin_sat_vb;
-            let witness_script = Some(coin_desc.witness_script());
             let witness_utxo = Some(bitcoin::TxOut {
                 value: coin.amount.to_sat(),
                 script_pubkey: coin_desc.script_pubkey(),
             });
             let non_witness_utxo = spent_txs.get(op).cloned();
-            let bip32_derivation = coin_desc.bip32_derivations();
-            psbt_ins.push(PsbtIn {
-                witness_script,
+            let mut psbt_in = PsbtIn {
                 witness_utxo,
-                bip32_derivation,
                 non_witness_utxo,
                 ..PsbtIn::default()
-            });
-        }
-
-        // Add the destinations outputs to the transaction and PSBT. At the same time record the
-        // total output value to later compute fees, and sanity check each output's value.
-        let mut out_value = bitcoin::Amount::from_sat(0);
-        let mut txouts = Vec::with_capacity(destinations.len());
-        let mut psbt_outs = Vec::with_capacity(destinations.len());
-        for (address, value_sat) in destinations {
-            let address = self.validate_address(address.clone())?;
-
-            let amount = bitcoin::Amount::from_sat(*value_sat);
-            check_output_value(amount)?;
-            out_value = out_value.checked_add(amount).unwrap();
-
-            txouts.push(bitcoin::TxOut {
-                value: amount.to_sat(),
-                script_pubkey: address.script_pubkey(),
-            });
-            // If it's an address of ours, signal it as change to signing devices by adding the
-            // BIP32 derivation path to the PSBT output.
-            let bip32_derivation =
-                if let Some((index, is_change)) = db_conn.derivation_index_by_address(&address) {
-                    let desc = if is_change {
-                        self.config.main_descriptor.change_descriptor()
-                    } else {
-                        self.config.main_descriptor.receive_descriptor()
-                    };
-                    desc.derive(index, &self.secp).bip32_derivations()
-                } else {
-                    Default::default()
-                };
-            psbt_outs.push(PsbtOut {
-                bip32_derivation,
-                ..PsbtOut::default()
-            });
+            };
+            coin_desc.update_psbt_in(&mut psbt_in);
+            psbt_ins.push(psbt_in);
         }
-        assert_eq!(txouts.is_empty(), is_self_send);
 
         // Now create the transaction, compute its fees and already sanity check if its feerate
         // isn't much less than what was asked (and obviously that fees aren't negative).
         let mut tx = bitcoin::Transaction {
             version: 2,
-            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO), // TODO: randomized anti fee sniping
+            lock_time: self.anti_fee_sniping_locktime(),
             input: txins,
             output: txouts,
         };
@@ -476,18 +1261,23 @@ impl DaemonControl {
         // the needed change value from the target feerate and the size of the transaction *with
         // an added output* (for the change).
         if is_self_send || nochange_feerate_vb > feerate_vb {
-            // Get the change address to create a dummy change txo.
-            let change_index = db_conn.change_index();
+            // Get the change address to create a dummy change txo. When replacing a transaction
+            // use the change index we were given, if any.
+            let change_index = if let Some(index) = replaced.and_then(|r| r.change_index) {
+                index
+            } else {
+                let change_index = db_conn.change_index();
+                // Don't forget to update our next change index!
+                let next_index = change_index
+                    .increment()
+                    .expect("Must not get into hardened territory");
+                db_conn.set_change_index(next_index, &self.secp);
+                change_index
+            };
             let change_desc = self
-                .config
                 .main_descriptor
                 .change_descriptor()
                 .derive(change_index, &self.secp);
-            // Don't forget to update our next change index!
-            let next_index = change_index
-                .increment()
-                .expect("Must not get into hardened territory");
-            db_conn.set_change_index(next_index, &self.secp);
             let mut change_txo = bitcoin::TxOut {
                 value: std::u64::MAX,
                 script_pubkey: change_desc.script_pubkey(),
@@ -510,13 +1300,11 @@ impl DaemonControl {
                 if change_amount.to_sat() >= DUST_OUTPUT_SATS {
                     check_output_value(change_amount)?;
 
-                    // TODO: shuffle once we have Taproot
                     change_txo.value = change_amount.to_sat();
                     tx.output.push(change_txo);
-                    psbt_outs.push(PsbtOut {
-                        bip32_derivation: change_desc.bip32_derivations(),
-                        ..PsbtOut::default()
-                    });
+                    let mut psbt_out = PsbtOut::default();
+                    change_desc.update_psbt_out(&mut psbt_out);
+                    psbt_outs.push(psbt_out);
                 } else if is_self_send {
                     return Err(CommandError::InsufficientFunds(in_value, None, feerate_vb));
                 }
@@ -524,6 +1312,7 @@ impl DaemonControl {
                 return Err(CommandError::InsufficientFunds(in_value, None, feerate_vb));
             }
         }
+        shuffle_outputs(&mut tx.output, &mut psbt_outs);
 
         let psbt = Psbt {
             unsigned_tx: tx,
@@ -534,14 +1323,22 @@ impl DaemonControl {
             inputs: psbt_ins,
             outputs: psbt_outs,
         };
-        sanity_check_psbt(&self.config.main_descriptor, &psbt)?;
+        sanity_check_psbt(
+            &self.main_descriptor,
+            &psbt,
+            self.config.guardrails_config.as_ref(),
+            self.config.bitcoin_config.network,
+        )?;
         // TODO: maybe check for common standardness rules (max size, ..)?
 
-        Ok(CreateSpendResult { psbt })
+        Ok(CreateSpendResult {
+            psbt,
+            coins: coins_outpoints.to_vec(),
+        })
     }
 
     pub fn update_spend(&self, mut psbt: Psbt) -> Result<(), CommandError> {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         let tx = &psbt.unsigned_tx;
 
         // If the transaction already exists in DB, merge the signatures for each input on a best
@@ -593,41 +1390,228 @@ impl DaemonControl {
     }
 
     pub fn update_labels(&self, items: &HashMap<LabelItem, Option<String>>) {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         db_conn.update_labels(items);
     }
 
     pub fn get_labels(&self, items: &HashSet<LabelItem>) -> GetLabelsResult {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         GetLabelsResult {
             labels: db_conn.labels(items),
         }
     }
 
     pub fn list_spend(&self) -> ListSpendResult {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         let spend_txs = db_conn
             .list_spend()
             .into_iter()
-            .map(|(psbt, updated_at)| ListSpendEntry { psbt, updated_at })
+            .map(ListSpendEntry::from)
             .collect();
         ListSpendResult { spend_txs }
     }
 
+    /// Attach a note to this stored Spend transaction. An empty note deletes it.
+    pub fn set_spend_note(&self, txid: &bitcoin::Txid, note: &str) -> Result<(), CommandError> {
+        let mut db_conn = self.wallet_conn();
+        if db_conn.spend_tx(txid).is_none() {
+            return Err(CommandError::UnknownSpend(*txid));
+        }
+        let note = Some(note).filter(|note| !note.is_empty());
+        db_conn.set_spend_note(txid, note);
+        Ok(())
+    }
+
     pub fn delete_spend(&self, txid: &bitcoin::Txid) {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
         db_conn.delete_spend(txid);
     }
 
-    /// Finalize and broadcast this stored Spend transaction.
-    pub fn broadcast_spend(&self, txid: &bitcoin::Txid) -> Result<(), CommandError> {
-        let mut db_conn = self.db.connection();
+    /// Sign this stored Spend transaction with all the hot signers loaded by the daemon, and store
+    /// it with the new signatures. Only the inputs with keys from our signers get signed.
+    pub fn sign_spend(&self, txid: &bitcoin::Txid) -> Result<SignSpendResult, CommandError> {
+        if self.signers.is_empty() {
+            return Err(CommandError::NoHotSigner);
+        }
+        let mut db_conn = self.wallet_conn();
+        let mut psbt = db_conn
+            .spend_tx(txid)
+            .ok_or(CommandError::UnknownSpend(*txid))?;
 
-        // First, try to finalize the spending transaction with the elements contained
-        // in the PSBT.
+        let count_signatures = |psbt: &Psbt| -> usize {
+            psbt.inputs
+                .iter()
+                .map(|psbt_in| {
+                    psbt_in.partial_sigs.len()
+                        + psbt_in.tap_script_sigs.len()
+                        + psbt_in.tap_key_sig.iter().count()
+                })
+                .sum()
+        };
+        let initial_count = count_signatures(&psbt);
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        for signer in self.signers.iter() {
+            psbt = signer
+                .sign_psbt(psbt, &secp)
+                .map_err(|e| CommandError::Signing(e.to_string()))?;
+        }
+        let signatures = count_signatures(&psbt) - initial_count;
+        if signatures > 0 {
+            db_conn.store_spend(&psbt);
+        }
+
+        Ok(SignSpendResult { psbt, signatures })
+    }
+
+    /// Analyze a PSBT spending coins of ours: the coins it spends, which of its outputs are ours,
+    /// its fee and, for each of our spending paths, the signatures it still needs and when it
+    /// becomes usable.
+    pub fn analyze_psbt(&self, psbt: &Psbt) -> Result<AnalyzePsbtResult, CommandError> {
+        let mut db_conn = self.wallet_conn();
+        let network = self.config.bitcoin_config.network;
+        let tx = &psbt.unsigned_tx;
+        let spend_info = self
+            .main_descriptor
+            .partial_spend_info(psbt)
+            .map_err(|e| CommandError::InvalidPsbt(e.to_string()))?;
+
+        let prevouts: Vec<bitcoin::OutPoint> =
+            tx.input.iter().map(|txin| txin.previous_output).collect();
+        let coins = db_conn.coins_by_outpoints(&prevouts);
+        let inputs: Vec<PsbtInputInfo> = tx
+            .input
+            .iter()
+            .zip(psbt.inputs.iter())
+            .map(|(txin, psbt_in)| {
+                let coin = coins.get(&txin.previous_output);
+                let mut key_origins: BTreeMap<bip32::Fingerprint, Vec<bip32::DerivationPath>> =
+                    BTreeMap::new();
+                let origins = psbt_in
+                    .bip32_derivation
+                    .values()
+                    .chain(psbt_in.tap_key_origins.values().map(|(_, origin)| origin));
+                for (fg, der_path) in origins {
+                    key_origins.entry(*fg).or_default().push(der_path.clone());
+                }
+                PsbtInputInfo {
+                    outpoint: txin.previous_output,
+                    amount: coin.map(|c| c.amount).or_else(|| {
+                        psbt_in
+                            .witness_utxo
+                            .as_ref()
+                            .map(|txo| bitcoin::Amount::from_sat(txo.value))
+                    }),
+                    is_mine: coin.is_some(),
+                    derivation_index: coin.map(|c| c.derivation_index.into()),
+                    is_change: coin.map(|c| c.is_change),
+                    block_height: coin.and_then(|c| c.block_info.map(|b| b.height)),
+                    key_origins,
+                }
+            })
+            .collect();
+
+        let outputs = tx
+            .output
+            .iter()
+            .map(|txo| {
+                let address = bitcoin::Address::from_script(&txo.script_pubkey, network).ok();
+                let derivation = address
+                    .as_ref()
+                    .and_then(|addr| db_conn.derivation_index_by_address(addr));
+                PsbtOutputInfo {
+                    address: address.map(|addr| bitcoin::Address::new(addr.network, addr.payload)),
+                    amount: bitcoin::Amount::from_sat(txo.value),
+                    is_mine: derivation.is_some(),
+                    is_change: derivation.map(|(_, is_change)| is_change),
+                    derivation_index: derivation.map(|(index, _)| index.into()),
+                }
+            })
+            .collect();
+
+        // We can only know the fee if we know the value of all the inputs.
+        let fee = inputs
+            .iter()
+            .map(|input| input.amount)
+            .sum::<Option<bitcoin::Amount>>()
+            .and_then(|value_in| {
+                let value_out: u64 = tx.output.iter().map(|txo| txo.value).sum();
+                value_in.checked_sub(bitcoin::Amount::from_sat(value_out))
+            });
+        let feerate_vb = fee.map(|fee| fee.to_sat() / max_vsize(&self.main_descriptor, tx));
+
+        // A recovery path becomes usable once its timelock expired for all the coins. We can't
+        // tell when if one of them isn't confirmed.
+        let policy = self.main_descriptor.policy();
+        let primary_path = psbt_path_info(policy.primary_path(), Some(spend_info.primary_path()));
+        let recovery_paths = policy
+            .recovery_paths()
+            .iter()
+            .map(|(timelock, path)| {
+                let path_spend_info = spend_info.recovery_paths().get(timelock);
+                let available_at = inputs
+                    .iter()
+                    .map(|input| input.block_height.map(|h| h + i32::from(*timelock)))
+                    .collect::<Option<Vec<i32>>>()
+                    .and_then(|heights| heights.into_iter().max());
+                PsbtRecoveryPathInfo {
+                    timelock: *timelock,
+                    is_enabled: path_spend_info.is_some(),
+                    available_at,
+                    path: psbt_path_info(path, path_spend_info),
+                }
+            })
+            .collect();
+
+        Ok(AnalyzePsbtResult {
+            txid: tx.txid(),
+            inputs,
+            outputs,
+            fee,
+            feerate_vb,
+            primary_path,
+            recovery_paths,
+        })
+    }
+
+    /// Analyze this stored Spend transaction. See [`DaemonControl::analyze_psbt`].
+    pub fn analyze_spend(&self, txid: &bitcoin::Txid) -> Result<AnalyzePsbtResult, CommandError> {
+        let psbt = self
+            .wallet_conn()
+            .spend_tx(txid)
+            .ok_or(CommandError::UnknownSpend(*txid))?;
+        self.analyze_psbt(&psbt)
+    }
+
+    // Get this stored Spend transaction's PSBT, finalized with the elements it contains.
+    fn finalized_spend(
+        &self,
+        db_conn: &mut dyn DatabaseConnection,
+        txid: &bitcoin::Txid,
+    ) -> Result<Psbt, CommandError> {
         let mut spend_psbt = db_conn
             .spend_tx(txid)
             .ok_or(CommandError::UnknownSpend(*txid))?;
+
+        // Make sure the transaction still abides by the guardrails, as the PSBT may have been
+        // updated since it was created, and that it doesn't bring the outflow over the limit.
+        if let Some(guardrails) = &self.config.guardrails_config {
+            let tx = &spend_psbt.unsigned_tx;
+            let prevouts: Vec<bitcoin::OutPoint> =
+                tx.input.iter().map(|txin| txin.previous_output).collect();
+            let coins = db_conn.coins_by_outpoints(&prevouts);
+            if let Some(op) = prevouts.iter().find(|op| !coins.contains_key(op)) {
+                return Err(CommandError::UnknownOutpoint(*op));
+            }
+            check_broadcast_guardrails(
+                db_conn,
+                guardrails,
+                tx,
+                &coins,
+                max_vsize(&self.main_descriptor, tx),
+                self.config.bitcoin_config.network,
+            )?;
+        }
+
         spend_psbt.finalize_mut(&self.secp).map_err(|e| {
             CommandError::SpendFinalization(
                 e.into_iter()
@@ -637,19 +1621,289 @@ impl DaemonControl {
             )
         })?;
 
+        Ok(spend_psbt)
+    }
+
+    /// Finalize this stored Spend transaction, for it to be broadcast through another channel.
+    /// The stored PSBT is left untouched.
+    pub fn finalize_spend(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Result<FinalizeSpendResult, CommandError> {
+        let psbt = self.finalized_spend(&mut *self.wallet_conn(), txid)?;
+        let tx = psbt.clone().extract_tx();
+        Ok(FinalizeSpendResult { psbt, tx })
+    }
+
+    /// Finalize and broadcast this stored Spend transaction.
+    pub fn broadcast_spend(&self, txid: &bitcoin::Txid) -> Result<(), CommandError> {
+        // First, try to finalize the spending transaction with the elements contained
+        // in the PSBT.
+        let mut db_conn = self.wallet_conn();
+        let spend_psbt = self.finalized_spend(&mut *db_conn, txid)?;
+
         // Then, broadcast it (or try to, we never know if we are not going to hit an
         // error at broadcast time).
         let final_tx = spend_psbt.extract_tx();
         self.bitcoin
             .broadcast_tx(&final_tx)
-            .map_err(CommandError::TxBroadcast)
+            .map_err(CommandError::TxBroadcast)?;
+        db_conn.set_spend_status(txid, SpendStatus::Broadcast);
+
+        Ok(())
+    }
+
+    /// Broadcast a transaction finalized outside of the daemon. It must only spend coins of ours
+    /// and, if guardrails are configured, abide by them.
+    pub fn broadcast_raw_tx(&self, tx: &bitcoin::Transaction) -> Result<(), CommandError> {
+        let mut db_conn = self.wallet_conn();
+        if tx.input.is_empty() {
+            return Err(CommandError::NoOutpoint);
+        }
+        let prevouts: Vec<bitcoin::OutPoint> =
+            tx.input.iter().map(|txin| txin.previous_output).collect();
+        let coins = db_conn.coins_by_outpoints(&prevouts);
+        if let Some(op) = prevouts.iter().find(|op| !coins.contains_key(op)) {
+            return Err(CommandError::UnknownOutpoint(*op));
+        }
+
+        if let Some(guardrails) = &self.config.guardrails_config {
+            check_broadcast_guardrails(
+                &mut *db_conn,
+                guardrails,
+                tx,
+                &coins,
+                tx.vsize() as u64,
+                self.config.bitcoin_config.network,
+            )?;
+        }
+
+        self.bitcoin
+            .broadcast_tx(tx)
+            .map_err(CommandError::TxBroadcast)?;
+        // It may have been finalized from one of our stored Spend transactions.
+        db_conn.set_spend_status(&tx.txid(), SpendStatus::Broadcast);
+
+        Ok(())
+    }
+
+    /// Create a transaction replacing this unconfirmed Spend transaction of ours with a higher
+    /// feerate.
+    ///
+    /// If `is_cancel` is set, all the coins it spends are sent back to a single change output.
+    /// Otherwise its payment outputs are kept, its change output is lowered and more confirmed
+    /// coins are added if necessary. If no feerate is given, the minimum feerate for the
+    /// replacement to be relayed is used.
+    pub fn rbf_psbt(
+        &self,
+        txid: &bitcoin::Txid,
+        is_cancel: bool,
+        feerate_vb: Option<u64>,
+    ) -> Result<CreateSpendResult, CommandError> {
+        let mut db_conn = self.wallet_conn();
+
+        let tx = db_conn
+            .spend_tx(txid)
+            .map(|psbt| psbt.unsigned_tx)
+            .or_else(|| self.bitcoin.wallet_transaction(txid).map(|(tx, _)| tx))
+            .ok_or(CommandError::UnknownSpend(*txid))?;
+        if !tx.is_explicitly_rbf() {
+            return Err(CommandError::RbfError(RbfErrorInfo::NotSignaling));
+        }
+        let mempool_entry = self
+            .bitcoin
+            .mempool_entry(txid)
+            .ok_or(CommandError::RbfError(RbfErrorInfo::NotInMempool))?;
+
+        // We must be able to sign for all the inputs of the replacement.
+        let prev_outpoints: Vec<bitcoin::OutPoint> =
+            tx.input.iter().map(|txin| txin.previous_output).collect();
+        let coins = db_conn.coins_by_outpoints(&prev_outpoints);
+        if let Some(op) = prev_outpoints.iter().find(|op| !coins.contains_key(op)) {
+            return Err(CommandError::UnknownOutpoint(*op));
+        }
+
+        // Sort the outputs between payments, which are kept in order unless we cancel, and our
+        // change. Outputs which aren't to an address (such as OP_RETURN) are payments too.
+        let mut destinations = Vec::with_capacity(tx.output.len());
+        let mut change_index = None;
+        for txo in &tx.output {
+            let derivation_index = bitcoin::Address::from_script(
+                &txo.script_pubkey,
+                self.config.bitcoin_config.network,
+            )
+            .ok()
+            .and_then(|address| db_conn.derivation_index_by_address(&address));
+            match derivation_index {
+                Some((index, true)) if change_index.is_none() => change_index = Some(index),
+                _ if is_cancel => {}
+                _ => destinations.push(txo.clone()),
+            }
+        }
+        // When cancelling, send everything to a fresh change address rather than reusing the one
+        // of the replaced transaction. Also use a fresh one if it had no change. Pick it once for
+        // all the attempts below, and only consume it if we end up adding a change output.
+        let fresh_change_index = if is_cancel || change_index.is_none() {
+            change_index = Some(db_conn.change_index());
+            change_index
+        } else {
+            None
+        };
+
+        // BIP125 requires the replacement to pay a higher feerate than the original transaction.
+        let min_feerate_vb = mempool_entry
+            .fees
+            .base
+            .to_sat()
+            .checked_div(mempool_entry.vsize)
+            .unwrap()
+            .checked_add(1)
+            .unwrap();
+        let mut feerate_vb = match feerate_vb {
+            Some(feerate_vb) if feerate_vb < min_feerate_vb => {
+                return Err(CommandError::RbfError(RbfErrorInfo::TooLowFeerate(
+                    min_feerate_vb,
+                )));
+            }
+            Some(feerate_vb) => feerate_vb,
+            None => min_feerate_vb,
+        };
+
+        // It also requires it to pay at least the fees of the transactions it evicts, plus the
+        // incremental relay fee for its own size. If it doesn't, bump the feerate and try again.
+        let replaced = ReplacedTx { change_index };
+        loop {
+            let res = self.create_spend_internal(
+                &mut db_conn,
+                &destinations,
+                &prev_outpoints,
+                feerate_vb,
+                Some(&replaced),
+            )?;
+            let tx = &res.psbt.unsigned_tx;
+            let in_value: u64 = res
+                .psbt
+                .inputs
+                .iter()
+                .filter_map(|psbt_in| psbt_in.witness_utxo.as_ref().map(|txo| txo.value))
+                .sum();
+            let out_value: u64 = tx.output.iter().map(|txo| txo.value).sum();
+            let fee = in_value.checked_sub(out_value).unwrap();
+            let tx_vb = max_vsize(&self.main_descriptor, tx);
+            let min_fee = mempool_entry
+                .fees
+                .descendant
+                .to_sat()
+                .checked_add(tx_vb.checked_mul(INCREMENTAL_RELAY_FEERATE).unwrap())
+                .unwrap();
+            if fee >= min_fee {
+                if let Some(index) = fresh_change_index {
+                    let change_spk = self
+                        .main_descriptor
+                        .change_descriptor()
+                        .derive(index, &self.secp)
+                        .script_pubkey();
+                    if tx.output.iter().any(|txo| txo.script_pubkey == change_spk) {
+                        let next_index = index
+                            .increment()
+                            .expect("Must not get into hardened territory");
+                        db_conn.set_change_index(next_index, &self.secp);
+                    }
+                }
+                return Ok(res);
+            }
+            let needed_feerate_vb = min_fee
+                .checked_add(tx_vb - 1)
+                .unwrap()
+                .checked_div(tx_vb)
+                .unwrap();
+            feerate_vb = cmp::max(feerate_vb + 1, needed_feerate_vb);
+        }
+    }
+
+    /// Create a transaction spending all our unspent outputs of this unconfirmed transaction to
+    /// a change output, paying enough fees for both transactions to reach the given feerate
+    /// (Child Pays For Parent).
+    pub fn create_cpfp(
+        &self,
+        txid: &bitcoin::Txid,
+        feerate_vb: u64,
+    ) -> Result<CreateSpendResult, CommandError> {
+        if feerate_vb < 1 {
+            return Err(CommandError::InvalidFeerate(feerate_vb));
+        }
+        let mut db_conn = self.wallet_conn();
+
+        let (parent_tx, parent_block) = self
+            .bitcoin
+            .wallet_transaction(txid)
+            .ok_or(CommandError::CpfpError(CpfpErrorInfo::UnknownTransaction))?;
+        let parent_fee = match (parent_block, self.bitcoin.mempool_entry(txid)) {
+            (None, Some(entry)) => entry.fees.base.to_sat(),
+            _ => return Err(CommandError::CpfpError(CpfpErrorInfo::NotInMempool)),
+        };
+        let parent_vb = parent_tx.vsize() as u64;
+        let parent_feerate_vb = parent_fee.checked_div(parent_vb).unwrap();
+        if parent_feerate_vb >= feerate_vb {
+            return Err(CommandError::CpfpError(CpfpErrorInfo::AlreadyAboveFeerate(
+                parent_feerate_vb,
+            )));
+        }
+
+        // The outputs of the parent we can spend.
+        let parent_outpoints: Vec<bitcoin::OutPoint> = (0..parent_tx.output.len())
+            .map(|vout| bitcoin::OutPoint::new(*txid, vout.try_into().unwrap()))
+            .collect();
+        let mut coins_outpoints: Vec<bitcoin::OutPoint> = db_conn
+            .coins(&[CoinStatus::Unconfirmed], &parent_outpoints)
+            .into_values()
+            .filter(|coin| !coin.is_immature)
+            .map(|coin| coin.outpoint)
+            .collect();
+        if coins_outpoints.is_empty() {
+            return Err(CommandError::CpfpError(CpfpErrorInfo::NoSpendableOutput));
+        }
+        coins_outpoints.sort_unstable();
+
+        // The child must pay for the whole package at the target feerate, minus what the parent
+        // already pays. It spends the coins to a single change output, so we can compute its size
+        // beforehand the same way we do when creating the transaction.
+        let change_txo = bitcoin::TxOut {
+            value: u64::MAX,
+            script_pubkey: self
+                .main_descriptor
+                .change_descriptor()
+                .derive(db_conn.change_index(), &self.secp)
+                .script_pubkey(),
+        };
+        let child_tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+            input: vec![bitcoin::TxIn::default(); coins_outpoints.len()],
+            output: vec![change_txo],
+        };
+        let child_vb = (child_tx.vsize()
+            + self.main_descriptor.max_sat_vbytes() * coins_outpoints.len())
+            as u64;
+        let child_fee = feerate_vb
+            .checked_mul(parent_vb.checked_add(child_vb).unwrap())
+            .unwrap()
+            .checked_sub(parent_fee)
+            .unwrap();
+        let child_feerate_vb = child_fee
+            .checked_add(child_vb - 1)
+            .unwrap()
+            .checked_div(child_vb)
+            .unwrap();
+
+        self.create_spend_internal(&mut db_conn, &[], &coins_outpoints, child_feerate_vb, None)
     }
 
     /// Trigger a rescan of the block chain for transactions involving our main descriptor between
     /// the given date and the current tip.
     /// The date must be after the genesis block time and before the current tip blocktime.
     pub fn start_rescan(&self, timestamp: u32) -> Result<(), CommandError> {
-        let mut db_conn = self.db.connection();
+        let mut db_conn = self.wallet_conn();
 
         let future_timestamp = self
             .bitcoin
@@ -667,82 +1921,479 @@ impl DaemonControl {
         // rescanning. This could make us crash with the bitcoind backend if someone triggered a
         // rescan of the wallet just after we checked above and did now.
         self.bitcoin
-            .start_rescan(&self.config.main_descriptor, timestamp)
+            .start_rescan(&self.main_descriptor, timestamp)
             .map_err(CommandError::RescanTrigger)?;
         db_conn.set_rescan(timestamp);
 
         Ok(())
     }
 
-    /// list_confirmed_transactions retrieves a limited list of transactions which occured between two given dates.
-    pub fn list_confirmed_transactions(
-        &self,
-        start: u32,
-        end: u32,
-        limit: u64,
-    ) -> ListTransactionsResult {
-        let mut db_conn = self.db.connection();
-        let txids = db_conn.list_txids(start, end, limit);
-        let transactions = txids
-            .iter()
-            .filter_map(|txid| {
-                // TODO: batch those calls to the Bitcoin backend
-                // so it can in turn optimize its queries.
-                self.bitcoin
-                    .wallet_transaction(txid)
-                    .map(|(tx, block)| TransactionInfo {
-                        tx,
-                        height: block.map(|b| b.height),
-                        time: block.map(|b| b.time),
-                    })
-            })
-            .collect();
-        ListTransactionsResult { transactions }
-    }
+    /// Write a consistent copy of the database to a new file with this name, in the backups
+    /// directory of our data directory. Returns the path of the written file.
+    pub fn backup(&self, file_name: &str) -> Result<path::PathBuf, CommandError> {
+        // Don't let the caller write our database anywhere else.
+        let mut components = path::Path::new(file_name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(path::Component::Normal(_)), None)
+        ) {
+            return Err(CommandError::InvalidBackupName(file_name.to_string()));
+        }
+        let mut backup_dir = self
+            .config
+            .data_dir()
+            .expect("The data directory is set at startup");
+        backup_dir.push(self.config.bitcoin_config.network.to_string());
+        backup_dir.push("backups");
+        fs::create_dir_all(&backup_dir).map_err(|e| CommandError::Backup(e.to_string()))?;
+        let path = backup_dir.join(file_name);
+        if path.exists() {
+            return Err(CommandError::InvalidBackupName(file_name.to_string()));
+        }
 
-    /// list_transactions retrieves the transactions with the given txids.
-    pub fn list_transactions(&self, txids: &[bitcoin::Txid]) -> ListTransactionsResult {
-        let transactions = txids
-            .iter()
-            .filter_map(|txid| {
-                // TODO: batch those calls to the Bitcoin backend
-                // so it can in turn optimize its queries.
-                self.bitcoin
-                    .wallet_transaction(txid)
-                    .map(|(tx, block)| TransactionInfo {
-                        tx,
-                        height: block.map(|b| b.height),
-                        time: block.map(|b| b.time),
-                    })
-            })
-            .collect();
-        ListTransactionsResult { transactions }
+        self.db
+            .connection()
+            .backup(&path)
+            .map_err(CommandError::Backup)?;
+        Ok(path)
     }
 
-    /// Create a transaction that sweeps all coins for which a timelocked recovery path is
-    /// currently available to a provided address with the provided feerate.
-    ///
-    /// The `timelock` parameter can be used to specify which recovery path to use. By default,
-    /// we'll use the first recovery path available.
-    ///
-    /// Note that not all coins may be spendable through a single recovery path at the same time.
-    pub fn create_recovery(
-        &self,
-        address: bitcoin::Address<address::NetworkUnchecked>,
-        feerate_vb: u64,
-        timelock: Option<u16>,
-    ) -> Result<CreateRecoveryResult, CommandError> {
-        if feerate_vb < 1 {
-            return Err(CommandError::InvalidFeerate(feerate_vb));
+    /// Export the state of this wallet which can't be retrieved from the block chain.
+    pub fn export_wallet(&self) -> WalletExport {
+        let mut db_conn = self.wallet_conn();
+        WalletExport {
+            network: self.config.bitcoin_config.network,
+            descriptor: self.main_descriptor.clone(),
+            timestamp: db_conn.wallet().timestamp,
+            receive_index: db_conn.receive_index().into(),
+            change_index: db_conn.change_index().into(),
+            labels: db_conn.list_labels(),
+            spend_txs: db_conn
+                .list_spend()
+                .into_iter()
+                .map(ListSpendEntry::from)
+                .collect(),
         }
-        let address = self.validate_address(address)?;
-        let mut db_conn = self.db.connection();
+    }
+
+    /// Restore the state of this wallet from an export. Derivation indexes are never decreased,
+    /// and existing labels and Spend transactions are overwritten. If the exported wallet was
+    /// created before this one, the block chain is rescanned from its creation date.
+    pub fn import_wallet(&self, export: WalletExport) -> Result<(), CommandError> {
+        let network = self.config.bitcoin_config.network;
+        if export.network != network {
+            return Err(CommandError::DescriptorNetworkMismatch(network));
+        }
+        if export.descriptor != self.main_descriptor {
+            return Err(CommandError::WalletExportMismatch(Box::new(
+                export.descriptor,
+            )));
+        }
+        let receive_index = bip32::ChildNumber::from_normal_idx(export.receive_index)
+            .map_err(|_| CommandError::InvalidDerivationIndex(export.receive_index))?;
+        let change_index = bip32::ChildNumber::from_normal_idx(export.change_index)
+            .map_err(|_| CommandError::InvalidDerivationIndex(export.change_index))?;
+        let mut labels = HashMap::with_capacity(export.labels.len());
+        for (item, value) in export.labels {
+            let item =
+                LabelItem::from_str(&item, network).ok_or(CommandError::InvalidLabelItem(item))?;
+            labels.insert(item, Some(value));
+        }
+
+        let mut db_conn = self.wallet_conn();
+        if export.timestamp < db_conn.wallet().timestamp {
+            self.start_rescan(export.timestamp)?;
+        }
+        if receive_index > db_conn.receive_index() {
+            db_conn.set_receive_index(receive_index, &self.secp);
+        }
+        if change_index > db_conn.change_index() {
+            db_conn.set_change_index(change_index, &self.secp);
+        }
+        db_conn.update_labels(&labels);
+        for spend in export.spend_txs {
+            db_conn.store_spend(&spend.psbt);
+            if spend.note.is_some() {
+                db_conn.set_spend_note(&spend.psbt.unsigned_tx.txid(), spend.note.as_deref());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export the labels of this wallet as BIP329 records. The xpubs of the descriptor are
+    /// labelled with the name of the wallet. Labels on our coins are exported both as the label
+    /// of the output and, if the coin was spent, as the label of the input spending it.
+    pub fn export_labels(&self) -> ExportLabelsResult {
+        let network = self.config.bitcoin_config.network;
+        let mut db_conn = self.wallet_conn();
+        let wallet_name = db_conn.wallet().name;
+
+        let mut labels: Vec<Bip329Label> = self
+            .main_descriptor
+            .xpubs()
+            .into_iter()
+            .map(|xpub| Bip329Label::new(Bip329Type::Xpub, xpub.to_string(), wallet_name.clone()))
+            .collect();
+        let mut outpoint_labels = Vec::new();
+        for (item, value) in db_conn
+            .list_labels()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+        {
+            match LabelItem::from_str(&item, network) {
+                Some(LabelItem::Txid(txid)) => {
+                    labels.push(Bip329Label::new(Bip329Type::Tx, txid.to_string(), value))
+                }
+                Some(LabelItem::Address(addr)) => {
+                    labels.push(Bip329Label::new(Bip329Type::Addr, addr.to_string(), value))
+                }
+                Some(LabelItem::OutPoint(op)) => outpoint_labels.push((op, value)),
+                None => log::error!("Invalid label item in database: '{}'", item),
+            }
+        }
+
+        let outpoints: Vec<bitcoin::OutPoint> = outpoint_labels.iter().map(|(op, _)| *op).collect();
+        let coins = db_conn.coins_by_outpoints(&outpoints);
+        for (op, value) in outpoint_labels {
+            let coin = coins.get(&op);
+            let mut output_label = Bip329Label::new(Bip329Type::Output, op.to_string(), value);
+            // We don't have a notion of frozen coins. All of ours are spendable.
+            output_label.spendable = coin.map(|_| true);
+            let input_label = coin
+                .and_then(|coin| coin.spend_txid)
+                .and_then(|txid| self.bitcoin.wallet_transaction(&txid))
+                .and_then(|(tx, _)| {
+                    let txid = tx.txid();
+                    tx.input
+                        .iter()
+                        .position(|txin| txin.previous_output == op)
+                        .map(|vin| {
+                            Bip329Label::new(
+                                Bip329Type::Input,
+                                format!("{}:{}", txid, vin),
+                                output_label.label.clone().expect("Always set above"),
+                            )
+                        })
+                });
+            labels.push(output_label);
+            labels.extend(input_label);
+        }
+
+        ExportLabelsResult { labels }
+    }
+
+    /// Import BIP329 records as labels for this wallet, overwriting existing ones. Labels longer
+    /// than we support are truncated. Input labels are applied to the coin they spend, if it is
+    /// ours and its output isn't labelled in the same import. Records which don't map onto an item
+    /// we can label are skipped.
+    pub fn import_labels(&self, records: Vec<Bip329Label>) -> ImportLabelsResult {
+        let network = self.config.bitcoin_config.network;
+        let records_count = records.len();
+        let mut items = HashMap::new();
+        let mut inputs = Vec::new();
+        for record in records {
+            let label = match record.label.as_deref().map(truncated_label) {
+                Some(label) if !label.is_empty() => label,
+                _ => continue,
+            };
+            let item = match record.label_type {
+                Bip329Type::Tx => bitcoin::Txid::from_str(&record.reference)
+                    .ok()
+                    .map(LabelItem::Txid),
+                Bip329Type::Addr => {
+                    bitcoin::Address::<address::NetworkUnchecked>::from_str(&record.reference)
+                        .ok()
+                        .filter(|addr| addr.is_valid_for_network(network))
+                        .map(|addr| LabelItem::Address(addr.assume_checked()))
+                }
+                Bip329Type::Output => bitcoin::OutPoint::from_str(&record.reference)
+                    .ok()
+                    .map(LabelItem::OutPoint),
+                Bip329Type::Input => {
+                    if let Ok(input) = bitcoin::OutPoint::from_str(&record.reference) {
+                        inputs.push((input, label));
+                    }
+                    continue;
+                }
+                Bip329Type::Pubkey | Bip329Type::Xpub | Bip329Type::Unknown => None,
+            };
+            if let Some(item) = item {
+                items.insert(item, Some(label));
+            }
+        }
+
+        let mut db_conn = self.wallet_conn();
+        for (input, label) in inputs {
+            let prevout = self
+                .bitcoin
+                .wallet_transaction(&input.txid)
+                .and_then(|(tx, _)| {
+                    tx.input
+                        .get(input.vout as usize)
+                        .map(|txin| txin.previous_output)
+                })
+                .filter(|prevout| !db_conn.coins_by_outpoints(&[*prevout]).is_empty());
+            if let Some(prevout) = prevout {
+                if let hash_map::Entry::Vacant(entry) = items.entry(LabelItem::OutPoint(prevout)) {
+                    entry.insert(Some(label));
+                }
+            }
+        }
+        db_conn.update_labels(&items);
+
+        ImportLabelsResult {
+            imported: items.len(),
+            skipped: records_count - items.len(),
+        }
+    }
+
+    /// Get a handle to run the commands against the wallet with this name.
+    pub fn with_wallet(&self, name: &str) -> Result<DaemonControl, CommandError> {
+        let wallet = self
+            .db
+            .connection()
+            .wallets()
+            .into_iter()
+            .find(|wallet| wallet.name == name)
+            .ok_or_else(|| CommandError::UnknownWallet(name.to_string()))?;
+        Ok(DaemonControl {
+            wallet_id: Some(wallet.id),
+            main_descriptor: wallet.main_descriptor,
+            ..self.clone()
+        })
+    }
+
+    /// List all the wallets managed by this daemon.
+    pub fn list_wallets(&self) -> ListWalletsResult {
+        let mut db_conn = self.db.connection();
+        let wallets = db_conn
+            .wallets()
+            .into_iter()
+            .map(|wallet| ListWalletsEntry {
+                name: wallet.name,
+                main_descriptor: wallet.main_descriptor,
+                timestamp: wallet.timestamp,
+                rescan_timestamp: wallet.rescan_timestamp,
+            })
+            .collect();
+        ListWalletsResult { wallets }
+    }
+
+    // Check a new wallet could be created with this name and descriptor.
+    fn check_new_wallet(
+        &self,
+        db_conn: &mut Box<dyn DatabaseConnection>,
+        name: &str,
+        main_descriptor: &descriptors::LianaDescriptor,
+    ) -> Result<(), CommandError> {
+        let network = self.config.bitcoin_config.network;
+        if !main_descriptor.all_xpubs_net_is(network) {
+            return Err(CommandError::DescriptorNetworkMismatch(network));
+        }
+        for wallet in db_conn.wallets() {
+            if wallet.name == name {
+                return Err(CommandError::WalletNameTaken(wallet.name));
+            }
+            if &wallet.main_descriptor == main_descriptor {
+                return Err(CommandError::DescriptorAlreadyTracked(wallet.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new wallet for a descriptor which was never used, and start tracking its coins.
+    pub fn create_wallet(
+        &self,
+        name: &str,
+        main_descriptor: &descriptors::LianaDescriptor,
+    ) -> Result<(), CommandError> {
+        let mut db_conn = self.db.connection();
+        self.check_new_wallet(&mut db_conn, name, main_descriptor)?;
+
+        // There is no need to rescan for a fresh descriptor, but the Bitcoin backend needs to
+        // start watching for it.
+        let timestamp = curr_timestamp();
+        self.bitcoin
+            .start_rescan(main_descriptor, timestamp)
+            .map_err(CommandError::RescanTrigger)?;
+        db_conn.create_wallet(name, main_descriptor, timestamp, &self.secp);
+
+        Ok(())
+    }
+
+    /// Create a new wallet for a descriptor which may have been used in the past, and rescan the
+    /// block chain for its transactions since the given date.
+    /// The date must be after the genesis block time and before the current tip blocktime.
+    pub fn load_wallet(
+        &self,
+        name: &str,
+        main_descriptor: &descriptors::LianaDescriptor,
+        timestamp: u32,
+    ) -> Result<(), CommandError> {
+        let mut db_conn = self.db.connection();
+        self.check_new_wallet(&mut db_conn, name, main_descriptor)?;
+
+        let future_timestamp = self
+            .bitcoin
+            .tip_time()
+            .map(|t| timestamp >= t)
+            .unwrap_or(false);
+        if timestamp < MAINNET_GENESIS_TIME || future_timestamp {
+            return Err(CommandError::InsaneRescanTimestamp(timestamp));
+        }
+        // The Bitcoin backend rescans for all the wallets at once.
+        let rescanning = db_conn
+            .wallets()
+            .iter()
+            .any(|wallet| wallet.rescan_timestamp.is_some());
+        if rescanning || self.bitcoin.rescan_progress().is_some() {
+            return Err(CommandError::AlreadyRescanning);
+        }
+
+        self.bitcoin
+            .start_rescan(main_descriptor, timestamp)
+            .map_err(CommandError::RescanTrigger)?;
+        let wallet_id = db_conn.create_wallet(name, main_descriptor, curr_timestamp(), &self.secp);
+        self.db.wallet_connection(wallet_id).set_rescan(timestamp);
+
+        Ok(())
+    }
+
+    /// list_confirmed_transactions retrieves a limited list of transactions which occured between two given dates.
+    pub fn list_confirmed_transactions(
+        &self,
+        start: u32,
+        end: u32,
+        limit: u64,
+    ) -> ListTransactionsResult {
+        let mut db_conn = self.wallet_conn();
+        let txids = db_conn.list_txids(start, end, limit);
+        self.transactions_info(db_conn.as_mut(), &txids)
+    }
+
+    /// list_transactions retrieves the transactions with the given txids.
+    pub fn list_transactions(&self, txids: &[bitcoin::Txid]) -> ListTransactionsResult {
+        let mut db_conn = self.wallet_conn();
+        self.transactions_info(db_conn.as_mut(), txids)
+    }
+
+    /// Retrieve a page of all the transactions of this wallet. Unconfirmed transactions come
+    /// first, then the most recently confirmed ones.
+    pub fn list_recent_transactions(&self, limit: u64, offset: u64) -> ListTransactionsResult {
+        let mut db_conn = self.wallet_conn();
+        let txids = db_conn.list_recent_txids(limit, offset);
+        self.transactions_info(db_conn.as_mut(), &txids)
+    }
+
+    // Get the transactions with these txids from the Bitcoin backend, along with the information
+    // about them we can compute from our coins.
+    fn transactions_info(
+        &self,
+        db_conn: &mut dyn DatabaseConnection,
+        txids: &[bitcoin::Txid],
+    ) -> ListTransactionsResult {
+        let network = self.config.bitcoin_config.network;
+        let tip_height = db_conn.chain_tip().map(|tip| tip.height);
+        let transactions = txids
+            .iter()
+            .filter_map(|txid| {
+                // TODO: batch those calls to the Bitcoin backend
+                // so it can in turn optimize its queries.
+                self.bitcoin.wallet_transaction(txid)
+            })
+            .map(|(tx, block)| {
+                let txid = tx.txid();
+                let prevouts: Vec<bitcoin::OutPoint> =
+                    tx.input.iter().map(|txin| txin.previous_output).collect();
+                let outpoints: Vec<bitcoin::OutPoint> = (0..tx.output.len())
+                    .map(|vout| bitcoin::OutPoint::new(txid, vout as u32))
+                    .collect();
+                let spent_coins = db_conn.coins_by_outpoints(&prevouts);
+                let received_coins = db_conn.coins_by_outpoints(&outpoints);
+                let outgoing: bitcoin::Amount = spent_coins.values().map(|c| c.amount).sum();
+                let incoming: bitcoin::Amount = received_coins.values().map(|c| c.amount).sum();
+
+                // We can only know the fee if we know the value of all the inputs.
+                let fee = if !spent_coins.is_empty() && spent_coins.len() == tx.input.len() {
+                    let outputs_value: bitcoin::Amount = tx
+                        .output
+                        .iter()
+                        .map(|txo| bitcoin::Amount::from_sat(txo.value))
+                        .sum();
+                    outgoing.checked_sub(outputs_value)
+                } else {
+                    None
+                };
+                let kind = if spent_coins.is_empty() {
+                    TransactionKind::Incoming
+                } else if received_coins.len() == tx.output.len() {
+                    TransactionKind::SelfSend
+                } else {
+                    TransactionKind::Outgoing
+                };
+
+                let mut items = HashSet::new();
+                items.insert(LabelItem::Txid(txid));
+                items.extend(prevouts.into_iter().map(LabelItem::OutPoint));
+                items.extend(outpoints.into_iter().map(LabelItem::OutPoint));
+                items.extend(tx.output.iter().filter_map(|txo| {
+                    bitcoin::Address::from_script(&txo.script_pubkey, network)
+                        .ok()
+                        .map(LabelItem::Address)
+                }));
+                let labels = db_conn.labels(&items);
+
+                let height = block.map(|b| b.height);
+                let confirmations = match (height, tip_height) {
+                    (Some(height), Some(tip_height)) if tip_height >= height => {
+                        (tip_height - height + 1) as u32
+                    }
+                    _ => 0,
+                };
+                TransactionInfo {
+                    tx,
+                    height,
+                    time: block.map(|b| b.time),
+                    confirmations,
+                    kind,
+                    incoming,
+                    outgoing,
+                    amount: bitcoin::SignedAmount::from_sat(
+                        incoming.to_sat() as i64 - outgoing.to_sat() as i64,
+                    ),
+                    fee,
+                    labels,
+                }
+            })
+            .collect();
+        ListTransactionsResult { transactions }
+    }
+
+    /// Create a transaction that sweeps all coins for which a timelocked recovery path is
+    /// currently available to a provided address with the provided feerate.
+    ///
+    /// The `timelock` parameter can be used to specify which recovery path to use. By default,
+    /// we'll use the first recovery path available.
+    ///
+    /// Note that not all coins may be spendable through a single recovery path at the same time.
+    pub fn create_recovery(
+        &self,
+        address: bitcoin::Address<address::NetworkUnchecked>,
+        feerate_vb: u64,
+        timelock: Option<u16>,
+    ) -> Result<CreateRecoveryResult, CommandError> {
+        if feerate_vb < 1 {
+            return Err(CommandError::InvalidFeerate(feerate_vb));
+        }
+        let address = self.validate_address(address)?;
+        let mut db_conn = self.wallet_conn();
 
         // The transaction template. We'll fill-in the inputs afterward.
         let mut psbt = Psbt {
             unsigned_tx: bitcoin::Transaction {
                 version: 2,
-                lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO), // TODO: anti-fee sniping
+                lock_time: self.anti_fee_sniping_locktime(),
                 input: Vec::new(),
                 output: vec![bitcoin::TxOut {
                     script_pubkey: address.script_pubkey(),
@@ -760,8 +2411,7 @@ impl DaemonControl {
         // Query the coins that we can spend through the specified recovery path (if no recovery
         // path specified, use the first available one) from the database.
         let current_height = self.bitcoin.chain_tip().height;
-        let timelock =
-            timelock.unwrap_or_else(|| self.config.main_descriptor.first_timelock_value());
+        let timelock = timelock.unwrap_or_else(|| self.main_descriptor.first_timelock_value());
         let height_delta: i32 = timelock.try_into().expect("Must fit, it's a u16");
         let sweepable_coins = db_conn
             .coins(&[CoinStatus::Unconfirmed, CoinStatus::Confirmed], &[])
@@ -776,15 +2426,16 @@ impl DaemonControl {
         // Fill-in the transaction inputs and PSBT inputs information. Record the value
         // that is fed to the transaction while doing so, to compute the fees afterward.
         let mut in_value = bitcoin::Amount::from_sat(0);
-        let txin_sat_vb = self.config.main_descriptor.max_sat_vbytes();
+        let txin_sat_vb = self.main_descriptor.max_sat_vbytes();
         let mut sat_vb = 1; // Start at 1 for the segwit marker size, rounded up.
         let mut spent_txs = HashMap::new();
         for coin in sweepable_coins {
             in_value += coin.amount;
             psbt.unsigned_tx.input.push(bitcoin::TxIn {
                 previous_output: coin.outpoint,
+                // The nSequence must encode the relative timelock of the recovery path, so it can't
+                // be used for anti-fee-sniping. The nLockTime set above takes care of it instead.
                 sequence: bitcoin::Sequence::from_height(timelock),
-                // TODO: once we move to Taproot, anti-fee-sniping using nSequence
                 ..bitcoin::TxIn::default()
             });
 
@@ -799,20 +2450,18 @@ impl DaemonControl {
 
             let coin_desc = self.derived_desc(&coin);
             sat_vb += txin_sat_vb;
-            let witness_script = Some(coin_desc.witness_script());
             let witness_utxo = Some(bitcoin::TxOut {
                 value: coin.amount.to_sat(),
                 script_pubkey: coin_desc.script_pubkey(),
             });
             let non_witness_utxo = spent_txs.get(&coin.outpoint).cloned();
-            let bip32_derivation = coin_desc.bip32_derivations();
-            psbt.inputs.push(PsbtIn {
-                witness_script,
+            let mut psbt_in = PsbtIn {
                 witness_utxo,
                 non_witness_utxo,
-                bip32_derivation,
                 ..PsbtIn::default()
-            });
+            };
+            coin_desc.update_psbt_in(&mut psbt_in);
+            psbt.inputs.push(psbt_in);
         }
 
         // The sweepable_coins iterator may have been empty.
@@ -828,10 +2477,21 @@ impl DaemonControl {
             .ok_or(CommandError::InsufficientFunds(in_value, None, feerate_vb))?;
         psbt.unsigned_tx.output[0].value = output_value.to_sat();
 
-        sanity_check_psbt(&self.config.main_descriptor, &psbt)?;
+        sanity_check_psbt(
+            &self.main_descriptor,
+            &psbt,
+            self.config.guardrails_config.as_ref(),
+            self.config.bitcoin_config.network,
+        )?;
 
         Ok(CreateRecoveryResult { psbt })
     }
+
+    /// Subscribe to the events emitted by the daemon about all our wallets: new tips, coins
+    /// updates and rescan progress. Events are received until the returned receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        self.events.subscribe()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -839,6 +2499,21 @@ pub struct GetInfoDescriptors {
     pub main: descriptors::LianaDescriptor,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListWalletsEntry {
+    pub name: String,
+    pub main_descriptor: descriptors::LianaDescriptor,
+    /// The date since which we've seen all transactions of this wallet.
+    pub timestamp: u32,
+    /// The date since which we are rescanning the block chain for this wallet, if we are.
+    pub rescan_timestamp: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListWalletsResult {
+    pub wallets: Vec<ListWalletsEntry>,
+}
+
 /// Information about the daemon
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetInfoResult {
@@ -857,6 +2532,55 @@ pub struct GetAddressResult {
     address: bitcoin::Address,
 }
 
+/// The usage of one of our addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressEntry {
+    #[serde(
+        serialize_with = "ser_to_string",
+        deserialize_with = "deser_addr_assume_checked"
+    )]
+    pub address: bitcoin::Address,
+    pub label: Option<String>,
+    /// The number of coins, spent or not, received on this address.
+    pub coins_count: usize,
+    /// The total value received on this address.
+    #[serde(
+        serialize_with = "ser_amount",
+        deserialize_with = "deser_amount_from_sats"
+    )]
+    pub received: bitcoin::Amount,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListAddressesEntry {
+    pub index: u32,
+    pub receive: AddressEntry,
+    pub change: AddressEntry,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListAddressesResult {
+    pub addresses: Vec<ListAddressesEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetAddressInfoResult {
+    #[serde(
+        serialize_with = "ser_to_string",
+        deserialize_with = "deser_addr_assume_checked"
+    )]
+    pub address: bitcoin::Address,
+    /// Whether this address is one of the addresses watched by the wallet.
+    pub is_mine: bool,
+    pub is_change: Option<bool>,
+    pub derivation_index: Option<u32>,
+    /// The descriptor derived at the index of this address.
+    pub descriptor: Option<descriptors::DerivedSinglePathLianaDesc>,
+    /// The witness script of this address, if it is a P2WSH.
+    pub witness_script: Option<bitcoin::ScriptBuf>,
+    pub label: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetLabelsResult {
     pub labels: HashMap<String, String>,
@@ -904,17 +2628,141 @@ pub struct ListCoinsResult {
     pub coins: Vec<ListCoinsEntry>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeeEstimate {
+    /// The number of blocks within which the transaction is expected to be confirmed.
+    pub conf_target: u16,
+    /// The estimated feerate in sats/vb, if the Bitcoin backend could estimate it.
+    pub feerate: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GetFeeEstimatesResult {
+    pub estimates: Vec<FeeEstimate>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CreateSpendResult {
     #[serde(serialize_with = "ser_to_string", deserialize_with = "deser_fromstr")]
     pub psbt: Psbt,
+    /// The coins spent by the transaction.
+    pub coins: Vec<bitcoin::OutPoint>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListSpendEntry {
     #[serde(serialize_with = "ser_to_string", deserialize_with = "deser_fromstr")]
     pub psbt: Psbt,
+    #[serde(default)]
+    pub created_at: Option<u32>,
     pub updated_at: Option<u32>,
+    /// A note attached by the creator of the transaction.
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default = "draft_status")]
+    pub status: SpendStatus,
+}
+
+// Spend transactions exported by older versions don't have a status.
+fn draft_status() -> SpendStatus {
+    SpendStatus::Draft
+}
+
+impl From<SpendTx> for ListSpendEntry {
+    fn from(spend: SpendTx) -> ListSpendEntry {
+        ListSpendEntry {
+            psbt: spend.psbt,
+            created_at: spend.created_at,
+            updated_at: spend.updated_at,
+            note: spend.note,
+            status: spend.status,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignSpendResult {
+    #[serde(serialize_with = "ser_to_string", deserialize_with = "deser_fromstr")]
+    pub psbt: Psbt,
+    /// The number of signatures added to the PSBT.
+    pub signatures: usize,
+}
+
+/// The status of one of our spending paths for a PSBT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtPathInfo {
+    /// The number of signatures required to spend through this path.
+    pub threshold: usize,
+    /// The number of signatures present for this path.
+    pub sigs_count: usize,
+    pub is_satisfied: bool,
+    /// The fingerprints of the keys which provided a signature for this path.
+    pub signed_fingerprints: Vec<bip32::Fingerprint>,
+    /// The fingerprints of the keys which may provide the signatures still needed, if any.
+    pub missing_fingerprints: Vec<bip32::Fingerprint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtRecoveryPathInfo {
+    pub timelock: u16,
+    /// Whether the nSequence of the inputs allows to spend through this path.
+    pub is_enabled: bool,
+    /// The height of the first block the transaction may be included in when spending through
+    /// this path. Unknown if one of the coins isn't confirmed (or isn't ours).
+    pub available_at: Option<i32>,
+    #[serde(flatten)]
+    pub path: PsbtPathInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtInputInfo {
+    pub outpoint: bitcoin::OutPoint,
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub amount: Option<bitcoin::Amount>,
+    /// Whether this input spends one of our coins.
+    pub is_mine: bool,
+    pub derivation_index: Option<u32>,
+    pub is_change: Option<bool>,
+    /// The height of the block the spent coin was confirmed in.
+    pub block_height: Option<i32>,
+    /// The derivation paths of the keys which may sign this input, by master key fingerprint.
+    pub key_origins: BTreeMap<bip32::Fingerprint, Vec<bip32::DerivationPath>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtOutputInfo {
+    /// The address of this output, unless its script isn't a standard one.
+    pub address: Option<bitcoin::Address<address::NetworkUnchecked>>,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+    /// Whether this output pays to one of our addresses.
+    pub is_mine: bool,
+    pub is_change: Option<bool>,
+    pub derivation_index: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalyzePsbtResult {
+    pub txid: bitcoin::Txid,
+    pub inputs: Vec<PsbtInputInfo>,
+    pub outputs: Vec<PsbtOutputInfo>,
+    /// The fee of the transaction, if the value of all its inputs is known.
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub fee: Option<bitcoin::Amount>,
+    /// The feerate of the transaction once all its inputs are satisfied, in sats/vb.
+    pub feerate_vb: Option<u64>,
+    pub primary_path: PsbtPathInfo,
+    pub recovery_paths: Vec<PsbtRecoveryPathInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalizeSpendResult {
+    /// The PSBT with the final scriptSigs and witnesses of all its inputs.
+    #[serde(serialize_with = "ser_to_string", deserialize_with = "deser_fromstr")]
+    pub psbt: Psbt,
+    /// The finalized transaction, ready to be broadcast.
+    #[serde(serialize_with = "ser_hex", deserialize_with = "deser_hex")]
+    pub tx: bitcoin::Transaction,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -922,17 +2770,115 @@ pub struct ListSpendResult {
     pub spend_txs: Vec<ListSpendEntry>,
 }
 
+/// The state of a wallet which can't be retrieved from the block chain, from which a fresh lianad
+/// may be restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletExport {
+    pub network: bitcoin::Network,
+    pub descriptor: descriptors::LianaDescriptor,
+    /// The date since which we've seen all transactions of this wallet.
+    pub timestamp: u32,
+    pub receive_index: u32,
+    pub change_index: u32,
+    pub labels: HashMap<String, String>,
+    pub spend_txs: Vec<ListSpendEntry>,
+}
+
+/// The type of a BIP329 record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bip329Type {
+    Tx,
+    Addr,
+    Pubkey,
+    Input,
+    Output,
+    Xpub,
+    /// A type we don't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A label record in the BIP329 format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bip329Label {
+    #[serde(rename = "type")]
+    pub label_type: Bip329Type,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+impl Bip329Label {
+    pub fn new(label_type: Bip329Type, reference: String, label: String) -> Bip329Label {
+        Bip329Label {
+            label_type,
+            reference,
+            label: Some(label),
+            origin: None,
+            spendable: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportLabelsResult {
+    pub labels: Vec<Bip329Label>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportLabelsResult {
+    /// The number of items which were labelled.
+    pub imported: usize,
+    /// The number of records which were not imported.
+    pub skipped: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListTransactionsResult {
     pub transactions: Vec<TransactionInfo>,
 }
 
+/// How a transaction affects the wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+    /// None of the transaction inputs are ours.
+    Incoming,
+    /// Some of the transaction inputs are ours, and not all of its outputs.
+    Outgoing,
+    /// The transaction spends our coins to ourselves.
+    SelfSend,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransactionInfo {
     #[serde(serialize_with = "ser_hex", deserialize_with = "deser_hex")]
     pub tx: bitcoin::Transaction,
     pub height: Option<i32>,
     pub time: Option<u32>,
+    /// The number of blocks confirming this transaction. 0 if it is unconfirmed.
+    pub confirmations: u32,
+    pub kind: TransactionKind,
+    /// The total value of our coins created by this transaction.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub incoming: bitcoin::Amount,
+    /// The total value of our coins spent by this transaction.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub outgoing: bitcoin::Amount,
+    /// The change in the balance of the wallet caused by this transaction.
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::SignedAmount,
+    /// The fee paid by this transaction, if all its inputs are ours.
+    #[serde(default, with = "bitcoin::amount::serde::as_sat::opt")]
+    pub fee: Option<bitcoin::Amount>,
+    /// The labels of the transaction, its inputs and outputs, and the addresses it pays to.
+    pub labels: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -941,52 +2887,1576 @@ pub struct CreateRecoveryResult {
     pub psbt: Psbt,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{bitcoin::Block, database::BlockInfo, testutils::*};
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitcoin::{d::MempoolEntryFees, Block, MempoolEntry},
+        database::BlockInfo,
+        signer,
+        testutils::*,
+    };
+
+    use bitcoin::{
+        bip32::{self, ChildNumber},
+        blockdata::transaction::{TxIn, TxOut},
+        locktime::absolute,
+        OutPoint, ScriptBuf, Sequence, Transaction, Txid, Witness,
+    };
+    use std::str::FromStr;
+
+    // The position of the output paying to this script. The outputs of the transactions we create
+    // are shuffled.
+    fn output_index(tx: &Transaction, spk: &bitcoin::Script) -> usize {
+        tx.output
+            .iter()
+            .position(|txo| txo.script_pubkey.as_script() == spk)
+            .expect("No output pays to this script")
+    }
+
+    #[test]
+    fn getinfo() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        // We can query getinfo
+        ms.handle.control.get_info();
+        ms.shutdown();
+    }
+
+    #[test]
+    fn fee_estimates() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+
+        assert_eq!(control.estimate_feerate(1), Ok(10));
+        assert_eq!(control.estimate_feerate(1008), Ok(1));
+        assert_eq!(
+            control.estimate_feerate(0),
+            Err(CommandError::InvalidConfTarget(0))
+        );
+        assert_eq!(
+            control.estimate_feerate(1009),
+            Err(CommandError::InvalidConfTarget(1009))
+        );
+
+        let estimates = control.get_fee_estimates().estimates;
+        assert_eq!(estimates.len(), FEE_ESTIMATES_CONF_TARGETS.len());
+        assert_eq!(
+            estimates[0],
+            FeeEstimate {
+                conf_target: 1,
+                feerate: Some(10)
+            }
+        );
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn anti_fee_sniping() {
+        // Most of the time we use the tip height.
+        let mut random = [0xff; 32];
+        assert_eq!(anti_fee_sniping_height(100, &random), 100);
+        random[0..4].copy_from_slice(&11u32.to_be_bytes());
+        assert_eq!(anti_fee_sniping_height(100, &random), 100);
+
+        // Sometimes we go back up to 99 blocks, but never below the genesis.
+        random[0..4].copy_from_slice(&10u32.to_be_bytes());
+        random[4..8].copy_from_slice(&42u32.to_be_bytes());
+        assert_eq!(anti_fee_sniping_height(100, &random), 58);
+        random[4..8].copy_from_slice(&199u32.to_be_bytes());
+        assert_eq!(anti_fee_sniping_height(100, &random), 1);
+        assert_eq!(anti_fee_sniping_height(50, &random), 0);
+        random[4..8].copy_from_slice(&100u32.to_be_bytes());
+        assert_eq!(anti_fee_sniping_height(100, &random), 100);
+    }
+
+    #[test]
+    fn wallets() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+        let desc = descriptors::LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8KLW4HGLXZBJknja7kDUJuFHnM424LbziEXsfkh1WQCiEjjHw4zLqSUm4rvhgyGkkuRowE9tCJSgt3TQB5J3SKAbZ2SdcKST/<0;1>/*),older(52560),pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*)))").unwrap();
+
+        // We can't select an unknown wallet.
+        assert!(matches!(
+            control.with_wallet("vault"),
+            Err(CommandError::UnknownWallet(name)) if name == "vault"
+        ));
+
+        // Create a wallet and select it.
+        control.create_wallet("vault", &desc).unwrap();
+        let wallets = control.list_wallets().wallets;
+        assert_eq!(wallets.len(), 1);
+        assert_eq!(wallets[0].name, "vault");
+        assert_eq!(wallets[0].main_descriptor, desc);
+        assert!(wallets[0].rescan_timestamp.is_none());
+        let vault_control = control.with_wallet("vault").unwrap();
+        assert_eq!(vault_control.get_info().descriptors.main, desc);
+
+        // The name and the descriptor may not be reused.
+        assert_eq!(
+            control.create_wallet("vault", &control.main_descriptor),
+            Err(CommandError::WalletNameTaken("vault".to_string()))
+        );
+        assert_eq!(
+            control.create_wallet("other", &desc),
+            Err(CommandError::DescriptorAlreadyTracked("vault".to_string()))
+        );
+
+        // The descriptor must be for our network.
+        let testnet_desc = descriptors::LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs").unwrap();
+        assert_eq!(
+            control.create_wallet("testnet", &testnet_desc),
+            Err(CommandError::DescriptorNetworkMismatch(
+                bitcoin::Network::Bitcoin
+            ))
+        );
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn wallet_export() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+        let desc = descriptors::LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8KLW4HGLXZBJknja7kDUJuFHnM424LbziEXsfkh1WQCiEjjHw4zLqSUm4rvhgyGkkuRowE9tCJSgt3TQB5J3SKAbZ2SdcKST/<0;1>/*),older(52560),pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*)))").unwrap();
+        control.create_wallet("vault", &desc).unwrap();
+        let vault_control = control.with_wallet("vault").unwrap();
+
+        // Export a wallet with a couple addresses handed out and a label.
+        vault_control.get_new_address();
+        vault_control.get_new_address();
+        let txid_str = "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7";
+        let mut labels = HashMap::new();
+        labels.insert(
+            LabelItem::from_str(txid_str, bitcoin::Network::Bitcoin).unwrap(),
+            Some("deposit".to_string()),
+        );
+        vault_control.update_labels(&labels);
+        let export = vault_control.export_wallet();
+        assert_eq!(export.descriptor, desc);
+        assert_eq!(export.network, bitcoin::Network::Bitcoin);
+        assert_eq!(export.receive_index, 2);
+        assert_eq!(export.labels.get(txid_str), Some(&"deposit".to_string()));
+        let mut export: WalletExport = serde_json::from_value(serde_json::json!(&export)).unwrap();
+
+        // It can't be imported into a wallet for another descriptor, or on another network.
+        assert!(matches!(
+            control.import_wallet(export.clone()),
+            Err(CommandError::WalletExportMismatch(d)) if *d == desc
+        ));
+        let mut testnet_export = export.clone();
+        testnet_export.network = bitcoin::Network::Testnet;
+        assert_eq!(
+            vault_control.import_wallet(testnet_export),
+            Err(CommandError::DescriptorNetworkMismatch(
+                bitcoin::Network::Bitcoin
+            ))
+        );
+
+        // Restore it on a fresh daemon. Don't trigger a rescan on the dummy Bitcoin backend.
+        export.timestamp = u32::MAX;
+        let fresh_ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let fresh_control = &fresh_ms.handle.control;
+        fresh_control.create_wallet("vault", &desc).unwrap();
+        let fresh_vault_control = fresh_control.with_wallet("vault").unwrap();
+        let mut invalid_export = export.clone();
+        invalid_export
+            .labels
+            .insert("not an item".to_string(), "label".to_string());
+        assert_eq!(
+            fresh_vault_control.import_wallet(invalid_export),
+            Err(CommandError::InvalidLabelItem("not an item".to_string()))
+        );
+        fresh_vault_control.import_wallet(export).unwrap();
+        let restored = fresh_vault_control.export_wallet();
+        assert_eq!(restored.receive_index, 2);
+        assert_eq!(restored.labels.get(txid_str), Some(&"deposit".to_string()));
+
+        fresh_ms.shutdown();
+        ms.shutdown();
+    }
+
+    #[test]
+    fn backup() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+
+        // Backups may only be written to the backups directory of the data directory.
+        let backup_dir = control
+            .config
+            .data_dir()
+            .unwrap()
+            .join("bitcoin")
+            .join("backups");
+        let path = control.backup("lianad.sqlite3.bak").unwrap();
+        assert_eq!(path, backup_dir.join("lianad.sqlite3.bak"));
+        for name in &[
+            "",
+            "..",
+            "../lianad.sqlite3.bak",
+            "a/b",
+            "/tmp/lianad.sqlite3.bak",
+        ] {
+            assert_eq!(
+                control.backup(name),
+                Err(CommandError::InvalidBackupName(name.to_string()))
+            );
+        }
+
+        // They can't overwrite an existing file.
+        fs::write(backup_dir.join("existing"), b"").unwrap();
+        assert_eq!(
+            control.backup("existing"),
+            Err(CommandError::InvalidBackupName("existing".to_string()))
+        );
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn sign_spend() {
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let signer = signer::HotSigner::generate(bitcoin::Network::Bitcoin).unwrap();
+        let origin = bip32::DerivationPath::from_str("m/48'/0'/0'/2'").unwrap();
+        let owner_key = format!(
+            "[{}/48'/0'/0'/2']{}/<0;1>/*",
+            signer.fingerprint(&secp),
+            signer.xpub_at(&origin, &secp)
+        );
+        let desc = descriptors::LianaDescriptor::from_str(&format!("wsh(andor(pk({}),older(52560),pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*)))", owner_key)).unwrap();
+
+        let dummy_op = bitcoin::OutPoint::from_str(
+            "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(
+            dummy_op.txid,
+            (
+                bitcoin::Transaction {
+                    version: 2,
+                    lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                    input: vec![],
+                    output: vec![],
+                },
+                None,
+            ),
+        );
+        let ms = DummyLiana::new_with_signer(dummy_bitcoind, DummyDatabase::new(), &signer);
+        let control = &ms.handle.control;
+        control.create_wallet("hot", &desc).unwrap();
+        let hot_control = control.with_wallet("hot").unwrap();
+
+        // Create a Spend transaction for a coin of the wallet whose primary key is our signer's.
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let destinations = [(dummy_addr, 10_000)].iter().cloned().collect();
+        let psbt = hot_control
+            .create_spend(&destinations, &[dummy_op], 1)
+            .unwrap()
+            .psbt;
+        let txid = psbt.unsigned_tx.txid();
+        assert_eq!(
+            hot_control.sign_spend(&txid).unwrap_err(),
+            CommandError::UnknownSpend(txid)
+        );
+        hot_control.update_spend(psbt).unwrap();
+
+        // The daemon signs it and stores the signature.
+        let res = hot_control.sign_spend(&txid).unwrap();
+        assert_eq!(res.signatures, 1);
+        assert_eq!(res.psbt.inputs[0].partial_sigs.len(), 1);
+        let stored_psbt = db_conn.spend_tx(&txid).unwrap();
+        assert_eq!(stored_psbt.inputs[0].partial_sigs.len(), 1);
+
+        // Signing again doesn't add any signature.
+        assert_eq!(hot_control.sign_spend(&txid).unwrap().signatures, 0);
+
+        // A daemon without hot signer can't sign.
+        let no_signer_ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        assert_eq!(
+            no_signer_ms.handle.control.sign_spend(&txid).unwrap_err(),
+            CommandError::NoHotSigner
+        );
+
+        no_signer_ms.shutdown();
+        ms.shutdown();
+    }
+
+    #[test]
+    fn analyze_psbt() {
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let signer = signer::HotSigner::generate(bitcoin::Network::Bitcoin).unwrap();
+        let origin = bip32::DerivationPath::from_str("m/48'/0'/0'/2'").unwrap();
+        let owner_key = format!(
+            "[{}/48'/0'/0'/2']{}/<0;1>/*",
+            signer.fingerprint(&secp),
+            signer.xpub_at(&origin, &secp)
+        );
+        let desc = descriptors::LianaDescriptor::from_str(&format!("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*),older(52560),pk({})))", owner_key)).unwrap();
+
+        let dummy_op = bitcoin::OutPoint::from_str(
+            "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(
+            dummy_op.txid,
+            (
+                bitcoin::Transaction {
+                    version: 2,
+                    lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                    input: vec![],
+                    output: vec![],
+                },
+                None,
+            ),
+        );
+        let mut db = DummyDatabase::new();
+        let ms = DummyLiana::new_with_signer(dummy_bitcoind, db.clone(), &signer);
+        let control = &ms.handle.control;
+        control.create_wallet("hot", &desc).unwrap();
+        let hot_control = control.with_wallet("hot").unwrap();
+        let change_addr = desc
+            .change_descriptor()
+            .derive(0.into(), &control.secp)
+            .address(bitcoin::Network::Bitcoin);
+        db.insert_address(change_addr, 0.into(), true);
+
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op,
+            is_immature: false,
+            block_info: Some(BlockInfo {
+                height: 100,
+                time: 1_700_000_000,
+            }),
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let destinations = [(dummy_addr.clone(), 10_000)].iter().cloned().collect();
+        let psbt = hot_control
+            .create_spend(&destinations, &[dummy_op], 1)
+            .unwrap()
+            .psbt;
+        let txid = psbt.unsigned_tx.txid();
+
+        // The coin spent, the payment and change outputs, the fee and the signatures needed.
+        let analysis = hot_control.analyze_psbt(&psbt).unwrap();
+        assert_eq!(analysis.txid, txid);
+        assert_eq!(analysis.inputs.len(), 1);
+        let input = &analysis.inputs[0];
+        assert_eq!(input.outpoint, dummy_op);
+        assert_eq!(input.amount, Some(bitcoin::Amount::from_sat(100_000)));
+        assert!(input.is_mine);
+        assert_eq!(
+            (input.derivation_index, input.is_change, input.block_height),
+            (Some(13), Some(false), Some(100))
+        );
+        let origins = input.key_origins.get(&signer.fingerprint(&secp)).unwrap();
+        assert_eq!(origins[0].to_string(), "m/48'/0'/0'/2'/0/13");
+        assert_eq!(analysis.outputs.len(), 2);
+        let payment = analysis
+            .outputs
+            .iter()
+            .find(|output| output.address == Some(dummy_addr.clone()))
+            .unwrap();
+        assert_eq!(payment.amount, bitcoin::Amount::from_sat(10_000));
+        assert!(!payment.is_mine && payment.is_change.is_none());
+        let change = analysis.outputs.iter().find(|o| o.is_mine).unwrap();
+        assert_eq!(
+            (change.is_change, change.derivation_index),
+            (Some(true), Some(0))
+        );
+        let value_out: u64 = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+        assert_eq!(
+            analysis.fee,
+            Some(bitcoin::Amount::from_sat(100_000 - value_out))
+        );
+        assert_eq!(analysis.feerate_vb, Some(1));
+        assert_eq!(
+            analysis.primary_path,
+            PsbtPathInfo {
+                threshold: 1,
+                sigs_count: 0,
+                is_satisfied: false,
+                signed_fingerprints: vec![],
+                missing_fingerprints: vec![signer.fingerprint(&secp)],
+            }
+        );
+        assert_eq!(analysis.recovery_paths.len(), 1);
+        let recovery = &analysis.recovery_paths[0];
+        assert_eq!(recovery.timelock, 52560);
+        assert!(!recovery.is_enabled);
+        assert_eq!(recovery.available_at, Some(100 + 52560));
+        assert_eq!(
+            recovery.path.missing_fingerprints,
+            vec![bip32::Fingerprint::from_str("aabbccdd").unwrap()]
+        );
+
+        // Once signed by our hot signer, the primary path is satisfied. A stored Spend may be
+        // analyzed by its txid.
+        assert_eq!(
+            hot_control.analyze_spend(&txid).unwrap_err(),
+            CommandError::UnknownSpend(txid)
+        );
+        hot_control.update_spend(psbt).unwrap();
+        hot_control.sign_spend(&txid).unwrap();
+        let analysis = hot_control.analyze_spend(&txid).unwrap();
+        assert!(analysis.primary_path.is_satisfied);
+        assert_eq!(
+            analysis.primary_path.signed_fingerprints,
+            vec![signer.fingerprint(&secp)]
+        );
+        assert!(analysis.primary_path.missing_fingerprints.is_empty());
+
+        // An empty PSBT can't be analyzed.
+        let mut empty_psbt = db_conn.spend_tx(&txid).unwrap();
+        empty_psbt.unsigned_tx.input.clear();
+        empty_psbt.inputs.clear();
+        assert!(matches!(
+            hot_control.analyze_psbt(&empty_psbt),
+            Err(CommandError::InvalidPsbt(..))
+        ));
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn finalize_spend() {
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let signer = signer::HotSigner::generate(bitcoin::Network::Bitcoin).unwrap();
+        let origin = bip32::DerivationPath::from_str("m/48'/0'/0'/2'").unwrap();
+        let owner_key = format!(
+            "[{}/48'/0'/0'/2']{}/<0;1>/*",
+            signer.fingerprint(&secp),
+            signer.xpub_at(&origin, &secp)
+        );
+        let desc = descriptors::LianaDescriptor::from_str(&format!("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*),older(52560),pk({})))", owner_key)).unwrap();
+
+        let dummy_op = bitcoin::OutPoint::from_str(
+            "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(
+            dummy_op.txid,
+            (
+                bitcoin::Transaction {
+                    version: 2,
+                    lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                    input: vec![],
+                    output: vec![],
+                },
+                None,
+            ),
+        );
+        let mut db = DummyDatabase::new();
+        let ms = DummyLiana::new_with_signer(dummy_bitcoind, db.clone(), &signer);
+        let control = &ms.handle.control;
+        control.create_wallet("hot", &desc).unwrap();
+        let mut hot_control = control.with_wallet("hot").unwrap();
+        let change_addr = desc
+            .change_descriptor()
+            .derive(0.into(), &control.secp)
+            .address(bitcoin::Network::Bitcoin);
+        db.insert_address(change_addr, 0.into(), true);
+
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let destinations = [(dummy_addr, 10_000)].iter().cloned().collect();
+        let psbt = hot_control
+            .create_spend(&destinations, &[dummy_op], 1)
+            .unwrap()
+            .psbt;
+        let txid = psbt.unsigned_tx.txid();
+        assert_eq!(
+            hot_control.finalize_spend(&txid).unwrap_err(),
+            CommandError::UnknownSpend(txid)
+        );
+        hot_control.update_spend(psbt).unwrap();
+
+        // It can't be finalized until it's signed.
+        assert!(matches!(
+            hot_control.finalize_spend(&txid),
+            Err(CommandError::SpendFinalization(..))
+        ));
+        hot_control.sign_spend(&txid).unwrap();
+        let res = hot_control.finalize_spend(&txid).unwrap();
+        assert_eq!(res.tx.txid(), txid);
+        assert!(!res.tx.input[0].witness.is_empty());
+        assert!(res.psbt.inputs[0].final_script_witness.is_some());
+        // The stored PSBT is left untouched.
+        let stored_psbt = db_conn.spend_tx(&txid).unwrap();
+        assert!(stored_psbt.inputs[0].final_script_witness.is_none());
+        assert_eq!(stored_psbt.inputs[0].partial_sigs.len(), 1);
+
+        // The finalized transaction may be broadcast as is, as long as it only spends our coins.
+        assert_eq!(
+            hot_control.list_spend().spend_txs[0].status,
+            SpendStatus::Signing
+        );
+        hot_control.broadcast_raw_tx(&res.tx).unwrap();
+        assert_eq!(
+            hot_control.list_spend().spend_txs[0].status,
+            SpendStatus::Broadcast
+        );
+        let mut foreign_tx = res.tx.clone();
+        foreign_tx.input[0].previous_output.vout = 1;
+        assert_eq!(
+            hot_control.broadcast_raw_tx(&foreign_tx).unwrap_err(),
+            CommandError::UnknownOutpoint(foreign_tx.input[0].previous_output)
+        );
+        foreign_tx.input.clear();
+        assert_eq!(
+            hot_control.broadcast_raw_tx(&foreign_tx).unwrap_err(),
+            CommandError::NoOutpoint
+        );
+
+        // Both are subject to the guardrails. The change output isn't accounted as a payment.
+        hot_control.config.guardrails_config = Some(GuardrailsConfig {
+            max_tx_amount: Some(9_000),
+            ..GuardrailsConfig::default()
+        });
+        let limit_err = CommandError::TxAmountAboveLimit(
+            bitcoin::Amount::from_sat(10_000),
+            bitcoin::Amount::from_sat(9_000),
+        );
+        assert_eq!(hot_control.finalize_spend(&txid).unwrap_err(), limit_err);
+        assert_eq!(
+            hot_control.broadcast_raw_tx(&res.tx).unwrap_err(),
+            limit_err
+        );
+        hot_control.config.guardrails_config = Some(GuardrailsConfig {
+            max_tx_amount: Some(10_000),
+            ..GuardrailsConfig::default()
+        });
+        hot_control.finalize_spend(&txid).unwrap();
+        hot_control.broadcast_raw_tx(&res.tx).unwrap();
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn bip329_labels() {
+        let coin_a = OutPoint::from_str(
+            "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:0",
+        )
+        .unwrap();
+        let coin_b = OutPoint::from_str(
+            "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:1",
+        )
+        .unwrap();
+        let foreign_output = OutPoint::from_str(
+            "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7:3",
+        )
+        .unwrap();
+        let spend_tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+            input: vec![TxIn {
+                witness: Witness::new(),
+                previous_output: coin_a,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence(0),
+            }],
+            output: vec![TxOut {
+                script_pubkey: ScriptBuf::new(),
+                value: 90_000,
+            }],
+        };
+        let coin = |outpoint, spend_txid| Coin {
+            outpoint,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: ChildNumber::from(0),
+            is_change: false,
+            spend_txid,
+            spend_block: None,
+        };
+        let mut db = DummyDatabase::new();
+        db.insert_coins(vec![
+            coin(coin_a, Some(spend_tx.txid())),
+            coin(coin_b, None),
+        ]);
+        let mut btc = DummyBitcoind::new();
+        btc.txs.insert(spend_tx.txid(), (spend_tx.clone(), None));
+        let ms = DummyLiana::new(btc, db);
+        let control = &ms.handle.control;
+        let desc = descriptors::LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8KLW4HGLXZBJknja7kDUJuFHnM424LbziEXsfkh1WQCiEjjHw4zLqSUm4rvhgyGkkuRowE9tCJSgt3TQB5J3SKAbZ2SdcKST/<0;1>/*),older(52560),pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*)))").unwrap();
+        control.create_wallet("vault", &desc).unwrap();
+        let control = control.with_wallet("vault").unwrap();
+
+        // Import labels from another wallet. Only those which map onto an item we can label are
+        // imported.
+        let txid_str = "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7";
+        let records: Vec<Bip329Label> = serde_json::from_value(serde_json::json!([
+            {"type": "tx", "ref": txid_str, "label": "a".repeat(120)},
+            {"type": "addr", "ref": "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", "label": "addr"},
+            {"type": "addr", "ref": "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", "label": "testnet"},
+            {"type": "input", "ref": format!("{}:0", spend_tx.txid()), "label": "deposit a"},
+            {"type": "output", "ref": foreign_output.to_string(), "label": "foreign"},
+            {"type": "output", "ref": coin_b.to_string(), "spendable": false},
+            {"type": "xpub", "ref": desc.xpubs()[0].to_string(), "label": "some key"},
+            {"type": "somethingnew", "ref": "whatever", "label": "unknown"},
+        ]))
+        .unwrap();
+        assert_eq!(records[7].label_type, Bip329Type::Unknown);
+        assert_eq!(
+            control.import_labels(records),
+            ImportLabelsResult {
+                imported: 4,
+                skipped: 4
+            }
+        );
+
+        // Export them back. The label on the spent coin is also exported as the label of the
+        // input spending it.
+        let labels = control.export_labels().labels;
+        let find = |label_type, reference: &str| {
+            labels
+                .iter()
+                .find(|l| l.label_type == label_type && l.reference == reference)
+                .cloned()
+        };
+        for xpub in desc.xpubs() {
+            assert_eq!(
+                find(Bip329Type::Xpub, &xpub.to_string()).unwrap().label,
+                Some("vault".to_string())
+            );
+        }
+        assert_eq!(
+            find(Bip329Type::Tx, txid_str).unwrap().label,
+            Some("a".repeat(MAX_LABEL_LEN))
+        );
+        assert!(find(
+            Bip329Type::Addr,
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        )
+        .is_some());
+        let output_a = find(Bip329Type::Output, &coin_a.to_string()).unwrap();
+        assert_eq!(output_a.label, Some("deposit a".to_string()));
+        assert_eq!(output_a.spendable, Some(true));
+        let input_a = find(Bip329Type::Input, &format!("{}:0", spend_tx.txid())).unwrap();
+        assert_eq!(input_a.label, Some("deposit a".to_string()));
+        let foreign = find(Bip329Type::Output, &foreign_output.to_string()).unwrap();
+        assert_eq!(foreign.spendable, None);
+        assert!(find(Bip329Type::Output, &coin_b.to_string()).is_none());
+        assert_eq!(labels.len(), desc.xpubs().len() + 5);
+
+        // The records serialize as expected by BIP329.
+        assert_eq!(
+            serde_json::to_value(&output_a).unwrap(),
+            serde_json::json!({
+                "type": "output",
+                "ref": coin_a.to_string(),
+                "label": "deposit a",
+                "spendable": true,
+            })
+        );
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn getnewaddress() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+
+        let control = &ms.handle.control;
+        // We can get an address
+        let addr = control.get_new_address().address;
+        assert_eq!(
+            addr,
+            bitcoin::Address::from_str(
+                "bc1q9ksrc647hx8zp2cewl8p5f487dgux3777yees8rjcx46t4daqzzqt7yga8"
+            )
+            .unwrap()
+            .assume_checked()
+        );
+        // We won't get the same twice.
+        let addr2 = control.get_new_address().address;
+        assert_ne!(addr, addr2);
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn addresses() {
+        let mut db = DummyDatabase::new();
+        let ms = DummyLiana::new(DummyBitcoind::new(), db.clone());
+        let control = &ms.handle.control;
+        let desc = &control.config.main_descriptor;
+        let secp = &control.secp;
+        let address_at = |index: u32, is_change: bool| {
+            let desc = if is_change {
+                desc.change_descriptor()
+            } else {
+                desc.receive_descriptor()
+            };
+            desc.derive(index.into(), secp)
+                .address(bitcoin::Network::Bitcoin)
+        };
+        for index in 0..3 {
+            db.insert_address(address_at(index, false), index.into(), false);
+            db.insert_address(address_at(index, true), index.into(), true);
+        }
+
+        // We can get an address at a given index without bumping the next derivation index.
+        assert_eq!(
+            control.get_address(2, false).unwrap().address,
+            address_at(2, false)
+        );
+        assert_eq!(
+            control.get_address(1, true).unwrap().address,
+            address_at(1, true)
+        );
+        assert_eq!(control.get_new_address().address, address_at(0, false));
+        assert_eq!(
+            control.get_address(3, false).unwrap_err(),
+            CommandError::UnwatchedDerivationIndex(3)
+        );
+        assert_eq!(
+            control.get_address(1 << 31, false).unwrap_err(),
+            CommandError::InvalidDerivationIndex(1 << 31)
+        );
+
+        // Information about our addresses, and others'.
+        let mut labels = HashMap::new();
+        labels.insert(
+            LabelItem::from(address_at(1, true)),
+            Some("change".to_string()),
+        );
+        control.update_labels(&labels);
+        let info = control
+            .get_address_info(bitcoin::Address::from_str(&address_at(1, true).to_string()).unwrap())
+            .unwrap();
+        assert!(info.is_mine);
+        assert_eq!(info.is_change, Some(true));
+        assert_eq!(info.derivation_index, Some(1));
+        assert_eq!(info.label, Some("change".to_string()));
+        let derived = desc.change_descriptor().derive(1.into(), secp);
+        assert_eq!(info.witness_script, Some(derived.witness_script()));
+        assert_eq!(info.descriptor, Some(derived));
+        let info = control
+            .get_address_info(
+                bitcoin::Address::from_str(&address_at(3, false).to_string()).unwrap(),
+            )
+            .unwrap();
+        assert!(!info.is_mine);
+        assert!(info.derivation_index.is_none() && info.descriptor.is_none());
+
+        // List the addresses along with the coins they received.
+        let coin = |vout, amount, index: u32, is_change| Coin {
+            outpoint: bitcoin::OutPoint::new(
+                Txid::from_str("9ff4a4a4cef18da0e7a8c9ff5f5d3bd3bc3ccd8d18b1a3fe3d5b56d64e0d3e86")
+                    .unwrap(),
+                vout,
+            ),
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(amount),
+            derivation_index: index.into(),
+            is_change,
+            spend_txid: None,
+            spend_block: None,
+        };
+        db.insert_coins(vec![
+            coin(0, 10_000, 0, false),
+            coin(1, 20_000, 0, false),
+            coin(2, 5_000, 1, true),
+        ]);
+        // By default all the addresses up to the next derivation index are listed.
+        let addresses = control.list_addresses(0, None).unwrap().addresses;
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].index, 0);
+        assert_eq!(addresses[0].receive.address, address_at(0, false));
+        assert_eq!(addresses[0].receive.coins_count, 2);
+        assert_eq!(
+            addresses[0].receive.received,
+            bitcoin::Amount::from_sat(30_000)
+        );
+        assert_eq!(addresses[0].change.coins_count, 0);
+        let addresses = control.list_addresses(1, Some(2)).unwrap().addresses;
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].index, 1);
+        assert_eq!(addresses[0].change.address, address_at(1, true));
+        assert_eq!(addresses[0].change.label, Some("change".to_string()));
+        assert_eq!(addresses[0].change.coins_count, 1);
+        assert_eq!(
+            addresses[0].change.received,
+            bitcoin::Amount::from_sat(5_000)
+        );
+        assert_eq!(addresses[1].index, 2);
+        assert!(addresses[1].receive.label.is_none());
+        assert!(control.list_addresses(1 << 31, Some(1)).is_err());
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn create_spend() {
+        let dummy_op = bitcoin::OutPoint::from_str(
+            "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(
+            dummy_op.txid,
+            (
+                bitcoin::Transaction {
+                    version: 2,
+                    lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                    input: vec![],
+                    output: vec![],
+                },
+                None,
+            ),
+        );
+        let ms = DummyLiana::new(dummy_bitcoind, DummyDatabase::new());
+        let control = &ms.handle.control;
+
+        // Arguments sanity checking
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let dummy_value = 10_000;
+        let mut destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
+            [(dummy_addr.clone(), dummy_value)]
+                .iter()
+                .cloned()
+                .collect();
+        assert_eq!(
+            control.create_spend(&HashMap::new(), &[], 1),
+            Err(CommandError::NoOutpoint)
+        );
+        assert_eq!(
+            control.create_spend(&destinations, &[], 1),
+            Err(CommandError::InsufficientFunds(
+                bitcoin::Amount::from_sat(0),
+                Some(bitcoin::Amount::from_sat(dummy_value)),
+                1
+            ))
+        );
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 0),
+            Err(CommandError::InvalidFeerate(0))
+        );
+
+        // The coin doesn't exist. If we create a new unspent one at this outpoint with a much
+        // higher value, we'll get a Spend transaction with a change output.
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::UnknownOutpoint(dummy_op))
+        );
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        let res = control.create_spend(&destinations, &[dummy_op], 1).unwrap();
+        assert_eq!(res.coins, vec![dummy_op]);
+        assert!(res.psbt.inputs[0].non_witness_utxo.is_some());
+        // If no coin is provided, it'll be selected automatically.
+        let auto_res = control.create_spend(&destinations, &[], 1).unwrap();
+        assert_eq!(auto_res.coins, vec![dummy_op]);
+        assert_eq!(auto_res.psbt.unsigned_tx.input, res.psbt.unsigned_tx.input);
+        let tx = res.psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, dummy_op);
+        // The nLockTime is set to the current tip, or a bit before it, to discourage fee sniping.
+        match tx.lock_time {
+            absolute::LockTime::Blocks(height) => {
+                assert!(height.to_consensus_u32() > 0 && height.to_consensus_u32() <= 100)
+            }
+            absolute::LockTime::Seconds(_) => panic!("Must be a block height"),
+        }
+        assert_eq!(tx.output.len(), 2);
+        let payment = output_index(&tx, &dummy_addr.payload.script_pubkey());
+        assert_eq!(tx.output[payment].value, dummy_value);
+
+        // Transaction is 1 in (P2WSH satisfaction), 2 outs. At 1sat/vb, it's 170 sats fees.
+        // At 2sats/vb, it's twice that.
+        assert_eq!(tx.output[1 - payment].value, 89_830);
+        let res = control.create_spend(&destinations, &[dummy_op], 2).unwrap();
+        let tx = res.psbt.unsigned_tx;
+        let payment = output_index(&tx, &dummy_addr.payload.script_pubkey());
+        assert_eq!(tx.output[1 - payment].value, 89_660);
+
+        // A feerate of 555 won't trigger the sanity checks (they were previously not taking the
+        // satisfaction size into account and overestimating the feerate).
+        control
+            .create_spend(&destinations, &[dummy_op], 555)
+            .unwrap();
+
+        // If we ask for a too high feerate, or a too large/too small output, it'll fail.
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 10_000),
+            Err(CommandError::InsufficientFunds(
+                bitcoin::Amount::from_sat(100_000),
+                Some(bitcoin::Amount::from_sat(10_000)),
+                10_000
+            ))
+        );
+        *destinations.get_mut(&dummy_addr).unwrap() = 100_001;
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::InsufficientFunds(
+                bitcoin::Amount::from_sat(100_000),
+                Some(bitcoin::Amount::from_sat(100_001)),
+                1
+            ))
+        );
+        *destinations.get_mut(&dummy_addr).unwrap() = 4_500;
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::InvalidOutputValue(bitcoin::Amount::from_sat(
+                4_500
+            )))
+        );
+
+        // If we ask to create an output for an address from another network, it will fail.
+        let invalid_addr =
+            bitcoin::Address::new(bitcoin::Network::Testnet, dummy_addr.payload.clone());
+        let invalid_destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
+            [(invalid_addr, dummy_value)].iter().cloned().collect();
+        assert!(matches!(
+            control.create_spend(&invalid_destinations, &[dummy_op], 1),
+            Err(CommandError::Address(
+                address::Error::NetworkValidation { .. }
+            ))
+        ));
+
+        // If we ask for a large, but valid, output we won't get a change output. 95_000 because we
+        // won't create an output lower than 5k sats.
+        *destinations.get_mut(&dummy_addr).unwrap() = 95_000;
+        let res = control.create_spend(&destinations, &[dummy_op], 1).unwrap();
+        let tx = res.psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, dummy_op);
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(
+            tx.output[0].script_pubkey,
+            dummy_addr.payload.script_pubkey()
+        );
+        assert_eq!(tx.output[0].value, 95_000);
+
+        // Now if we mark the coin as spent, we won't create another Spend transaction containing
+        // it.
+        db_conn.spend_coins(&[(
+            dummy_op,
+            bitcoin::Txid::from_str(
+                "ef78f79ba747813887747cf8582897a48f1a09f1ca04d2cd3d6fcfdcbb5e0797",
+            )
+            .unwrap(),
+        )]);
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::AlreadySpent(dummy_op))
+        );
+
+        // We'd bail out if they tried to create a transaction with a too high feerate.
+        let dummy_op_dup = bitcoin::OutPoint {
+            txid: dummy_op.txid,
+            vout: dummy_op.vout + 10,
+        };
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op_dup,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(400_000),
+            derivation_index: bip32::ChildNumber::from(42),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op_dup], 1_001),
+            Err(CommandError::InsaneFees(InsaneFeeInfo::TooHighFeerate(
+                1001
+            )))
+        );
+
+        // Can't create a transaction that spends an immature coinbase deposit.
+        let imma_op = bitcoin::OutPoint::from_str(
+            "4753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: imma_op,
+            is_immature: true,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        assert_eq!(
+            control.create_spend(&destinations, &[imma_op], 1_001),
+            Err(CommandError::ImmatureCoinbase(imma_op))
+        );
+
+        // Coin selection never picks spent coins nor immature coinbase deposits. It prefers
+        // confirmed coins over unconfirmed ones.
+        let res = control.create_spend(&destinations, &[], 1).unwrap();
+        assert_eq!(res.coins, vec![dummy_op_dup]);
+        let conf_op = bitcoin::OutPoint {
+            txid: dummy_op.txid,
+            vout: dummy_op.vout + 20,
+        };
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: conf_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(200_000),
+            derivation_index: bip32::ChildNumber::from(43),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        db_conn.confirm_coins(&[(conf_op, 174500, 174500)]);
+        let res = control.create_spend(&destinations, &[], 1).unwrap();
+        assert_eq!(res.coins, vec![conf_op]);
+        *destinations.get_mut(&dummy_addr).unwrap() = 500_000;
+        let res = control.create_spend(&destinations, &[], 1).unwrap();
+        assert_eq!(res.coins.len(), 2);
+        assert!(res.coins.contains(&conf_op) && res.coins.contains(&dummy_op_dup));
+        *destinations.get_mut(&dummy_addr).unwrap() = 600_000;
+        assert_eq!(
+            control.create_spend(&destinations, &[], 1),
+            Err(CommandError::InsufficientFunds(
+                bitcoin::Amount::from_sat(600_000),
+                Some(bitcoin::Amount::from_sat(600_000)),
+                1
+            ))
+        );
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn spend_guardrails() {
+        let dummy_op = bitcoin::OutPoint::from_str(
+            "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
+        )
+        .unwrap();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(
+            dummy_op.txid,
+            (
+                bitcoin::Transaction {
+                    version: 2,
+                    lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                    input: vec![],
+                    output: vec![],
+                },
+                None,
+            ),
+        );
+        let mut dummy_db = DummyDatabase::new();
+        let ms = DummyLiana::new(dummy_bitcoind, dummy_db.clone());
+        let mut control = ms.handle.control.clone();
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: dummy_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: bip32::ChildNumber::from(13),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
+            [(dummy_addr.clone(), 10_000)].iter().cloned().collect();
+
+        // Without guardrails any payment may be made.
+        let psbt = control
+            .create_spend(&destinations, &[dummy_op], 1)
+            .unwrap()
+            .psbt;
+
+        // The value sent out of the wallet, the fee and the feerate may be capped.
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            max_tx_amount: Some(9_000),
+            ..GuardrailsConfig::default()
+        });
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::TxAmountAboveLimit(
+                bitcoin::Amount::from_sat(10_000),
+                bitcoin::Amount::from_sat(9_000)
+            ))
+        );
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            max_tx_amount: Some(10_000),
+            max_fee: Some(100),
+            ..GuardrailsConfig::default()
+        });
+        assert!(matches!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::FeeAboveLimit(..))
+        ));
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            max_feerate: Some(2),
+            ..GuardrailsConfig::default()
+        });
+        control.create_spend(&destinations, &[dummy_op], 2).unwrap();
+        assert!(matches!(
+            control.create_spend(&destinations, &[dummy_op], 3),
+            Err(CommandError::FeerateAboveLimit(_, 2))
+        ));
+
+        // Coins may only be sent to the allowed destinations. The change output is ours.
+        let other_addr =
+            bitcoin::Address::from_str("bc1qvklensptw5lk7d470ds60pcpsr0psdpgyvwepv").unwrap();
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            allowed_destinations: vec![other_addr],
+            ..GuardrailsConfig::default()
+        });
+        assert_eq!(
+            control.create_spend(&destinations, &[dummy_op], 1),
+            Err(CommandError::DestinationNotAllowed(
+                "bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv".to_string()
+            ))
+        );
+        control
+            .config
+            .guardrails_config
+            .as_mut()
+            .unwrap()
+            .allowed_destinations
+            .push(dummy_addr.clone());
+        control.create_spend(&destinations, &[dummy_op], 1).unwrap();
+
+        // The guardrails are checked again before broadcasting, along with the value which left
+        // the wallet over the last 24 hours.
+        let payment = output_index(&psbt.unsigned_tx, &dummy_addr.payload.script_pubkey());
+        let change_addr = bitcoin::Address::from_script(
+            &psbt.unsigned_tx.output[1 - payment].script_pubkey,
+            bitcoin::Network::Bitcoin,
+        )
+        .unwrap();
+        dummy_db.insert_address(change_addr, bip32::ChildNumber::from(0), true);
+        control.update_spend(psbt.clone()).unwrap();
+        let txid = psbt.unsigned_tx.txid();
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            max_tx_amount: Some(9_000),
+            ..GuardrailsConfig::default()
+        });
+        assert!(matches!(
+            control.broadcast_spend(&txid),
+            Err(CommandError::TxAmountAboveLimit(..))
+        ));
+        // The PSBT may be updated to claim the payment goes to one of our addresses and that the
+        // coin is worth less than it is. Only our own records are trusted.
+        let mut forged_psbt = psbt.clone();
+        forged_psbt.outputs[payment] = forged_psbt.outputs[1 - payment].clone();
+        forged_psbt.inputs[0].witness_utxo.as_mut().unwrap().value = 10_500;
+        control.update_spend(forged_psbt).unwrap();
+        assert!(matches!(
+            control.broadcast_spend(&txid),
+            Err(CommandError::TxAmountAboveLimit(..))
+        ));
+        let spent_op = bitcoin::OutPoint::from_str(
+            "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:1",
+        )
+        .unwrap();
+        let spend_txid =
+            Txid::from_str("0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7")
+                .unwrap();
+        db_conn.new_unspent_coins(&[Coin {
+            outpoint: spent_op,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(45_000),
+            derivation_index: bip32::ChildNumber::from(14),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        db_conn.spend_coins(&[(spent_op, spend_txid)]);
+        control.config.guardrails_config = Some(GuardrailsConfig {
+            max_daily_outflow: Some(50_000),
+            ..GuardrailsConfig::default()
+        });
+        assert!(matches!(
+            control.broadcast_spend(&txid),
+            Err(CommandError::DailyOutflowAboveLimit(outflow, _)) if outflow > bitcoin::Amount::from_sat(55_000)
+        ));
+        // Once the other payment was confirmed two days ago, it doesn't count anymore. (The
+        // transaction isn't signed so it can't be finalized.)
+        db_conn.confirm_spend(&[(spent_op, spend_txid, 1, curr_timestamp() - 2 * 24 * 3_600)]);
+        assert!(matches!(
+            control.broadcast_spend(&txid),
+            Err(CommandError::SpendFinalization(..))
+        ));
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn rbf_psbt() {
+        let dummy_txid =
+            Txid::from_str("3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810")
+                .unwrap();
+        let dummy_tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+            input: vec![],
+            output: vec![],
+        };
+        let (op_a, op_b, op_c) = (
+            OutPoint::new(dummy_txid, 0),
+            OutPoint::new(dummy_txid, 1),
+            OutPoint::new(dummy_txid, 2),
+        );
+        let coin = |outpoint, amount, index: u32| Coin {
+            outpoint,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(amount),
+            derivation_index: ChildNumber::from(index),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        };
+        // Coin A will be spent by the transaction to be replaced. Coin B is confirmed, and
+        // coin C is not.
+        let dummy_db = || {
+            let mut db = DummyDatabase::new();
+            db.insert_coins(vec![
+                coin(op_a, 100_000, 1),
+                coin(op_b, 50_000, 2),
+                coin(op_c, 1_000_000, 3),
+            ]);
+            db.connection()
+                .confirm_coins(&[(op_a, 174500, 174500), (op_b, 174500, 174500)]);
+            db
+        };
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
+            [(dummy_addr.clone(), 90_000)].iter().cloned().collect();
+
+        // Create the transaction to be replaced.
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind
+            .txs
+            .insert(dummy_txid, (dummy_tx.clone(), None));
+        let ms = DummyLiana::new(dummy_bitcoind, dummy_db());
+        let mut prev_psbt = ms
+            .handle
+            .control
+            .create_spend(&destinations, &[op_a], 1)
+            .unwrap()
+            .psbt;
+        // For convenience make the payment its first output and the change its last one.
+        if output_index(&prev_psbt.unsigned_tx, &dummy_addr.payload.script_pubkey()) != 0 {
+            prev_psbt.unsigned_tx.output.swap(0, 1);
+            prev_psbt.outputs.swap(0, 1);
+        }
+        let prev_tx = prev_psbt.unsigned_tx.clone();
+        let prev_txid = prev_tx.txid();
+        assert_eq!(prev_tx.output.len(), 2);
+        ms.shutdown();
+
+        // Another version of it with two payments to the same address and an OP_RETURN output.
+        let mut multi_psbt = prev_psbt.clone();
+        let multi_tx = &mut multi_psbt.unsigned_tx;
+        multi_tx.output[0].value = 40_000;
+        multi_tx.output.insert(0, multi_tx.output[0].clone());
+        multi_tx.output.insert(
+            2,
+            bitcoin::TxOut {
+                value: 0,
+                script_pubkey: bitcoin::ScriptBuf::new_op_return(&[0x42; 8]),
+            },
+        );
+        multi_tx.output[3].value = 100_000 - 80_000 - 170;
+        let multi_tx = multi_tx.clone();
+        let multi_txid = multi_tx.txid();
+
+        // And one without change.
+        let mut nochange_psbt = prev_psbt.clone();
+        nochange_psbt.unsigned_tx.output.pop();
+        nochange_psbt.outputs.pop();
+        let nochange_txid = nochange_psbt.unsigned_tx.txid();
+
+        // Now they were broadcast and are in the mempool.
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind.txs.insert(dummy_txid, (dummy_tx, None));
+        for txid in &[prev_txid, multi_txid] {
+            dummy_bitcoind.mempool.insert(
+                *txid,
+                MempoolEntry {
+                    vsize: 170,
+                    fees: MempoolEntryFees {
+                        base: bitcoin::Amount::from_sat(170),
+                        descendant: bitcoin::Amount::from_sat(170),
+                    },
+                },
+            );
+        }
+        // The one without change has a descendant paying large fees.
+        dummy_bitcoind.mempool.insert(
+            nochange_txid,
+            MempoolEntry {
+                vsize: 170,
+                fees: MempoolEntryFees {
+                    base: bitcoin::Amount::from_sat(1_000),
+                    descendant: bitcoin::Amount::from_sat(20_000),
+                },
+            },
+        );
+        let mut db = dummy_db();
+        let change_addr = bitcoin::Address::from_script(
+            &prev_tx.output[1].script_pubkey,
+            bitcoin::Network::Bitcoin,
+        )
+        .unwrap();
+        db.insert_address(change_addr, ChildNumber::from(0), true);
+        let mut db_conn = db.connection();
+        db_conn.store_spend(&prev_psbt);
+        db_conn.store_spend(&multi_psbt);
+        db_conn.store_spend(&nochange_psbt);
+        db_conn.spend_coins(&[(op_a, prev_txid)]);
+        let ms = DummyLiana::new(dummy_bitcoind, db);
+        let control = &ms.handle.control;
+        // The change index was incremented when creating it.
+        db_conn.set_change_index(ChildNumber::from(1), &control.secp);
+
+        // The feerate must be higher than the one of the replaced transaction.
+        assert_eq!(
+            control.rbf_psbt(&prev_txid, false, Some(1)),
+            Err(CommandError::RbfError(RbfErrorInfo::TooLowFeerate(2)))
+        );
+
+        // By default we use the minimum feerate. The payment is kept, and the change output is
+        // lowered and still pays to the same address.
+        let res = control.rbf_psbt(&prev_txid, false, None).unwrap();
+        assert_eq!(res.coins, vec![op_a]);
+        let tx = &res.psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 2);
+        let change = output_index(tx, &prev_tx.output[1].script_pubkey);
+        assert_eq!(tx.output[1 - change], prev_tx.output[0]);
+        assert!(tx.output[change].value < prev_tx.output[1].value);
+        let fee = 100_000 - tx.output.iter().map(|o| o.value).sum::<u64>();
+        assert!(fee >= 170 + max_vsize(&control.config.main_descriptor, tx));
+
+        // If the coins of the replaced transaction are not sufficient, only confirmed coins are
+        // added.
+        let res = control.rbf_psbt(&prev_txid, false, Some(100)).unwrap();
+        assert_eq!(res.coins, vec![op_a, op_b]);
+        assert!(res.psbt.unsigned_tx.output.contains(&prev_tx.output[0]));
+
+        // All the payments are kept, even if they are to the same address or not to an address
+        // at all.
+        let res = control.rbf_psbt(&multi_txid, false, None).unwrap();
+        let tx = &res.psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 4);
+        for txo in &multi_tx.output[..3] {
+            let count = |outputs: &[TxOut]| outputs.iter().filter(|o| *o == txo).count();
+            assert_eq!(count(&tx.output), count(&multi_tx.output[..3]));
+        }
+        let change = output_index(tx, &multi_tx.output[3].script_pubkey);
+        assert!(tx.output[change].value < multi_tx.output[3].value);
 
-    use bitcoin::{
-        bip32::{self, ChildNumber},
-        blockdata::transaction::{TxIn, TxOut},
-        locktime::absolute,
-        OutPoint, ScriptBuf, Sequence, Transaction, Txid, Witness,
-    };
-    use std::str::FromStr;
+        // We can cancel the transaction by sending everything back to a fresh change address.
+        let res = control.rbf_psbt(&prev_txid, true, None).unwrap();
+        assert_eq!(res.coins, vec![op_a]);
+        let tx = &res.psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 1);
+        assert_ne!(tx.output[0].script_pubkey, prev_tx.output[1].script_pubkey);
+        let cancel_change = control
+            .main_descriptor
+            .change_descriptor()
+            .derive(ChildNumber::from(1), &control.secp)
+            .script_pubkey();
+        assert_eq!(tx.output[0].script_pubkey, cancel_change);
+        assert_eq!(db_conn.change_index(), ChildNumber::from(2));
+        assert!(!res.psbt.outputs[0].bip32_derivation.is_empty());
+
+        // Replacing a transaction without change, we may need a few attempts to pay enough fees to
+        // evict its descendants. A single fresh change address is used for all of them.
+        let res = control.rbf_psbt(&nochange_txid, false, None).unwrap();
+        let tx = &res.psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 2);
+        let nochange_change = control
+            .main_descriptor
+            .change_descriptor()
+            .derive(ChildNumber::from(2), &control.secp)
+            .script_pubkey();
+        output_index(tx, &nochange_change);
+        assert_eq!(db_conn.change_index(), ChildNumber::from(3));
+
+        // We can only replace a transaction we know, which is in our mempool.
+        let unknown_txid =
+            Txid::from_str("ef78f79ba747813887747cf8582897a48f1a09f1ca04d2cd3d6fcfdcbb5e0797")
+                .unwrap();
+        assert_eq!(
+            control.rbf_psbt(&unknown_txid, false, None),
+            Err(CommandError::UnknownSpend(unknown_txid))
+        );
+        let other_psbt = control
+            .create_spend(&destinations, &[op_b, op_c], 1)
+            .unwrap()
+            .psbt;
+        db_conn.store_spend(&other_psbt);
+        assert_eq!(
+            control.rbf_psbt(&other_psbt.unsigned_tx.txid(), false, None),
+            Err(CommandError::RbfError(RbfErrorInfo::NotInMempool))
+        );
 
-    #[test]
-    fn getinfo() {
-        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
-        // We can query getinfo
-        ms.handle.control.get_info();
         ms.shutdown();
     }
 
     #[test]
-    fn getnewaddress() {
-        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
-
+    fn create_cpfp() {
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
+        let txo = |value| TxOut {
+            value,
+            script_pubkey: dummy_addr.payload.script_pubkey(),
+        };
+        // A parent paying 1sat/vb with two outputs of ours, and one which isn't in the mempool.
+        let parent_tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+            input: vec![TxIn::default()],
+            output: vec![txo(100_000), txo(20_000), txo(30_000)],
+        };
+        let parent_txid = parent_tx.txid();
+        let parent_vb = parent_tx.vsize() as u64;
+        let dropped_tx = Transaction {
+            output: vec![txo(40_000)],
+            ..parent_tx.clone()
+        };
+        let dropped_txid = dropped_tx.txid();
+        let mut dummy_bitcoind = DummyBitcoind::new();
+        dummy_bitcoind
+            .txs
+            .insert(parent_txid, (parent_tx.clone(), None));
+        dummy_bitcoind.txs.insert(dropped_txid, (dropped_tx, None));
+        dummy_bitcoind.mempool.insert(
+            parent_txid,
+            MempoolEntry {
+                vsize: parent_vb,
+                fees: MempoolEntryFees {
+                    base: bitcoin::Amount::from_sat(parent_vb),
+                    descendant: bitcoin::Amount::from_sat(parent_vb),
+                },
+            },
+        );
+        let mut db = DummyDatabase::new();
+        db.insert_coins(
+            [(0, 100_000), (1, 20_000)]
+                .iter()
+                .map(|(vout, amount)| Coin {
+                    outpoint: OutPoint::new(parent_txid, *vout),
+                    is_immature: false,
+                    block_info: None,
+                    amount: bitcoin::Amount::from_sat(*amount),
+                    derivation_index: ChildNumber::from(*vout),
+                    is_change: false,
+                    spend_txid: None,
+                    spend_block: None,
+                })
+                .collect(),
+        );
+        let ms = DummyLiana::new(dummy_bitcoind, db);
         let control = &ms.handle.control;
-        // We can get an address
-        let addr = control.get_new_address().address;
+
+        // Sanity checks.
         assert_eq!(
-            addr,
-            bitcoin::Address::from_str(
-                "bc1q9ksrc647hx8zp2cewl8p5f487dgux3777yees8rjcx46t4daqzzqt7yga8"
-            )
-            .unwrap()
-            .assume_checked()
+            control.create_cpfp(&parent_txid, 0),
+            Err(CommandError::InvalidFeerate(0))
+        );
+        let unknown_txid =
+            Txid::from_str("ef78f79ba747813887747cf8582897a48f1a09f1ca04d2cd3d6fcfdcbb5e0797")
+                .unwrap();
+        assert_eq!(
+            control.create_cpfp(&unknown_txid, 10),
+            Err(CommandError::CpfpError(CpfpErrorInfo::UnknownTransaction))
+        );
+        assert_eq!(
+            control.create_cpfp(&dropped_txid, 10),
+            Err(CommandError::CpfpError(CpfpErrorInfo::NotInMempool))
+        );
+        assert_eq!(
+            control.create_cpfp(&parent_txid, 1),
+            Err(CommandError::CpfpError(CpfpErrorInfo::AlreadyAboveFeerate(
+                1
+            )))
+        );
+
+        // The child spends all our outputs of the parent to a single change output, and pays
+        // for the whole package.
+        let res = control.create_cpfp(&parent_txid, 10).unwrap();
+        assert_eq!(
+            res.coins,
+            vec![OutPoint::new(parent_txid, 0), OutPoint::new(parent_txid, 1)]
+        );
+        let child_tx = &res.psbt.unsigned_tx;
+        assert_eq!(child_tx.output.len(), 1);
+        let child_fee = 120_000 - child_tx.output[0].value;
+        let child_vb = max_vsize(&control.config.main_descriptor, child_tx);
+        assert!(child_fee + parent_vb >= 10 * (parent_vb + child_vb));
+        assert!(child_fee + parent_vb < 11 * (parent_vb + child_vb));
+
+        // Once the parent's outputs are being spent, we can't create a child anymore.
+        let mut db_conn = control.db().lock().unwrap().connection();
+        db_conn.spend_coins(&[
+            (OutPoint::new(parent_txid, 0), child_tx.txid()),
+            (OutPoint::new(parent_txid, 1), child_tx.txid()),
+        ]);
+        assert_eq!(
+            control.create_cpfp(&parent_txid, 10),
+            Err(CommandError::CpfpError(CpfpErrorInfo::NoSpendableOutput))
         );
-        // We won't get the same twice.
-        let addr2 = control.get_new_address().address;
-        assert_ne!(addr, addr2);
 
         ms.shutdown();
     }
 
     #[test]
-    fn create_spend() {
-        let dummy_op = bitcoin::OutPoint::from_str(
+    fn create_recovery() {
+        let desc = descriptors::LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]xpub68JJTXc1MWK8KLW4HGLXZBJknja7kDUJuFHnM424LbziEXsfkh1WQCiEjjHw4zLqSUm4rvhgyGkkuRowE9tCJSgt3TQB5J3SKAbZ2SdcKST/<0;1>/*),older(10),pk([aabbccdd]xpub68JJTXc1MWK8PEQozKsRatrUHXKFNkD1Cb1BuQU9Xr5moCv87anqGyXLyUd4KpnDyZgo3gz4aN1r3NiaoweFW8UutBsBbgKHzaD5HkTkifK/<0;1>/*)))").unwrap();
+        let dummy_op = OutPoint::from_str(
             "3753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
         )
         .unwrap();
@@ -994,7 +4464,7 @@ mod tests {
         dummy_bitcoind.txs.insert(
             dummy_op.txid,
             (
-                bitcoin::Transaction {
+                Transaction {
                     version: 2,
                     lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
                     input: vec![],
@@ -1005,174 +4475,46 @@ mod tests {
         );
         let ms = DummyLiana::new(dummy_bitcoind, DummyDatabase::new());
         let control = &ms.handle.control;
-
-        // Arguments sanity checking
-        let dummy_addr =
-            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
-        let dummy_value = 10_000;
-        let mut destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
-            [(dummy_addr.clone(), dummy_value)]
-                .iter()
-                .cloned()
-                .collect();
-        assert_eq!(
-            control.create_spend(&destinations, &[], 1),
-            Err(CommandError::NoOutpoint)
-        );
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 0),
-            Err(CommandError::InvalidFeerate(0))
-        );
-
-        // The coin doesn't exist. If we create a new unspent one at this outpoint with a much
-        // higher value, we'll get a Spend transaction with a change output.
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 1),
-            Err(CommandError::UnknownOutpoint(dummy_op))
-        );
+        control.create_wallet("recovery", &desc).unwrap();
+        let recovery_control = control.with_wallet("recovery").unwrap();
         let mut db_conn = control.db().lock().unwrap().connection();
         db_conn.new_unspent_coins(&[Coin {
             outpoint: dummy_op,
             is_immature: false,
             block_info: None,
             amount: bitcoin::Amount::from_sat(100_000),
-            derivation_index: bip32::ChildNumber::from(13),
+            derivation_index: ChildNumber::from(3),
             is_change: false,
             spend_txid: None,
             spend_block: None,
         }]);
-        let res = control.create_spend(&destinations, &[dummy_op], 1).unwrap();
-        assert!(res.psbt.inputs[0].non_witness_utxo.is_some());
-        let tx = res.psbt.unsigned_tx;
-        assert_eq!(tx.input.len(), 1);
-        assert_eq!(tx.input[0].previous_output, dummy_op);
-        assert_eq!(tx.output.len(), 2);
-        assert_eq!(
-            tx.output[0].script_pubkey,
-            dummy_addr.payload.script_pubkey()
-        );
-        assert_eq!(tx.output[0].value, dummy_value);
-
-        // Transaction is 1 in (P2WSH satisfaction), 2 outs. At 1sat/vb, it's 170 sats fees.
-        // At 2sats/vb, it's twice that.
-        assert_eq!(tx.output[1].value, 89_830);
-        let res = control.create_spend(&destinations, &[dummy_op], 2).unwrap();
-        let tx = res.psbt.unsigned_tx;
-        assert_eq!(tx.output[1].value, 89_660);
-
-        // A feerate of 555 won't trigger the sanity checks (they were previously not taking the
-        // satisfaction size into account and overestimating the feerate).
-        control
-            .create_spend(&destinations, &[dummy_op], 555)
-            .unwrap();
+        let dummy_addr =
+            bitcoin::Address::from_str("bc1qnsexk3gnuyayu92fc3tczvc7k62u22a22ua2kv").unwrap();
 
-        // If we ask for a too high feerate, or a too large/too small output, it'll fail.
+        // The recovery path isn't available for an unconfirmed coin.
         assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 10_000),
-            Err(CommandError::InsufficientFunds(
-                bitcoin::Amount::from_sat(100_000),
-                Some(bitcoin::Amount::from_sat(10_000)),
-                10_000
-            ))
-        );
-        *destinations.get_mut(&dummy_addr).unwrap() = 100_001;
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 1),
-            Err(CommandError::InsufficientFunds(
-                bitcoin::Amount::from_sat(100_000),
-                Some(bitcoin::Amount::from_sat(100_001)),
-                1
-            ))
-        );
-        *destinations.get_mut(&dummy_addr).unwrap() = 4_500;
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 1),
-            Err(CommandError::InvalidOutputValue(bitcoin::Amount::from_sat(
-                4_500
-            )))
+            recovery_control.create_recovery(dummy_addr.clone(), 1, None),
+            Err(CommandError::RecoveryNotAvailable)
         );
 
-        // If we ask to create an output for an address from another network, it will fail.
-        let invalid_addr =
-            bitcoin::Address::new(bitcoin::Network::Testnet, dummy_addr.payload.clone());
-        let invalid_destinations: HashMap<bitcoin::Address<address::NetworkUnchecked>, u64> =
-            [(invalid_addr, dummy_value)].iter().cloned().collect();
-        assert!(matches!(
-            control.create_spend(&invalid_destinations, &[dummy_op], 1),
-            Err(CommandError::Address(
-                address::Error::NetworkValidation { .. }
-            ))
-        ));
-
-        // If we ask for a large, but valid, output we won't get a change output. 95_000 because we
-        // won't create an output lower than 5k sats.
-        *destinations.get_mut(&dummy_addr).unwrap() = 95_000;
-        let res = control.create_spend(&destinations, &[dummy_op], 1).unwrap();
-        let tx = res.psbt.unsigned_tx;
+        // Once the timelock of the recovery path expired, the coin can be swept.
+        db_conn.confirm_coins(&[(dummy_op, 50, 50)]);
+        let tx = recovery_control
+            .create_recovery(dummy_addr, 1, None)
+            .unwrap()
+            .psbt
+            .unsigned_tx;
         assert_eq!(tx.input.len(), 1);
         assert_eq!(tx.input[0].previous_output, dummy_op);
-        assert_eq!(tx.output.len(), 1);
-        assert_eq!(
-            tx.output[0].script_pubkey,
-            dummy_addr.payload.script_pubkey()
-        );
-        assert_eq!(tx.output[0].value, 95_000);
-
-        // Now if we mark the coin as spent, we won't create another Spend transaction containing
-        // it.
-        db_conn.spend_coins(&[(
-            dummy_op,
-            bitcoin::Txid::from_str(
-                "ef78f79ba747813887747cf8582897a48f1a09f1ca04d2cd3d6fcfdcbb5e0797",
-            )
-            .unwrap(),
-        )]);
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op], 1),
-            Err(CommandError::AlreadySpent(dummy_op))
-        );
-
-        // We'd bail out if they tried to create a transaction with a too high feerate.
-        let dummy_op_dup = bitcoin::OutPoint {
-            txid: dummy_op.txid,
-            vout: dummy_op.vout + 10,
-        };
-        db_conn.new_unspent_coins(&[Coin {
-            outpoint: dummy_op_dup,
-            is_immature: false,
-            block_info: None,
-            amount: bitcoin::Amount::from_sat(400_000),
-            derivation_index: bip32::ChildNumber::from(42),
-            is_change: false,
-            spend_txid: None,
-            spend_block: None,
-        }]);
-        assert_eq!(
-            control.create_spend(&destinations, &[dummy_op_dup], 1_001),
-            Err(CommandError::InsaneFees(InsaneFeeInfo::TooHighFeerate(
-                1001
-            )))
-        );
-
-        // Can't create a transaction that spends an immature coinbase deposit.
-        let imma_op = bitcoin::OutPoint::from_str(
-            "4753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:0",
-        )
-        .unwrap();
-        db_conn.new_unspent_coins(&[Coin {
-            outpoint: imma_op,
-            is_immature: true,
-            block_info: None,
-            amount: bitcoin::Amount::from_sat(100_000),
-            derivation_index: bip32::ChildNumber::from(13),
-            is_change: false,
-            spend_txid: None,
-            spend_block: None,
-        }]);
-        assert_eq!(
-            control.create_spend(&destinations, &[imma_op], 1_001),
-            Err(CommandError::ImmatureCoinbase(imma_op))
-        );
+        // The input signals the relative timelock of the recovery path, while the nLockTime is set
+        // to discourage fee sniping like for any other transaction we create.
+        assert_eq!(tx.input[0].sequence, bitcoin::Sequence::from_height(10));
+        match tx.lock_time {
+            absolute::LockTime::Blocks(height) => {
+                assert!(height.to_consensus_u32() > 0 && height.to_consensus_u32() <= 100)
+            }
+            absolute::LockTime::Seconds(_) => panic!("Must be a block height"),
+        }
 
         ms.shutdown();
     }
@@ -1288,6 +4630,59 @@ mod tests {
         control.update_spend(psbt_c.clone()).unwrap();
         assert_eq!(db_conn.spend_tx(&txid_c).unwrap(), psbt_c);
 
+        // Their status depends on whether they were signed, and a note may be attached to them.
+        control.set_spend_note(&txid_b, "Rent").unwrap();
+        let spend_status = |txid: &bitcoin::Txid| {
+            control
+                .list_spend()
+                .spend_txs
+                .into_iter()
+                .find(|spend| &spend.psbt.unsigned_tx.txid() == txid)
+                .unwrap()
+        };
+        assert_eq!(spend_status(&txid_a).status, SpendStatus::Signing);
+        assert_eq!(spend_status(&txid_b).status, SpendStatus::Draft);
+        assert_eq!(spend_status(&txid_b).note.as_deref(), Some("Rent"));
+        assert!(spend_status(&txid_c).note.is_none());
+        control.set_spend_note(&txid_b, "").unwrap();
+        assert!(spend_status(&txid_b).note.is_none());
+        let unknown_txid = bitcoin::Txid::from_str(
+            "8753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810",
+        )
+        .unwrap();
+        assert_eq!(
+            control.set_spend_note(&unknown_txid, "Rent"),
+            Err(CommandError::UnknownSpend(unknown_txid))
+        );
+
+        // Once a coin is spent by one of them, the others spending it are invalid.
+        db_conn.spend_coins(&[(dummy_op_a, txid_a)]);
+        let coins = db_conn.coins_by_outpoints(&[dummy_op_a, dummy_op_b]);
+        let spend = spend_status(&txid_a);
+        assert_eq!(
+            spend.status.updated(&spend.psbt, &coins),
+            SpendStatus::Broadcast
+        );
+        let spend = spend_status(&txid_b);
+        assert_eq!(
+            spend.status.updated(&spend.psbt, &coins),
+            SpendStatus::Draft
+        );
+        let spend = spend_status(&txid_c);
+        assert_eq!(
+            spend.status.updated(&spend.psbt, &coins),
+            SpendStatus::Invalid
+        );
+        // Unless it was broadcast, in which case it was replaced.
+        assert_eq!(
+            SpendStatus::Broadcast.updated(&spend.psbt, &coins),
+            SpendStatus::Replaced
+        );
+        // Storing it again doesn't reset the status of an invalid transaction.
+        db_conn.set_spend_status(&txid_c, SpendStatus::Invalid);
+        control.update_spend(psbt_c.clone()).unwrap();
+        assert_eq!(spend_status(&txid_c).status, SpendStatus::Invalid);
+
         // We can't store a PSBT spending an external coin
         let external_op = bitcoin::OutPoint::from_str(
             "8753a1d74c0af8dd0a0f3b763c14faf3bd9ed03cbdf33337a074fb0e9f6c7810:2",
@@ -1436,6 +4831,17 @@ mod tests {
             },
         ]);
 
+        {
+            let mut db_conn = db.connection();
+            // Use the tip of the dummy backend, for the poller not to update it under our feet.
+            db_conn.update_tip(&DummyBitcoind::new().chain_tip());
+            let mut labels = HashMap::new();
+            labels.insert(
+                LabelItem::Txid(spend_tx.txid()),
+                Some("payment".to_string()),
+            );
+            db_conn.update_labels(&labels);
+        }
         let mut btc = DummyBitcoind::new();
         btc.txs.insert(
             deposit1.txid(),
@@ -1526,6 +4932,33 @@ mod tests {
         assert_eq!(transactions[0].time, Some(3));
         assert_eq!(transactions[0].tx, spend_tx);
 
+        // The effect of the transactions on the wallet is computed from our coins.
+        let spend_info = &transactions[0];
+        assert_eq!(spend_info.kind, TransactionKind::Outgoing);
+        assert_eq!(spend_info.outgoing.to_sat(), 100_000_000);
+        assert_eq!(spend_info.incoming.to_sat(), 100_000_000 - 4000 - 1000);
+        assert_eq!(spend_info.amount.to_sat(), -5000);
+        assert_eq!(spend_info.fee, Some(bitcoin::Amount::from_sat(1000)));
+        assert_eq!(spend_info.confirmations, 98);
+        assert_eq!(
+            spend_info.labels.get(&spend_tx.txid().to_string()),
+            Some(&"payment".to_string())
+        );
+        let deposit_info = &control.list_transactions(&[deposit2.txid()]).transactions[0];
+        assert_eq!(deposit_info.kind, TransactionKind::Incoming);
+        assert_eq!(deposit_info.amount.to_sat(), 2000);
+        assert_eq!(deposit_info.fee, None);
+        assert_eq!(deposit_info.confirmations, 99);
+
+        // All the transactions can be paginated through, most recent first.
+        let transactions = control.list_recent_transactions(2, 1).transactions;
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].tx, spend_tx);
+        assert_eq!(transactions[1].tx, deposit2);
+        let transactions = control.list_recent_transactions(10, 3).transactions;
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].tx, deposit1);
+
         ms.shutdown();
     }
 
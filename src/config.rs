@@ -2,7 +2,7 @@ use crate::descriptors::LianaDescriptor;
 
 use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 
-use miniscript::bitcoin::Network;
+use miniscript::bitcoin::{address::NetworkUnchecked, Address, Network};
 
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
@@ -43,6 +43,14 @@ fn default_poll_interval() -> Duration {
     Duration::from_secs(30)
 }
 
+// We store as many addresses per descriptor in database, and the Bitcoin backend must be able to
+// watch them all.
+const MAX_GAP_LIMIT: u32 = 10_000;
+
+fn default_gap_limit() -> u32 {
+    200
+}
+
 #[cfg(unix)]
 fn default_daemon() -> bool {
     false
@@ -57,6 +65,101 @@ pub struct BitcoindConfig {
     pub addr: SocketAddr,
 }
 
+/// Everything we need to know for talking to an Electrum server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ElectrumConfig {
+    /// The IP:port the Electrum server is listening on. The connection is plain TCP (no SSL), so
+    /// this must be a local server or one reached over a trusted network.
+    pub addr: SocketAddr,
+}
+
+/// Everything we need to know for syncing using compact block filters from a P2P node
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CbfConfig {
+    /// The IP:port of the node serving compact block filters
+    pub peer: SocketAddr,
+    /// The height from which to start scanning the block chain for our transactions
+    #[serde(default)]
+    pub start_height: u32,
+}
+
+/// Settings for serving the JSONRPC API over HTTP, in addition to the Unix socket
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HttpRpcConfig {
+    /// The IP:port to listen on
+    pub addr: SocketAddr,
+    /// The user name clients must authenticate with. If neither it nor the password are set, a
+    /// cookie file containing random credentials is created in the data directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The password clients must authenticate with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// Named credentials for a restricted access to the JSONRPC API. A dedicated Unix socket is
+/// created for them, and they may be used over HTTP if a password is set.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RpcAuthConfig {
+    /// The name of these credentials. It is the user name over HTTP, and the dedicated socket
+    /// is named 'lianad_rpc_<name>'
+    pub name: String,
+    /// The password to authenticate with over HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// The JSONRPC methods which may be called using these credentials
+    pub methods: Vec<String>,
+}
+
+/// Settings for signing Spend transactions with the hot signers stored in the data directory.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SignerConfig {
+    /// Path to a file containing the passphrase to decrypt the mnemonics, if they are stored
+    /// encrypted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passphrase_file: Option<PathBuf>,
+}
+
+/// Shell commands to run upon events concerning our wallets. The event is passed as JSON on the
+/// standard input of the command.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HooksConfig {
+    /// Command to run when a coin is received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_received: Option<String>,
+    /// Command to run when a coin is confirmed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_confirmed: Option<String>,
+    /// Command to run when the transaction spending a coin is confirmed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_spent: Option<String>,
+    /// Command to run when a recovery path becomes available for a coin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_available: Option<String>,
+}
+
+/// Limits on the transactions the daemon creates and broadcasts, so a bug in an automated
+/// spending job can't empty the wallet. Unset limits are not enforced.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GuardrailsConfig {
+    /// Maximum value, in satoshis, a transaction may send to addresses which are not ours
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tx_amount: Option<u64>,
+    /// Maximum value, in satoshis, which may leave the wallet over the last 24 hours, fees
+    /// included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_daily_outflow: Option<u64>,
+    /// Maximum absolute fee of a transaction, in satoshis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fee: Option<u64>,
+    /// Maximum feerate of a transaction, in sats/vb
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_feerate: Option<u64>,
+    /// The only addresses, besides ours, transactions may send coins to. Any if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_destinations: Vec<Address<NetworkUnchecked>>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BitcoinConfig {
     /// The network we are operating on, one of "bitcoin", "testnet", "regtest", "signet"
@@ -68,6 +171,10 @@ pub struct BitcoinConfig {
         default = "default_poll_interval"
     )]
     pub poll_interval_secs: Duration,
+    /// How many addresses beyond the last used one we watch, for each of the receive and change
+    /// descriptors. Coins received on addresses past this gap are not detected.
+    #[serde(default = "default_gap_limit")]
+    pub gap_limit: u32,
 }
 
 /// Static informations we require to operate
@@ -96,6 +203,27 @@ pub struct Config {
     pub bitcoin_config: BitcoinConfig,
     /// Settings specific to bitcoind as the Bitcoin interface
     pub bitcoind_config: Option<BitcoindConfig>,
+    /// Settings specific to an Electrum server as the Bitcoin interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub electrum_config: Option<ElectrumConfig>,
+    /// Settings specific to a P2P node serving compact block filters as the Bitcoin interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cbf_config: Option<CbfConfig>,
+    /// Commands to run upon events concerning our wallets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks_config: Option<HooksConfig>,
+    /// Settings for serving the JSONRPC API over HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_rpc_config: Option<HttpRpcConfig>,
+    /// Credentials with a restricted access to the JSONRPC API
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rpc_auth: Vec<RpcAuthConfig>,
+    /// Settings for signing Spend transactions with the hot signers in the data directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer_config: Option<SignerConfig>,
+    /// Limits on the transactions created and broadcast by the daemon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guardrails_config: Option<GuardrailsConfig>,
 }
 
 impl Config {
@@ -124,7 +252,7 @@ impl std::fmt::Display for ConfigError {
             Self::ReadingFile(e) => write!(f, "Failed to read configuration file: {}", e),
             Self::UnexpectedDescriptor(desc) => write!(
                 f,
-                "Unexpected descriptor '{}'. We only support wsh() descriptors for now.",
+                "Unexpected descriptor '{}'. We only support wsh() and tr() descriptors.",
                 desc
             ),
             Self::Unexpected(e) => write!(f, "Configuration error: {}", e),
@@ -209,6 +337,93 @@ impl Config {
             )));
         }
 
+        if self.bitcoin_config.gap_limit == 0 || self.bitcoin_config.gap_limit > MAX_GAP_LIMIT {
+            return Err(ConfigError::Unexpected(format!(
+                "The 'gap_limit' must be between 1 and {}.",
+                MAX_GAP_LIMIT
+            )));
+        }
+
+        let backends = [
+            self.bitcoind_config.is_some(),
+            self.electrum_config.is_some(),
+            self.cbf_config.is_some(),
+        ];
+        if backends.iter().filter(|is_set| **is_set).count() > 1 {
+            return Err(ConfigError::Unexpected(
+                "Only one of 'bitcoind_config', 'electrum_config' and 'cbf_config' may be set."
+                    .to_string(),
+            ));
+        }
+
+        if let Some(http_rpc_config) = &self.http_rpc_config {
+            if http_rpc_config.user.is_some() != http_rpc_config.password.is_some() {
+                return Err(ConfigError::Unexpected(
+                    "Both or none of 'user' and 'password' must be set in 'http_rpc_config'."
+                        .to_string(),
+                ));
+            }
+            if http_rpc_config
+                .user
+                .as_ref()
+                .map(|user| user.contains(':'))
+                .unwrap_or(false)
+            {
+                return Err(ConfigError::Unexpected(
+                    "The 'user' in 'http_rpc_config' must not contain a colon.".to_string(),
+                ));
+            }
+        }
+
+        let mut rpc_auth_names = std::collections::HashSet::new();
+        for rpc_auth in &self.rpc_auth {
+            // The name is used in the socket file name and as a user name over HTTP.
+            if rpc_auth.name.is_empty()
+                || !rpc_auth
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(ConfigError::Unexpected(format!(
+                    "Invalid 'rpc_auth' name '{}': it may only contain alphanumeric characters, \
+                     '-' and '_'.",
+                    rpc_auth.name
+                )));
+            }
+            let is_http_user = self
+                .http_rpc_config
+                .as_ref()
+                .and_then(|http_rpc_config| http_rpc_config.user.as_ref())
+                .map(|user| user == &rpc_auth.name)
+                .unwrap_or(false);
+            // Those would clash with the cookie file and the cookie user name.
+            let is_reserved = rpc_auth.name == "cookie" || rpc_auth.name == "__cookie__";
+            if !rpc_auth_names.insert(&rpc_auth.name) || is_http_user || is_reserved {
+                return Err(ConfigError::Unexpected(format!(
+                    "Duplicate or reserved 'rpc_auth' name '{}'.",
+                    rpc_auth.name
+                )));
+            }
+        }
+
+        if let Some(guardrails_config) = &self.guardrails_config {
+            if guardrails_config.max_feerate == Some(0) {
+                return Err(ConfigError::Unexpected(
+                    "The 'max_feerate' in 'guardrails_config' must be at least 1.".to_string(),
+                ));
+            }
+            if let Some(address) = guardrails_config
+                .allowed_destinations
+                .iter()
+                .find(|addr| !addr.is_valid_for_network(self.bitcoin_config.network))
+            {
+                return Err(ConfigError::Unexpected(format!(
+                    "Allowed destination '{:?}' is not for network {}.",
+                    address, self.bitcoin_config.network
+                )));
+            }
+        }
+
         // TODO: check the semantics of the main descriptor
 
         Ok(())
@@ -217,7 +432,7 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::{config_file_path, Config};
+    use super::{config_file_path, BitcoindConfig, Config, ElectrumConfig};
 
     // Test the format of the configuration file
     #[test]
@@ -249,6 +464,7 @@ mod tests {
             [bitcoin_config]
             network = 'bitcoin'
             poll_interval_secs = 18
+            gap_limit = 200
 
             [bitcoind_config]
             cookie_path = '/home/user/.bitcoin/.cookie'
@@ -296,6 +512,176 @@ mod tests {
         "#;
         let config_res: Result<Config, toml::de::Error> = toml::from_str(toml_str);
         config_res.expect_err("Deserializing an invalid toml_str");
+
+        // An Electrum server may be used instead of bitcoind, but not both.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [electrum_config]
+            addr = "127.0.0.1:60001"
+        "#;
+        let mut config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert!(config.bitcoind_config.is_none());
+        assert_eq!(config.bitcoin_config.gap_limit, 200);
+        config.check().expect("Valid config");
+        config.bitcoin_config.gap_limit = 0;
+        config.check().expect_err("Null gap limit");
+        config.bitcoin_config.gap_limit = 1_000;
+        config
+            .check()
+            .expect("Valid config, with a larger gap limit");
+        config.bitcoind_config = Some(BitcoindConfig {
+            cookie_path: "/home/user/.bitcoin/.cookie".into(),
+            addr: "127.0.0.1:8332".parse().unwrap(),
+        });
+        config.check().expect_err("Both bitcoind and Electrum");
+
+        // Same for syncing using compact block filters.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [cbf_config]
+            peer = "127.0.0.1:18333"
+            start_height = 2500000
+        "#;
+        let mut config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(config.cbf_config.as_ref().unwrap().start_height, 2_500_000);
+        config.check().expect("Valid config");
+        config.electrum_config = Some(ElectrumConfig {
+            addr: "127.0.0.1:60001".parse().unwrap(),
+        });
+        config
+            .check()
+            .expect_err("Both Electrum and compact block filters");
+
+        // Commands may be run upon events.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [hooks_config]
+            coin_received = "/usr/local/bin/notify-received.sh"
+            recovery_available = "curl -X POST -d @- http://127.0.0.1:8080/recovery"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        let hooks = config.hooks_config.unwrap();
+        assert_eq!(
+            hooks.coin_received.as_deref(),
+            Some("/usr/local/bin/notify-received.sh")
+        );
+        assert!(hooks.coin_confirmed.is_none() && hooks.coin_spent.is_none());
+        assert!(hooks.recovery_available.is_some());
+
+        // The JSONRPC API may be served over HTTP, using either a cookie or a user and password.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [http_rpc_config]
+            addr = "127.0.0.1:9332"
+        "#;
+        let mut config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        config.check().expect("Valid config, with cookie");
+        let http_rpc_config = config.http_rpc_config.as_mut().unwrap();
+        http_rpc_config.user = Some("alice".to_string());
+        config.check().expect_err("Missing password");
+        let http_rpc_config = config.http_rpc_config.as_mut().unwrap();
+        http_rpc_config.password = Some("hunter2".to_string());
+        config.check().expect("Valid config, with password");
+        let http_rpc_config = config.http_rpc_config.as_mut().unwrap();
+        http_rpc_config.user = Some("ali:ce".to_string());
+        config.check().expect_err("Colon in the user name");
+
+        // The daemon may sign with its hot signers, decrypting them with a passphrase.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [signer_config]
+            passphrase_file = "/run/secrets/liana_passphrase"
+        "#;
+        let config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        assert_eq!(
+            config.signer_config.unwrap().passphrase_file,
+            Some(std::path::PathBuf::from("/run/secrets/liana_passphrase"))
+        );
+
+        // Limits may be set on the transactions the daemon creates and broadcasts.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [guardrails_config]
+            max_tx_amount = 10000000
+            max_daily_outflow = 50000000
+            max_feerate = 200
+            allowed_destinations = ["tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"]
+        "#;
+        let mut config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        config.check().expect("Valid config");
+        let guardrails = config.guardrails_config.as_mut().unwrap();
+        assert_eq!(guardrails.max_tx_amount, Some(10_000_000));
+        assert!(guardrails.max_fee.is_none());
+        assert_eq!(guardrails.allowed_destinations.len(), 1);
+        guardrails.max_feerate = Some(0);
+        config.check().expect_err("Null feerate cap");
+        let guardrails = config.guardrails_config.as_mut().unwrap();
+        guardrails.max_feerate = None;
+        guardrails.allowed_destinations.push(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+                .parse()
+                .unwrap(),
+        );
+        config.check().expect_err("Mainnet destination on testnet");
+
+        // Some restricted credentials may be configured.
+        let toml_str = r#"
+            main_descriptor = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs"
+
+            [bitcoin_config]
+            network = "testnet"
+
+            [http_rpc_config]
+            addr = "127.0.0.1:9332"
+            user = "alice"
+            password = "hunter2"
+
+            [[rpc_auth]]
+            name = "viewer"
+            password = "viewerpass"
+            methods = ["getinfo", "listcoins"]
+
+            [[rpc_auth]]
+            name = "spender"
+            methods = ["createspend", "updatespend"]
+        "#;
+        let mut config: Config = toml::from_str(toml_str).expect("Deserializing toml_str");
+        config.check().expect("Valid config");
+        assert_eq!(config.rpc_auth.len(), 2);
+        assert_eq!(config.rpc_auth[0].methods, vec!["getinfo", "listcoins"]);
+        assert!(config.rpc_auth[1].password.is_none());
+        config.rpc_auth[1].name = "viewer".to_string();
+        config.check().expect_err("Duplicate name");
+        config.rpc_auth[1].name = "alice".to_string();
+        config.check().expect_err("Same name as the HTTP user");
+        config.rpc_auth[1].name = "cookie".to_string();
+        config.check().expect_err("Reserved name");
+        config.rpc_auth[1].name = "../spender".to_string();
+        config.check().expect_err("Invalid name");
     }
 
     #[test]
@@ -7,29 +7,43 @@ pub mod sqlite;
 use crate::{
     bitcoin::BlockChainTip,
     database::sqlite::{
-        schema::{DbBlockInfo, DbCoin, DbTip},
+        schema::{DbBlockInfo, DbCoin, DbSpendTransaction, DbTip, DbWallet},
         SqliteConn, SqliteDb,
     },
+    descriptors::LianaDescriptor,
 };
 
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
     iter::FromIterator,
+    path,
     str::FromStr,
     sync,
 };
 
 use miniscript::bitcoin::{self, bip32, psbt::PartiallySignedTransaction as Psbt, secp256k1};
+use serde::{Deserialize, Serialize};
 
 pub trait DatabaseInterface: Send {
+    /// Get a connection to the database, scoped to the main wallet.
     fn connection(&self) -> Box<dyn DatabaseConnection>;
+
+    /// Get a connection to the database, scoped to the wallet with this id.
+    fn wallet_connection(&self, wallet_id: i64) -> Box<dyn DatabaseConnection>;
 }
 
 impl DatabaseInterface for SqliteDb {
     fn connection(&self) -> Box<dyn DatabaseConnection> {
         Box::new(self.connection().expect("Database must be available"))
     }
+
+    fn wallet_connection(&self, wallet_id: i64) -> Box<dyn DatabaseConnection> {
+        Box::new(
+            self.wallet_connection(wallet_id)
+                .expect("Database must be available"),
+        )
+    }
 }
 
 // FIXME: do we need to repeat the entire trait implemenation? Isn't there a nicer way?
@@ -37,6 +51,10 @@ impl DatabaseInterface for sync::Arc<sync::Mutex<dyn DatabaseInterface>> {
     fn connection(&self) -> Box<dyn DatabaseConnection> {
         self.lock().unwrap().connection()
     }
+
+    fn wallet_connection(&self, wallet_id: i64) -> Box<dyn DatabaseConnection> {
+        self.lock().unwrap().wallet_connection(wallet_id)
+    }
 }
 
 pub trait DatabaseConnection {
@@ -49,6 +67,19 @@ pub trait DatabaseConnection {
     /// Update our best chain seen.
     fn update_tip(&mut self, tip: &BlockChainTip);
 
+    /// Get all the wallets tracked in database.
+    fn wallets(&mut self) -> Vec<Wallet>;
+
+    /// Create a new wallet with the given name and main descriptor, and return its id. The name
+    /// must not already be used by another wallet.
+    fn create_wallet(
+        &mut self,
+        name: &str,
+        main_descriptor: &LianaDescriptor,
+        timestamp: u32,
+        secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    ) -> i64;
+
     /// Get the derivation index for the next receiving address
     fn receive_index(&mut self) -> bip32::ChildNumber;
 
@@ -120,11 +151,18 @@ pub trait DatabaseConnection {
 
     fn spend_tx(&mut self, txid: &bitcoin::Txid) -> Option<Psbt>;
 
-    /// Insert a new Spend transaction or replace an existing one.
+    /// Insert a new Spend transaction or replace an existing one. Unless it was already
+    /// broadcast or invalidated, its status is set according to whether it is signed.
     fn store_spend(&mut self, psbt: &Psbt);
 
-    /// List all existing Spend transactions, along with an optional last update timestamp.
-    fn list_spend(&mut self) -> Vec<(Psbt, Option<u32>)>;
+    /// List all existing Spend transactions, along with their metadata.
+    fn list_spend(&mut self) -> Vec<SpendTx>;
+
+    /// Set the status of a stored Spend transaction.
+    fn set_spend_status(&mut self, txid: &bitcoin::Txid, status: SpendStatus);
+
+    /// Set the note attached to a stored Spend transaction. A `None` value deletes it.
+    fn set_spend_note(&mut self, txid: &bitcoin::Txid, note: Option<&str>);
 
     /// Delete a Spend transaction from database.
     fn delete_spend(&mut self, txid: &bitcoin::Txid);
@@ -135,11 +173,24 @@ pub trait DatabaseConnection {
 
     fn labels(&mut self, labels: &HashSet<LabelItem>) -> HashMap<String, String>;
 
+    /// Get all the labels of the wallet, by item.
+    fn list_labels(&mut self) -> HashMap<String, String>;
+
     /// Mark the given tip as the new best seen block. Update stored data accordingly.
     fn rollback_tip(&mut self, new_tip: &BlockChainTip);
 
     /// Retrieve a limited list of txids that where deposited or spent between the start and end timestamps (inclusive bounds)
     fn list_txids(&mut self, start: u32, end: u32, limit: u64) -> Vec<bitcoin::Txid>;
+
+    /// Retrieve a page of the txids of all the transactions that deposited or spent our coins.
+    /// The unconfirmed ones come first, then the most recently confirmed ones.
+    fn list_recent_txids(&mut self, limit: u64, offset: u64) -> Vec<bitcoin::Txid>;
+
+    /// Get the wallet this connection is scoped to.
+    fn wallet(&mut self) -> Wallet;
+
+    /// Write a consistent copy of the whole database to a new file at this path.
+    fn backup(&mut self, path: &path::Path) -> Result<(), String>;
 }
 
 impl DatabaseConnection for SqliteConn {
@@ -162,6 +213,20 @@ impl DatabaseConnection for SqliteConn {
         self.update_tip(tip)
     }
 
+    fn wallets(&mut self) -> Vec<Wallet> {
+        self.db_wallets().into_iter().map(Wallet::from).collect()
+    }
+
+    fn create_wallet(
+        &mut self,
+        name: &str,
+        main_descriptor: &LianaDescriptor,
+        timestamp: u32,
+        secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    ) -> i64 {
+        self.create_wallet(name, main_descriptor, timestamp, secp)
+    }
+
     fn receive_index(&mut self) -> bip32::ChildNumber {
         self.db_wallet().deposit_derivation_index
     }
@@ -266,11 +331,16 @@ impl DatabaseConnection for SqliteConn {
         self.store_spend(psbt)
     }
 
-    fn list_spend(&mut self) -> Vec<(Psbt, Option<u32>)> {
-        self.list_spend()
-            .into_iter()
-            .map(|db_spend| (db_spend.psbt, db_spend.updated_at))
-            .collect()
+    fn list_spend(&mut self) -> Vec<SpendTx> {
+        self.list_spend().into_iter().map(SpendTx::from).collect()
+    }
+
+    fn set_spend_status(&mut self, txid: &bitcoin::Txid, status: SpendStatus) {
+        self.set_spend_status(txid, status)
+    }
+
+    fn set_spend_note(&mut self, txid: &bitcoin::Txid, note: Option<&str>) {
+        self.set_spend_note(txid, note)
     }
 
     fn delete_spend(&mut self, txid: &bitcoin::Txid) {
@@ -286,6 +356,11 @@ impl DatabaseConnection for SqliteConn {
         HashMap::from_iter(labels.into_iter().map(|label| (label.item, label.value)))
     }
 
+    fn list_labels(&mut self) -> HashMap<String, String> {
+        let labels = self.db_list_labels();
+        HashMap::from_iter(labels.into_iter().map(|label| (label.item, label.value)))
+    }
+
     fn rollback_tip(&mut self, new_tip: &BlockChainTip) {
         self.rollback_tip(new_tip)
     }
@@ -293,6 +368,47 @@ impl DatabaseConnection for SqliteConn {
     fn list_txids(&mut self, start: u32, end: u32, limit: u64) -> Vec<bitcoin::Txid> {
         self.db_list_txids(start, end, limit)
     }
+
+    fn list_recent_txids(&mut self, limit: u64, offset: u64) -> Vec<bitcoin::Txid> {
+        self.db_list_recent_txids(limit, offset)
+    }
+
+    fn wallet(&mut self) -> Wallet {
+        self.db_wallet().into()
+    }
+
+    fn backup(&mut self, path: &path::Path) -> Result<(), String> {
+        self.backup(path).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wallet {
+    pub id: i64,
+    pub name: String,
+    pub main_descriptor: LianaDescriptor,
+    pub timestamp: u32,
+    pub rescan_timestamp: Option<u32>,
+}
+
+impl From<DbWallet> for Wallet {
+    fn from(db_wallet: DbWallet) -> Wallet {
+        let DbWallet {
+            id,
+            name,
+            main_descriptor,
+            timestamp,
+            rescan_timestamp,
+            ..
+        } = db_wallet;
+        Wallet {
+            id,
+            name,
+            main_descriptor,
+            timestamp,
+            rescan_timestamp,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -383,6 +499,110 @@ impl CoinStatus {
     }
 }
 
+/// Possible status of a Spend transaction we stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpendStatus {
+    /// Not signed by any key yet.
+    Draft,
+    /// Signed by at least one key, but not broadcast.
+    Signing,
+    /// Broadcast, but not yet included in a block.
+    Broadcast,
+    /// Included in a block.
+    Confirmed,
+    /// Broadcast, then replaced by another transaction spending some of the same coins.
+    Replaced,
+    /// Some of the coins it spends were spent by another transaction before it was broadcast.
+    /// It can't be broadcast anymore.
+    Invalid,
+}
+
+impl SpendStatus {
+    /// The status of a Spend transaction that was not broadcast, depending on whether any of its
+    /// inputs is signed.
+    pub fn unbroadcast(psbt: &Psbt) -> SpendStatus {
+        let is_signed = psbt.inputs.iter().any(|psbtin| {
+            !psbtin.partial_sigs.is_empty()
+                || psbtin.tap_key_sig.is_some()
+                || !psbtin.tap_script_sigs.is_empty()
+                || psbtin.final_script_witness.is_some()
+        });
+        if is_signed {
+            SpendStatus::Signing
+        } else {
+            SpendStatus::Draft
+        }
+    }
+
+    /// The status of a Spend transaction currently in this status, given the state of the coins
+    /// it spends.
+    pub fn updated(self, psbt: &Psbt, coins: &HashMap<bitcoin::OutPoint, Coin>) -> SpendStatus {
+        let txid = psbt.unsigned_tx.txid();
+        let mut conflicting = false;
+        for txin in psbt.unsigned_tx.input.iter() {
+            let coin = match coins.get(&txin.previous_output) {
+                Some(coin) => coin,
+                None => continue,
+            };
+            match coin.spend_txid {
+                Some(spend_txid) if spend_txid == txid => {
+                    return if coin.spend_block.is_some() {
+                        SpendStatus::Confirmed
+                    } else {
+                        SpendStatus::Broadcast
+                    };
+                }
+                Some(_) => conflicting = true,
+                None => {}
+            }
+        }
+
+        match (conflicting, self) {
+            (true, SpendStatus::Broadcast | SpendStatus::Confirmed | SpendStatus::Replaced) => {
+                SpendStatus::Replaced
+            }
+            (true, _) => SpendStatus::Invalid,
+            // We may have broadcast it before its coins were marked as spending.
+            (false, SpendStatus::Broadcast) => SpendStatus::Broadcast,
+            (false, _) => SpendStatus::unbroadcast(psbt),
+        }
+    }
+}
+
+/// A Spend transaction we stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendTx {
+    pub psbt: Psbt,
+    /// When it was first stored. Unknown for those stored by older versions.
+    pub created_at: Option<u32>,
+    /// When it was last stored.
+    pub updated_at: Option<u32>,
+    /// A free-form note attached by its creator.
+    pub note: Option<String>,
+    pub status: SpendStatus,
+}
+
+impl From<DbSpendTransaction> for SpendTx {
+    fn from(db_spend: DbSpendTransaction) -> SpendTx {
+        let DbSpendTransaction {
+            psbt,
+            created_at,
+            updated_at,
+            note,
+            status,
+            ..
+        } = db_spend;
+        SpendTx {
+            psbt,
+            created_at,
+            updated_at,
+            note,
+            status,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum LabelItem {
     Address(bitcoin::Address),
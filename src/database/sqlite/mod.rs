@@ -15,15 +15,15 @@ use crate::{
     database::{
         sqlite::{
             schema::{
-                DbAddress, DbCoin, DbLabel, DbLabelledKind, DbSpendTransaction, DbTip, DbWallet,
-                SCHEMA,
+                spend_status_to_db, DbAddress, DbCoin, DbLabel, DbLabelledKind, DbSpendTransaction,
+                DbTip, DbWallet, SCHEMA,
             },
             utils::{
-                create_fresh_db, curr_timestamp, db_exec, db_query, db_tx_query, db_version,
-                maybe_apply_migration, LOOK_AHEAD_LIMIT,
+                create_db_file, create_fresh_db, curr_timestamp, db_exec, db_query, db_tx_query,
+                db_version, fill_addresses, insert_wallet, maybe_apply_migration,
             },
         },
-        Coin, CoinStatus, LabelItem,
+        Coin, CoinStatus, LabelItem, SpendStatus,
     },
     descriptors::LianaDescriptor,
 };
@@ -43,7 +43,11 @@ use miniscript::bitcoin::{
     secp256k1,
 };
 
-const DB_VERSION: i64 = 3;
+const DB_VERSION: i64 = 5;
+
+/// The name of the wallet created along with the database, for the main descriptor set in the
+/// configuration.
+pub const MAIN_WALLET_NAME: &str = "main";
 
 #[derive(Debug)]
 pub enum SqliteDbError {
@@ -135,19 +139,23 @@ impl FreshDbOptions {
 #[derive(Debug, Clone)]
 pub struct SqliteDb {
     db_path: path::PathBuf,
+    /// How many addresses past the highest derivation index we store for each wallet.
+    gap_limit: u32,
 }
 
 impl SqliteDb {
     /// Instanciate an SQLite database either from an existing database file or by creating a fresh
-    /// one.
+    /// one. The addresses of each wallet are cached up to `gap_limit` indexes past the highest of
+    /// its derivation indexes.
     pub fn new(
         db_path: path::PathBuf,
         fresh_options: Option<FreshDbOptions>,
+        gap_limit: u32,
         secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
     ) -> Result<SqliteDb, SqliteDbError> {
         // Create the database if needed, and make sure the db file exists.
         if let Some(options) = fresh_options {
-            create_fresh_db(&db_path, options, secp)?;
+            create_fresh_db(&db_path, options, gap_limit, secp)?;
             log::info!("Created a fresh database at {}.", db_path.display());
         }
         if !db_path.exists() {
@@ -157,14 +165,27 @@ impl SqliteDb {
         log::info!("Checking if the database needs upgrading.");
         maybe_apply_migration(&db_path)?;
 
-        Ok(SqliteDb { db_path })
+        // The gap limit may have been increased since the addresses were cached.
+        let db = SqliteDb { db_path, gap_limit };
+        db.connection()?.fill_addresses(secp);
+
+        Ok(db)
     }
 
-    /// Get a new connection to the database.
+    /// Get a new connection to the database, scoped to the main wallet.
     pub fn connection(&self) -> Result<SqliteConn, SqliteDbError> {
+        self.wallet_connection(MAIN_WALLET_ID)
+    }
+
+    /// Get a new connection to the database, scoped to the wallet with the given id.
+    pub fn wallet_connection(&self, wallet_id: i64) -> Result<SqliteConn, SqliteDbError> {
         let conn = rusqlite::Connection::open(&self.db_path)?;
         conn.busy_timeout(std::time::Duration::from_secs(60))?;
-        Ok(SqliteConn { conn })
+        Ok(SqliteConn {
+            conn,
+            wallet_id,
+            gap_limit: self.gap_limit,
+        })
     }
 
     /// Perform startup sanity checks.
@@ -199,11 +220,14 @@ impl SqliteDb {
     }
 }
 
-// We only support single wallet. The id of the wallet row is always 1.
-const WALLET_ID: i64 = 1;
+// The wallet created along with the database always has id 1.
+const MAIN_WALLET_ID: i64 = 1;
 
+/// A connection to the database. Queries about wallet data are scoped to a single wallet.
 pub struct SqliteConn {
     conn: rusqlite::Connection,
+    wallet_id: i64,
+    gap_limit: u32,
 }
 
 impl SqliteConn {
@@ -228,13 +252,52 @@ impl SqliteConn {
     pub fn db_wallet(&mut self) -> DbWallet {
         db_query(
             &mut self.conn,
-            "SELECT * FROM wallets",
-            rusqlite::params![],
+            "SELECT * FROM wallets WHERE id = ?1",
+            rusqlite::params![self.wallet_id],
             |row| row.try_into(),
         )
         .expect("Db must not fail")
         .pop()
-        .expect("There is always a row in the wallet table")
+        .expect("The wallet of a connection always exists")
+    }
+
+    /// Get the information about all the wallets.
+    pub fn db_wallets(&mut self) -> Vec<DbWallet> {
+        db_query(
+            &mut self.conn,
+            "SELECT * FROM wallets ORDER BY id",
+            rusqlite::params![],
+            |row| row.try_into(),
+        )
+        .expect("Db must not fail")
+    }
+
+    /// Create a new wallet with the given name and main descriptor. Returns the id of the new
+    /// wallet.
+    pub fn create_wallet(
+        &mut self,
+        name: &str,
+        main_descriptor: &LianaDescriptor,
+        timestamp: u32,
+        secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    ) -> i64 {
+        let network = self.db_tip().network;
+        let gap_limit = self.gap_limit;
+        let mut wallet_id = 0;
+        db_exec(&mut self.conn, |db_tx| {
+            wallet_id = insert_wallet(
+                db_tx,
+                name,
+                main_descriptor,
+                network,
+                timestamp,
+                gap_limit,
+                secp,
+            )?;
+            Ok(())
+        })
+        .expect("Database must be available");
+        wallet_id
     }
 
     /// Update the network tip.
@@ -250,6 +313,33 @@ impl SqliteConn {
         .expect("Database must be available")
     }
 
+    /// Make sure the addresses of all the wallets are cached up to the gap limit past their
+    /// highest derivation index.
+    pub fn fill_addresses(&mut self, secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>) {
+        let network = self.db_tip().network;
+        let db_wallets = self.db_wallets();
+        let gap_limit = self.gap_limit;
+        db_exec(&mut self.conn, |db_tx| {
+            for db_wallet in db_wallets {
+                let highest_index = cmp::max(
+                    db_wallet.deposit_derivation_index,
+                    db_wallet.change_derivation_index,
+                );
+                fill_addresses(
+                    db_tx,
+                    db_wallet.id,
+                    &db_wallet.main_descriptor,
+                    highest_index.into(),
+                    network,
+                    gap_limit,
+                    secp,
+                )?;
+            }
+            Ok(())
+        })
+        .expect("Database must be available")
+    }
+
     /// Set the derivation index for receiving or change addresses.
     ///
     /// This will populate the address->deriv_index mapping with all the new entries between the
@@ -261,19 +351,25 @@ impl SqliteConn {
         secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
     ) {
         let network = self.db_tip().network;
+        let gap_limit = self.gap_limit;
 
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
-            let db_wallet: DbWallet =
-                db_tx_query(db_tx, "SELECT * FROM wallets", rusqlite::params![], |row| {
-                    row.try_into()
-                })?
-                .pop()
-                .expect("There is always a row in the wallet table");
+            let db_wallet: DbWallet = db_tx_query(
+                db_tx,
+                "SELECT * FROM wallets WHERE id = ?1",
+                rusqlite::params![wallet_id],
+                |row| row.try_into(),
+            )?
+            .pop()
+            .expect("The wallet of a connection always exists");
 
             // Make sure we don't set a lower derivation index. This can happen since the
             // derivation is set outside the atomic transaction. So there may be a race between say
             // the Bitcoin poller thread and the JSONRPC commands thread.
-            if (change && index <= db_wallet.change_derivation_index) || (!change && index <= db_wallet.deposit_derivation_index) {
+            if (change && index <= db_wallet.change_derivation_index)
+                || (!change && index <= db_wallet.deposit_derivation_index)
+            {
                 // It was already set at a higher index.
                 return Ok(());
             }
@@ -282,13 +378,13 @@ impl SqliteConn {
             let index_u32: u32 = index.into();
             if change {
                 db_tx.execute(
-                    "UPDATE wallets SET change_derivation_index = (?1)",
-                    rusqlite::params![index_u32],
+                    "UPDATE wallets SET change_derivation_index = (?1) WHERE id = (?2)",
+                    rusqlite::params![index_u32, wallet_id],
                 )?;
             } else {
                 db_tx.execute(
-                    "UPDATE wallets SET deposit_derivation_index = (?1)",
-                    rusqlite::params![index_u32],
+                    "UPDATE wallets SET deposit_derivation_index = (?1) WHERE id = (?2)",
+                    rusqlite::params![index_u32, wallet_id],
                 )?;
             }
 
@@ -298,21 +394,18 @@ impl SqliteConn {
             let curr_highest_index = cmp::max(
                 db_wallet.deposit_derivation_index,
                 db_wallet.change_derivation_index,
-            ).into();
+            )
+            .into();
             if index_u32 > curr_highest_index {
-                let receive_desc = db_wallet.main_descriptor.receive_descriptor();
-                let change_desc = db_wallet.main_descriptor.change_descriptor();
-
-                for index in curr_highest_index + 1..=index_u32 {
-                    let la_index = index + LOOK_AHEAD_LIMIT - 1;
-                    let receive_addr = receive_desc.derive(la_index.into(), secp).address(network);
-                    let change_addr = change_desc.derive(la_index.into(), secp).address(network);
-                    db_tx.execute(
-                        "INSERT INTO addresses (receive_address, change_address, derivation_index) VALUES (?1, ?2, ?3)",
-                        rusqlite::params![receive_addr.to_string(), change_addr.to_string(), la_index],
-                    )?;
-                }
-
+                fill_addresses(
+                    db_tx,
+                    wallet_id,
+                    &db_wallet.main_descriptor,
+                    index_u32,
+                    network,
+                    gap_limit,
+                    secp,
+                )?;
             }
 
             Ok(())
@@ -321,12 +414,12 @@ impl SqliteConn {
     }
 
     pub fn set_wallet_rescan_timestamp(&mut self, timestamp: u32) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
-            // NOTE: this will need to be updated if we ever implement multi-wallet support
             db_tx
                 .execute(
-                    "UPDATE wallets SET rescan_timestamp = (?1)",
-                    rusqlite::params![timestamp],
+                    "UPDATE wallets SET rescan_timestamp = (?1) WHERE id = (?2)",
+                    rusqlite::params![timestamp, wallet_id],
                 )
                 .map(|_| ())
         })
@@ -345,12 +438,12 @@ impl SqliteConn {
             db_wallet.timestamp,
         );
 
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
-            // NOTE: this will need to be updated if we ever implement multi-wallet support
             db_tx
                 .execute(
-                    "UPDATE wallets SET timestamp = (?1), rescan_timestamp = NULL",
-                    rusqlite::params![new_timestamp],
+                    "UPDATE wallets SET timestamp = (?1), rescan_timestamp = NULL WHERE id = (?2)",
+                    rusqlite::params![new_timestamp, wallet_id],
                 )
                 .map(|_| ())
         })
@@ -405,18 +498,21 @@ impl SqliteConn {
             String::new()
         };
         let where_clause = if !status_condition.is_empty() && !op_condition.is_empty() {
-            format!(" WHERE ({}) AND ({})", status_condition, op_condition)
+            format!(" AND ({}) AND ({})", status_condition, op_condition)
         } else if status_condition.is_empty() && !op_condition.is_empty() {
-            format!(" WHERE {}", op_condition)
+            format!(" AND {}", op_condition)
         } else if !status_condition.is_empty() && op_condition.is_empty() {
-            format!(" WHERE {}", status_condition)
+            format!(" AND ({})", status_condition)
         } else {
             String::new()
         };
-        let query = format!("SELECT * FROM coins{}", where_clause);
-        db_query(&mut self.conn, &query, rusqlite::params![], |row| {
-            row.try_into()
-        })
+        let query = format!("SELECT * FROM coins WHERE wallet_id = ?1{}", where_clause);
+        db_query(
+            &mut self.conn,
+            &query,
+            rusqlite::params![self.wallet_id],
+            |row| row.try_into(),
+        )
         .expect("Db must not fail")
     }
 
@@ -429,6 +525,7 @@ impl SqliteConn {
     /// Store new, unconfirmed and unspent, coins.
     /// Will panic if given a coin that is already in DB.
     pub fn new_unspent_coins<'a>(&mut self, coins: impl IntoIterator<Item = &'a Coin>) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for coin in coins {
                 let deriv_index: u32 = coin.derivation_index.into();
@@ -436,7 +533,7 @@ impl SqliteConn {
                     "INSERT INTO coins (wallet_id, txid, vout, amount_sat, derivation_index, is_change, is_immature) \
                          VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
                     rusqlite::params![
-                        WALLET_ID,
+                        wallet_id,
                         coin.outpoint.txid[..].to_vec(),
                         coin.outpoint.vout,
                         coin.amount.to_sat(),
@@ -453,11 +550,12 @@ impl SqliteConn {
 
     /// Remove a set of coins from the database.
     pub fn remove_coins(&mut self, outpoints: &[bitcoin::OutPoint]) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for outpoint in outpoints {
                 db_tx.execute(
-                    "DELETE FROM coins WHERE txid = ?1 AND vout = ?2",
-                    rusqlite::params![outpoint.txid[..].to_vec(), outpoint.vout,],
+                    "DELETE FROM coins WHERE wallet_id = ?1 AND txid = ?2 AND vout = ?3",
+                    rusqlite::params![wallet_id, outpoint.txid[..].to_vec(), outpoint.vout,],
                 )?;
             }
 
@@ -474,11 +572,12 @@ impl SqliteConn {
         &mut self,
         outpoints: impl IntoIterator<Item = &'a (bitcoin::OutPoint, i32, u32)>,
     ) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for (outpoint, height, time) in outpoints {
                 db_tx.execute(
-                    "UPDATE coins SET blockheight = ?1, blocktime = ?2, is_immature = 0 WHERE txid = ?3 AND vout = ?4",
-                    rusqlite::params![height, time, outpoint.txid[..].to_vec(), outpoint.vout,],
+                    "UPDATE coins SET blockheight = ?1, blocktime = ?2, is_immature = 0 WHERE wallet_id = ?3 AND txid = ?4 AND vout = ?5",
+                    rusqlite::params![height, time, wallet_id, outpoint.txid[..].to_vec(), outpoint.vout,],
                 )?;
             }
 
@@ -492,12 +591,14 @@ impl SqliteConn {
         &mut self,
         outpoints: impl IntoIterator<Item = &'a (bitcoin::OutPoint, bitcoin::Txid)>,
     ) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for (outpoint, spend_txid) in outpoints {
                 db_tx.execute(
-                    "UPDATE coins SET spend_txid = ?1 WHERE txid = ?2 AND vout = ?3",
+                    "UPDATE coins SET spend_txid = ?1 WHERE wallet_id = ?2 AND txid = ?3 AND vout = ?4",
                     rusqlite::params![
                         spend_txid[..].to_vec(),
+                        wallet_id,
                         outpoint.txid[..].to_vec(),
                         outpoint.vout,
                     ],
@@ -515,14 +616,16 @@ impl SqliteConn {
         &mut self,
         outpoints: impl IntoIterator<Item = &'a (bitcoin::OutPoint, bitcoin::Txid, i32, u32)>,
     ) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for (outpoint, spend_txid, height, time) in outpoints {
                 db_tx.execute(
-                    "UPDATE coins SET spend_txid = ?1, spend_block_height = ?2, spend_block_time = ?3 WHERE txid = ?4 AND vout = ?5",
+                    "UPDATE coins SET spend_txid = ?1, spend_block_height = ?2, spend_block_time = ?3 WHERE wallet_id = ?4 AND txid = ?5 AND vout = ?6",
                     rusqlite::params![
                         spend_txid[..].to_vec(),
                         height,
                         time,
+                        wallet_id,
                         outpoint.txid[..].to_vec(),
                         outpoint.vout,
                     ],
@@ -537,8 +640,8 @@ impl SqliteConn {
     pub fn db_address(&mut self, address: &bitcoin::Address) -> Option<DbAddress> {
         db_query(
             &mut self.conn,
-            "SELECT * FROM addresses WHERE receive_address = ?1 OR change_address = ?1",
-            rusqlite::params![address.to_string()],
+            "SELECT * FROM addresses WHERE wallet_id = ?1 AND (receive_address = ?2 OR change_address = ?2)",
+            rusqlite::params![self.wallet_id, address.to_string()],
             |row| row.try_into(),
         )
         .expect("Db must not fail")
@@ -552,23 +655,28 @@ impl SqliteConn {
     pub fn db_spend(&mut self, txid: &bitcoin::Txid) -> Option<DbSpendTransaction> {
         db_query(
             &mut self.conn,
-            "SELECT * FROM spend_transactions WHERE txid = ?1",
-            rusqlite::params![txid[..].to_vec()],
+            "SELECT * FROM spend_transactions WHERE wallet_id = ?1 AND txid = ?2",
+            rusqlite::params![self.wallet_id, txid[..].to_vec()],
             |row| row.try_into(),
         )
         .expect("Db must not fail")
         .pop()
     }
 
-    /// Insert a new Spend transaction or replace an existing one.
+    /// Insert a new Spend transaction or replace an existing one. Its status is only updated
+    /// if it was not broadcast or invalidated.
     pub fn store_spend(&mut self, psbt: &Psbt) {
         let txid = &psbt.unsigned_tx.txid()[..].to_vec();
+        let status = spend_status_to_db(SpendStatus::unbroadcast(psbt));
 
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             db_tx.execute(
-                "INSERT into spend_transactions (psbt, txid, updated_at) VALUES (?1, ?2, ?3) \
-                 ON CONFLICT DO UPDATE SET psbt=excluded.psbt",
-                rusqlite::params![psbt.serialize(), txid, curr_timestamp()],
+                "INSERT into spend_transactions (psbt, txid, updated_at, wallet_id, created_at, status) \
+                 VALUES (?1, ?2, ?3, ?4, ?3, ?5) \
+                 ON CONFLICT DO UPDATE SET psbt=excluded.psbt, updated_at=excluded.updated_at, \
+                 status=CASE WHEN status IN (0, 1) THEN excluded.status ELSE status END",
+                rusqlite::params![psbt.serialize(), txid, curr_timestamp(), wallet_id, status],
             )?;
             Ok(())
         })
@@ -578,14 +686,39 @@ impl SqliteConn {
     pub fn list_spend(&mut self) -> Vec<DbSpendTransaction> {
         db_query(
             &mut self.conn,
-            "SELECT * FROM spend_transactions",
-            rusqlite::params![],
+            "SELECT * FROM spend_transactions WHERE wallet_id = ?1",
+            rusqlite::params![self.wallet_id],
             |row| row.try_into(),
         )
         .expect("Db must not fail")
     }
 
+    pub fn set_spend_status(&mut self, txid: &bitcoin::Txid, status: SpendStatus) {
+        let wallet_id = self.wallet_id;
+        db_exec(&mut self.conn, |db_tx| {
+            db_tx.execute(
+                "UPDATE spend_transactions SET status = ?1 WHERE wallet_id = ?2 AND txid = ?3",
+                rusqlite::params![spend_status_to_db(status), wallet_id, txid[..].to_vec()],
+            )?;
+            Ok(())
+        })
+        .expect("Db must not fail");
+    }
+
+    pub fn set_spend_note(&mut self, txid: &bitcoin::Txid, note: Option<&str>) {
+        let wallet_id = self.wallet_id;
+        db_exec(&mut self.conn, |db_tx| {
+            db_tx.execute(
+                "UPDATE spend_transactions SET note = ?1 WHERE wallet_id = ?2 AND txid = ?3",
+                rusqlite::params![note, wallet_id, txid[..].to_vec()],
+            )?;
+            Ok(())
+        })
+        .expect("Db must not fail");
+    }
+
     pub fn update_labels(&mut self, items: &HashMap<LabelItem, Option<String>>) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             for (labelled, kind, value) in items
                 .iter()
@@ -600,12 +733,12 @@ impl SqliteConn {
                     db_tx.execute(
                         "INSERT INTO labels (wallet_id, item, item_kind, value) VALUES (?1, ?2, ?3, ?4) \
                         ON CONFLICT DO UPDATE SET value=excluded.value",
-                        rusqlite::params![WALLET_ID, labelled, kind as i64, value],
+                        rusqlite::params![wallet_id, labelled, kind as i64, value],
                     )?;
                 } else {
                     db_tx.execute(
                         "DELETE FROM labels WHERE wallet_id = ?1 AND item = ?2",
-                        rusqlite::params![WALLET_ID, labelled],
+                        rusqlite::params![wallet_id, labelled],
                     )?;
                 }
             }
@@ -614,21 +747,53 @@ impl SqliteConn {
         .expect("Db must not fail")
     }
 
+    /// Get all the labels of this wallet.
+    pub fn db_list_labels(&mut self) -> Vec<DbLabel> {
+        db_query(
+            &mut self.conn,
+            "SELECT * FROM labels WHERE wallet_id = ?1 ORDER BY id",
+            rusqlite::params![self.wallet_id],
+            |row| row.try_into(),
+        )
+        .expect("Db must not fail")
+    }
+
     pub fn db_labels(&mut self, items: &HashSet<LabelItem>) -> Vec<DbLabel> {
         let query = format!(
-            "SELECT * FROM labels where item in ({})",
+            "SELECT * FROM labels WHERE wallet_id = ?1 AND item in ({})",
             items
                 .iter()
                 .map(|a| format!("'{}'", a))
                 .collect::<Vec<String>>()
                 .join(",")
         );
-        db_query(&mut self.conn, &query, rusqlite::params![], |row| {
-            row.try_into()
-        })
+        db_query(
+            &mut self.conn,
+            &query,
+            rusqlite::params![self.wallet_id],
+            |row| row.try_into(),
+        )
         .expect("Db must not fail")
     }
 
+    /// Write a consistent copy of the whole database to a new file at this path, using SQLite's
+    /// online backup API. The file must not already exist.
+    pub fn backup(&mut self, path: &path::Path) -> Result<(), SqliteDbError> {
+        create_db_file(path)?;
+        if let Err(e) = self.conn.backup(rusqlite::DatabaseName::Main, path, None) {
+            // Don't leave a partial copy behind.
+            if let Err(e) = std::fs::remove_file(path) {
+                log::error!(
+                    "Error removing partial backup at '{}': {}",
+                    path.display(),
+                    e
+                );
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
     /// Retrieves a limited and ordered list of transactions ids that happened during the given
     /// range.
     pub fn db_list_txids(&mut self, start: u32, end: u32, limit: u64) -> Vec<bitcoin::Txid> {
@@ -637,20 +802,48 @@ impl SqliteConn {
             "SELECT DISTINCT(txid) FROM ( \
                 SELECT * from ( \
                     SELECT txid, blocktime AS date FROM coins \
-                    WHERE blocktime >= (?1) \
+                    WHERE wallet_id = (?4) \
+                    AND blocktime >= (?1) \
                     AND blocktime <= (?2) \
                     ORDER BY blocktime \
                 ) \
                 UNION \
                 SELECT * FROM (
                     SELECT spend_txid AS txid, spend_block_time AS date FROM coins \
-                    WHERE spend_block_time >= (?1) \
+                    WHERE wallet_id = (?4) \
+                    AND spend_block_time >= (?1) \
                     AND spend_block_time <= (?2) \
                     ORDER BY spend_block_time \
                 ) \
                 ORDER BY date DESC LIMIT (?3) \
             )",
-            rusqlite::params![start, end, limit],
+            rusqlite::params![start, end, limit, self.wallet_id],
+            |row| {
+                let txid: Vec<u8> = row.get(0)?;
+                let txid: bitcoin::Txid =
+                    encode::deserialize(&txid).expect("We only store valid txids");
+                Ok(txid)
+            },
+        )
+        .expect("Db must not fail")
+    }
+
+    /// Get a page of the txids of all the transactions that deposited or spent our coins. The
+    /// unconfirmed ones come first, then the most recent ones. Ties are broken by txid.
+    pub fn db_list_recent_txids(&mut self, limit: u64, offset: u64) -> Vec<bitcoin::Txid> {
+        db_query(
+            &mut self.conn,
+            "SELECT txid, MAX(date) AS max_date FROM ( \
+                SELECT txid, blocktime AS date FROM coins \
+                WHERE wallet_id = (?3) \
+                UNION ALL \
+                SELECT spend_txid AS txid, spend_block_time AS date FROM coins \
+                WHERE wallet_id = (?3) AND spend_txid IS NOT NULL \
+            ) \
+            GROUP BY txid \
+            ORDER BY max_date IS NOT NULL, max_date DESC, txid \
+            LIMIT (?1) OFFSET (?2)",
+            rusqlite::params![limit, offset, self.wallet_id],
             |row| {
                 let txid: Vec<u8> = row.get(0)?;
                 let txid: bitcoin::Txid =
@@ -662,10 +855,11 @@ impl SqliteConn {
     }
 
     pub fn delete_spend(&mut self, txid: &bitcoin::Txid) {
+        let wallet_id = self.wallet_id;
         db_exec(&mut self.conn, |db_tx| {
             db_tx.execute(
-                "DELETE FROM spend_transactions WHERE txid = ?1",
-                rusqlite::params![txid[..].to_vec()],
+                "DELETE FROM spend_transactions WHERE wallet_id = ?1 AND txid = ?2",
+                rusqlite::params![wallet_id, txid[..].to_vec()],
             )?;
             Ok(())
         })
@@ -674,7 +868,7 @@ impl SqliteConn {
 
     // TODO: mark coinbase deposits that were mature and became immature as such.
     /// Unconfirm all data that was marked as being confirmed *after* the given chain
-    /// tip, and set it as our new best block seen.
+    /// tip, and set it as our new best block seen. This applies to all wallets.
     ///
     /// This includes:
     /// - Coins (coinbase deposits that became immature isn't currently implemented)
@@ -792,12 +986,60 @@ CREATE TABLE spend_transactions (
         Psbt::from_str(psbt_str).unwrap()
     }
 
+    const GAP_LIMIT: u32 = 200;
+
     fn dummy_options() -> FreshDbOptions {
         let desc_str = "wsh(andor(pk([aabbccdd]tpubDEN9WSToTyy9ZQfaYqSKfmVqmq1VVLNtYfj3Vkqh67et57eJ5sTKZQBkHqSwPUsoSskJeaYnPttHe2VrkCsKA27kUaN9SDc5zhqeLzKa1rr/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))#dw4ulnrs";
         let main_descriptor = LianaDescriptor::from_str(desc_str).unwrap();
         FreshDbOptions::new(bitcoin::Network::Bitcoin, main_descriptor)
     }
 
+    // Create a database with version 0, using the old schema, as the first versions of the
+    // software did.
+    fn create_v0_db(db_path: &path::Path, secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>) {
+        let options = dummy_options();
+        utils::create_db_file(db_path).unwrap();
+
+        let mut query = String::with_capacity(100 * GAP_LIMIT as usize);
+        for index in 0..GAP_LIMIT {
+            let receive_address = options
+                .main_descriptor
+                .receive_descriptor()
+                .derive(index.into(), secp)
+                .address(options.bitcoind_network);
+            let change_address = options
+                .main_descriptor
+                .change_descriptor()
+                .derive(index.into(), secp)
+                .address(options.bitcoind_network);
+            query += &format!(
+                "INSERT INTO addresses (receive_address, change_address, derivation_index) VALUES (\"{}\", \"{}\", {});\n",
+                receive_address, change_address, index
+            );
+        }
+
+        let mut conn = rusqlite::Connection::open(db_path).unwrap();
+        db_exec(&mut conn, |tx| {
+            tx.execute_batch(V0_SCHEMA)?;
+            tx.execute(
+                "INSERT INTO version (version) VALUES (0)",
+                rusqlite::params![],
+            )?;
+            tx.execute(
+                "INSERT INTO tip (network, blockheight, blockhash) VALUES (?1, NULL, NULL)",
+                rusqlite::params![options.bitcoind_network.to_string()],
+            )?;
+            tx.execute(
+                "INSERT INTO wallets (timestamp, main_descriptor, deposit_derivation_index, change_derivation_index) \
+                         VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![curr_timestamp(), options.main_descriptor.to_string(), 0, 0],
+            )?;
+            tx.execute_batch(&query)?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
     fn dummy_db() -> (
         path::PathBuf,
         FreshDbOptions,
@@ -812,7 +1054,7 @@ CREATE TABLE spend_transactions (
             .iter()
             .collect();
         let options = dummy_options();
-        let db = SqliteDb::new(db_path, Some(options.clone()), &secp).unwrap();
+        let db = SqliteDb::new(db_path, Some(options.clone()), GAP_LIMIT, &secp).unwrap();
 
         (tmp_dir, options, secp, db)
     }
@@ -826,14 +1068,14 @@ CREATE TABLE spend_transactions (
         let db_path: path::PathBuf = [tmp_dir.as_path(), path::Path::new("lianad.sqlite3")]
             .iter()
             .collect();
-        assert!(SqliteDb::new(db_path.clone(), None, &secp)
+        assert!(SqliteDb::new(db_path.clone(), None, GAP_LIMIT, &secp)
             .unwrap_err()
             .to_string()
             .contains("database file not found"));
 
         let options = dummy_options();
 
-        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), &secp).unwrap();
+        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), GAP_LIMIT, &secp).unwrap();
         db.sanity_check(bitcoin::Network::Testnet, &options.main_descriptor)
             .unwrap_err()
             .to_string()
@@ -841,7 +1083,7 @@ CREATE TABLE spend_transactions (
         fs::remove_file(&db_path).unwrap();
         let other_desc_str = "wsh(andor(pk([aabbccdd]tpubDExU4YLJkyQ9RRbVScQq2brFxWWha7WmAUByPWyaWYwmcTv3Shx8aHp6mVwuE5n4TeM4z5DTWGf2YhNPmXtfvyr8cUDVvA3txdrFnFgNdF7/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))";
         let other_desc = LianaDescriptor::from_str(other_desc_str).unwrap();
-        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), &secp).unwrap();
+        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), GAP_LIMIT, &secp).unwrap();
         db.sanity_check(bitcoin::Network::Bitcoin, &other_desc)
             .unwrap_err()
             .to_string()
@@ -849,10 +1091,10 @@ CREATE TABLE spend_transactions (
         fs::remove_file(&db_path).unwrap();
         // TODO: version check
 
-        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), &secp).unwrap();
+        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), GAP_LIMIT, &secp).unwrap();
         db.sanity_check(bitcoin::Network::Bitcoin, &options.main_descriptor)
             .unwrap();
-        let db = SqliteDb::new(db_path, None, &secp).unwrap();
+        let db = SqliteDb::new(db_path, None, GAP_LIMIT, &secp).unwrap();
         db.sanity_check(bitcoin::Network::Bitcoin, &options.main_descriptor)
             .unwrap();
 
@@ -925,6 +1167,82 @@ CREATE TABLE spend_transactions (
         fs::remove_dir_all(tmp_dir).unwrap();
     }
 
+    #[test]
+    fn db_spend_storage() {
+        let (tmp_dir, _, _, db) = dummy_db();
+        let mut conn = db.connection().unwrap();
+
+        let signed_psbt = psbt_from_str("cHNidP8BAIkCAAAAAWi3OFgkj1CqCDT3Swm8kbxZS9lxz4L3i4W2v9KGC7nqAQAAAAD9////AkANAwAAAAAAIgAg27lNc1rog+dOq80ohRuds4Hgg/RcpxVun2XwgpuLSrFYMwwAAAAAACIAIDyWveqaElWmFGkTbFojg1zXWHODtiipSNjfgi2DqBy9AAAAAAABAOoCAAAAAAEBsRWl70USoAFFozxc86pC7Dovttdg4kvja//3WMEJskEBAAAAAP7///8CWKmCIk4GAAAWABRKBWYWkCNS46jgF0r69Ehdnq+7T0BCDwAAAAAAIgAgTt5fs+CiB+FRzNC8lHcgWLH205sNjz1pT59ghXlG5tQCRzBEAiBXK9MF8z3bX/VnY2aefgBBmiAHPL4tyDbUOe7+KpYA4AIgL5kU0DFG8szKd+szRzz/OTUWJ0tZqij41h2eU9rSe1IBIQNBB1hy+jKsg1TihMT0dXw7etpu9TkO3NuvhBDFJlBj1cP2AQABAStAQg8AAAAAACIAIE7eX7PgogfhUczQvJR3IFix9tObDY89aU+fYIV5RubUIgICSKJsNs0zFJN58yd2aYQ+C3vhMbi0x7k0FV3wBhR4THlIMEUCIQCPWWWOhs2lThxOq/G8X2fYBRvM9MXSm7qPH+dRVYQZEwIgfut2vx3RvwZWcgEj4ohQJD5lNJlwOkA4PAiN1fjx6dABIgID3mvj1zerZKohOVhKCiskYk+3qrCum6PIwDhQ16ePACpHMEQCICZNR+0/1hPkrDQwPFmg5VjUHkh6aK9cXUu3kPbM8hirAiAyE/5NUXKfmFKij30isuyysJbq8HrURjivd+S9vdRGKQEBBZNSIQJIomw2zTMUk3nzJ3ZphD4Le+ExuLTHuTQVXfAGFHhMeSEC9OfCXl+sJOrxUFLBuMV4ZUlJYjuzNGZSld5ioY14y8FSrnNkUSED3mvj1zerZKohOVhKCiskYk+3qrCum6PIwDhQ16ePACohA+ECH+HlR+8Sf3pumaXH3IwSsoqSLCH7H1THiBP93z3ZUq9SsmgiBgJIomw2zTMUk3nzJ3ZphD4Le+ExuLTHuTQVXfAGFHhMeRxjat8/MAAAgAEAAIAAAACAAgAAgAAAAAABAAAAIgYC9OfCXl+sJOrxUFLBuMV4ZUlJYjuzNGZSld5ioY14y8Ec/9Y8jTAAAIABAACAAAAAgAIAAIAAAAAAAQAAACIGA95r49c3q2SqITlYSgorJGJPt6qwrpujyMA4UNenjwAqHGNq3z8wAACAAQAAgAEAAIACAACAAAAAAAEAAAAiBgPhAh/h5UfvEn96bpmlx9yMErKKkiwh+x9Ux4gT/d892Rz/1jyNMAAAgAEAAIABAACAAgAAgAAAAAABAAAAACICAlBQ7gGocg7eF3sXrCio+zusAC9+xfoyIV95AeR69DWvHGNq3z8wAACAAQAAgAEAAIACAACAAAAAAAMAAAAiAgMvVy984eg8Kgvj058PBHetFayWbRGb7L0DMnS9KHSJzBxjat8/MAAAgAEAAIAAAACAAgAAgAAAAAADAAAAIgIDSRIG1dn6njdjsDXenHa2lUvQHWGPLKBVrSzbQOhiIxgc/9Y8jTAAAIABAACAAAAAgAIAAIAAAAAAAwAAACICA0/epE59sVEj7Et0I4R9qJQNuX23RNvDZKCRL7eUps9FHP/WPI0wAACAAQAAgAEAAIACAACAAAAAAAMAAAAAIgICgldCOK6iHscv//2NipgaMABLV5TICU/zlP7HlQmlg08cY2rfPzAAAIABAACAAQAAgAIAAIABAAAAAQAAACICApb0p9rfpJshB3J186PGWrvzQdixcwQZWmebOUMdkquZHP/WPI0wAACAAQAAgAAAAIACAACAAQAAAAEAAAAiAgLY5q+unoDxC/HI5BaNiPq12ei1REZIcUAN304JfKXUwxz/1jyNMAAAgAEAAIABAACAAgAAgAEAAAABAAAAIgIDg6cUVCJB79cMcofiURHojxFARWyS4YEhJNRixuOZZRgcY2rfPzAAAIABAACAAAAAgAIAAIABAAAAAQAAAAA=");
+        let txid = signed_psbt.unsigned_tx.txid();
+        let mut psbt = signed_psbt.clone();
+        for psbtin in psbt.inputs.iter_mut() {
+            psbtin.partial_sigs.clear();
+        }
+
+        // A new Spend is a draft, created when it was first stored.
+        conn.store_spend(&psbt);
+        let db_spend = conn.db_spend(&txid).unwrap();
+        assert_eq!(db_spend.status, SpendStatus::Draft);
+        assert!(db_spend.created_at.is_some());
+        assert_eq!(db_spend.created_at, db_spend.updated_at);
+        assert!(db_spend.note.is_none());
+
+        // Taproot signatures, for the key path or a script path, count as well.
+        let tap_sig = bitcoin::taproot::Signature {
+            sig: bitcoin::secp256k1::schnorr::Signature::from_slice(&[1; 64]).unwrap(),
+            hash_ty: bitcoin::sighash::TapSighashType::Default,
+        };
+        let mut taproot_psbt = psbt.clone();
+        taproot_psbt.inputs[0].tap_key_sig = Some(tap_sig);
+        conn.store_spend(&taproot_psbt);
+        assert_eq!(conn.db_spend(&txid).unwrap().status, SpendStatus::Signing);
+        let mut taproot_psbt = psbt.clone();
+        let xonly_key = bitcoin::secp256k1::XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        taproot_psbt.inputs[0].tap_script_sigs.insert(
+            (xonly_key, bitcoin::taproot::TapLeafHash::all_zeros()),
+            tap_sig,
+        );
+        assert_eq!(
+            SpendStatus::unbroadcast(&taproot_psbt),
+            SpendStatus::Signing
+        );
+
+        // Its status is updated along with its signatures, and a note can be attached to it.
+        conn.set_spend_note(&txid, Some("Invoice #42"));
+        conn.store_spend(&signed_psbt);
+        let db_spend = conn.db_spend(&txid).unwrap();
+        assert_eq!(db_spend.psbt, signed_psbt);
+        assert_eq!(db_spend.status, SpendStatus::Signing);
+        assert_eq!(db_spend.note.as_deref(), Some("Invoice #42"));
+
+        // Once broadcast, storing it again doesn't change its status.
+        conn.set_spend_status(&txid, SpendStatus::Broadcast);
+        conn.store_spend(&psbt);
+        let db_spend = conn.db_spend(&txid).unwrap();
+        assert_eq!(db_spend.psbt, psbt);
+        assert_eq!(db_spend.status, SpendStatus::Broadcast);
+
+        // All statuses can be stored.
+        for status in &[
+            SpendStatus::Draft,
+            SpendStatus::Signing,
+            SpendStatus::Broadcast,
+            SpendStatus::Confirmed,
+            SpendStatus::Replaced,
+            SpendStatus::Invalid,
+        ] {
+            conn.set_spend_status(&txid, *status);
+            assert_eq!(conn.list_spend()[0].status, *status);
+        }
+        conn.set_spend_note(&txid, None);
+        assert!(conn.db_spend(&txid).unwrap().note.is_none());
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+
     #[test]
     fn db_coins() {
         let (tmp_dir, _, _, db) = dummy_db();
@@ -1549,6 +1867,97 @@ CREATE TABLE spend_transactions (
         fs::remove_dir_all(tmp_dir).unwrap();
     }
 
+    #[test]
+    fn sqlite_gap_limit() {
+        let tmp_dir = tmp_dir();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let secp = secp256k1::Secp256k1::verification_only();
+        let db_path: path::PathBuf = [tmp_dir.as_path(), path::Path::new("lianad.sqlite3")]
+            .iter()
+            .collect();
+        let options = dummy_options();
+        let receive_addr = |index: u32| {
+            options
+                .main_descriptor
+                .receive_descriptor()
+                .derive(index.into(), &secp)
+                .address(options.bitcoind_network)
+        };
+
+        // With a gap limit of 20, only the first 20 addresses are stored at first.
+        let db = SqliteDb::new(db_path.clone(), Some(options.clone()), 20, &secp).unwrap();
+        {
+            let mut conn = db.connection().unwrap();
+            assert!(conn.db_address(&receive_addr(19)).is_some());
+            assert!(conn.db_address(&receive_addr(20)).is_none());
+
+            // Moving the derivation index moves the window.
+            conn.set_derivation_index(5.into(), false, &secp);
+            assert!(conn.db_address(&receive_addr(24)).is_some());
+            assert!(conn.db_address(&receive_addr(25)).is_none());
+        }
+
+        // If the gap limit is increased, the missing addresses are stored upon startup.
+        let db = SqliteDb::new(db_path.clone(), None, 50, &secp).unwrap();
+        {
+            let mut conn = db.connection().unwrap();
+            let db_addr = conn.db_address(&receive_addr(54)).unwrap();
+            assert_eq!(db_addr.derivation_index, 54.into());
+            assert!(conn.db_address(&receive_addr(55)).is_none());
+
+            // And the window is moved accordingly.
+            conn.set_derivation_index(10.into(), true, &secp);
+            assert!(conn.db_address(&receive_addr(59)).is_some());
+            assert!(conn.db_address(&receive_addr(60)).is_none());
+        }
+
+        // Decreasing it doesn't remove any address.
+        let db = SqliteDb::new(db_path, None, 10, &secp).unwrap();
+        {
+            let mut conn = db.connection().unwrap();
+            assert!(conn.db_address(&receive_addr(59)).is_some());
+            conn.set_derivation_index(11.into(), true, &secp);
+            assert!(conn.db_address(&receive_addr(60)).is_none());
+        }
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_backup() {
+        let (tmp_dir, _, secp, db) = dummy_db();
+        let backup_path: path::PathBuf = [tmp_dir.as_path(), path::Path::new("backup.sqlite3")]
+            .iter()
+            .collect();
+
+        let txid_str = "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7";
+        {
+            let mut conn = db.connection().unwrap();
+            let mut labels = HashMap::new();
+            labels.insert(
+                LabelItem::from_str(txid_str, bitcoin::Network::Bitcoin).unwrap(),
+                Some("hello".to_string()),
+            );
+            conn.update_labels(&labels);
+            conn.backup(&backup_path).unwrap();
+
+            // We won't overwrite an existing file.
+            conn.backup(&backup_path).unwrap_err();
+        }
+
+        // The backup is a usable database with the same content.
+        let backup_db = SqliteDb::new(backup_path, None, GAP_LIMIT, &secp).unwrap();
+        {
+            let mut conn = backup_db.connection().unwrap();
+            let labels = conn.db_list_labels();
+            assert_eq!(labels.len(), 1);
+            assert_eq!(labels[0].item, txid_str);
+            assert_eq!(labels[0].value, "hello");
+        }
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+
     #[test]
     fn sqlite_tip_rollback() {
         let (tmp_dir, _, _, db) = dummy_db();
@@ -1940,6 +2349,24 @@ CREATE TABLE spend_transactions (
                     .unwrap(),
                 ]
             );
+
+            // All the transactions can be paginated through, unconfirmed ones first.
+            let all_txids: Vec<bitcoin::Txid> = [
+                "6f0dc85a369b44458eba3a1f0ea5b5935d563afb6994f70f5b0094e05be1676c",
+                "7477017f992cdc7ba08acafb77cb3b5bc0f42ac340d3e1e1da0785bdda20d5f6",
+                "ed6c8f1af9325f84de521e785e7ddfd33dc28c9ada4d687dcd3850100bde54e9",
+                "19f56e65069f0a7a3bfb00c6a7085cc0669e03e91befeca1ee9891c9e737b2fb",
+                "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7",
+                "f0801fd9ca8bca0624c230ab422b2e2c4c8dc995e4e1dbc6412510959cce1e4f",
+                "c449539458c60bee6c0d8905ba1dadb20b9187b82045d306a408b894cea492b0",
+            ]
+            .iter()
+            .map(|txid| bitcoin::Txid::from_str(txid).unwrap())
+            .collect();
+            assert_eq!(conn.db_list_recent_txids(10, 0), all_txids);
+            assert_eq!(conn.db_list_recent_txids(3, 0), all_txids[..3]);
+            assert_eq!(conn.db_list_recent_txids(3, 3), all_txids[3..6]);
+            assert_eq!(conn.db_list_recent_txids(3, 6), all_txids[6..]);
         }
 
         fs::remove_dir_all(tmp_dir).unwrap();
@@ -1956,10 +2383,7 @@ CREATE TABLE spend_transactions (
         let db_path: path::PathBuf = [tmp_dir.as_path(), path::Path::new("lianad_v0.sqlite3")]
             .iter()
             .collect();
-        let mut options = dummy_options();
-        options.schema = V0_SCHEMA;
-        options.version = 0;
-        create_fresh_db(&db_path, options, &secp).unwrap();
+        create_v0_db(&db_path, &secp);
 
         // Two PSBTs we'll insert in the DB before and after the migration. Note they are random
         // PSBTs taken from the descriptor unit tests, it doesn't matter.
@@ -2003,7 +2427,7 @@ CREATE TABLE spend_transactions (
                         "INSERT INTO coins (wallet_id, txid, vout, amount_sat, derivation_index, is_change) \
                              VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
                         rusqlite::params![
-                            WALLET_ID,
+                            MAIN_WALLET_ID,
                             outpoint.txid[..].to_vec(),
                             outpoint.vout,
                             amount.to_sat(),
@@ -2044,7 +2468,7 @@ CREATE TABLE spend_transactions (
         // Migrate the DB.
         maybe_apply_migration(&db_path).unwrap();
         maybe_apply_migration(&db_path).unwrap(); // Migrating twice will be a no-op.
-        let db = SqliteDb::new(db_path, None, &secp).unwrap();
+        let db = SqliteDb::new(db_path, None, GAP_LIMIT, &secp).unwrap();
 
         // We should now be able to insert another PSBT, to query both, and the first PSBT must
         // have no associated timestamp.
@@ -2057,6 +2481,7 @@ CREATE TABLE spend_transactions (
                 .find(|db_spend| db_spend.psbt == first_psbt)
                 .unwrap();
             assert!(first_spend.updated_at.is_none());
+            assert!(first_spend.created_at.is_none());
             let second_spend = db_spends
                 .iter()
                 .find(|db_spend| db_spend.psbt == second_psbt)
@@ -2090,7 +2515,7 @@ CREATE TABLE spend_transactions (
     }
 
     #[test]
-    fn v0_to_v3_migration() {
+    fn v0_to_v5_migration() {
         let secp = secp256k1::Secp256k1::verification_only();
 
         // Create a database with version 0, using the old schema.
@@ -2099,18 +2524,15 @@ CREATE TABLE spend_transactions (
         let db_path: path::PathBuf = [tmp_dir.as_path(), path::Path::new("lianad_v0.sqlite3")]
             .iter()
             .collect();
-        let mut options = dummy_options();
-        options.schema = V0_SCHEMA;
-        options.version = 0;
-        create_fresh_db(&db_path, options, &secp).unwrap();
+        create_v0_db(&db_path, &secp);
 
         // SqliteDb new is doing the migration.
-        let db = SqliteDb::new(db_path, None, &secp).unwrap();
+        let db = SqliteDb::new(db_path, None, GAP_LIMIT, &secp).unwrap();
 
         {
             let mut conn = db.connection().unwrap();
             let version = conn.db_version();
-            assert_eq!(version, 3);
+            assert_eq!(version, 5);
 
             let txid_str = "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7";
             let txid = LabelItem::from_str(txid_str, bitcoin::Network::Bitcoin).unwrap();
@@ -2122,8 +2544,106 @@ CREATE TABLE spend_transactions (
             items.insert(txid);
             let db_labels = conn.db_labels(&items);
             assert_eq!(db_labels[0].value, "hello");
+
+            // The existing wallet and its addresses were kept as the main wallet.
+            let wallets = conn.db_wallets();
+            assert_eq!(wallets.len(), 1);
+            assert_eq!(wallets[0].id, MAIN_WALLET_ID);
+            assert_eq!(wallets[0].name, MAIN_WALLET_NAME);
+            let addr = dummy_options()
+                .main_descriptor
+                .receive_descriptor()
+                .derive(10.into(), &secp)
+                .address(bitcoin::Network::Bitcoin);
+            assert_eq!(conn.db_address(&addr).unwrap().derivation_index, 10.into());
         }
 
         fs::remove_dir_all(tmp_dir).unwrap();
     }
+
+    #[test]
+    fn db_multi_wallets() {
+        let (tmp_dir, options, secp, db) = dummy_db();
+        let other_desc = LianaDescriptor::from_str("wsh(andor(pk([aabbccdd]tpubDExU4YLJkyQ9RRbVScQq2brFxWWha7WmAUByPWyaWYwmcTv3Shx8aHp6mVwuE5n4TeM4z5DTWGf2YhNPmXtfvyr8cUDVvA3txdrFnFgNdF7/<0;1>/*),older(10000),pk([aabbccdd]tpubD8LYfn6njiA2inCoxwM7EuN3cuLVcaHAwLYeups13dpevd3nHLRdK9NdQksWXrhLQVxcUZRpnp5CkJ1FhE61WRAsHxDNAkvGkoQkAeWDYjV/<0;1>/*)))").unwrap();
+
+        // Create a second wallet along the main one.
+        let other_id = {
+            let mut conn = db.connection().unwrap();
+            conn.create_wallet("other", &other_desc, 1_000, &secp)
+        };
+        let mut main_conn = db.connection().unwrap();
+        let mut other_conn = db.wallet_connection(other_id).unwrap();
+        let wallets = main_conn.db_wallets();
+        assert_eq!(wallets.len(), 2);
+        assert_eq!(wallets[1].name, "other");
+        assert_eq!(wallets[1].main_descriptor, other_desc);
+        assert_eq!(wallets[1].timestamp, 1_000);
+        assert_eq!(other_conn.db_wallet().id, other_id);
+        assert_eq!(
+            main_conn.db_wallet().main_descriptor,
+            options.main_descriptor
+        );
+
+        // Each wallet only knows about its own addresses.
+        let other_addr = other_desc
+            .receive_descriptor()
+            .derive(0.into(), &secp)
+            .address(options.bitcoind_network);
+        assert!(main_conn.db_address(&other_addr).is_none());
+        assert!(other_conn.db_address(&other_addr).is_some());
+
+        // Derivation indexes and rescans are set per wallet.
+        other_conn.set_derivation_index(5.into(), false, &secp);
+        assert_eq!(other_conn.db_wallet().deposit_derivation_index, 5.into());
+        assert_eq!(main_conn.db_wallet().deposit_derivation_index, 0.into());
+        other_conn.set_wallet_rescan_timestamp(500);
+        assert_eq!(other_conn.db_wallet().rescan_timestamp, Some(500));
+        assert!(main_conn.db_wallet().rescan_timestamp.is_none());
+        other_conn.complete_wallet_rescan();
+        assert_eq!(other_conn.db_wallet().timestamp, 500);
+
+        // Coins, Spend transactions and labels are per wallet.
+        let outpoint = bitcoin::OutPoint::from_str(
+            "6f0dc85a369b44458eba3a1f0ea5b5935d563afb6994f70f5b0094e05be1676c:1",
+        )
+        .unwrap();
+        other_conn.new_unspent_coins(&[Coin {
+            outpoint,
+            is_immature: false,
+            block_info: None,
+            amount: bitcoin::Amount::from_sat(98765),
+            derivation_index: 2.into(),
+            is_change: false,
+            spend_txid: None,
+            spend_block: None,
+        }]);
+        assert!(main_conn.coins(&[], &[]).is_empty());
+        assert_eq!(other_conn.coins(&[CoinStatus::Unconfirmed], &[]).len(), 1);
+        assert_eq!(other_conn.db_coins(&[outpoint]).len(), 1);
+
+        let psbt = psbt_from_str("cHNidP8BAIkCAAAAAWi3OFgkj1CqCDT3Swm8kbxZS9lxz4L3i4W2v9KGC7nqAQAAAAD9////AkANAwAAAAAAIgAg27lNc1rog+dOq80ohRuds4Hgg/RcpxVun2XwgpuLSrFYMwwAAAAAACIAIDyWveqaElWmFGkTbFojg1zXWHODtiipSNjfgi2DqBy9AAAAAAABAOoCAAAAAAEBsRWl70USoAFFozxc86pC7Dovttdg4kvja//3WMEJskEBAAAAAP7///8CWKmCIk4GAAAWABRKBWYWkCNS46jgF0r69Ehdnq+7T0BCDwAAAAAAIgAgTt5fs+CiB+FRzNC8lHcgWLH205sNjz1pT59ghXlG5tQCRzBEAiBXK9MF8z3bX/VnY2aefgBBmiAHPL4tyDbUOe7+KpYA4AIgL5kU0DFG8szKd+szRzz/OTUWJ0tZqij41h2eU9rSe1IBIQNBB1hy+jKsg1TihMT0dXw7etpu9TkO3NuvhBDFJlBj1cP2AQABAStAQg8AAAAAACIAIE7eX7PgogfhUczQvJR3IFix9tObDY89aU+fYIV5RubUIgICSKJsNs0zFJN58yd2aYQ+C3vhMbi0x7k0FV3wBhR4THlIMEUCIQCPWWWOhs2lThxOq/G8X2fYBRvM9MXSm7qPH+dRVYQZEwIgfut2vx3RvwZWcgEj4ohQJD5lNJlwOkA4PAiN1fjx6dABIgID3mvj1zerZKohOVhKCiskYk+3qrCum6PIwDhQ16ePACpHMEQCICZNR+0/1hPkrDQwPFmg5VjUHkh6aK9cXUu3kPbM8hirAiAyE/5NUXKfmFKij30isuyysJbq8HrURjivd+S9vdRGKQEBBZNSIQJIomw2zTMUk3nzJ3ZphD4Le+ExuLTHuTQVXfAGFHhMeSEC9OfCXl+sJOrxUFLBuMV4ZUlJYjuzNGZSld5ioY14y8FSrnNkUSED3mvj1zerZKohOVhKCiskYk+3qrCum6PIwDhQ16ePACohA+ECH+HlR+8Sf3pumaXH3IwSsoqSLCH7H1THiBP93z3ZUq9SsmgiBgJIomw2zTMUk3nzJ3ZphD4Le+ExuLTHuTQVXfAGFHhMeRxjat8/MAAAgAEAAIAAAACAAgAAgAAAAAABAAAAIgYC9OfCXl+sJOrxUFLBuMV4ZUlJYjuzNGZSld5ioY14y8Ec/9Y8jTAAAIABAACAAAAAgAIAAIAAAAAAAQAAACIGA95r49c3q2SqITlYSgorJGJPt6qwrpujyMA4UNenjwAqHGNq3z8wAACAAQAAgAEAAIACAACAAAAAAAEAAAAiBgPhAh/h5UfvEn96bpmlx9yMErKKkiwh+x9Ux4gT/d892Rz/1jyNMAAAgAEAAIABAACAAgAAgAAAAAABAAAAACICAlBQ7gGocg7eF3sXrCio+zusAC9+xfoyIV95AeR69DWvHGNq3z8wAACAAQAAgAEAAIACAACAAAAAAAMAAAAiAgMvVy984eg8Kgvj058PBHetFayWbRGb7L0DMnS9KHSJzBxjat8/MAAAgAEAAIAAAACAAgAAgAAAAAADAAAAIgIDSRIG1dn6njdjsDXenHa2lUvQHWGPLKBVrSzbQOhiIxgc/9Y8jTAAAIABAACAAAAAgAIAAIAAAAAAAwAAACICA0/epE59sVEj7Et0I4R9qJQNuX23RNvDZKCRL7eUps9FHP/WPI0wAACAAQAAgAEAAIACAACAAAAAAAMAAAAAIgICgldCOK6iHscv//2NipgaMABLV5TICU/zlP7HlQmlg08cY2rfPzAAAIABAACAAQAAgAIAAIABAAAAAQAAACICApb0p9rfpJshB3J186PGWrvzQdixcwQZWmebOUMdkquZHP/WPI0wAACAAQAAgAAAAIACAACAAQAAAAEAAAAiAgLY5q+unoDxC/HI5BaNiPq12ei1REZIcUAN304JfKXUwxz/1jyNMAAAgAEAAIABAACAAgAAgAEAAAABAAAAIgIDg6cUVCJB79cMcofiURHojxFARWyS4YEhJNRixuOZZRgcY2rfPzAAAIABAACAAAAAgAIAAIABAAAAAQAAAAA=");
+        other_conn.store_spend(&psbt);
+        assert!(main_conn.list_spend().is_empty());
+        assert!(main_conn.db_spend(&psbt.unsigned_tx.txid()).is_none());
+        assert_eq!(other_conn.list_spend().len(), 1);
+        main_conn.set_spend_note(&psbt.unsigned_tx.txid(), Some("main"));
+        assert!(other_conn.list_spend()[0].note.is_none());
+
+        let txid = LabelItem::from_str(
+            "0c62a990d20d54429e70859292e82374ba6b1b951a3ab60f26bb65fee5724ff7",
+            bitcoin::Network::Bitcoin,
+        )
+        .unwrap();
+        let mut items = HashSet::new();
+        items.insert(txid.clone());
+        let mut labels = HashMap::new();
+        labels.insert(txid.clone(), Some("main".to_string()));
+        main_conn.update_labels(&labels);
+        labels.insert(txid, Some("other".to_string()));
+        other_conn.update_labels(&labels);
+        assert_eq!(main_conn.db_labels(&items)[0].value, "main");
+        assert_eq!(other_conn.db_labels(&items)[0].value, "other");
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
 }
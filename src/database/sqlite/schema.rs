@@ -1,4 +1,4 @@
-use crate::descriptors::LianaDescriptor;
+use crate::{database::SpendStatus, descriptors::LianaDescriptor};
 
 use std::{convert::TryFrom, str::FromStr};
 
@@ -18,13 +18,14 @@ CREATE TABLE tip (
     blockhash BLOB
 );
 
-/* This stores metadata about our wallet. We only support single wallet for
- * now (and the foreseeable future).
+/* This stores metadata about our wallets. The wallet created along with the database
+ * is the one whose main descriptor is set in the configuration, it always has id 1.
  *
  * The 'timestamp' field is the creation date of the wallet. We guarantee to have seen all
  * information related to our descriptor(s) that occured after this date.
  * The optional 'rescan_timestamp' field is a the timestamp we need to rescan the chain
  * for events related to our descriptor(s) from.
+ * The 'name' field is how the wallet is selected by the user.
  */
 CREATE TABLE wallets (
     id INTEGER PRIMARY KEY NOT NULL,
@@ -32,8 +33,10 @@ CREATE TABLE wallets (
     main_descriptor TEXT NOT NULL,
     deposit_derivation_index INTEGER NOT NULL,
     change_derivation_index INTEGER NOT NULL,
-    rescan_timestamp INTEGER
+    rescan_timestamp INTEGER,
+    name TEXT NOT NULL
 );
+CREATE UNIQUE INDEX wallets_name ON wallets (name);
 
 /* Our (U)TxOs.
  *
@@ -65,21 +68,34 @@ CREATE TABLE coins (
         ON DELETE RESTRICT
 );
 
-/* A mapping from descriptor address to derivation index. Necessary until
- * we can get the derivation index from the parent descriptor from bitcoind.
+/* A mapping from descriptor address to derivation index, for each wallet. Necessary
+ * until we can get the derivation index from the parent descriptor from bitcoind.
  */
 CREATE TABLE addresses (
     receive_address TEXT NOT NULL UNIQUE,
     change_address TEXT NOT NULL UNIQUE,
-    derivation_index INTEGER NOT NULL UNIQUE
+    derivation_index INTEGER NOT NULL,
+    wallet_id INTEGER NOT NULL,
+    UNIQUE (wallet_id, derivation_index),
+    FOREIGN KEY (wallet_id) REFERENCES wallets (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
 );
 
-/* Transactions we created that spend some of our coins. */
+/* Transactions we created that spend some of our coins. The status is one of draft (0),
+ * signing (1), broadcast (2), confirmed (3), replaced (4) or invalid (5). */
 CREATE TABLE spend_transactions (
     id INTEGER PRIMARY KEY NOT NULL,
     psbt BLOB UNIQUE NOT NULL,
     txid BLOB UNIQUE NOT NULL,
-    updated_at INTEGER
+    updated_at INTEGER,
+    wallet_id INTEGER NOT NULL,
+    created_at INTEGER,
+    note TEXT,
+    status INTEGER NOT NULL DEFAULT 0 CHECK (status IN (0,1,2,3,4,5)),
+    FOREIGN KEY (wallet_id) REFERENCES wallets (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
 );
 
 /* Labels applied on addresses (0), outpoints (1), txids (2) */
@@ -87,8 +103,9 @@ CREATE TABLE labels (
     id INTEGER PRIMARY KEY NOT NULL,
     wallet_id INTEGER NOT NULL,
     item_kind INTEGER NOT NULL CHECK (item_kind IN (0,1,2)),
-    item TEXT UNIQUE NOT NULL,
-    value TEXT NOT NULL
+    item TEXT NOT NULL,
+    value TEXT NOT NULL,
+    UNIQUE (wallet_id, item)
 );
 ";
 
@@ -130,6 +147,7 @@ pub struct DbWallet {
     pub deposit_derivation_index: bip32::ChildNumber,
     pub change_derivation_index: bip32::ChildNumber,
     pub rescan_timestamp: Option<u32>,
+    pub name: String,
 }
 
 impl TryFrom<&rusqlite::Row<'_>> for DbWallet {
@@ -149,6 +167,7 @@ impl TryFrom<&rusqlite::Row<'_>> for DbWallet {
         let change_derivation_index = bip32::ChildNumber::from(der_idx);
 
         let rescan_timestamp = row.get(5)?;
+        let name = row.get(6)?;
 
         Ok(DbWallet {
             id,
@@ -157,6 +176,7 @@ impl TryFrom<&rusqlite::Row<'_>> for DbWallet {
             deposit_derivation_index,
             change_derivation_index,
             rescan_timestamp,
+            name,
         })
     }
 }
@@ -277,6 +297,34 @@ pub struct DbSpendTransaction {
     pub psbt: Psbt,
     pub txid: bitcoin::Txid,
     pub updated_at: Option<u32>,
+    pub wallet_id: i64,
+    pub created_at: Option<u32>,
+    pub note: Option<String>,
+    pub status: SpendStatus,
+}
+
+/// The value stored in the "status" column of the "spend_transactions" table.
+pub fn spend_status_to_db(status: SpendStatus) -> i64 {
+    match status {
+        SpendStatus::Draft => 0,
+        SpendStatus::Signing => 1,
+        SpendStatus::Broadcast => 2,
+        SpendStatus::Confirmed => 3,
+        SpendStatus::Replaced => 4,
+        SpendStatus::Invalid => 5,
+    }
+}
+
+fn spend_status_from_db(value: i64) -> SpendStatus {
+    match value {
+        0 => SpendStatus::Draft,
+        1 => SpendStatus::Signing,
+        2 => SpendStatus::Broadcast,
+        3 => SpendStatus::Confirmed,
+        4 => SpendStatus::Replaced,
+        5 => SpendStatus::Invalid,
+        _ => unreachable!("Checked by the schema"),
+    }
 }
 
 impl TryFrom<&rusqlite::Row<'_>> for DbSpendTransaction {
@@ -293,12 +341,21 @@ impl TryFrom<&rusqlite::Row<'_>> for DbSpendTransaction {
         assert_eq!(txid, psbt.unsigned_tx.txid());
 
         let updated_at = row.get(3)?;
+        let wallet_id = row.get(4)?;
+        let created_at = row.get(5)?;
+        let note = row.get(6)?;
+        let status: i64 = row.get(7)?;
+        let status = spend_status_from_db(status);
 
         Ok(DbSpendTransaction {
             id,
             psbt,
             txid,
             updated_at,
+            wallet_id,
+            created_at,
+            note,
+            status,
         })
     }
 }
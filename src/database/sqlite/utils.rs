@@ -1,10 +1,11 @@
-use crate::database::sqlite::{FreshDbOptions, SqliteDbError, DB_VERSION};
+use crate::{
+    database::sqlite::{FreshDbOptions, SqliteDbError, DB_VERSION, MAIN_WALLET_NAME},
+    descriptors::LianaDescriptor,
+};
 
 use std::{convert::TryInto, fs, path, time};
 
-use miniscript::bitcoin::secp256k1;
-
-pub const LOOK_AHEAD_LIMIT: u32 = 200;
+use miniscript::bitcoin::{self, secp256k1};
 
 /// Perform a set of modifications to the database inside a single transaction
 pub fn db_exec<F>(conn: &mut rusqlite::Connection, modifications: F) -> Result<(), rusqlite::Error>
@@ -82,35 +83,83 @@ pub fn create_db_file(db_path: &path::Path) -> Result<(), std::io::Error> {
     };
 }
 
-/// Create a fresh Liana database with the given schema.
-pub fn create_fresh_db(
-    db_path: &path::Path,
-    options: FreshDbOptions,
+/// Make sure the addresses of this wallet are stored for all derivation indexes up to `gap_limit`
+/// indexes past `highest_index`. Only the missing ones are inserted.
+pub fn fill_addresses(
+    tx: &rusqlite::Transaction,
+    wallet_id: i64,
+    main_descriptor: &LianaDescriptor,
+    highest_index: u32,
+    network: bitcoin::Network,
+    gap_limit: u32,
     secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
-) -> Result<(), SqliteDbError> {
-    create_db_file(db_path)?;
-
-    let timestamp = curr_timestamp();
+) -> rusqlite::Result<()> {
+    let stored_max: Option<u32> = tx.query_row(
+        "SELECT MAX(derivation_index) FROM addresses WHERE wallet_id = ?1",
+        rusqlite::params![wallet_id],
+        |row| row.get(0),
+    )?;
+    let start = stored_max.map(|index| index + 1).unwrap_or(0);
+    let end = highest_index
+        .checked_add(gap_limit)
+        .expect("Derivation index must not overflow");
+    if start >= end {
+        return Ok(());
+    }
 
-    // Fill the initial addresses. On a fresh database, the deposit_derivation_index is
-    // necessarily 0.
-    let mut query = String::with_capacity(100 * LOOK_AHEAD_LIMIT as usize);
-    for index in 0..LOOK_AHEAD_LIMIT {
-        let receive_address = options
-            .main_descriptor
+    let mut query = String::with_capacity(100 * (end - start) as usize);
+    for index in start..end {
+        let receive_address = main_descriptor
             .receive_descriptor()
             .derive(index.into(), secp)
-            .address(options.bitcoind_network);
-        let change_address = options
-            .main_descriptor
+            .address(network);
+        let change_address = main_descriptor
             .change_descriptor()
             .derive(index.into(), secp)
-            .address(options.bitcoind_network);
+            .address(network);
         query += &format!(
-            "INSERT INTO addresses (receive_address, change_address, derivation_index) VALUES (\"{}\", \"{}\", {});\n",
-            receive_address, change_address, index
+            "INSERT INTO addresses (receive_address, change_address, derivation_index, wallet_id) VALUES (\"{}\", \"{}\", {}, {});\n",
+            receive_address, change_address, index, wallet_id
         );
     }
+    tx.execute_batch(&query)
+}
+
+/// Insert a new wallet with the given name and descriptor, along with its initial addresses.
+/// Returns the id of the new wallet.
+pub fn insert_wallet(
+    tx: &rusqlite::Transaction,
+    name: &str,
+    main_descriptor: &LianaDescriptor,
+    network: bitcoin::Network,
+    timestamp: u32,
+    gap_limit: u32,
+    secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+) -> rusqlite::Result<i64> {
+    tx.execute(
+        "INSERT INTO wallets (timestamp, main_descriptor, deposit_derivation_index, change_derivation_index, name) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![timestamp, main_descriptor.to_string(), 0, 0, name],
+    )?;
+    let wallet_id = tx.last_insert_rowid();
+
+    // Fill the initial addresses. On a fresh wallet, the deposit_derivation_index is
+    // necessarily 0.
+    fill_addresses(tx, wallet_id, main_descriptor, 0, network, gap_limit, secp)?;
+
+    Ok(wallet_id)
+}
+
+/// Create a fresh Liana database with the given schema.
+pub fn create_fresh_db(
+    db_path: &path::Path,
+    options: FreshDbOptions,
+    gap_limit: u32,
+    secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+) -> Result<(), SqliteDbError> {
+    create_db_file(db_path)?;
+
+    let timestamp = curr_timestamp();
 
     let mut conn = rusqlite::Connection::open(db_path)?;
     db_exec(&mut conn, |tx| {
@@ -123,12 +172,15 @@ pub fn create_fresh_db(
             "INSERT INTO tip (network, blockheight, blockhash) VALUES (?1, NULL, NULL)",
             rusqlite::params![options.bitcoind_network.to_string()],
         )?;
-        tx.execute(
-            "INSERT INTO wallets (timestamp, main_descriptor, deposit_derivation_index, change_derivation_index) \
-                     VALUES (?1, ?2, ?3, ?4)",
-            rusqlite::params![timestamp, options.main_descriptor.to_string(), 0, 0],
+        insert_wallet(
+            tx,
+            MAIN_WALLET_NAME,
+            &options.main_descriptor,
+            options.bitcoind_network,
+            timestamp,
+            gap_limit,
+            secp,
         )?;
-        tx.execute_batch(&query)?;
 
         Ok(())
     })?;
@@ -194,6 +246,65 @@ fn migrate_v2_to_v3(conn: &mut rusqlite::Connection) -> Result<(), SqliteDbError
     Ok(())
 }
 
+// To support multiple wallets per daemon, we upgraded the schema to give each wallet a name and
+// to scope the addresses, the Spend transactions and the labels by wallet. Existing data all
+// belongs to the wallet created along with the database.
+fn migrate_v3_to_v4(conn: &mut rusqlite::Connection) -> Result<(), SqliteDbError> {
+    db_exec(conn, |tx| {
+        tx.execute(
+            &format!(
+                "ALTER TABLE wallets ADD COLUMN name TEXT NOT NULL DEFAULT '{}'",
+                MAIN_WALLET_NAME
+            ),
+            rusqlite::params![],
+        )?;
+        tx.execute(
+            "CREATE UNIQUE INDEX wallets_name ON wallets (name)",
+            rusqlite::params![],
+        )?;
+        tx.execute(
+            "ALTER TABLE spend_transactions ADD COLUMN wallet_id INTEGER NOT NULL DEFAULT 1",
+            rusqlite::params![],
+        )?;
+        // The unicity constraints of the addresses and labels tables can't be altered. Recreate
+        // them.
+        tx.execute_batch(
+            "CREATE TABLE addresses_new (receive_address TEXT NOT NULL UNIQUE, change_address TEXT NOT NULL UNIQUE, derivation_index INTEGER NOT NULL, wallet_id INTEGER NOT NULL, UNIQUE (wallet_id, derivation_index), FOREIGN KEY (wallet_id) REFERENCES wallets (id) ON UPDATE RESTRICT ON DELETE RESTRICT);
+             INSERT INTO addresses_new SELECT receive_address, change_address, derivation_index, 1 FROM addresses;
+             DROP TABLE addresses;
+             ALTER TABLE addresses_new RENAME TO addresses;
+             CREATE TABLE labels_new (id INTEGER PRIMARY KEY NOT NULL, wallet_id INTEGER NOT NULL, item_kind INTEGER NOT NULL CHECK (item_kind IN (0,1,2)), item TEXT NOT NULL, value TEXT NOT NULL, UNIQUE (wallet_id, item));
+             INSERT INTO labels_new SELECT id, wallet_id, item_kind, item, value FROM labels;
+             DROP TABLE labels;
+             ALTER TABLE labels_new RENAME TO labels;",
+        )?;
+        tx.execute("UPDATE version SET version = 4", rusqlite::params![])?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+// To keep track of the Spend transactions' lifecycle, we added to them a creation timestamp, an
+// optional note and a status. We don't know when existing ones were created and they have no
+// note, so these are left NULL. Their status defaults to draft (0) and is corrected by the poller
+// on its next round, from the state of the coins they spend and the signatures in their PSBT. An
+// existing one that was already replaced will be marked as invalid, since we can't tell it was
+// broadcast.
+fn migrate_v4_to_v5(conn: &mut rusqlite::Connection) -> Result<(), SqliteDbError> {
+    db_exec(conn, |tx| {
+        tx.execute_batch(
+            "ALTER TABLE spend_transactions ADD COLUMN created_at INTEGER;
+             ALTER TABLE spend_transactions ADD COLUMN note TEXT;
+             ALTER TABLE spend_transactions ADD COLUMN status INTEGER NOT NULL DEFAULT 0 CHECK (status IN (0,1,2,3,4,5));",
+        )?;
+        tx.execute("UPDATE version SET version = 5", rusqlite::params![])?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
 /// Check the database version and if necessary apply the migrations to upgrade it to the current
 /// one.
 pub fn maybe_apply_migration(db_path: &path::Path) -> Result<(), SqliteDbError> {
@@ -222,6 +333,16 @@ pub fn maybe_apply_migration(db_path: &path::Path) -> Result<(), SqliteDbError>
                 migrate_v2_to_v3(&mut conn)?;
                 log::warn!("Migration from database version 2 to version 3 successful.");
             }
+            3 => {
+                log::warn!("Upgrading database from version 3 to version 4.");
+                migrate_v3_to_v4(&mut conn)?;
+                log::warn!("Migration from database version 3 to version 4 successful.");
+            }
+            4 => {
+                log::warn!("Upgrading database from version 4 to version 5.");
+                migrate_v4_to_v5(&mut conn)?;
+                log::warn!("Migration from database version 4 to version 5 successful.");
+            }
             _ => return Err(SqliteDbError::UnsupportedVersion(version)),
         }
     }
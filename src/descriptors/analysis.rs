@@ -1,5 +1,9 @@
 use miniscript::{
-    bitcoin::{bip32, Sequence},
+    bitcoin::{
+        self, bip32,
+        hashes::{sha256, Hash, HashEngine},
+        secp256k1, Sequence,
+    },
     descriptor,
     policy::{compiler, Concrete as ConcretePolicy, Liftable, Semantic as SemanticPolicy},
     ScriptContext,
@@ -9,8 +13,82 @@ use std::{
     collections::{BTreeMap, HashMap, HashSet},
     convert::TryFrom,
     error, fmt,
+    str::FromStr,
+    sync,
 };
 
+// The "Nothing Up My Sleeve" point from BIP341. It has no known discrete logarithm, and neither
+// do the keys derived from it.
+const NUMS_POINT: &str = "0250929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+fn nums_point() -> secp256k1::PublicKey {
+    secp256k1::PublicKey::from_str(NUMS_POINT).expect("Valid public key")
+}
+
+// A master xpub for the NUMS point.
+fn unspendable_xkey(
+    chain_code: bip32::ChainCode,
+    network: bitcoin::Network,
+) -> bip32::ExtendedPubKey {
+    bip32::ExtendedPubKey {
+        network,
+        depth: 0,
+        parent_fingerprint: bip32::Fingerprint::default(),
+        child_number: bip32::ChildNumber::from_normal_idx(0).expect("Zero is a normal index"),
+        public_key: nums_point(),
+        chain_code,
+    }
+}
+
+/// The fingerprint of the unspendable xpub, from which the derived unspendable internal keys
+/// can be recognized. It doesn't depend on the chaincode.
+pub(super) fn unspendable_fingerprint() -> bip32::Fingerprint {
+    unspendable_xkey(bip32::ChainCode::from([0; 32]), bitcoin::Network::Bitcoin).fingerprint()
+}
+
+/// The provably unspendable key used as the Taproot internal key when the primary spending path
+/// is a multisig. As suggested in BIP341, this is an xpub for the NUMS point with a chaincode
+/// committing to the other keys of the descriptor. It is derived like the other keys so the
+/// internal key differs across outputs, which therefore can't be linked together through it.
+pub fn unspendable_internal_key<'a>(
+    keys: impl Iterator<Item = &'a descriptor::DescriptorPublicKey>,
+) -> descriptor::DescriptorPublicKey {
+    let mut engine = sha256::HashEngine::default();
+    let mut network = bitcoin::Network::Bitcoin;
+    for key in keys {
+        if let descriptor::DescriptorPublicKey::MultiXPub(ref xpub) = key {
+            engine.input(&xpub.xkey.encode());
+            network = xpub.xkey.network;
+        }
+    }
+    let chain_code = bip32::ChainCode::from(sha256::Hash::from_engine(engine).to_byte_array());
+    let xkey = unspendable_xkey(chain_code, network);
+    let derivation_paths = descriptor::DerivPaths::new(vec![
+        vec![bip32::ChildNumber::from_normal_idx(0).expect("Normal index")].into(),
+        vec![bip32::ChildNumber::from_normal_idx(1).expect("Normal index")].into(),
+    ])
+    .expect("Not empty");
+    descriptor::DescriptorPublicKey::MultiXPub(descriptor::DescriptorMultiXKey {
+        origin: None,
+        xkey,
+        derivation_paths,
+        wildcard: descriptor::Wildcard::Unhardened,
+    })
+}
+
+/// Whether this key is a provably unspendable internal key as we use for Taproot descriptors.
+pub fn is_unspendable_internal_key(key: &descriptor::DescriptorPublicKey) -> bool {
+    match key {
+        descriptor::DescriptorPublicKey::MultiXPub(ref xpub) => {
+            xpub.origin.is_none()
+                && xpub.xkey.public_key == nums_point()
+                && xpub.derivation_paths.paths().len() == 2
+                && xpub.wildcard == descriptor::Wildcard::Unhardened
+        }
+        _ => false,
+    }
+}
+
 #[derive(Debug)]
 pub enum LianaPolicyError {
     MissingRecoveryPath,
@@ -25,6 +103,8 @@ pub enum LianaPolicyError {
     /// The spending policy is not a valid Miniscript policy: it may for instance be malleable, or
     /// overflow some limit.
     InvalidPolicy(compiler::CompilerError),
+    /// The Taproot tree created from the spending policy is invalid (too deep, ..).
+    InvalidTaprootTree(miniscript::Error),
 }
 
 impl std::fmt::Display for LianaPolicyError {
@@ -54,6 +134,7 @@ impl std::fmt::Display for LianaPolicyError {
                 "Descriptor is not compatible with a Liana spending policy."
             ),
             Self::InvalidPolicy(e) => write!(f, "Invalid Miniscript policy: {}", e),
+            Self::InvalidTaprootTree(e) => write!(f, "Invalid Taproot tree: {}", e),
         }
     }
 }
@@ -362,17 +443,41 @@ impl PathInfo {
 /// be used to retrieve the settings.
 /// Do note however that the descriptor generation process is not deterministic, therefore you
 /// **cannot roundtrip** a descriptor through a `LianaPolicy`.
+///
+/// A Liana policy may be expressed as a P2WSH or as a Taproot descriptor. In the latter case the
+/// primary path is used as the key path if it is a single key. Otherwise a provably unspendable
+/// internal key is used and the primary path is put in its own tapleaf. Each recovery path is
+/// always in its own tapleaf.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct LianaPolicy {
     pub(super) primary_path: PathInfo,
     pub(super) recovery_paths: BTreeMap<u16, PathInfo>,
+    pub(super) is_taproot: bool,
 }
 
 impl LianaPolicy {
-    /// Create a new Liana policy from a given configuration.
+    /// Create a new Liana policy from a given configuration. It will be expressed as a P2WSH
+    /// descriptor.
     pub fn new(
         primary_path: PathInfo,
         recovery_paths: BTreeMap<u16, PathInfo>,
+    ) -> Result<LianaPolicy, LianaPolicyError> {
+        LianaPolicy::new_inner(primary_path, recovery_paths, false)
+    }
+
+    /// Create a new Liana policy from a given configuration. It will be expressed as a Taproot
+    /// descriptor.
+    pub fn new_taproot(
+        primary_path: PathInfo,
+        recovery_paths: BTreeMap<u16, PathInfo>,
+    ) -> Result<LianaPolicy, LianaPolicyError> {
+        LianaPolicy::new_inner(primary_path, recovery_paths, true)
+    }
+
+    fn new_inner(
+        primary_path: PathInfo,
+        recovery_paths: BTreeMap<u16, PathInfo>,
+        is_taproot: bool,
     ) -> Result<LianaPolicy, LianaPolicyError> {
         if recovery_paths.is_empty() {
             return Err(LianaPolicyError::MissingRecoveryPath);
@@ -426,32 +531,41 @@ impl LianaPolicy {
         let policy = LianaPolicy {
             primary_path,
             recovery_paths,
+            is_taproot,
         };
-        policy.clone().into_miniscript()?;
+        policy.clone().into_descriptor()?;
         Ok(policy)
     }
 
     /// Create a Liana policy from a descriptor. This will check the descriptor is correctly formed
-    /// (P2WSH, multipath, ..) and has a valid Liana semantic.
+    /// (P2WSH or Taproot, multipath, ..) and has a valid Liana semantic.
     pub fn from_multipath_descriptor(
         desc: &descriptor::Descriptor<descriptor::DescriptorPublicKey>,
     ) -> Result<LianaPolicy, LianaPolicyError> {
-        // For now we only allow P2WSH descriptors.
-        let wsh_desc = match &desc {
-            descriptor::Descriptor::Wsh(desc) => desc,
-            _ => return Err(LianaPolicyError::IncompatibleDesc),
-        };
-
-        // Lift a semantic policy out of this Miniscript and normalize it to make sure we compare
-        // apples to apples below.
-        let ms = match wsh_desc.as_inner() {
-            descriptor::WshInner::Ms(ms) => ms,
+        // Lift a semantic policy out of this descriptor and normalize it to make sure we compare
+        // apples to apples below. We only allow P2WSH and Taproot descriptors.
+        let (policy, is_taproot) = match &desc {
+            descriptor::Descriptor::Wsh(wsh_desc) => {
+                let ms = match wsh_desc.as_inner() {
+                    descriptor::WshInner::Ms(ms) => ms,
+                    _ => return Err(LianaPolicyError::IncompatibleDesc),
+                };
+                let policy = ms.lift().expect("Lifting can't fail on a Miniscript");
+                (policy, false)
+            }
+            descriptor::Descriptor::Tr(tr_desc) => {
+                // A Liana Taproot descriptor always has at least one recovery path in the tree.
+                if tr_desc.taptree().is_none() {
+                    return Err(LianaPolicyError::IncompatibleDesc);
+                }
+                let policy = tr_desc
+                    .lift()
+                    .expect("Lifting can't fail on a Taproot descriptor");
+                (policy, true)
+            }
             _ => return Err(LianaPolicyError::IncompatibleDesc),
         };
-        let policy = ms
-            .lift()
-            .expect("Lifting can't fail on a Miniscript")
-            .normalized();
+        let policy = policy.normalized();
 
         // The policy must always be "1 of N spending paths" with at least an always-available
         // primary path with at least one key, and at least one timelocked recovery path with at
@@ -466,6 +580,14 @@ impl LianaPolicy {
         // one that isn't timelocked.
         let (mut primary_path, mut recovery_paths) = (None::<PathInfo>, BTreeMap::new());
         for sub in subs {
+            // In a Taproot descriptor the internal key may be the unspendable key, in which case
+            // the primary path is in one of the leaves.
+            if is_taproot
+                && matches!(sub, SemanticPolicy::Key(ref key) if is_unspendable_internal_key(key))
+            {
+                continue;
+            }
+
             // This is a (multi)key check. It must be the primary path.
             if is_single_key_or_multisig(&sub) {
                 // We only support a single primary path. But it may be that the primary path is a
@@ -495,7 +617,7 @@ impl LianaPolicy {
         // Use the constructor for sanity checking the keys and the Miniscript policy. Note this
         // makes sure the recovery paths mapping isn't empty, too.
         let prim_path = primary_path.ok_or(LianaPolicyError::IncompatibleDesc)?;
-        LianaPolicy::new(prim_path, recovery_paths)
+        LianaPolicy::new_inner(prim_path, recovery_paths, is_taproot)
     }
 
     pub fn primary_path(&self) -> &PathInfo {
@@ -509,6 +631,11 @@ impl LianaPolicy {
         &self.recovery_paths
     }
 
+    /// Whether this policy is expressed as a Taproot descriptor.
+    pub fn is_taproot(&self) -> bool {
+        self.is_taproot
+    }
+
     fn into_miniscript(
         self,
     ) -> Result<
@@ -518,6 +645,7 @@ impl LianaPolicy {
         let LianaPolicy {
             primary_path,
             recovery_paths,
+            ..
         } = self;
 
         // Start with the primary spending path. We'll then or() all the recovery paths to it.
@@ -541,6 +669,76 @@ impl LianaPolicy {
             .map_err(LianaPolicyError::InvalidPolicy)
     }
 
+    fn into_tr(self) -> Result<descriptor::Tr<descriptor::DescriptorPublicKey>, LianaPolicyError> {
+        let LianaPolicy {
+            primary_path,
+            recovery_paths,
+            ..
+        } = self;
+
+        // Compile each spending path into its own tapleaf. If the primary path is a single key,
+        // use it as the internal key instead.
+        let compile_leaf = |policy: ConcretePolicy<descriptor::DescriptorPublicKey>| {
+            policy
+                .compile::<miniscript::Tap>()
+                .map(|ms| descriptor::TapTree::Leaf(sync::Arc::new(ms)))
+                .map_err(LianaPolicyError::InvalidPolicy)
+        };
+        let (internal_key, mut leaves) = match primary_path {
+            PathInfo::Single(key) => (key, Vec::with_capacity(recovery_paths.len())),
+            PathInfo::Multi(thresh, keys) => {
+                let recovery_keys = recovery_paths
+                    .values()
+                    .flat_map(|path_info| match path_info {
+                        PathInfo::Single(key) => std::slice::from_ref(key),
+                        PathInfo::Multi(_, keys) => &keys[..],
+                    });
+                let internal_key = unspendable_internal_key(keys.iter().chain(recovery_keys));
+                let mut leaves = Vec::with_capacity(recovery_paths.len() + 1);
+                leaves.push(compile_leaf(
+                    PathInfo::Multi(thresh, keys).into_ms_policy(),
+                )?);
+                (internal_key, leaves)
+            }
+        };
+        for (timelock, path_info) in recovery_paths {
+            let timelock = ConcretePolicy::Older(Sequence::from_height(timelock));
+            let keys = path_info.into_ms_policy();
+            leaves.push(compile_leaf(ConcretePolicy::And(vec![keys, timelock]))?);
+        }
+
+        // Create the tree, from the last recovery path up to the primary path. We assume the
+        // larger the timelock the less likely a branch would be used, therefore we make the leaves
+        // for the larger timelocks deeper in the tree.
+        let mut leaves = leaves.into_iter().rev();
+        let last_leaf = leaves
+            .next()
+            .expect("There is always at least one recovery path.");
+        let tree = leaves.fold(last_leaf, |tree, leaf| {
+            descriptor::TapTree::Tree(sync::Arc::new(leaf), sync::Arc::new(tree))
+        });
+
+        descriptor::Tr::new(internal_key, Some(tree)).map_err(LianaPolicyError::InvalidTaprootTree)
+    }
+
+    // Get the descriptor corresponding to this policy, checking it's valid.
+    fn into_descriptor(
+        self,
+    ) -> Result<descriptor::Descriptor<descriptor::DescriptorPublicKey>, LianaPolicyError> {
+        if self.is_taproot {
+            let tr = self.into_tr()?;
+            tr.sanity_check()
+                .map_err(LianaPolicyError::InvalidTaprootTree)?;
+            Ok(descriptor::Descriptor::Tr(tr))
+        } else {
+            let ms = self.into_miniscript()?;
+            miniscript::Segwitv0::check_local_validity(&ms).expect("Miniscript must be sane");
+            Ok(descriptor::Descriptor::Wsh(
+                descriptor::Wsh::new(ms).expect("Must pass sanity checks"),
+            ))
+        }
+    }
+
     /// Create a descriptor from this spending policy with multipath key expressions. Note this
     /// involves a Miniscript policy compilation: this function is **not deterministic**. If you
     /// are inferring a `LianaPolicy` from a descriptor, generating a descriptor from this
@@ -548,11 +746,8 @@ impl LianaPolicy {
     pub fn into_multipath_descriptor(
         self,
     ) -> descriptor::Descriptor<descriptor::DescriptorPublicKey> {
-        let ms = self
-            .into_miniscript()
-            .expect("This is always checked when creating a LianaPolicy.");
-        miniscript::Segwitv0::check_local_validity(&ms).expect("Miniscript must be sane");
-        descriptor::Descriptor::Wsh(descriptor::Wsh::new(ms).expect("Must pass sanity checks"))
+        self.into_descriptor()
+            .expect("This is always checked when creating a LianaPolicy.")
     }
 }
 
@@ -1,13 +1,13 @@
 use miniscript::{
     bitcoin::{
         self, bip32,
-        psbt::{Input as PsbtIn, Psbt},
-        secp256k1,
+        psbt::{Input as PsbtIn, Output as PsbtOut, Psbt},
+        secp256k1, taproot,
     },
-    descriptor, translate_hash_clone, ForEachKey, TranslatePk, Translator,
+    descriptor, translate_hash_clone, ForEachKey, ToPublicKey, TranslatePk, Translator,
 };
 
-use std::{collections::BTreeMap, error, fmt, str};
+use std::{collections::BTreeMap, convert::TryFrom, error, fmt, str};
 
 use serde::{Deserialize, Serialize};
 
@@ -153,6 +153,26 @@ impl LianaDescriptor {
         })
     }
 
+    /// Whether this is a Taproot descriptor.
+    pub fn is_taproot(&self) -> bool {
+        matches!(self.multi_desc, descriptor::Descriptor::Tr(..))
+    }
+
+    /// Get all the xpubs contained in this descriptor, without duplicates, in the order they
+    /// appear. The unspendable Taproot internal key isn't included.
+    pub fn xpubs(&self) -> Vec<bip32::ExtendedPubKey> {
+        let mut xpubs = Vec::new();
+        self.multi_desc.for_each_key(|key| {
+            if let descriptor::DescriptorPublicKey::MultiXPub(xpub) = key {
+                if !xpubs.contains(&xpub.xkey) && !is_unspendable_internal_key(key) {
+                    xpubs.push(xpub.xkey);
+                }
+            }
+            true
+        });
+        xpubs
+    }
+
     /// Get the descriptor for receiving addresses.
     pub fn receive_descriptor(&self) -> &SinglePathLianaDesc {
         &self.receive_desc
@@ -226,11 +246,23 @@ impl LianaDescriptor {
         psbt_in: &PsbtIn,
         txin: &bitcoin::TxIn,
     ) -> PartialSpendInfo {
-        // Get the identifier of all the keys that signed this transaction.
-        let pubkeys_signed = psbt_in
+        // Get the identifier of all the keys that signed this transaction. For Taproot inputs
+        // the signature may be for the key path or for a key in any of the tapleaves.
+        let ecdsa_signed = psbt_in
             .partial_sigs
             .iter()
             .filter_map(|(pk, _)| psbt_in.bip32_derivation.get(&pk.inner));
+        let keypath_signed = psbt_in
+            .tap_key_sig
+            .as_ref()
+            .and(psbt_in.tap_internal_key.as_ref())
+            .and_then(|pk| psbt_in.tap_key_origins.get(pk))
+            .map(|(_, origin)| origin);
+        let scriptpath_signed = psbt_in
+            .tap_script_sigs
+            .keys()
+            .filter_map(|(pk, _)| psbt_in.tap_key_origins.get(pk).map(|(_, origin)| origin));
+        let pubkeys_signed = ecdsa_signed.chain(keypath_signed).chain(scriptpath_signed);
 
         // Determine the structure of the descriptor. Then compute the spend info for the primary
         // and recovery paths. Only provide the spend info for the recovery path if it is available
@@ -306,27 +338,32 @@ impl LianaDescriptor {
         // (Fingerprint, derivation path) pairs uniquely identify a key used in this spending path.
         let (_, path_origins) = spending_path.thresh_origins();
 
+        // Whether the key with this origin is from a signer used in this spending path.
+        let is_path_key = |fg: &bip32::Fingerprint, der_path: &bip32::DerivationPath| {
+            if let Some(der_paths) = path_origins.get(fg) {
+                // Get the derivation path from the master fingerprint to the parent used to
+                // derive this key, in order to check whether it's part of the derivation paths
+                // used in this spending path (only checking the fingerprint isn't sufficient
+                // as a single signer may be used in more than one spending path).
+                // NOTE: this assumes there is only one derivation step after the key used in
+                // the policy. This is fine, because the keys in the policy are normalized (so
+                // the derivation path up to the wildcard is part of the origin).
+                if let Some((_, der_path_no_wildcard)) = der_path[..].split_last() {
+                    return der_paths.contains(&der_path_no_wildcard.into());
+                }
+            }
+            false
+        };
+
         // Go through all the PSBT inputs and drop the BIP32 derivations for keys that are not from
-        // this spending path.
+        // this spending path. For Taproot inputs, do the same for the Taproot BIP32 derivations.
         for psbt_in in psbt.inputs.iter_mut() {
-            psbt_in.bip32_derivation.retain(|_, (fg, der_path)| {
-                // Does it come from a signer used in this spending path?
-                if let Some(der_paths) = path_origins.get(fg) {
-                    // Get the derivation path from the master fingerprint to the parent used to
-                    // derive this key, in order to check whether it's part of the derivation paths
-                    // used in this spending path (only checking the fingerprint isn't sufficient
-                    // as a single signer may be used in more than one spending path).
-                    // NOTE: this assumes there is only one derivation step after the key used in
-                    // the policy. This is fine, because the keys in the policy are normalized (so
-                    // the derivation path up to the wildcard is part of the origin).
-                    if let Some((_, der_path_no_wildcard)) = der_path[..].split_last() {
-                        if der_paths.contains(&der_path_no_wildcard.into()) {
-                            return true;
-                        }
-                    }
-                }
-                false
-            });
+            psbt_in
+                .bip32_derivation
+                .retain(|_, (fg, der_path)| is_path_key(fg, der_path));
+            psbt_in
+                .tap_key_origins
+                .retain(|_, (_, (fg, der_path))| is_path_key(fg, der_path));
         }
 
         psbt
@@ -419,11 +456,25 @@ impl SinglePathLianaDesc {
 /// Map of a raw public key to the xpub used to derive it and its derivation path
 pub type Bip32Deriv = BTreeMap<secp256k1::PublicKey, (bip32::Fingerprint, bip32::DerivationPath)>;
 
+/// Map of an x-only public key to the tapleaves it is used in, the xpub used to derive it and its
+/// derivation path.
+pub type TapBip32Deriv = BTreeMap<
+    secp256k1::XOnlyPublicKey,
+    (
+        Vec<taproot::TapLeafHash>,
+        (bip32::Fingerprint, bip32::DerivationPath),
+    ),
+>;
+
 impl DerivedSinglePathLianaDesc {
     pub fn address(&self, network: bitcoin::Network) -> bitcoin::Address {
         self.0
             .address(network)
-            .expect("A P2WSH always has an address")
+            .expect("A P2WSH or P2TR always has an address")
+    }
+
+    pub fn is_taproot(&self) -> bool {
+        matches!(self.0, descriptor::Descriptor::Tr(..))
     }
 
     pub fn script_pubkey(&self) -> bitcoin::ScriptBuf {
@@ -442,7 +493,7 @@ impl DerivedSinglePathLianaDesc {
                     unreachable!("None of our descriptors is a sorted multi")
                 }
             },
-            _ => unreachable!("All our descriptors are always P2WSH"),
+            _ => unreachable!("Only called on P2WSH descriptors"),
         };
 
         // For DerivedPublicKey, Pk::Hash == Self.
@@ -450,6 +501,84 @@ impl DerivedSinglePathLianaDesc {
             .map(|k| (k.key.inner, (k.origin.0, k.origin.1)))
             .collect()
     }
+
+    fn tr(&self) -> &descriptor::Tr<DerivedPublicKey> {
+        match self.0 {
+            descriptor::Descriptor::Tr(ref tr) => tr,
+            _ => unreachable!("Only called on Taproot descriptors"),
+        }
+    }
+
+    /// Get the Taproot BIP32 derivations of all the keys in this descriptor, along with the
+    /// tapleaves they are used in. The unspendable internal key isn't included.
+    pub fn tap_bip32_derivations(&self) -> TapBip32Deriv {
+        let tr = self.tr();
+        let mut derivs = TapBip32Deriv::new();
+
+        let internal_key = tr.internal_key();
+        if internal_key.origin.0 != unspendable_fingerprint() {
+            derivs.insert(
+                internal_key.to_x_only_pubkey(),
+                (Vec::new(), internal_key.origin.clone()),
+            );
+        }
+        for (_, ms) in tr.iter_scripts() {
+            let leaf_hash =
+                taproot::TapLeafHash::from_script(&ms.encode(), taproot::LeafVersion::TapScript);
+            for key in ms.iter_pk() {
+                derivs
+                    .entry(key.to_x_only_pubkey())
+                    .or_insert_with(|| (Vec::new(), key.origin.clone()))
+                    .0
+                    .push(leaf_hash);
+            }
+        }
+
+        derivs
+    }
+
+    /// Populate a PSBT input spending a coin from this descriptor with the information needed by
+    /// signers (witness script and BIP32 derivations for P2WSH, internal key, merkle root,
+    /// tapscripts and Taproot BIP32 derivations for Taproot).
+    pub fn update_psbt_in(&self, psbt_in: &mut PsbtIn) {
+        if self.is_taproot() {
+            let tr = self.tr();
+            let spend_info = tr.spend_info();
+            psbt_in.tap_internal_key = Some(spend_info.internal_key());
+            psbt_in.tap_merkle_root = spend_info.merkle_root();
+            for (_, ms) in tr.iter_scripts() {
+                let script_ver = (ms.encode(), taproot::LeafVersion::TapScript);
+                let control_block = spend_info
+                    .control_block(&script_ver)
+                    .expect("The script is in the tree");
+                psbt_in.tap_scripts.insert(control_block, script_ver);
+            }
+            psbt_in.tap_key_origins = self.tap_bip32_derivations();
+        } else {
+            psbt_in.witness_script = Some(self.witness_script());
+            psbt_in.bip32_derivation = self.bip32_derivations();
+        }
+    }
+
+    /// Populate a PSBT output paying to this descriptor with the information needed by signers to
+    /// recognize it as one of ours.
+    pub fn update_psbt_out(&self, psbt_out: &mut PsbtOut) {
+        if self.is_taproot() {
+            let tr = self.tr();
+            psbt_out.tap_internal_key = Some(tr.internal_key().to_x_only_pubkey());
+            let mut builder = taproot::TaprootBuilder::new();
+            for (depth, ms) in tr.iter_scripts() {
+                builder = builder
+                    .add_leaf(depth, ms.encode())
+                    .expect("Depths are from a valid tree");
+            }
+            psbt_out.tap_tree =
+                Some(taproot::TapTree::try_from(builder).expect("The tree is complete"));
+            psbt_out.tap_key_origins = self.tap_bip32_derivations();
+        } else {
+            psbt_out.bip32_derivation = self.bip32_derivations();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1249,5 +1378,118 @@ mod tests {
         assert_eq!(psbt, pruned_psbt);
     }
 
+    #[test]
+    fn taproot_descriptor() {
+        let secp = secp256k1::Secp256k1::new();
+        let owner_key = PathInfo::Single(descriptor::DescriptorPublicKey::from_str("[abcdef01]xpub6Eze7yAT3Y1wGrnzedCNVYDXUqa9NmHVWck5emBaTbXtURbe1NWZbK9bsz1TiVE7Cz341PMTfYgFw1KdLWdzcM1UMFTcdQfCYhhXZ2HJvTW/<0;1>/*").unwrap());
+        let heir_key = PathInfo::Single(descriptor::DescriptorPublicKey::from_str("[abcdef01]xpub688Hn4wScQAAiYJLPg9yH27hUpfZAUnmJejRQBCiwfP5PEDzjWMNW1wChcninxr5gyavFqbbDjdV1aK5USJz8NDVjUy7FRQaaqqXHh5SbXe/<0;1>/*").unwrap());
+
+        // A single key primary path is used as the internal key.
+        let policy = LianaPolicy::new_taproot(
+            owner_key.clone(),
+            [(52560, heir_key.clone())].iter().cloned().collect(),
+        )
+        .unwrap();
+        assert!(policy.is_taproot());
+        let desc = LianaDescriptor::new(policy.clone());
+        assert_eq!(desc.to_string(), "tr([abcdef01]xpub6Eze7yAT3Y1wGrnzedCNVYDXUqa9NmHVWck5emBaTbXtURbe1NWZbK9bsz1TiVE7Cz341PMTfYgFw1KdLWdzcM1UMFTcdQfCYhhXZ2HJvTW/<0;1>/*,and_v(v:pk([abcdef01]xpub688Hn4wScQAAiYJLPg9yH27hUpfZAUnmJejRQBCiwfP5PEDzjWMNW1wChcninxr5gyavFqbbDjdV1aK5USJz8NDVjUy7FRQaaqqXHh5SbXe/<0;1>/*),older(52560)))#0mt7e93c");
+        assert!(desc.is_taproot());
+        assert_eq!(desc.policy(), policy);
+        assert_eq!(LianaDescriptor::from_str(&desc.to_string()).unwrap(), desc);
+
+        // Spending through the key path is cheaper than through the recovery path, and the
+        // worst case is cheaper than for the equivalent P2WSH descriptor.
+        let wsh_desc = LianaDescriptor::new(
+            LianaPolicy::new(
+                owner_key.clone(),
+                [(52560, heir_key.clone())].iter().cloned().collect(),
+            )
+            .unwrap(),
+        );
+        assert!(desc.max_sat_weight() < wsh_desc.max_sat_weight());
+
+        // A multisig primary path is put in a tapleaf, with an unspendable internal key.
+        let primary_keys = PathInfo::Multi(
+            2,
+            vec![
+                descriptor::DescriptorPublicKey::from_str("[aabb0011/48'/0'/0'/2']xpub6Eze7yAT3Y1wGrnzedCNVYDXUqa9NmHVWck5emBaTbXtURbe1NWZbK9bsz1TiVE7Cz341PMTfYgFw1KdLWdzcM1UMFTcdQfCYhhXZ2HJvTW/<0;1>/*").unwrap(),
+                descriptor::DescriptorPublicKey::from_str("[aabb0012/48'/0'/0'/2']xpub6Bw79HbNSeS2xXw1sngPE3ehnk1U3iSPCgLYzC9LpN8m9nDuaKLZvkg8QXxL5pDmEmQtYscmUD8B9MkAAZbh6vxPzNXMaLfGQ9Sb3z85qhR/<0;1>/*").unwrap(),
+            ]
+        );
+        let recovery_keys = PathInfo::Single(descriptor::DescriptorPublicKey::from_str("[aabb0013/48'/0'/0'/2']xpub67zuTXF9Ln4731avKTBSawoVVNRuMfmRvkL7kLUaLBRqma9ZqdHBJg9qx8cPUm3oNQMiXT4TmGovXNoQPuwg17RFcVJ8YrnbcooN7pxVJqC/<0;1>/*").unwrap());
+        let policy = LianaPolicy::new_taproot(
+            primary_keys,
+            [(26352, recovery_keys), (52560, heir_key.clone())]
+                .iter()
+                .cloned()
+                .collect(),
+        )
+        .unwrap();
+        let desc = LianaDescriptor::new(policy.clone());
+        assert_eq!(desc.to_string(), "tr(xpub661MyMwAqRbcGKgGKjhSJq2sfSgQomCgrQ6Fxkjw66dY5Y2rA5LwcJJZGcNotLwqrLpPjmySzjE6kb97W88UPKcxgqbiMprvqRLKVR8GPhN/<0;1>/*,{multi_a(2,[aabb0011/48'/0'/0'/2']xpub6Eze7yAT3Y1wGrnzedCNVYDXUqa9NmHVWck5emBaTbXtURbe1NWZbK9bsz1TiVE7Cz341PMTfYgFw1KdLWdzcM1UMFTcdQfCYhhXZ2HJvTW/<0;1>/*,[aabb0012/48'/0'/0'/2']xpub6Bw79HbNSeS2xXw1sngPE3ehnk1U3iSPCgLYzC9LpN8m9nDuaKLZvkg8QXxL5pDmEmQtYscmUD8B9MkAAZbh6vxPzNXMaLfGQ9Sb3z85qhR/<0;1>/*),{and_v(v:pk([aabb0013/48'/0'/0'/2']xpub67zuTXF9Ln4731avKTBSawoVVNRuMfmRvkL7kLUaLBRqma9ZqdHBJg9qx8cPUm3oNQMiXT4TmGovXNoQPuwg17RFcVJ8YrnbcooN7pxVJqC/<0;1>/*),older(26352)),and_v(v:pk([abcdef01]xpub688Hn4wScQAAiYJLPg9yH27hUpfZAUnmJejRQBCiwfP5PEDzjWMNW1wChcninxr5gyavFqbbDjdV1aK5USJz8NDVjUy7FRQaaqqXHh5SbXe/<0;1>/*),older(52560))}})#6auhwrka");
+        assert_eq!(desc.policy(), policy);
+        assert!(desc.all_xpubs_net_is(bitcoin::Network::Bitcoin));
+        assert_eq!(LianaDescriptor::from_str(&desc.to_string()).unwrap(), desc);
+
+        // The derived descriptor populates the Taproot fields of PSBT inputs and outputs. The
+        // unspendable internal key isn't part of the BIP32 derivations.
+        let derived_desc = desc.receive_descriptor().derive(42.into(), &secp);
+        let mut psbt_in = PsbtIn::default();
+        derived_desc.update_psbt_in(&mut psbt_in);
+        let internal_key = psbt_in.tap_internal_key.unwrap();
+        assert!(!psbt_in.tap_key_origins.contains_key(&internal_key));
+        assert!(psbt_in.tap_merkle_root.is_some());
+        assert_eq!(psbt_in.tap_scripts.len(), 3);
+        assert_eq!(psbt_in.tap_key_origins.len(), 4);
+        assert!(psbt_in
+            .tap_key_origins
+            .values()
+            .all(|(leaves, _)| leaves.len() == 1));
+        let mut psbt_out = PsbtOut::default();
+        derived_desc.update_psbt_out(&mut psbt_out);
+        assert_eq!(psbt_out.tap_internal_key, Some(internal_key));
+        assert!(psbt_out.tap_tree.is_some());
+        assert_eq!(psbt_out.tap_key_origins.len(), 4);
+
+        // The unspendable internal key is derived like the other keys, so it can't be used to link
+        // outputs together. It isn't reported as one of the xpubs of the descriptor.
+        let mut other_psbt_in = PsbtIn::default();
+        desc.receive_descriptor()
+            .derive(43.into(), &secp)
+            .update_psbt_in(&mut other_psbt_in);
+        assert!(other_psbt_in.tap_internal_key.is_some());
+        assert_ne!(other_psbt_in.tap_internal_key, Some(internal_key));
+        desc.change_descriptor()
+            .derive(42.into(), &secp)
+            .update_psbt_in(&mut other_psbt_in);
+        assert_ne!(other_psbt_in.tap_internal_key, Some(internal_key));
+        assert_eq!(desc.xpubs().len(), 4);
+
+        // Pruning the BIP32 derivations works for the Taproot ones too.
+        let psbt = Psbt {
+            unsigned_tx: bitcoin::Transaction {
+                version: 2,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![bitcoin::TxIn::default()],
+                output: vec![bitcoin::TxOut::default()],
+            },
+            version: 0,
+            xpub: BTreeMap::new(),
+            proprietary: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+            inputs: vec![psbt_in],
+            outputs: vec![psbt_out],
+        };
+        let prim_path_info = desc.policy().primary_path;
+        let pruned_psbt = desc.prune_bip32_derivs(psbt.clone(), &prim_path_info);
+        assert_eq!(pruned_psbt.inputs[0].tap_key_origins.len(), 2);
+        let recov_path_info = desc.policy().recovery_paths.get(&52560).cloned().unwrap();
+        let pruned_psbt = desc.prune_bip32_derivs(psbt, &recov_path_info);
+        assert_eq!(pruned_psbt.inputs[0].tap_key_origins.len(), 1);
+
+        // A Taproot descriptor without a recovery path in the tree is invalid.
+        LianaDescriptor::from_str("tr([abcdef01]xpub6Eze7yAT3Y1wGrnzedCNVYDXUqa9NmHVWck5emBaTbXtURbe1NWZbK9bsz1TiVE7Cz341PMTfYgFw1KdLWdzcM1UMFTcdQfCYhhXZ2HJvTW/<0;1>/*)").unwrap_err();
+    }
+
     // TODO: test error conditions of deserialization.
 }
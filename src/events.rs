@@ -0,0 +1,171 @@
+//! Events
+//!
+//! Notifications about the state of our wallets, emitted as we poll the Bitcoin backend. Any
+//! number of subscribers may register to receive them.
+
+use std::sync::{self, mpsc};
+
+use miniscript::bitcoin;
+use serde::{Deserialize, Serialize};
+
+/// An event emitted by the daemon. It serializes as the `method` and `params` of a JSONRPC2
+/// notification.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum Event {
+    /// We moved to a new best block.
+    NewTip {
+        height: i32,
+        hash: bitcoin::BlockHash,
+    },
+    /// A new coin was received.
+    CoinReceived {
+        /// The name of the wallet this coin belongs to.
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+        #[serde(with = "bitcoin::amount::serde::as_sat")]
+        amount: bitcoin::Amount,
+        derivation_index: u32,
+        is_change: bool,
+    },
+    /// A coin was confirmed in a block.
+    CoinConfirmed {
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+        height: i32,
+        time: u32,
+    },
+    /// An unconfirmed coin disappeared from the mempool.
+    CoinExpired {
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+    },
+    /// A transaction spending a coin was detected.
+    CoinSpending {
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+        spend_txid: bitcoin::Txid,
+    },
+    /// The transaction spending a coin was confirmed in a block.
+    CoinSpent {
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+        spend_txid: bitcoin::Txid,
+        height: i32,
+        time: u32,
+    },
+    /// A recovery path became available for a coin: a transaction spending it through this path
+    /// may be included in the next block.
+    RecoveryAvailable {
+        wallet: String,
+        outpoint: bitcoin::OutPoint,
+        /// The relative timelock of the recovery path, in blocks.
+        timelock: u16,
+    },
+    /// The progress of the ongoing rescan of the Bitcoin backend, between 0 and 1.
+    RescanProgress { progress: f64 },
+    /// The rescan of the Bitcoin backend completed.
+    RescanCompleted {},
+}
+
+/// Dispatches events to all the current subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct EventNotifier {
+    subscribers: sync::Arc<sync::Mutex<Vec<mpsc::Sender<Event>>>>,
+}
+
+impl EventNotifier {
+    pub fn new() -> EventNotifier {
+        EventNotifier::default()
+    }
+
+    /// Register a new subscriber. It will receive all the events emitted from now on, until the
+    /// receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .expect("Events subscribers lock poisoned")
+            .push(sender);
+        receiver
+    }
+
+    /// Send this event to all the subscribers. Those which went away are forgotten.
+    pub fn notify(&self, event: Event) {
+        log::debug!("Emitting event: {:?}", event);
+        self.subscribers
+            .lock()
+            .expect("Events subscribers lock poisoned")
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    /// The number of current subscribers.
+    pub fn subscribers_count(&self) -> usize {
+        self.subscribers
+            .lock()
+            .expect("Events subscribers lock poisoned")
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn event_notifier() {
+        let notifier = EventNotifier::new();
+        let event = Event::RescanProgress { progress: 0.5 };
+
+        // Without subscriber this is a no-op.
+        notifier.notify(event.clone());
+
+        let first = notifier.subscribe();
+        let second = notifier.subscribe();
+        assert_eq!(notifier.subscribers_count(), 2);
+        notifier.notify(event.clone());
+        assert_eq!(first.try_recv().unwrap(), event);
+        assert_eq!(second.try_recv().unwrap(), event);
+        assert!(first.try_recv().is_err());
+
+        // A subscriber that went away is forgotten upon the next notification.
+        drop(second);
+        notifier.notify(Event::RescanCompleted {});
+        assert_eq!(notifier.subscribers_count(), 1);
+        assert_eq!(first.try_recv().unwrap(), Event::RescanCompleted {});
+    }
+
+    #[test]
+    fn event_serialization() {
+        let event = Event::CoinReceived {
+            wallet: "main".to_string(),
+            outpoint: bitcoin::OutPoint::from_str(
+                "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:1",
+            )
+            .unwrap(),
+            amount: bitcoin::Amount::from_sat(100_000),
+            derivation_index: 3,
+            is_change: false,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "method": "coinreceived",
+                "params": {
+                    "wallet": "main",
+                    "outpoint": "4e3f5ab5b8a6bb2e9ab1e9e2b3b8a3a5b6e7c8d9e0f1a2b3c4d5e6f7a8b9c0d1:1",
+                    "amount": 100_000,
+                    "derivation_index": 3,
+                    "is_change": false,
+                }
+            })
+        );
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+        assert_eq!(
+            serde_json::to_value(&Event::RescanCompleted {}).unwrap(),
+            serde_json::json!({"method": "rescancompleted", "params": {}})
+        );
+    }
+}
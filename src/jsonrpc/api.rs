@@ -1,6 +1,7 @@
 use crate::{
-    commands::{CoinStatus, LabelItem},
-    jsonrpc::{Error, Params, Request, Response},
+    commands::{Bip329Label, CoinStatus, LabelItem, WalletExport},
+    descriptors::LianaDescriptor,
+    jsonrpc::{Error, Params, Permissions, Request},
     DaemonControl,
 };
 
@@ -10,7 +11,9 @@ use std::{
     str::FromStr,
 };
 
-use miniscript::bitcoin::{self, psbt::PartiallySignedTransaction as Psbt};
+use miniscript::bitcoin::{
+    self, consensus, hashes::hex::FromHex, psbt::PartiallySignedTransaction as Psbt,
+};
 
 fn create_spend(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
     let destinations = params
@@ -41,11 +44,40 @@ fn create_spend(control: &DaemonControl, params: Params) -> Result<serde_json::V
                 .collect::<Option<Vec<bitcoin::OutPoint>>>()
         })
         .ok_or_else(|| Error::invalid_params("Invalid 'outpoints' parameter."))?;
-    let feerate: u64 = params
+    // The feerate may be given directly, or estimated for a given confirmation target.
+    let feerate: Option<u64> = params
         .get(2, "feerate")
-        .ok_or_else(|| Error::invalid_params("Missing 'feerate' parameter."))?
-        .as_u64()
-        .ok_or_else(|| Error::invalid_params("Invalid 'feerate' parameter."))?;
+        .filter(|feerate| !feerate.is_null())
+        .map(|feerate| {
+            feerate
+                .as_u64()
+                .ok_or_else(|| Error::invalid_params("Invalid 'feerate' parameter."))
+        })
+        .transpose()?;
+    let conf_target: Option<u16> = params
+        .get(3, "conf_target")
+        .filter(|conf_target| !conf_target.is_null())
+        .map(|conf_target| {
+            conf_target
+                .as_u64()
+                .and_then(|t| t.try_into().ok())
+                .ok_or_else(|| Error::invalid_params("Invalid 'conf_target' parameter."))
+        })
+        .transpose()?;
+    let feerate = match (feerate, conf_target) {
+        (Some(feerate), None) => feerate,
+        (None, Some(conf_target)) => control.estimate_feerate(conf_target)?,
+        (Some(_), Some(_)) => {
+            return Err(Error::invalid_params(
+                "Only one of 'feerate' and 'conf_target' may be given.",
+            ))
+        }
+        (None, None) => {
+            return Err(Error::invalid_params(
+                "Missing 'feerate' or 'conf_target' parameter.",
+            ))
+        }
+    };
 
     let res = control.create_spend(&destinations, &outpoints, feerate)?;
     Ok(serde_json::json!(&res))
@@ -58,7 +90,18 @@ fn update_spend(control: &DaemonControl, params: Params) -> Result<serde_json::V
         .as_str()
         .and_then(|s| Psbt::from_str(s).ok())
         .ok_or_else(|| Error::invalid_params("Invalid 'psbt' parameter."))?;
+    let note = params
+        .get(1, "note")
+        .map(|note| {
+            note.as_str()
+                .ok_or_else(|| Error::invalid_params("Invalid 'note' parameter."))
+        })
+        .transpose()?;
+    let txid = psbt.unsigned_tx.txid();
     control.update_spend(psbt)?;
+    if let Some(note) = note {
+        control.set_spend_note(&txid, note)?;
+    }
 
     Ok(serde_json::json!({}))
 }
@@ -87,6 +130,190 @@ fn broadcast_spend(control: &DaemonControl, params: Params) -> Result<serde_json
     Ok(serde_json::json!({}))
 }
 
+fn finalize_spend(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let txid = params
+        .get(0, "txid")
+        .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?
+        .as_str()
+        .and_then(|s| bitcoin::Txid::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'txid' parameter."))?;
+    let res = control.finalize_spend(&txid)?;
+
+    Ok(serde_json::json!(res))
+}
+
+fn broadcast_raw_tx(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let tx: bitcoin::Transaction = params
+        .get(0, "tx")
+        .ok_or_else(|| Error::invalid_params("Missing 'tx' parameter."))?
+        .as_str()
+        .and_then(|s| Vec::<u8>::from_hex(s).ok())
+        .and_then(|bytes| consensus::deserialize(&bytes).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'tx' parameter."))?;
+    control.broadcast_raw_tx(&tx)?;
+
+    Ok(serde_json::json!({}))
+}
+
+fn sign_spend(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let txid = params
+        .get(0, "txid")
+        .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?
+        .as_str()
+        .and_then(|s| bitcoin::Txid::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'txid' parameter."))?;
+    let res = control.sign_spend(&txid)?;
+
+    Ok(serde_json::json!(res))
+}
+
+fn analyze_psbt(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    // Either the txid of a stored Spend transaction, or a PSBT.
+    let psbt = params
+        .get(0, "psbt")
+        .ok_or_else(|| Error::invalid_params("Missing 'psbt' parameter."))?
+        .as_str()
+        .ok_or_else(|| Error::invalid_params("Invalid 'psbt' parameter."))?;
+    let res = if let Ok(txid) = bitcoin::Txid::from_str(psbt) {
+        control.analyze_spend(&txid)?
+    } else {
+        let psbt =
+            Psbt::from_str(psbt).map_err(|_| Error::invalid_params("Invalid 'psbt' parameter."))?;
+        control.analyze_psbt(&psbt)?
+    };
+
+    Ok(serde_json::json!(res))
+}
+
+fn rbf_psbt(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let txid = params
+        .get(0, "txid")
+        .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?
+        .as_str()
+        .and_then(|s| bitcoin::Txid::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'txid' parameter."))?;
+    let is_cancel = params
+        .get(1, "is_cancel")
+        .ok_or_else(|| Error::invalid_params("Missing 'is_cancel' parameter."))?
+        .as_bool()
+        .ok_or_else(|| Error::invalid_params("Invalid 'is_cancel' parameter."))?;
+    let feerate_vb: Option<u64> = params
+        .get(2, "feerate")
+        .map(|feerate| {
+            feerate
+                .as_u64()
+                .ok_or_else(|| Error::invalid_params("Invalid 'feerate' parameter."))
+        })
+        .transpose()?;
+
+    let res = control.rbf_psbt(&txid, is_cancel, feerate_vb)?;
+    Ok(serde_json::json!(&res))
+}
+
+fn create_cpfp(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let txid = params
+        .get(0, "txid")
+        .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?
+        .as_str()
+        .and_then(|s| bitcoin::Txid::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'txid' parameter."))?;
+    let feerate: u64 = params
+        .get(1, "feerate")
+        .ok_or_else(|| Error::invalid_params("Missing 'feerate' parameter."))?
+        .as_u64()
+        .ok_or_else(|| Error::invalid_params("Invalid 'feerate' parameter."))?;
+
+    let res = control.create_cpfp(&txid, feerate)?;
+    Ok(serde_json::json!(&res))
+}
+
+fn get_address(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let index: u32 = params
+        .get(0, "index")
+        .ok_or_else(|| Error::invalid_params("Missing 'index' parameter."))?
+        .as_u64()
+        .and_then(|i| i.try_into().ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'index' parameter."))?;
+    let is_change = params
+        .get(1, "change")
+        .map(|change| {
+            change
+                .as_bool()
+                .ok_or_else(|| Error::invalid_params("Invalid 'change' parameter."))
+        })
+        .transpose()?
+        .unwrap_or(false);
+
+    let res = control.get_address(index, is_change)?;
+    Ok(serde_json::json!(&res))
+}
+
+fn get_address_info(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let address = params
+        .get(0, "address")
+        .ok_or_else(|| Error::invalid_params("Missing 'address' parameter."))?
+        .as_str()
+        .and_then(|s| bitcoin::Address::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'address' parameter."))?;
+
+    let res = control.get_address_info(address)?;
+    Ok(serde_json::json!(&res))
+}
+
+fn backup(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let name = params
+        .get(0, "name")
+        .ok_or_else(|| Error::invalid_params("Missing 'name' parameter."))?
+        .as_str()
+        .ok_or_else(|| Error::invalid_params("Invalid 'name' parameter."))?;
+    let path = control.backup(name)?;
+
+    Ok(serde_json::json!({ "path": path }))
+}
+
+fn import_wallet(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let export: WalletExport = params
+        .get(0, "export")
+        .ok_or_else(|| Error::invalid_params("Missing 'export' parameter."))
+        .and_then(|export| {
+            serde_json::from_value(export.clone())
+                .map_err(|e| Error::invalid_params(format!("Invalid 'export' parameter: {}.", e)))
+        })?;
+    control.import_wallet(export)?;
+
+    Ok(serde_json::json!({}))
+}
+
+fn list_addresses(
+    control: &DaemonControl,
+    params: Option<Params>,
+) -> Result<serde_json::Value, Error> {
+    let start_index: u32 = params
+        .as_ref()
+        .and_then(|p| p.get(0, "start_index"))
+        .map(|start_index| {
+            start_index
+                .as_u64()
+                .and_then(|i| i.try_into().ok())
+                .ok_or_else(|| Error::invalid_params("Invalid 'start_index' parameter."))
+        })
+        .transpose()?
+        .unwrap_or(0);
+    let count: Option<u32> = params
+        .as_ref()
+        .and_then(|p| p.get(1, "count"))
+        .map(|count| {
+            count
+                .as_u64()
+                .and_then(|c| c.try_into().ok())
+                .ok_or_else(|| Error::invalid_params("Invalid 'count' parameter."))
+        })
+        .transpose()?;
+
+    let res = control.list_addresses(start_index, count)?;
+    Ok(serde_json::json!(&res))
+}
+
 fn list_coins(control: &DaemonControl, params: Option<Params>) -> Result<serde_json::Value, Error> {
     let statuses_arg = params
         .as_ref()
@@ -164,17 +391,36 @@ fn list_confirmed(control: &DaemonControl, params: Params) -> Result<serde_json:
 }
 
 fn list_transactions(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
-    let txids: Vec<bitcoin::Txid> = params
-        .get(0, "txids")
-        .ok_or_else(|| Error::invalid_params("Missing 'txids' parameter."))?
-        .as_array()
-        .and_then(|arr| {
-            arr.iter()
-                .map(|entry| entry.as_str().and_then(|e| bitcoin::Txid::from_str(e).ok()))
-                .collect()
+    // Either the transactions with the given txids, or a page of all our transactions.
+    if let Some(txids) = params.get(0, "txids").filter(|txids| !txids.is_null()) {
+        let txids: Vec<bitcoin::Txid> = txids
+            .as_array()
+            .and_then(|arr| {
+                arr.iter()
+                    .map(|entry| entry.as_str().and_then(|e| bitcoin::Txid::from_str(e).ok()))
+                    .collect()
+            })
+            .ok_or_else(|| Error::invalid_params("Invalid 'txids' parameter."))?;
+        return Ok(serde_json::json!(&control.list_transactions(&txids)));
+    }
+
+    let limit: u64 = params
+        .get(1, "limit")
+        .ok_or_else(|| Error::invalid_params("Missing 'txids' or 'limit' parameter."))?
+        .as_u64()
+        .ok_or_else(|| Error::invalid_params("Invalid 'limit' parameter."))?;
+    let offset: u64 = params
+        .get(2, "offset")
+        .map(|offset| {
+            offset
+                .as_u64()
+                .ok_or_else(|| Error::invalid_params("Invalid 'offset' parameter."))
         })
-        .ok_or_else(|| Error::invalid_params("Invalid 'txids' parameter."))?;
-    Ok(serde_json::json!(&control.list_transactions(&txids)))
+        .transpose()?
+        .unwrap_or(0);
+    Ok(serde_json::json!(
+        &control.list_recent_transactions(limit, offset)
+    ))
 }
 
 fn start_rescan(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
@@ -189,6 +435,43 @@ fn start_rescan(control: &DaemonControl, params: Params) -> Result<serde_json::V
     Ok(serde_json::json!({}))
 }
 
+// Parse the name and descriptor of a new wallet.
+fn new_wallet_params(params: &Params) -> Result<(&str, LianaDescriptor), Error> {
+    let name = params
+        .get(0, "name")
+        .ok_or_else(|| Error::invalid_params("Missing 'name' parameter."))?
+        .as_str()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| Error::invalid_params("Invalid 'name' parameter."))?;
+    let descriptor = params
+        .get(1, "descriptor")
+        .ok_or_else(|| Error::invalid_params("Missing 'descriptor' parameter."))?
+        .as_str()
+        .and_then(|s| LianaDescriptor::from_str(s).ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'descriptor' parameter."))?;
+    Ok((name, descriptor))
+}
+
+fn create_wallet(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let (name, descriptor) = new_wallet_params(&params)?;
+    control.create_wallet(name, &descriptor)?;
+
+    Ok(serde_json::json!({}))
+}
+
+fn load_wallet(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let (name, descriptor) = new_wallet_params(&params)?;
+    let timestamp: u32 = params
+        .get(2, "timestamp")
+        .ok_or_else(|| Error::invalid_params("Missing 'timestamp' parameter."))?
+        .as_u64()
+        .and_then(|t| t.try_into().ok())
+        .ok_or_else(|| Error::invalid_params("Invalid 'timestamp' parameter."))?;
+    control.load_wallet(name, &descriptor, timestamp)?;
+
+    Ok(serde_json::json!({}))
+}
+
 fn create_recovery(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
     let address = params
         .get(0, "address")
@@ -246,6 +529,18 @@ fn update_labels(control: &DaemonControl, params: Params) -> Result<serde_json::
     Ok(serde_json::json!({}))
 }
 
+fn import_labels(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
+    let labels: Vec<Bip329Label> = params
+        .get(0, "labels")
+        .ok_or_else(|| Error::invalid_params("Missing 'labels' parameter."))
+        .and_then(|labels| {
+            serde_json::from_value(labels.clone())
+                .map_err(|e| Error::invalid_params(format!("Invalid 'labels' parameter: {}.", e)))
+        })?;
+
+    Ok(serde_json::json!(control.import_labels(labels)))
+}
+
 fn get_labels(control: &DaemonControl, params: Params) -> Result<serde_json::Value, Error> {
     let mut items = HashSet::new();
     for item in params
@@ -275,15 +570,70 @@ fn get_labels(control: &DaemonControl, params: Params) -> Result<serde_json::Val
     Ok(serde_json::json!(control.get_labels(&items)))
 }
 
-/// Handle an incoming JSONRPC2 request.
-pub fn handle_request(control: &DaemonControl, req: Request) -> Result<Response, Error> {
+/// Handle an incoming JSONRPC2 request, if the permissions of the connection it was received from
+/// allow it. Returns the result to respond with.
+pub fn handle_request(
+    control: &DaemonControl,
+    mut req: Request,
+    permissions: &Permissions,
+) -> Result<serde_json::Value, Error> {
+    if !permissions.allows(&req.method) {
+        log::debug!(
+            "Denied call to '{}' on a restricted connection.",
+            req.method
+        );
+        return Err(Error::permission_denied(&req.method));
+    }
+
+    // Any command may be run against another wallet than the main one by passing its name as the
+    // 'wallet' named parameter.
+    let wallet = match req.params {
+        Some(Params::Map(ref mut map)) => map.remove("wallet"),
+        _ => None,
+    };
+    let wallet_control;
+    let control = match wallet {
+        Some(wallet) => {
+            let name = wallet
+                .as_str()
+                .ok_or_else(|| Error::invalid_params("Invalid 'wallet' parameter."))?;
+            wallet_control = control.with_wallet(name)?;
+            &wallet_control
+        }
+        None => control,
+    };
+
     let result = match req.method.as_str() {
+        "analyzepsbt" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'psbt' parameter."))?;
+            analyze_psbt(control, params)?
+        }
+        "backup" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'path' parameter."))?;
+            backup(control, params)?
+        }
+        "broadcastrawtx" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'tx' parameter."))?;
+            broadcast_raw_tx(control, params)?
+        }
         "broadcastspend" => {
             let params = req
                 .params
                 .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?;
             broadcast_spend(control, params)?
         }
+        "createcpfp" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'txid' and 'feerate' parameters."))?;
+            create_cpfp(control, params)?
+        }
         "createrecovery" => {
             let params = req.params.ok_or_else(|| {
                 Error::invalid_params("Missing 'address' and 'feerate' parameters.")
@@ -293,19 +643,59 @@ pub fn handle_request(control: &DaemonControl, req: Request) -> Result<Response,
         "createspend" => {
             let params = req.params.ok_or_else(|| {
                 Error::invalid_params(
-                    "Missing 'outpoints', 'destinations' and 'feerate' parameters.",
+                    "Missing 'destinations', 'outpoints' and 'feerate' or 'conf_target' parameters.",
                 )
             })?;
             create_spend(control, params)?
         }
+        "createwallet" => {
+            let params = req.params.ok_or_else(|| {
+                Error::invalid_params("Missing 'name' and 'descriptor' parameters.")
+            })?;
+            create_wallet(control, params)?
+        }
         "delspendtx" => {
             let params = req
                 .params
                 .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?;
             delete_spend(control, params)?
         }
+        "exportlabels" => serde_json::json!(&control.export_labels()),
+        "exportwallet" => serde_json::json!(&control.export_wallet()),
+        "getaddress" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'index' parameter."))?;
+            get_address(control, params)?
+        }
+        "getaddressinfo" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'address' parameter."))?;
+            get_address_info(control, params)?
+        }
+        "getfeeestimates" => serde_json::json!(&control.get_fee_estimates()),
+        "finalizespend" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?;
+            finalize_spend(control, params)?
+        }
         "getinfo" => serde_json::json!(&control.get_info()),
         "getnewaddress" => serde_json::json!(&control.get_new_address()),
+        "importlabels" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'labels' parameter."))?;
+            import_labels(control, params)?
+        }
+        "importwallet" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'export' parameter."))?;
+            import_wallet(control, params)?
+        }
+        "listaddresses" => list_addresses(control, req.params)?,
         "listcoins" => {
             let params = req.params;
             list_coins(control, params)?
@@ -319,14 +709,33 @@ pub fn handle_request(control: &DaemonControl, req: Request) -> Result<Response,
             list_confirmed(control, params)?
         }
         "listspendtxs" => serde_json::json!(&control.list_spend()),
+        "listwallets" => serde_json::json!(&control.list_wallets()),
         "listtransactions" => {
             let params = req.params.ok_or_else(|| {
                 Error::invalid_params(
-                    "The 'listtransactions' command requires 1 parameter: 'txids'",
+                    "The 'listtransactions' command requires either a 'txids' or a 'limit' parameter",
                 )
             })?;
             list_transactions(control, params)?
         }
+        "loadwallet" => {
+            let params = req.params.ok_or_else(|| {
+                Error::invalid_params("Missing 'name', 'descriptor' and 'timestamp' parameters.")
+            })?;
+            load_wallet(control, params)?
+        }
+        "rbfpsbt" => {
+            let params = req.params.ok_or_else(|| {
+                Error::invalid_params("Missing 'txid' and 'is_cancel' parameters.")
+            })?;
+            rbf_psbt(control, params)?
+        }
+        "signspend" => {
+            let params = req
+                .params
+                .ok_or_else(|| Error::invalid_params("Missing 'txid' parameter."))?;
+            sign_spend(control, params)?
+        }
         "startrescan" => {
             let params = req
                 .params
@@ -357,5 +766,5 @@ pub fn handle_request(control: &DaemonControl, req: Request) -> Result<Response,
         }
     };
 
-    Ok(Response::success(req.id, result))
+    Ok(result)
 }
@@ -1,9 +1,10 @@
 mod api;
+pub mod http;
 pub mod server;
 
-use crate::commands;
+use crate::{commands, events::Event};
 
-use std::{error, fmt};
+use std::{collections::HashSet, error, fmt};
 
 use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
 
@@ -35,6 +36,16 @@ impl Params {
 pub enum ReqId {
     Num(u64),
     Str(String),
+    /// Also used when responding to a request whose identifier could not be read.
+    Null,
+}
+
+// An explicit `null` identifier must not be confused with a missing one.
+fn deserialize_req_id<'de, D>(deserializer: D) -> Result<Option<ReqId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    ReqId::deserialize(deserializer).map(Some)
 }
 
 /// A JSONRPC2 request. See https://www.jsonrpc.org/specification#request_object.
@@ -47,16 +58,74 @@ pub struct Request {
     pub method: String,
     /// Command parameters.
     pub params: Option<Params>,
-    /// Request identifier.
-    pub id: ReqId,
+    /// Request identifier. A request without identifier is a notification: it is processed but
+    /// not responded to.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_req_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<ReqId>,
+}
+
+impl Request {
+    fn from_value(value: serde_json::Value) -> Result<Request, Error> {
+        let req: Request =
+            serde_json::from_value(value).map_err(|e| Error::invalid_request(e.to_string()))?;
+        if req.jsonrpc != "2.0" {
+            return Err(Error::invalid_request(
+                "The 'jsonrpc' version must be \"2.0\".",
+            ));
+        }
+        Ok(req)
+    }
+}
+
+/// A call from a client: either a single request or a batch of requests. See
+/// https://www.jsonrpc.org/specification#batch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    Single(Request),
+    /// The invalid requests of a batch are replaced by the error to respond with.
+    Batch(Vec<Result<Request, Error>>),
+}
+
+impl Call {
+    /// Parse a call from its JSON serialization. Returns the error to respond with if it is not
+    /// valid JSON or not a request.
+    pub fn from_slice(data: &[u8]) -> Result<Call, Error> {
+        let value: serde_json::Value =
+            serde_json::from_slice(data).map_err(|e| Error::parse_error(e.to_string()))?;
+        match value {
+            serde_json::Value::Array(values) => {
+                if values.is_empty() {
+                    return Err(Error::invalid_request("Empty batch."));
+                }
+                Ok(Call::Batch(
+                    values.into_iter().map(Request::from_value).collect(),
+                ))
+            }
+            value => Request::from_value(value).map(Call::Single),
+        }
+    }
 }
 
 /// A failure to broadcast a transaction to the P2P network.
 const BROADCAST_ERROR: i64 = 1_000;
 
+/// The method is not allowed for the credentials used.
+const PERMISSION_DENIED_ERROR: i64 = 1_001;
+
+/// The transaction exceeds the limits configured in the guardrails.
+const GUARDRAIL_ERROR: i64 = 1_002;
+
 /// JSONRPC2 error codes. See https://www.jsonrpc.org/specification#error_object.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ErrorCode {
+    /// Invalid JSON was received.
+    ParseError,
+    /// The JSON sent is not a valid request.
+    InvalidRequest,
     /// The method does not exist / is not available.
     MethodNotFound,
     /// Invalid method parameter(s).
@@ -70,6 +139,8 @@ pub enum ErrorCode {
 impl From<&ErrorCode> for i64 {
     fn from(code: &ErrorCode) -> i64 {
         match code {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
             ErrorCode::MethodNotFound => -32601,
             ErrorCode::InvalidParams => -32602,
             ErrorCode::InternalError => -32603,
@@ -81,6 +152,8 @@ impl From<&ErrorCode> for i64 {
 impl From<i64> for ErrorCode {
     fn from(code: i64) -> ErrorCode {
         match code {
+            -32700 => ErrorCode::ParseError,
+            -32600 => ErrorCode::InvalidRequest,
             -32601 => ErrorCode::MethodNotFound,
             -32602 => ErrorCode::InvalidParams,
             -32603 => ErrorCode::InternalError,
@@ -127,6 +200,20 @@ impl Error {
         }
     }
 
+    pub fn parse_error(message: impl Into<String>) -> Error {
+        Error::new(
+            ErrorCode::ParseError,
+            format!("Parse error: {}", message.into()),
+        )
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Error {
+        Error::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid request: {}", message.into()),
+        )
+    }
+
     pub fn method_not_found() -> Error {
         Error::new(ErrorCode::MethodNotFound, "Method not found")
     }
@@ -137,6 +224,31 @@ impl Error {
             format!("Invalid params: {}", message.into()),
         )
     }
+
+    pub fn permission_denied(method: &str) -> Error {
+        Error::new(
+            ErrorCode::ServerError(PERMISSION_DENIED_ERROR),
+            format!("Permission denied for method '{}'", method),
+        )
+    }
+}
+
+/// The JSONRPC methods which may be called through a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Permissions {
+    /// Any method may be called.
+    All,
+    /// Only these methods may be called.
+    Methods(HashSet<String>),
+}
+
+impl Permissions {
+    pub fn allows(&self, method: &str) -> bool {
+        match self {
+            Permissions::All => true,
+            Permissions::Methods(methods) => methods.contains(method),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -164,17 +276,41 @@ impl From<commands::CommandError> for Error {
             | commands::CommandError::SpendFinalization(..)
             | commands::CommandError::InsaneRescanTimestamp(..)
             | commands::CommandError::AlreadyRescanning
-            | commands::CommandError::RecoveryNotAvailable => {
+            | commands::CommandError::RecoveryNotAvailable
+            | commands::CommandError::RbfError(..)
+            | commands::CommandError::CpfpError(..)
+            | commands::CommandError::InvalidConfTarget(..)
+            | commands::CommandError::UnknownWallet(..)
+            | commands::CommandError::InvalidDerivationIndex(..)
+            | commands::CommandError::UnwatchedDerivationIndex(..)
+            | commands::CommandError::WalletNameTaken(..)
+            | commands::CommandError::DescriptorAlreadyTracked(..)
+            | commands::CommandError::DescriptorNetworkMismatch(..)
+            | commands::CommandError::InvalidBackupName(..)
+            | commands::CommandError::WalletExportMismatch(..)
+            | commands::CommandError::InvalidLabelItem(..)
+            | commands::CommandError::NoHotSigner
+            | commands::CommandError::InvalidPsbt(..) => {
                 Error::new(ErrorCode::InvalidParams, e.to_string())
             }
             commands::CommandError::FetchingTransaction(..)
+            | commands::CommandError::Backup(..)
+            | commands::CommandError::Signing(..)
             | commands::CommandError::SanityCheckFailure(_)
-            | commands::CommandError::RescanTrigger(..) => {
+            | commands::CommandError::RescanTrigger(..)
+            | commands::CommandError::FeerateEstimation(..) => {
                 Error::new(ErrorCode::InternalError, e.to_string())
             }
             commands::CommandError::TxBroadcast(_) => {
                 Error::new(ErrorCode::ServerError(BROADCAST_ERROR), e.to_string())
             }
+            commands::CommandError::TxAmountAboveLimit(..)
+            | commands::CommandError::DailyOutflowAboveLimit(..)
+            | commands::CommandError::FeeAboveLimit(..)
+            | commands::CommandError::FeerateAboveLimit(..)
+            | commands::CommandError::DestinationNotAllowed(..) => {
+                Error::new(ErrorCode::ServerError(GUARDRAIL_ERROR), e.to_string())
+            }
         }
     }
 }
@@ -213,3 +349,31 @@ impl Response {
         Response::new(id, None, Some(error))
     }
 }
+
+/// The response to a call: a single response or, for a batch, an array of responses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum CallResponse {
+    Single(Response),
+    Batch(Vec<Response>),
+}
+
+/// JSONRPC2 notification, sent to the connections subscribed to our events. See
+/// https://www.jsonrpc.org/specification#notification.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// Version. Must be "2.0".
+    jsonrpc: String,
+    /// The event as the notification method and parameters.
+    #[serde(flatten)]
+    event: Event,
+}
+
+impl Notification {
+    pub fn new(event: Event) -> Notification {
+        Notification {
+            jsonrpc: "2.0".to_string(),
+            event,
+        }
+    }
+}
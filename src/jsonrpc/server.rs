@@ -1,25 +1,36 @@
 //! JSONRPC2 server
 //!
 //! This module implements the connections and streams handling logic for receiving
-//! JSONRPC2 requests on a Unix Domain Socket.
+//! JSONRPC2 requests on a Unix Domain Socket, and optionally over HTTP.
 
 use crate::{
-    jsonrpc::{api, Request, Response},
-    DaemonControl,
+    config::HttpRpcConfig,
+    jsonrpc::{
+        api,
+        http::{self, HttpAuth},
+        Call, CallResponse, Error, Notification, Permissions, ReqId, Request, Response,
+    },
+    random, DaemonControl,
 };
 
 use std::{
-    io,
+    fs,
+    io::{self, Read, Write},
+    net as tcp_net,
     os::unix::net,
     path,
-    sync::{self, atomic},
+    sync::{self, atomic, mpsc},
     thread, time,
 };
 
 // Maximum number of concurrent RPC connections we may accept.
 const MAX_CONNECTIONS: u32 = 16;
 
-// Read a command from the stream.
+// How long we wait for an event before checking whether a subscribed connection was closed, in
+// milliseconds.
+const SUBSCRIPTION_POLL_INTERVAL: u64 = 500;
+
+// Read a call from the stream. Returns the error to respond with if it is not a valid call.
 //
 // In order to both treat commands separately (respond as soon as we read one), and support
 // multiple commands in a single read or in multiple parts, we are given the context as writable
@@ -36,7 +47,7 @@ fn read_command(
     buf: &mut Vec<u8>,
     end: &mut usize,
     cursor: &mut usize,
-) -> Result<Option<Request>, io::Error> {
+) -> Result<Option<Result<Call, Error>>, io::Error> {
     assert!(!buf.is_empty());
 
     loop {
@@ -49,18 +60,15 @@ fn read_command(
         );
         if let Some(pos) = pos {
             log::trace!(
-                "Parsing Request from: {:?}",
+                "Parsing Call from: {:?}",
                 String::from_utf8_lossy(&buf[..*cursor + pos])
             );
-            // TODO: don't return an io::Error here, instead try to parse a Request. Failing that,
-            // try to parse a serde_json::Value. Then return accordingly a JSONRPC "malformed
-            // request" or "invalid JSON" error.
-            let req: Request = serde_json::from_slice(&buf[..*cursor + pos])?;
+            let call = Call::from_slice(&buf[..*cursor + pos]);
             *buf = buf[pos + 1..].to_vec(); // FIXME: can we avoid reallocating here?
             *cursor = 0;
             *end -= pos + 1;
 
-            return Ok(Some(req));
+            return Ok(Some(call));
         }
 
         // If nothing can be gathered from the buffer, continue reading.
@@ -79,10 +87,130 @@ fn read_command(
     }
 }
 
+// Push the events emitted by the daemon through this connection as JSONRPC notifications, until
+// it is closed or we are shutting down.
+fn subscription_handler(
+    control: &DaemonControl,
+    mut stream: net::UnixStream,
+    req: Request,
+    shutdown: sync::Arc<atomic::AtomicBool>,
+) -> Result<(), io::Error> {
+    // Register before acknowledging the subscription to not miss any event.
+    let events = control.subscribe();
+    if let Some(req_id) = req.id {
+        let response = Response::success(req_id, serde_json::json!({}));
+        if let Err(e) = serde_json::to_writer(&stream, &response) {
+            log::error!("Error writing response: '{}'", e);
+            return Ok(());
+        }
+    }
+    log::debug!("JSONRPC connection subscribed to events.");
+
+    // We don't expect any more request from the client. We only read from the connection to
+    // detect when it gets closed.
+    stream.set_read_timeout(Some(time::Duration::from_millis(1)))?;
+    let mut buf = [0; 256];
+    while !shutdown.load(atomic::Ordering::Relaxed) {
+        match events.recv_timeout(time::Duration::from_millis(SUBSCRIPTION_POLL_INTERVAL)) {
+            Ok(event) => {
+                let notif = Notification::new(event);
+                log::trace!("JSONRPC notification: {:?}", serde_json::to_string(&notif));
+                if let Err(e) = serde_json::to_writer(&stream, &notif) {
+                    log::debug!("Error writing notification: '{}'", e);
+                    return Ok(());
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        match stream.read(&mut buf) {
+            Ok(0) => {
+                // Connection closed.
+                return Ok(());
+            }
+            Ok(_) => log::debug!("Ignoring data received on a subscribed connection."),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the response to this request, whichever listener we received it from. Notifications are
+/// processed but not responded to.
+pub fn process_request(
+    control: &DaemonControl,
+    req: Request,
+    permissions: &Permissions,
+    shutdown: &atomic::AtomicBool,
+) -> Option<Response> {
+    let req_id = req.id.clone();
+    if &req.method == "stop" && permissions.allows("stop") {
+        shutdown.store(true, atomic::Ordering::Relaxed);
+        log::info!("Stopping the liana daemon.");
+    }
+
+    log::trace!("JSONRPC request: {:?}", serde_json::to_string(&req));
+    let result = api::handle_request(control, req, permissions);
+    let response = match (req_id, result) {
+        (Some(req_id), Ok(result)) => Response::success(req_id, result),
+        (Some(req_id), Err(e)) => Response::error(req_id, e),
+        (None, result) => {
+            log::trace!("JSONRPC notification result: {:?}", result);
+            return None;
+        }
+    };
+    log::trace!("JSONRPC response: {:?}", serde_json::to_string(&response));
+    Some(response)
+}
+
+/// Get the response to this call, whichever listener we received it from. There is none if it
+/// only contains notifications.
+pub fn process_call(
+    control: &DaemonControl,
+    call: Result<Call, Error>,
+    permissions: &Permissions,
+    shutdown: &atomic::AtomicBool,
+) -> Option<CallResponse> {
+    match call {
+        Ok(Call::Single(req)) => {
+            process_request(control, req, permissions, shutdown).map(CallResponse::Single)
+        }
+        Ok(Call::Batch(reqs)) => {
+            let responses: Vec<Response> = reqs
+                .into_iter()
+                .filter_map(|req| match req {
+                    // A subscription takes over the whole connection.
+                    Ok(req) if req.method == "subscribe" => {
+                        let error = Error::invalid_request("Can't subscribe within a batch.");
+                        req.id.map(|req_id| Response::error(req_id, error))
+                    }
+                    Ok(req) => process_request(control, req, permissions, shutdown),
+                    Err(e) => Some(Response::error(ReqId::Null, e)),
+                })
+                .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(CallResponse::Batch(responses))
+            }
+        }
+        Err(e) => {
+            log::debug!("Invalid JSONRPC call: '{}'", e);
+            Some(CallResponse::Single(Response::error(ReqId::Null, e)))
+        }
+    }
+}
+
 // Handle all messages from this connection.
 fn connection_handler(
     control: DaemonControl,
     mut stream: net::UnixStream,
+    permissions: Permissions,
     shutdown: sync::Arc<atomic::AtomicBool>,
 ) -> Result<(), io::Error> {
     let mut buf = vec![0; 2048];
@@ -90,52 +218,86 @@ fn connection_handler(
     let mut cursor = 0;
 
     while !shutdown.load(atomic::Ordering::Relaxed) {
-        let req = match read_command(&mut stream, &mut buf, &mut end, &mut cursor)? {
-            Some(req) => req,
+        let call = match read_command(&mut stream, &mut buf, &mut end, &mut cursor)? {
+            Some(call) => call,
             None => {
                 // Connection closed.
                 return Ok(());
             }
         };
 
-        let req_id = req.id.clone();
-        if &req.method == "stop" {
-            shutdown.store(true, atomic::Ordering::Relaxed);
-            log::info!("Stopping the liana daemon.");
-        }
-
-        log::trace!("JSONRPC request: {:?}", serde_json::to_string(&req));
-        let response =
-            api::handle_request(&control, req).unwrap_or_else(|e| Response::error(req_id, e));
-        log::trace!("JSONRPC response: {:?}", serde_json::to_string(&response));
-        if let Err(e) = serde_json::to_writer(&stream, &response) {
-            log::error!("Error writing response: '{}'", e);
-            return Ok(());
+        // A subscription turns this connection into a channel for pushing events to the client.
+        let response = match call {
+            Ok(Call::Single(req)) if &req.method == "subscribe" => {
+                if permissions.allows(&req.method) {
+                    return subscription_handler(&control, stream, req, shutdown);
+                }
+                let error = Error::permission_denied(&req.method);
+                req.id
+                    .map(|req_id| CallResponse::Single(Response::error(req_id, error)))
+            }
+            call => process_call(&control, call, &permissions, &shutdown),
+        };
+        if let Some(response) = response {
+            if let Err(e) = serde_json::to_writer(&stream, &response) {
+                log::error!("Error writing response: '{}'", e);
+                return Ok(());
+            }
         }
     }
 
     Ok(())
 }
 
+// A new connection on one of our listeners.
+enum Connection {
+    Unix(net::UnixStream, Permissions),
+    Http(tcp_net::TcpStream),
+}
+
 // FIXME: have a decent way to share the DaemonControl between connections. Maybe make it Clone?
 /// The main event loop. Wait for connections, and treat requests sent through them.
+///
+/// Each Unix socket listener comes with the permissions granted to the connections it accepts.
+/// Requests may also be received over HTTP on the given TCP listener, from clients presenting the
+/// given credentials.
 pub fn rpcserver_loop(
-    listener: net::UnixListener,
+    listeners: Vec<(net::UnixListener, Permissions)>,
+    http_listener: Option<(tcp_net::TcpListener, HttpAuth)>,
     daemon_control: DaemonControl,
 ) -> Result<(), io::Error> {
     // Keep it simple. We don't need great performances so just treat each connection in
-    // its thread, with a given maximum number of connections.
+    // its thread, with a given maximum number of connections. The limit is shared between the
+    // Unix socket and the HTTP listener.
     let connections_counter = sync::Arc::from(atomic::AtomicU32::new(0));
     let shutdown = sync::Arc::from(atomic::AtomicBool::new(false));
 
-    listener.set_nonblocking(true)?;
+    for (listener, _) in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    if let Some((http_listener, _)) = &http_listener {
+        http_listener.set_nonblocking(true)?;
+    }
     while !shutdown.load(atomic::Ordering::Relaxed) {
-        let (connection, _) = match listener.accept() {
-            Ok(c) => c,
-            Err(_) => {
-                thread::sleep(time::Duration::from_millis(100));
-                continue;
-            }
+        let unix_connection = listeners.iter().find_map(|(listener, permissions)| {
+            listener
+                .accept()
+                .ok()
+                .map(|(c, _)| (c, permissions.clone()))
+        });
+        let connection = match unix_connection {
+            Some((c, permissions)) => Connection::Unix(c, permissions),
+            None => match http_listener.as_ref().map(|(l, _)| l.accept()) {
+                Some(Ok((c, _))) => {
+                    // The connection must not inherit the non-blocking mode of the listener.
+                    c.set_nonblocking(false)?;
+                    Connection::Http(c)
+                }
+                _ => {
+                    thread::sleep(time::Duration::from_millis(100));
+                    continue;
+                }
+            },
         };
         log::trace!("New JSONRPC connection");
 
@@ -151,9 +313,23 @@ pub fn rpcserver_loop(
                 let control = daemon_control.clone();
                 let counter = connections_counter.clone();
                 let shutdown = shutdown.clone();
+                let http_auth = http_listener.as_ref().map(|(_, auth)| auth.clone());
 
                 move || {
-                    if let Err(e) = connection_handler(control, connection, shutdown) {
+                    let res = match connection {
+                        Connection::Unix(c, permissions) => {
+                            connection_handler(control, c, permissions, shutdown)
+                        }
+                        Connection::Http(c) => http::connection_handler(
+                            control,
+                            c,
+                            http_auth
+                                .as_ref()
+                                .expect("Only accepted if there is a listener"),
+                            shutdown,
+                        ),
+                    };
+                    if let Err(e) = res {
                         log::error!("Error while handling connection {}: '{}'", handler_id, e);
                     } else {
                         log::trace!("Connection {} terminated without error.", handler_id);
@@ -207,16 +383,72 @@ pub fn rpcserver_setup(socket_path: &path::Path) -> Result<net::UnixListener, io
     listener
 }
 
+// The user name for authenticating with the credentials from the cookie file.
+const COOKIE_USER: &str = "__cookie__";
+
+/// Bind to the TCP port for serving the JSONRPC API over HTTP. If no credentials are configured,
+/// random ones are generated and written to the cookie file at `cookie_path`.
+pub fn http_rpcserver_setup(
+    config: &HttpRpcConfig,
+    cookie_path: &path::Path,
+) -> Result<(tcp_net::TcpListener, HttpAuth), io::Error> {
+    let auth = match (&config.user, &config.password) {
+        (Some(user), Some(password)) => HttpAuth::new(user.clone(), password.clone()),
+        _ => {
+            let password: String = random::random_bytes()
+                .map_err(|e| {
+                    log::error!("Error generating the JSONRPC cookie: '{}'", e);
+                    io::Error::from(io::ErrorKind::Other)
+                })?
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            let auth = HttpAuth::new(COOKIE_USER.to_string(), password);
+
+            // Only the current user may read the cookie.
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options
+                .open(cookie_path)?
+                .write_all(auth.cookie().as_bytes())?;
+            log::info!("JSONRPC cookie written at '{}'.", cookie_path.display());
+            auth
+        }
+    };
+
+    log::debug!("Binding JSONRPC HTTP server at {}", config.addr);
+    let listener = tcp_net::TcpListener::bind(config.addr)?;
+    log::info!(
+        "JSONRPC HTTP server listening on {}.",
+        listener.local_addr()?
+    );
+    Ok((listener, auth))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        jsonrpc::{Params, ReqId},
+        events::Event,
+        jsonrpc::{ErrorCode, Params, ReqId},
         testutils::*,
     };
 
     use std::{env, fs, io::Write, process};
 
+    // Most tests only send single, valid, requests.
+    fn single_request(call: Result<Call, Error>) -> Request {
+        match call.unwrap() {
+            Call::Single(req) => req,
+            call => panic!("Expected a single request, got {:?}", call),
+        }
+    }
+
     fn read_one_command(socket_path: &path::Path) -> thread::JoinHandle<Option<Request>> {
         let listener = rpcserver_setup(socket_path).unwrap();
         thread::spawn(move || {
@@ -224,30 +456,44 @@ mod tests {
             let mut buf = vec![0; 32];
             let mut end = 0;
             let mut cursor = 0;
-            read_command(&mut conn, &mut buf, &mut end, &mut cursor).unwrap()
+            read_command(&mut conn, &mut buf, &mut end, &mut cursor)
+                .unwrap()
+                .map(single_request)
         })
     }
 
-    fn read_all_commands(socket_path: &path::Path) -> thread::JoinHandle<Vec<Request>> {
+    fn read_all_calls(socket_path: &path::Path) -> thread::JoinHandle<Vec<Result<Call, Error>>> {
         let listener = rpcserver_setup(socket_path).unwrap();
         thread::spawn(move || {
             let (mut conn, _) = listener.accept().unwrap();
             let mut buf = vec![0; 32];
             let mut end = 0;
             let mut cursor = 0;
-            let mut reqs = Vec::new();
+            let mut calls = Vec::new();
 
             loop {
                 match read_command(&mut conn, &mut buf, &mut end, &mut cursor).unwrap() {
-                    Some(req) => {
-                        reqs.push(req);
+                    Some(call) => {
+                        calls.push(call);
                     }
-                    None => return reqs,
+                    None => return calls,
                 }
             }
         })
     }
 
+    fn read_all_commands(socket_path: &path::Path) -> thread::JoinHandle<Vec<Request>> {
+        let calls = read_all_calls(socket_path);
+        thread::spawn(move || {
+            calls
+                .join()
+                .unwrap()
+                .into_iter()
+                .map(single_request)
+                .collect()
+        })
+    }
+
     fn write_messages(socket_path: &path::Path, messages: &[&[u8]]) {
         let mut client = net::UnixStream::connect(socket_path).unwrap();
         for mess in messages {
@@ -387,7 +633,7 @@ mod tests {
             jsonrpc: "2.0".to_string(),
             method: "dummy".to_string(),
             params: Some(Params::Map(params)),
-            id: ReqId::Num(0),
+            id: Some(ReqId::Num(0)),
         };
         write_messages(&socket_path, &[&serde_json::to_vec(&req).unwrap(), b"\n"]);
         let read_req = t.join().unwrap().unwrap();
@@ -396,6 +642,119 @@ mod tests {
         fs::remove_file(&socket_path).unwrap();
     }
 
+    #[test]
+    fn command_read_invalid() {
+        let socket_path = env::temp_dir().join(format!(
+            "lianad-jsonrpc-socket-{}-{:?}",
+            process::id(),
+            thread::current().id()
+        ));
+
+        // Invalid calls don't prevent reading the next ones.
+        let t = read_all_calls(&socket_path);
+        let msgs = [
+            &b"{]"[..],
+            b"\n",
+            br#"{"jsonrpc": "2.0", "id": 0, "method": 1}"#,
+            b"\n",
+            br#"{"jsonrpc": "1.0", "id": 0, "method": "test"}"#,
+            b"\n",
+            b"[]\n",
+            br#"{"jsonrpc": "2.0", "id": 1, "method": "test", "params": ["a", 10]}"#,
+            b"\n",
+        ];
+        write_messages(&socket_path, &msgs);
+        let calls = t.join().unwrap();
+        assert_eq!(calls.len(), 5);
+        assert_eq!(calls[0].as_ref().unwrap_err().code, ErrorCode::ParseError);
+        for call in &calls[1..4] {
+            assert_eq!(call.as_ref().unwrap_err().code, ErrorCode::InvalidRequest);
+        }
+        assert_eq!(
+            single_request(calls[4].clone()),
+            serde_json::from_slice(msgs[7]).unwrap()
+        );
+
+        // A batch of requests, some of which may be invalid or notifications.
+        let t = read_all_calls(&socket_path);
+        let msgs = [
+            &br#"[{"jsonrpc": "2.0", "id": 0, "method": "test"}, 1, "#[..],
+            br#"{"jsonrpc": "2.0", "method": "notif"}, {"jsonrpc": "2.0", "id": null, "method": "null"}]"#,
+            b"\n",
+        ];
+        write_messages(&socket_path, &msgs);
+        let reqs = match t.join().unwrap().pop().unwrap().unwrap() {
+            Call::Batch(reqs) => reqs,
+            call => panic!("Expected a batch, got {:?}", call),
+        };
+        assert_eq!(reqs.len(), 4);
+        assert_eq!(reqs[0].as_ref().unwrap().id, Some(ReqId::Num(0)));
+        assert_eq!(
+            reqs[1].as_ref().unwrap_err().code,
+            ErrorCode::InvalidRequest
+        );
+        let notif = reqs[2].as_ref().unwrap();
+        assert_eq!((notif.method.as_str(), &notif.id), ("notif", &None));
+        assert_eq!(reqs[3].as_ref().unwrap().id, Some(ReqId::Null));
+
+        fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn process_batch_call() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+        let shutdown = atomic::AtomicBool::new(false);
+        let process = |call: &[u8]| {
+            process_call(
+                control,
+                Call::from_slice(call),
+                &Permissions::All,
+                &shutdown,
+            )
+            .map(|resp| serde_json::to_value(&resp).unwrap())
+        };
+
+        // Invalid calls are responded to with a null id.
+        let resp = process(b"{]").unwrap();
+        assert_eq!(resp["id"], serde_json::Value::Null);
+        assert_eq!(resp["error"]["code"], -32700);
+        let resp = process(br#"{"jsonrpc": "2.0", "id": 0}"#).unwrap();
+        assert_eq!(resp["id"], serde_json::Value::Null);
+        assert_eq!(resp["error"]["code"], -32600);
+
+        // Notifications are not responded to.
+        assert!(process(br#"{"jsonrpc": "2.0", "method": "getinfo"}"#).is_none());
+        assert!(process(
+            br#"[{"jsonrpc": "2.0", "method": "getinfo"}, {"jsonrpc": "2.0", "method": "getnewaddress"}]"#
+        )
+        .is_none());
+
+        // A batch is responded to with an array of the responses to its requests.
+        let resp = process(
+            br#"[
+                {"jsonrpc": "2.0", "id": 0, "method": "getinfo"},
+                {"jsonrpc": "2.0", "method": "getinfo"},
+                1,
+                {"jsonrpc": "2.0", "id": "a", "method": "unknown"},
+                {"jsonrpc": "2.0", "id": 2, "method": "subscribe"}
+            ]"#,
+        )
+        .unwrap();
+        let resps = resp.as_array().unwrap();
+        assert_eq!(resps.len(), 4);
+        assert_eq!(resps[0]["id"], 0);
+        assert!(resps[0]["result"]["version"].is_string());
+        assert_eq!(resps[1]["id"], serde_json::Value::Null);
+        assert_eq!(resps[1]["error"]["code"], -32600);
+        assert_eq!(resps[2]["id"], "a");
+        assert_eq!(resps[2]["error"]["code"], -32601);
+        assert_eq!(resps[3]["id"], 2);
+        assert_eq!(resps[3]["error"]["code"], -32600);
+
+        ms.shutdown();
+    }
+
     // TODO: debug on MacOS
     #[cfg(not(target_os = "macos"))]
     #[test]
@@ -419,7 +778,168 @@ mod tests {
             jsonrpc: "2.0".to_string(),
             method: "stop".to_string(),
             params: None,
-            id: ReqId::Num(0),
+            id: Some(ReqId::Num(0)),
+        };
+        write_messages(
+            &socket_path,
+            &[&serde_json::to_vec(&stop_req).unwrap(), b"\n"],
+        );
+
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn process_request_permissions() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let control = &ms.handle.control;
+        let shutdown = atomic::AtomicBool::new(false);
+        let request = |method: &str| Request {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params: None,
+            id: Some(ReqId::Num(0)),
+        };
+        let viewer = Permissions::Methods(
+            vec!["getinfo".to_string(), "listcoins".to_string()]
+                .into_iter()
+                .collect(),
+        );
+
+        // Allowed methods are processed as usual.
+        let resp = process_request(control, request("getinfo"), &viewer, &shutdown).unwrap();
+        assert!(resp.error.is_none());
+        assert!(resp.result.is_some());
+
+        // Others are denied, and don't take effect.
+        let resp = process_request(control, request("getnewaddress"), &viewer, &shutdown).unwrap();
+        assert!(resp.result.is_none());
+        let error = serde_json::to_value(resp.error.unwrap()).unwrap();
+        assert_eq!(error["code"], 1_001);
+        let resp = process_request(control, request("stop"), &viewer, &shutdown).unwrap();
+        assert!(resp.error.is_some());
+        assert!(!shutdown.load(atomic::Ordering::Relaxed));
+
+        // Without restriction any method may be called.
+        let resp = process_request(
+            control,
+            request("getnewaddress"),
+            &Permissions::All,
+            &shutdown,
+        )
+        .unwrap();
+        assert!(resp.error.is_none());
+
+        ms.shutdown();
+    }
+
+    #[test]
+    fn http_server_setup() {
+        let tmp_dir = tmp_dir();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let cookie_path = tmp_dir.join("cookie");
+
+        // With no credentials configured, a cookie is generated.
+        let mut config = HttpRpcConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            user: None,
+            password: None,
+        };
+        let (_, auth) = http_rpcserver_setup(&config, &cookie_path).unwrap();
+        let cookie = fs::read_to_string(&cookie_path).unwrap();
+        assert_eq!(cookie, auth.cookie());
+        assert!(cookie.starts_with("__cookie__:"));
+        assert_eq!(cookie.len(), "__cookie__:".len() + 64);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&cookie_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A new one is generated at each startup.
+        let (_, auth) = http_rpcserver_setup(&config, &cookie_path).unwrap();
+        assert_ne!(cookie, auth.cookie());
+        assert_eq!(fs::read_to_string(&cookie_path).unwrap(), auth.cookie());
+        fs::remove_file(&cookie_path).unwrap();
+
+        // Otherwise the configured credentials are used.
+        config.user = Some("alice".to_string());
+        config.password = Some("hunter2".to_string());
+        let (_, auth) = http_rpcserver_setup(&config, &cookie_path).unwrap();
+        assert_eq!(auth.cookie(), "alice:hunter2");
+        assert!(!cookie_path.exists());
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn server_subscribe() {
+        let ms = DummyLiana::new(DummyBitcoind::new(), DummyDatabase::new());
+        let events = ms.handle.control.events.clone();
+        let socket_path: path::PathBuf = [
+            ms.tmp_dir.as_path(),
+            path::Path::new("d"),
+            path::Path::new("bitcoin"),
+            path::Path::new("lianad_rpc"),
+        ]
+        .iter()
+        .collect();
+
+        let t = thread::spawn(move || ms.rpc_server().unwrap());
+        while !socket_path.exists() {
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        // Subscribe, the request is acknowledged.
+        let subscribe_req = Request {
+            jsonrpc: "2.0".to_string(),
+            method: "subscribe".to_string(),
+            params: None,
+            id: Some(ReqId::Num(1)),
+        };
+        let mut client = net::UnixStream::connect(&socket_path).unwrap();
+        client
+            .write_all(&serde_json::to_vec(&subscribe_req).unwrap())
+            .unwrap();
+        client.write_all(b"\n").unwrap();
+        let mut messages = serde_json::Deserializer::from_reader(client.try_clone().unwrap())
+            .into_iter::<serde_json::Value>();
+        let resp = messages.next().unwrap().unwrap();
+        assert_eq!(
+            resp,
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": {}})
+        );
+        assert_eq!(events.subscribers_count(), 1);
+
+        // The events are pushed as notifications. The poller may emit some, skip them.
+        let event = Event::RescanProgress { progress: 0.25 };
+        events.notify(event);
+        let notif = messages
+            .map(|msg| msg.unwrap())
+            .find(|msg| msg["method"] == "rescanprogress")
+            .unwrap();
+        assert_eq!(
+            notif,
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "rescanprogress",
+                "params": {"progress": 0.25}
+            })
+        );
+
+        // Closing the connection unsubscribes.
+        drop(client);
+        while events.subscribers_count() > 0 {
+            events.notify(Event::RescanCompleted {});
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        let stop_req = Request {
+            jsonrpc: "2.0".to_string(),
+            method: "stop".to_string(),
+            params: None,
+            id: Some(ReqId::Num(0)),
         };
         write_messages(
             &socket_path,
@@ -5,6 +5,7 @@ pub mod config;
 mod daemonize;
 mod database;
 pub mod descriptors;
+pub mod events;
 #[cfg(feature = "daemon")]
 mod jsonrpc;
 mod random;
@@ -15,9 +16,16 @@ mod testutils;
 pub use bip39;
 pub use miniscript;
 
-pub use crate::bitcoin::d::{BitcoinD, BitcoindError, WalletError};
+pub use crate::bitcoin::{
+    cbf::{Cbf, CbfError},
+    d::{BitcoinD, BitcoindError, WalletError},
+    electrum::{Electrum, ElectrumError},
+};
 #[cfg(feature = "daemon")]
-use crate::jsonrpc::server::{rpcserver_loop, rpcserver_setup};
+use crate::jsonrpc::{
+    server::{http_rpcserver_setup, rpcserver_loop, rpcserver_setup},
+    Permissions,
+};
 use crate::{
     bitcoin::{poller, BitcoinInterface},
     config::Config,
@@ -25,6 +33,8 @@ use crate::{
         sqlite::{FreshDbOptions, SqliteDb, SqliteDbError},
         DatabaseInterface,
     },
+    events::EventNotifier,
+    signer::{HotSigner, SignerError},
 };
 
 use std::{error, fmt, fs, io, path, sync};
@@ -91,6 +101,10 @@ pub enum StartupError {
     MissingBitcoindConfig,
     Database(SqliteDbError),
     Bitcoind(BitcoindError),
+    Electrum(ElectrumError),
+    Cbf(CbfError),
+    SignerPassphrase(path::PathBuf, io::Error),
+    Signer(SignerError),
     #[cfg(unix)]
     Daemonization(&'static str),
 }
@@ -113,6 +127,19 @@ impl fmt::Display for StartupError {
             ),
             Self::Database(e) => write!(f, "Error initializing database: '{}'.", e),
             Self::Bitcoind(e) => write!(f, "Error setting up bitcoind interface: '{}'.", e),
+            Self::Electrum(e) => write!(f, "Error setting up Electrum interface: '{}'.", e),
+            Self::Cbf(e) => write!(
+                f,
+                "Error setting up compact block filters interface: '{}'.",
+                e
+            ),
+            Self::SignerPassphrase(file_path, e) => write!(
+                f,
+                "Error reading the signer passphrase from '{}': '{}'.",
+                file_path.display(),
+                e
+            ),
+            Self::Signer(e) => write!(f, "Error loading the hot signers: '{}'.", e),
             #[cfg(unix)]
             Self::Daemonization(e) => write!(f, "Error when daemonizing: '{}'.", e),
         }
@@ -139,6 +166,24 @@ impl From<BitcoindError> for StartupError {
     }
 }
 
+impl From<ElectrumError> for StartupError {
+    fn from(e: ElectrumError) -> Self {
+        Self::Electrum(e)
+    }
+}
+
+impl From<CbfError> for StartupError {
+    fn from(e: CbfError) -> Self {
+        Self::Cbf(e)
+    }
+}
+
+impl From<SignerError> for StartupError {
+    fn from(e: SignerError) -> Self {
+        Self::Signer(e)
+    }
+}
+
 fn create_datadir(datadir_path: &path::Path) -> Result<(), StartupError> {
     #[cfg(unix)]
     return {
@@ -180,7 +225,7 @@ fn setup_sqlite(
     } else {
         None
     };
-    let sqlite = SqliteDb::new(db_path, options, secp)?;
+    let sqlite = SqliteDb::new(db_path, options, config.bitcoin_config.gap_limit, secp)?;
     sqlite.sanity_check(config.bitcoin_config.network, &config.main_descriptor)?;
     log::info!("Database initialized and checked.");
 
@@ -282,7 +327,8 @@ fn setup_bitcoind(
     bitcoind.node_sanity_checks(config.bitcoin_config.network)?;
     if fresh_data_dir {
         log::info!("Creating a new watchonly wallet on bitcoind.");
-        bitcoind.create_watchonly_wallet(&config.main_descriptor)?;
+        bitcoind
+            .create_watchonly_wallet(&config.main_descriptor, config.bitcoin_config.gap_limit)?;
         log::info!("Watchonly wallet created.");
     } else if !wo_path.exists() && !cfg!(test) {
         // TODO: remove this hack.
@@ -311,6 +357,81 @@ fn setup_bitcoind(
     Ok(bitcoind)
 }
 
+// Connect to the Electrum server and sanity check it.
+fn setup_electrum(
+    config: &Config,
+    electrum_config: &config::ElectrumConfig,
+    main_descs: &[descriptors::LianaDescriptor],
+) -> Result<Electrum, StartupError> {
+    let electrum = Electrum::new(
+        electrum_config,
+        config.bitcoin_config.network,
+        config.bitcoin_config.gap_limit,
+        main_descs,
+    )?;
+    log::info!("Connected to the Electrum server.");
+    Ok(electrum)
+}
+
+// Connect to the P2P node serving compact block filters and start syncing from it.
+fn setup_cbf(
+    config: &Config,
+    cbf_config: &config::CbfConfig,
+    main_descs: &[descriptors::LianaDescriptor],
+) -> Result<Cbf, StartupError> {
+    Ok(Cbf::new(
+        cbf_config,
+        config.bitcoin_config.network,
+        config.bitcoin_config.gap_limit,
+        main_descs,
+    )?)
+}
+
+// Load the hot signers stored in the data directory, decrypting them with the passphrase from the
+// configured file if necessary.
+fn setup_signers(
+    config: &Config,
+    signer_config: &config::SignerConfig,
+) -> Result<Vec<HotSigner>, StartupError> {
+    let passphrase = signer_config
+        .passphrase_file
+        .as_ref()
+        .map(|file_path| {
+            fs::read_to_string(file_path)
+                .map(|passphrase| {
+                    // Trim the trailing newline in place, not to leave a copy of the passphrase
+                    // behind.
+                    let mut passphrase = zeroize::Zeroizing::new(passphrase);
+                    let trimmed_len = passphrase.trim_end_matches(&['\n', '\r'][..]).len();
+                    passphrase.truncate(trimmed_len);
+                    passphrase
+                })
+                .map_err(|e| StartupError::SignerPassphrase(file_path.clone(), e))
+        })
+        .transpose()?;
+    let data_dir = config
+        .data_dir()
+        .ok_or(StartupError::DefaultDataDirNotFound)?;
+    let signers = HotSigner::from_datadir(
+        &data_dir,
+        config.bitcoin_config.network,
+        passphrase.as_ref().map(|p| p.as_str()),
+    )?;
+
+    let secp = secp256k1::Secp256k1::signing_only();
+    for signer in &signers {
+        log::info!(
+            "Loaded hot signer with fingerprint {}.",
+            signer.fingerprint(&secp)
+        );
+    }
+    if signers.is_empty() {
+        log::warn!("A signer is configured but no mnemonic is stored in the data directory.");
+    }
+
+    Ok(signers)
+}
+
 #[derive(Clone)]
 pub struct DaemonControl {
     config: Config,
@@ -318,6 +439,13 @@ pub struct DaemonControl {
     // FIXME: Should we require Sync on DatabaseInterface rather than using a Mutex?
     db: sync::Arc<sync::Mutex<dyn DatabaseInterface>>,
     secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    events: EventNotifier,
+    /// The id of the wallet the commands are run against. `None` for the main wallet.
+    wallet_id: Option<i64>,
+    /// The main descriptor of the wallet the commands are run against.
+    main_descriptor: descriptors::LianaDescriptor,
+    /// The hot signers the daemon may sign Spend transactions with.
+    signers: sync::Arc<Vec<HotSigner>>,
 }
 
 impl DaemonControl {
@@ -325,13 +453,20 @@ impl DaemonControl {
         config: Config,
         bitcoin: sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
         db: sync::Arc<sync::Mutex<dyn DatabaseInterface>>,
+        events: EventNotifier,
         secp: secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+        signers: Vec<HotSigner>,
     ) -> DaemonControl {
+        let main_descriptor = config.main_descriptor.clone();
         DaemonControl {
             config,
             bitcoin,
             db,
             secp,
+            events,
+            wallet_id: None,
+            main_descriptor,
+            signers: sync::Arc::new(signers),
         }
     }
 
@@ -389,16 +524,42 @@ impl DaemonHandle {
             )?)) as sync::Arc<sync::Mutex<dyn DatabaseInterface>>,
         };
 
-        // Now, set up the Bitcoin interface.
-        let bit = match bitcoin {
-            Some(bit) => sync::Arc::from(sync::Mutex::from(bit)),
-            None => sync::Arc::from(sync::Mutex::from(setup_bitcoind(
+        // Now, set up the Bitcoin interface. Use an Electrum server or a P2P node serving compact
+        // block filters if one was configured, bitcoind otherwise. Those need to be told the
+        // descriptors of all our wallets.
+        let main_descs: Vec<descriptors::LianaDescriptor> = db
+            .connection()
+            .wallets()
+            .into_iter()
+            .map(|wallet| wallet.main_descriptor)
+            .collect();
+        let bit = match (bitcoin, &config.electrum_config, &config.cbf_config) {
+            (Some(bit), _, _) => sync::Arc::from(sync::Mutex::from(bit)),
+            (None, Some(electrum_config), _) => sync::Arc::from(sync::Mutex::from(setup_electrum(
+                &config,
+                electrum_config,
+                &main_descs,
+            )?))
+                as sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
+            (None, None, Some(cbf_config)) => sync::Arc::from(sync::Mutex::from(setup_cbf(
+                &config,
+                cbf_config,
+                &main_descs,
+            )?))
+                as sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
+            (None, None, None) => sync::Arc::from(sync::Mutex::from(setup_bitcoind(
                 &config,
                 &data_dir,
                 fresh_data_dir,
             )?)) as sync::Arc<sync::Mutex<dyn BitcoinInterface>>,
         };
 
+        // Load the hot signers, if we were told to sign with them.
+        let signers = match &config.signer_config {
+            Some(signer_config) => setup_signers(&config, signer_config)?,
+            None => Vec::new(),
+        };
+
         // If we are on a UNIX system and they told us to daemonize, do it now.
         // NOTE: it's safe to daemonize now, as we don't carry any open DB connection
         // https://www.sqlite.org/howtocorrupt.html#_carrying_an_open_database_connection_across_a_fork_
@@ -413,16 +574,20 @@ impl DaemonHandle {
             }
         }
 
-        // Spawn the bitcoind poller with a retry limit high enough that we'd fail after that.
+        // Spawn the bitcoind poller with a retry limit high enough that we'd fail after that. It
+        // emits events about our wallets to the subscribers registered through the API.
+        let events = EventNotifier::new();
         let bitcoin_poller = poller::Poller::start(
             bit.clone(),
             db.clone(),
+            events.clone(),
+            config.hooks_config.clone().map(poller::Hooks::new),
             config.bitcoin_config.poll_interval_secs,
-            config.main_descriptor.clone(),
+            config.bitcoin_config.gap_limit,
         );
 
         // Finally, set up the API.
-        let control = DaemonControl::new(config, bit, db, secp);
+        let control = DaemonControl::new(config, bit, db, events, secp, signers);
 
         Ok(Self {
             control,
@@ -445,22 +610,63 @@ impl DaemonHandle {
             bitcoin_poller: poller,
         } = self;
 
-        let rpc_socket: path::PathBuf = [
+        let data_dir: path::PathBuf = [
             control
                 .config
                 .data_dir()
                 .expect("Didn't fail at startup, must not now")
                 .as_path(),
             path::Path::new(&control.config.bitcoin_config.network.to_string()),
-            path::Path::new("lianad_rpc"),
         ]
         .iter()
         .collect();
-        let listener = rpcserver_setup(&rpc_socket)?;
+        let rpc_socket = data_dir.join("lianad_rpc");
+        let mut listeners = vec![(rpcserver_setup(&rpc_socket)?, Permissions::All)];
+
+        // Restricted credentials each get their own socket.
+        let rpc_auth_config = control.config.rpc_auth.clone();
+        let restricted_auth: Vec<_> = rpc_auth_config
+            .iter()
+            .map(|rpc_auth| {
+                let permissions = Permissions::Methods(rpc_auth.methods.iter().cloned().collect());
+                (rpc_auth, permissions)
+            })
+            .collect();
+        for (rpc_auth, permissions) in &restricted_auth {
+            let socket_path = data_dir.join(format!("lianad_rpc_{}", rpc_auth.name));
+            listeners.push((rpcserver_setup(&socket_path)?, permissions.clone()));
+        }
         log::info!("JSONRPC server started.");
 
-        rpcserver_loop(listener, control)?;
+        // If configured to, also serve the API over HTTP. Restricted credentials with a password
+        // may be used there too.
+        let rpc_cookie = data_dir.join("lianad_rpc_cookie");
+        let http_listener = control
+            .config
+            .http_rpc_config
+            .as_ref()
+            .map(|http_config| http_rpcserver_setup(http_config, &rpc_cookie))
+            .transpose()?
+            .map(|(listener, mut auth)| {
+                for (rpc_auth, permissions) in &restricted_auth {
+                    if let Some(password) = &rpc_auth.password {
+                        auth = auth.with_user(
+                            rpc_auth.name.clone(),
+                            password.clone(),
+                            permissions.clone(),
+                        );
+                    }
+                }
+                (listener, auth)
+            });
+
+        rpcserver_loop(listeners, http_listener, control)?;
         log::info!("JSONRPC server stopped.");
+        if rpc_cookie.exists() {
+            if let Err(e) = fs::remove_file(&rpc_cookie) {
+                log::error!("Error removing the JSONRPC cookie file: '{}'", e);
+            }
+        }
 
         poller.stop();
 
@@ -719,6 +925,7 @@ mod tests {
         let bitcoin_config = BitcoinConfig {
             network,
             poll_interval_secs: time::Duration::from_secs(2),
+            gap_limit: 200,
         };
         let bitcoind_config = BitcoindConfig {
             addr,
@@ -733,6 +940,13 @@ mod tests {
         let config = Config {
             bitcoin_config,
             bitcoind_config: Some(bitcoind_config),
+            electrum_config: None,
+            cbf_config: None,
+            hooks_config: None,
+            http_rpc_config: None,
+            rpc_auth: Vec::new(),
+            signer_config: None,
+            guardrails_config: None,
             data_dir: Some(data_dir),
             #[cfg(unix)]
             daemon: false,
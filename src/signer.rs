@@ -13,13 +13,20 @@ use std::{
     str::FromStr,
 };
 
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use zeroize::{Zeroize, Zeroizing};
+
 use miniscript::bitcoin::{
     self,
     bip32::{self, Error as Bip32Error},
     ecdsa,
     hashes::Hash,
+    key::TapTweak,
     psbt::Psbt,
-    secp256k1, sighash,
+    secp256k1, sighash, taproot,
 };
 
 /// An error related to using a signer.
@@ -29,6 +36,10 @@ pub enum SignerError {
     Mnemonic(bip39::Error),
     Bip32(Bip32Error),
     MnemonicStorage(io::Error),
+    PassphraseRequired,
+    MnemonicDecryption,
+    UnsupportedMnemonicFile(u8),
+    InvalidMnemonicFile,
     InsanePsbt,
     IncompletePsbt,
 }
@@ -40,6 +51,18 @@ impl fmt::Display for SignerError {
             Self::Mnemonic(s) => write!(f, "Error when working with mnemonics: {}", s),
             Self::Bip32(e) => write!(f, "BIP32 error: {}", e),
             Self::MnemonicStorage(e) => write!(f, "BIP39 mnemonic storage error: {}", e),
+            Self::PassphraseRequired => write!(
+                f,
+                "A passphrase is required to decrypt the mnemonics stored on disk."
+            ),
+            Self::MnemonicDecryption => write!(
+                f,
+                "Could not decrypt the stored mnemonic: wrong passphrase or corrupted file."
+            ),
+            Self::UnsupportedMnemonicFile(v) => {
+                write!(f, "Unsupported encrypted mnemonic file version: {}", v)
+            }
+            Self::InvalidMnemonicFile => write!(f, "Invalid encrypted mnemonic file."),
             Self::InsanePsbt => write!(f, "Information contained in the PSBT is wrong."),
             Self::IncompletePsbt => write!(
                 f,
@@ -53,9 +76,132 @@ impl error::Error for SignerError {}
 
 pub const MNEMONICS_FOLDER_NAME: &str = "mnemonics";
 
-// TODO: zeroize, mlock, etc.. For now we don't even encrypt the seed on disk so that'd be
-// overkill.
+// The extensions of the files storing a mnemonic, depending on whether it is encrypted.
+const PLAINTEXT_EXTENSION: &str = "txt";
+const ENCRYPTED_EXTENSION: &str = "enc";
+
+// An encrypted mnemonic file is laid out as follows:
+//   magic (8 bytes) | version (1 byte) | scrypt log_n (1 byte) | scrypt r (4 bytes, BE) |
+//   scrypt p (4 bytes, BE) | salt (16 bytes) | nonce (12 bytes) | ciphertext and tag
+// The key is derived from the passphrase using scrypt and the mnemonic is encrypted using
+// ChaCha20Poly1305. The whole header is authenticated as associated data.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"lianamne";
+const ENCRYPTED_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + 1 + 4 + 4 + SALT_LEN + NONCE_LEN;
+
+// The scrypt cost parameters used when encrypting a mnemonic. They are stored in the file so
+// they may be increased in the future without breaking existing files.
+#[cfg(not(test))]
+const SCRYPT_LOG_N: u8 = 17;
+#[cfg(test)]
+const SCRYPT_LOG_N: u8 = 8;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// The largest scrypt cost parameters we accept when decrypting a mnemonic. Since the header is
+// only authenticated after the key is derived, a tampered file must not make us spend an
+// unbounded amount of memory and time deriving the key. This allows up to 1GiB of memory.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 4;
+
+// Derive the encryption key from the passphrase.
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+) -> Result<Zeroizing<[u8; 32]>, SignerError> {
+    let params =
+        scrypt::Params::new(log_n, r, p, 32).map_err(|_| SignerError::InvalidMnemonicFile)?;
+    let mut key = Zeroizing::new([0; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key[..])
+        .expect("Output length is valid");
+    Ok(key)
+}
+
+// Encrypt this mnemonic with a key derived from this passphrase. Returns the content of the
+// file.
+fn encrypt_mnemonic(mnemonic: &str, passphrase: &str) -> Result<Vec<u8>, SignerError> {
+    let random_bytes = random::random_bytes().map_err(SignerError::Randomness)?;
+    let (salt, nonce) = (
+        &random_bytes[..SALT_LEN],
+        &random_bytes[SALT_LEN..SALT_LEN + NONCE_LEN],
+    );
+
+    let mut data = Vec::with_capacity(HEADER_LEN + mnemonic.len() + 16);
+    data.extend_from_slice(ENCRYPTED_MAGIC);
+    data.push(ENCRYPTED_VERSION);
+    data.push(SCRYPT_LOG_N);
+    data.extend_from_slice(&SCRYPT_R.to_be_bytes());
+    data.extend_from_slice(&SCRYPT_P.to_be_bytes());
+    data.extend_from_slice(salt);
+    data.extend_from_slice(nonce);
+
+    let key = derive_key(passphrase, salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: mnemonic.as_bytes(),
+                aad: &data,
+            },
+        )
+        .expect("Plaintext is never too large");
+    data.extend_from_slice(&ciphertext);
+
+    Ok(data)
+}
+
+// Decrypt the mnemonic from the content of an encrypted mnemonic file.
+fn decrypt_mnemonic(data: &[u8], passphrase: &str) -> Result<Zeroizing<String>, SignerError> {
+    if data.len() < HEADER_LEN || &data[..ENCRYPTED_MAGIC.len()] != ENCRYPTED_MAGIC {
+        return Err(SignerError::InvalidMnemonicFile);
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let version = header[8];
+    if version != ENCRYPTED_VERSION {
+        return Err(SignerError::UnsupportedMnemonicFile(version));
+    }
+    let log_n = header[9];
+    let r = u32::from_be_bytes(header[10..14].try_into().expect("4 bytes"));
+    let p = u32::from_be_bytes(header[14..18].try_into().expect("4 bytes"));
+    if log_n > MAX_SCRYPT_LOG_N || r > MAX_SCRYPT_R || p > MAX_SCRYPT_P {
+        return Err(SignerError::InvalidMnemonicFile);
+    }
+    let salt = &header[18..18 + SALT_LEN];
+    let nonce = &header[18 + SALT_LEN..];
+
+    let key = derive_key(passphrase, salt, log_n, r, p)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| SignerError::MnemonicDecryption)?,
+    );
+    String::from_utf8(plaintext.to_vec())
+        .map(Zeroizing::new)
+        .map_err(|_| SignerError::InvalidMnemonicFile)
+}
+
+// Whether the content of this mnemonic file is encrypted.
+fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
 /// A signer that keeps the key on the laptop. Based on BIP39.
+///
+/// The key material is wiped from memory when the signer is dropped.
 pub struct HotSigner {
     mnemonic: bip39::Mnemonic,
     master_xpriv: bip32::ExtendedPrivKey,
@@ -103,8 +249,10 @@ impl HotSigner {
         network: bitcoin::Network,
         mnemonic: bip39::Mnemonic,
     ) -> Result<Self, SignerError> {
-        let master_xpriv = bip32::ExtendedPrivKey::new_master(network, &mnemonic.to_seed(""))
-            .map_err(SignerError::Bip32)?;
+        let mut seed = mnemonic.to_seed("");
+        let master_xpriv = bip32::ExtendedPrivKey::new_master(network, &seed);
+        seed.zeroize();
+        let master_xpriv = master_xpriv.map_err(SignerError::Bip32)?;
         Ok(Self {
             mnemonic,
             master_xpriv,
@@ -135,24 +283,84 @@ impl HotSigner {
         .collect()
     }
 
+    // Read the mnemonic stored in this file, decrypting it if necessary.
+    fn from_file(
+        file_path: &path::Path,
+        network: bitcoin::Network,
+        passphrase: Option<&str>,
+    ) -> Result<Self, SignerError> {
+        let data = Zeroizing::new(fs::read(file_path).map_err(SignerError::MnemonicStorage)?);
+        let mnemonic = if is_encrypted(&data) {
+            let passphrase = passphrase.ok_or(SignerError::PassphraseRequired)?;
+            decrypt_mnemonic(&data, passphrase)?
+        } else {
+            Zeroizing::new(
+                String::from_utf8(data.to_vec()).map_err(|_| SignerError::InvalidMnemonicFile)?,
+            )
+        };
+        Self::from_str(network, &mnemonic)
+    }
+
     /// Read all the mnemonics from the datadir for the given network.
+    ///
+    /// The passphrase is only necessary if some of the mnemonics are stored encrypted. Plaintext
+    /// mnemonics are always read.
     pub fn from_datadir(
         datadir_root: &path::Path,
         network: bitcoin::Network,
+        passphrase: Option<&str>,
     ) -> Result<Vec<Self>, SignerError> {
         let mut signers = Vec::new();
 
         let mnemonic_paths = fs::read_dir(Self::mnemonics_folder(datadir_root, network))
             .map_err(SignerError::MnemonicStorage)?;
         for entry in mnemonic_paths {
-            let mnemonic = fs::read_to_string(entry.map_err(SignerError::MnemonicStorage)?.path())
-                .map_err(SignerError::MnemonicStorage)?;
-            signers.push(Self::from_str(network, &mnemonic)?);
+            let path = entry.map_err(SignerError::MnemonicStorage)?.path();
+            signers.push(Self::from_file(&path, network, passphrase)?);
         }
 
         Ok(signers)
     }
 
+    /// Encrypt with this passphrase all the mnemonics stored in plaintext in the datadir for the
+    /// given network. The plaintext files are removed once the encrypted ones are written.
+    /// Returns the number of mnemonics which were encrypted.
+    pub fn encrypt_datadir(
+        datadir_root: &path::Path,
+        network: bitcoin::Network,
+        passphrase: &str,
+    ) -> Result<usize, SignerError> {
+        let mut count = 0;
+
+        let mnemonic_paths = fs::read_dir(Self::mnemonics_folder(datadir_root, network))
+            .map_err(SignerError::MnemonicStorage)?;
+        for entry in mnemonic_paths {
+            let plaintext_path = entry.map_err(SignerError::MnemonicStorage)?.path();
+            let data =
+                Zeroizing::new(fs::read(&plaintext_path).map_err(SignerError::MnemonicStorage)?);
+            if is_encrypted(&data) {
+                continue;
+            }
+
+            // Make sure the mnemonic is valid before encrypting it.
+            let signer = Self::from_file(&plaintext_path, network, None)?;
+            let mnemonic_str = Zeroizing::new(signer.mnemonic_str());
+            let encrypted_path = plaintext_path.with_extension(ENCRYPTED_EXTENSION);
+            let mut encrypted_file =
+                create_file(&encrypted_path).map_err(SignerError::MnemonicStorage)?;
+            encrypted_file
+                .write_all(&encrypt_mnemonic(&mnemonic_str, passphrase)?)
+                .map_err(SignerError::MnemonicStorage)?;
+            encrypted_file
+                .sync_all()
+                .map_err(SignerError::MnemonicStorage)?;
+            fs::remove_file(&plaintext_path).map_err(SignerError::MnemonicStorage)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
     /// The BIP39 mnemonics from which the master key of this signer is derived.
     pub fn words(&self) -> [&'static str; 12] {
         let words: Vec<&'static str> = self.mnemonic.word_iter().collect();
@@ -184,25 +392,46 @@ impl HotSigner {
 
     /// Store the mnemonic in a file within the given "data directory".
     /// The file is stored within a "mnemonics" folder, with the filename set to the fingerprint of
-    /// the master xpub corresponding to this mnemonic.
+    /// the master xpub corresponding to this mnemonic. If a passphrase is given, the mnemonic is
+    /// encrypted using a key derived from it.
     pub fn store(
         &self,
         datadir_root: &path::Path,
         network: bitcoin::Network,
         secp: &secp256k1::Secp256k1<impl secp256k1::Signing>,
+        passphrase: Option<&str>,
     ) -> Result<(), SignerError> {
         let mut mnemonics_folder = Self::mnemonics_folder(datadir_root, network);
         if !mnemonics_folder.exists() {
             create_dir(&mnemonics_folder).map_err(SignerError::MnemonicStorage)?;
         }
 
-        // This will fail if a file with this fingerprint exists already.
-        mnemonics_folder.push(format!("mnemonic-{:x}.txt", self.fingerprint(secp)));
-        let mnemonic_path = mnemonics_folder;
+        // This will fail if a file with this fingerprint exists already, whether it is encrypted
+        // or not.
+        mnemonics_folder.push(format!("mnemonic-{:x}", self.fingerprint(secp)));
+        for ext in [PLAINTEXT_EXTENSION, ENCRYPTED_EXTENSION] {
+            if mnemonics_folder.with_extension(ext).exists() {
+                return Err(SignerError::MnemonicStorage(
+                    io::ErrorKind::AlreadyExists.into(),
+                ));
+            }
+        }
+        let mnemonic_str = Zeroizing::new(self.mnemonic_str());
+        let (mnemonic_path, content) = if let Some(passphrase) = passphrase {
+            (
+                mnemonics_folder.with_extension(ENCRYPTED_EXTENSION),
+                Zeroizing::new(encrypt_mnemonic(&mnemonic_str, passphrase)?),
+            )
+        } else {
+            (
+                mnemonics_folder.with_extension(PLAINTEXT_EXTENSION),
+                Zeroizing::new(mnemonic_str.as_bytes().to_vec()),
+            )
+        };
         let mut mnemonic_file =
             create_file(&mnemonic_path).map_err(SignerError::MnemonicStorage)?;
         mnemonic_file
-            .write_all(self.mnemonic_str().as_bytes())
+            .write_all(&content)
             .map_err(SignerError::MnemonicStorage)?;
 
         Ok(())
@@ -228,6 +457,128 @@ impl HotSigner {
         bip32::ExtendedPubKey::from_priv(secp, &xpriv)
     }
 
+    // Provide an ECDSA signature for all the keys from this signer in a P2WSH input.
+    fn sign_p2wsh_input(
+        &self,
+        psbt: &mut Psbt,
+        i: usize,
+        sighash_cache: &mut sighash::SighashCache<&bitcoin::Transaction>,
+        master_fingerprint: bip32::Fingerprint,
+        secp: &secp256k1::Secp256k1<impl secp256k1::Signing>,
+    ) -> Result<(), SignerError> {
+        // First of all compute the sighash for this input. We assume P2WSH spend: the sighash
+        // script code is always the witness script.
+        let witscript = psbt.inputs[i]
+            .witness_script
+            .as_ref()
+            .ok_or(SignerError::IncompletePsbt)?;
+        let value = psbt.inputs[i]
+            .witness_utxo
+            .as_ref()
+            .ok_or(SignerError::IncompletePsbt)?
+            .value;
+        let sig_type = sighash::EcdsaSighashType::All;
+        let sighash = sighash_cache
+            .segwit_signature_hash(i, witscript, value, sig_type)
+            .map_err(|_| SignerError::InsanePsbt)?;
+        let sighash = secp256k1::Message::from_slice(sighash.as_byte_array())
+            .expect("Sighash is always 32 bytes.");
+
+        // Then provide a signature for all the keys they asked for.
+        let input = &mut psbt.inputs[i]; // for borrowck reasons
+        for (curr_pubkey, (fingerprint, der_path)) in input.bip32_derivation.iter() {
+            if *fingerprint != master_fingerprint {
+                continue;
+            }
+            let privkey = self.xpriv_at(der_path, secp).to_priv();
+            let pubkey = privkey.public_key(secp);
+            if pubkey.inner != *curr_pubkey {
+                return Err(SignerError::InsanePsbt);
+            }
+            let sig = secp.sign_ecdsa_low_r(&sighash, &privkey.inner);
+            input.partial_sigs.insert(
+                pubkey,
+                ecdsa::Signature {
+                    sig,
+                    hash_ty: sig_type,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    // Provide a Schnorr signature for all the keys from this signer in a Taproot input, be it for
+    // the key path or for any of the script paths.
+    fn sign_taproot_input(
+        &self,
+        psbt: &mut Psbt,
+        i: usize,
+        sighash_cache: &mut sighash::SighashCache<&bitcoin::Transaction>,
+        master_fingerprint: bip32::Fingerprint,
+        secp: &secp256k1::Secp256k1<impl secp256k1::Signing>,
+    ) -> Result<(), SignerError> {
+        // The Taproot sighash commits to all the spent outputs.
+        let prevouts = psbt
+            .inputs
+            .iter()
+            .map(|psbt_in| psbt_in.witness_utxo.clone())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(SignerError::IncompletePsbt)?;
+        let prevouts = sighash::Prevouts::All(&prevouts);
+        let sig_type = sighash::TapSighashType::Default;
+        // Tweaking the key for the key path spend requires a verification context.
+        let verif_secp = secp256k1::Secp256k1::verification_only();
+
+        let input = &mut psbt.inputs[i]; // for borrowck reasons
+        for (curr_pubkey, (leaf_hashes, (fingerprint, der_path))) in input.tap_key_origins.iter() {
+            if *fingerprint != master_fingerprint {
+                continue;
+            }
+            let privkey = self.xpriv_at(der_path, secp).to_priv();
+            let keypair = secp256k1::KeyPair::from_secret_key(secp, &privkey.inner);
+            if keypair.x_only_public_key().0 != *curr_pubkey {
+                return Err(SignerError::InsanePsbt);
+            }
+
+            // If it's the internal key, provide a signature for the key path.
+            if input.tap_internal_key == Some(*curr_pubkey) {
+                let sighash = sighash_cache
+                    .taproot_key_spend_signature_hash(i, &prevouts, sig_type)
+                    .map_err(|_| SignerError::InsanePsbt)?;
+                let sighash = secp256k1::Message::from_slice(sighash.as_byte_array())
+                    .expect("Sighash is always 32 bytes.");
+                let tweaked_keypair = keypair
+                    .tap_tweak(&verif_secp, input.tap_merkle_root)
+                    .to_inner();
+                let sig = secp.sign_schnorr_no_aux_rand(&sighash, &tweaked_keypair);
+                input.tap_key_sig = Some(taproot::Signature {
+                    sig,
+                    hash_ty: sig_type,
+                });
+            }
+
+            // Then provide a signature for each of the leaves this key is used in.
+            for leaf_hash in leaf_hashes {
+                let sighash = sighash_cache
+                    .taproot_script_spend_signature_hash(i, &prevouts, *leaf_hash, sig_type)
+                    .map_err(|_| SignerError::InsanePsbt)?;
+                let sighash = secp256k1::Message::from_slice(sighash.as_byte_array())
+                    .expect("Sighash is always 32 bytes.");
+                let sig = secp.sign_schnorr_no_aux_rand(&sighash, &keypair);
+                input.tap_script_sigs.insert(
+                    (*curr_pubkey, *leaf_hash),
+                    taproot::Signature {
+                        sig,
+                        hash_ty: sig_type,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     /// Sign all inputs of the given PSBT.
     ///
     /// **This does not perform any check. It will blindly sign anything that's passed.**
@@ -237,47 +588,22 @@ impl HotSigner {
         secp: &secp256k1::Secp256k1<impl secp256k1::Signing>,
     ) -> Result<Psbt, SignerError> {
         let master_fingerprint = self.fingerprint(secp);
-        let mut sighash_cache = sighash::SighashCache::new(&psbt.unsigned_tx);
+        let unsigned_tx = psbt.unsigned_tx.clone();
+        let mut sighash_cache = sighash::SighashCache::new(&unsigned_tx);
 
-        // Sign each input in the PSBT.
+        // Sign each input in the PSBT. Taproot inputs are recognized by their Taproot BIP32
+        // derivations.
         for i in 0..psbt.inputs.len() {
-            // First of all compute the sighash for this input. We assume P2WSH spend: the sighash
-            // script code is always the witness script.
-            let witscript = psbt.inputs[i]
-                .witness_script
-                .as_ref()
-                .ok_or(SignerError::IncompletePsbt)?;
-            let value = psbt.inputs[i]
-                .witness_utxo
-                .as_ref()
-                .ok_or(SignerError::IncompletePsbt)?
-                .value;
-            let sig_type = sighash::EcdsaSighashType::All;
-            let sighash = sighash_cache
-                .segwit_signature_hash(i, witscript, value, sig_type)
-                .map_err(|_| SignerError::InsanePsbt)?;
-            let sighash = secp256k1::Message::from_slice(sighash.as_byte_array())
-                .expect("Sighash is always 32 bytes.");
-
-            // Then provide a signature for all the keys they asked for.
-            let input = &mut psbt.inputs[i]; // for borrowck reasons
-            for (curr_pubkey, (fingerprint, der_path)) in input.bip32_derivation.iter() {
-                if *fingerprint != master_fingerprint {
-                    continue;
-                }
-                let privkey = self.xpriv_at(der_path, secp).to_priv();
-                let pubkey = privkey.public_key(secp);
-                if pubkey.inner != *curr_pubkey {
-                    return Err(SignerError::InsanePsbt);
-                }
-                let sig = secp.sign_ecdsa_low_r(&sighash, &privkey.inner);
-                input.partial_sigs.insert(
-                    pubkey,
-                    ecdsa::Signature {
-                        sig,
-                        hash_ty: sig_type,
-                    },
-                );
+            if psbt.inputs[i].tap_key_origins.is_empty() {
+                self.sign_p2wsh_input(&mut psbt, i, &mut sighash_cache, master_fingerprint, secp)?;
+            } else {
+                self.sign_taproot_input(
+                    &mut psbt,
+                    i,
+                    &mut sighash_cache,
+                    master_fingerprint,
+                    secp,
+                )?;
             }
         }
 
@@ -292,6 +618,14 @@ impl HotSigner {
     }
 }
 
+impl Drop for HotSigner {
+    fn drop(&mut self) {
+        // The mnemonic wipes itself.
+        self.master_xpriv.private_key.non_secure_erase();
+        self.master_xpriv.chain_code = bip32::ChainCode::from([0; 32]);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -299,6 +633,7 @@ mod tests {
     use miniscript::{
         bitcoin::{locktime::absolute, psbt::Input as PsbtIn},
         descriptor::{DerivPaths, DescriptorMultiXKey, DescriptorPublicKey, Wildcard},
+        psbt::PsbtExt,
     };
     use std::collections::{BTreeMap, HashSet};
 
@@ -342,17 +677,150 @@ mod tests {
         let words_set: HashSet<_> = (0..10)
             .map(|_| {
                 let signer = HotSigner::generate(network).unwrap();
-                signer.store(&tmp_dir, network, &secp).unwrap();
+                signer.store(&tmp_dir, network, &secp, None).unwrap();
+                signer.words()
+            })
+            .collect();
+        let words_read: HashSet<_> = HotSigner::from_datadir(&tmp_dir, network, None)
+            .unwrap()
+            .into_iter()
+            .map(|signer| signer.words())
+            .collect();
+        assert_eq!(words_set, words_read);
+
+        fs::remove_dir_all(tmp_dir).unwrap();
+    }
+
+    #[test]
+    fn hot_signer_encrypted_storage() {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let tmp_dir = tmp_dir();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let network = bitcoin::Network::Bitcoin;
+        let passphrase = "correct horse battery staple";
+
+        // Store some mnemonics encrypted, and some in plaintext.
+        let mut words_set: HashSet<_> = (0..3)
+            .map(|_| {
+                let signer = HotSigner::generate(network).unwrap();
+                signer
+                    .store(&tmp_dir, network, &secp, Some(passphrase))
+                    .unwrap();
                 signer.words()
             })
             .collect();
-        let words_read: HashSet<_> = HotSigner::from_datadir(&tmp_dir, network)
+        let plaintext_signer = HotSigner::generate(network).unwrap();
+        plaintext_signer
+            .store(&tmp_dir, network, &secp, None)
+            .unwrap();
+        words_set.insert(plaintext_signer.words());
+
+        // The mnemonic must not be written in clear in the encrypted files.
+        let folder = HotSigner::mnemonics_folder(&tmp_dir, network);
+        for entry in fs::read_dir(&folder).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == ENCRYPTED_EXTENSION {
+                let content = fs::read(&path).unwrap();
+                assert!(content.starts_with(ENCRYPTED_MAGIC));
+                for words in words_set.iter() {
+                    let mnemonic = words.join(" ");
+                    assert!(!content
+                        .windows(mnemonic.len())
+                        .any(|w| w == mnemonic.as_bytes()));
+                }
+            }
+        }
+
+        // We can't store the same mnemonic twice, encrypted or not.
+        assert!(matches!(
+            plaintext_signer.store(&tmp_dir, network, &secp, Some(passphrase)),
+            Err(SignerError::MnemonicStorage(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+
+        // We need the right passphrase to read them.
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, None),
+            Err(SignerError::PassphraseRequired)
+        ));
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some("wrong passphrase")),
+            Err(SignerError::MnemonicDecryption)
+        ));
+        let words_read: HashSet<_> = HotSigner::from_datadir(&tmp_dir, network, Some(passphrase))
+            .unwrap()
+            .into_iter()
+            .map(|signer| signer.words())
+            .collect();
+        assert_eq!(words_set, words_read);
+
+        // Migrate the plaintext mnemonic. It can only be read with the passphrase afterward.
+        assert_eq!(
+            HotSigner::encrypt_datadir(&tmp_dir, network, passphrase).unwrap(),
+            1
+        );
+        assert_eq!(
+            HotSigner::encrypt_datadir(&tmp_dir, network, passphrase).unwrap(),
+            0
+        );
+        for entry in fs::read_dir(&folder).unwrap() {
+            let path = entry.unwrap().path();
+            assert_eq!(path.extension().unwrap(), ENCRYPTED_EXTENSION);
+        }
+        let words_read: HashSet<_> = HotSigner::from_datadir(&tmp_dir, network, Some(passphrase))
             .unwrap()
             .into_iter()
             .map(|signer| signer.words())
             .collect();
         assert_eq!(words_set, words_read);
 
+        // A tampered file is detected.
+        let enc_path = folder
+            .join(format!(
+                "mnemonic-{:x}",
+                plaintext_signer.fingerprint(&secp)
+            ))
+            .with_extension(ENCRYPTED_EXTENSION);
+        let mut content = fs::read(&enc_path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        fs::remove_file(&enc_path).unwrap();
+        fs::write(&enc_path, &content).unwrap();
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some(passphrase)),
+            Err(SignerError::MnemonicDecryption)
+        ));
+        // Other scrypt parameters are used to derive the key, as long as they are not too large.
+        content[last] ^= 1;
+        content[9] = SCRYPT_LOG_N + 1;
+        fs::remove_file(&enc_path).unwrap();
+        fs::write(&enc_path, &content).unwrap();
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some(passphrase)),
+            Err(SignerError::MnemonicDecryption)
+        ));
+        content[9] = 64;
+        fs::remove_file(&enc_path).unwrap();
+        fs::write(&enc_path, &content).unwrap();
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some(passphrase)),
+            Err(SignerError::InvalidMnemonicFile)
+        ));
+        content[9] = SCRYPT_LOG_N;
+        content[10..14].copy_from_slice(&u32::MAX.to_be_bytes());
+        fs::remove_file(&enc_path).unwrap();
+        fs::write(&enc_path, &content).unwrap();
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some(passphrase)),
+            Err(SignerError::InvalidMnemonicFile)
+        ));
+        content[8] = 2;
+        fs::remove_file(&enc_path).unwrap();
+        fs::write(&enc_path, &content).unwrap();
+        assert!(matches!(
+            HotSigner::from_datadir(&tmp_dir, network, Some(passphrase)),
+            Err(SignerError::UnsupportedMnemonicFile(2))
+        ));
+
         fs::remove_dir_all(tmp_dir).unwrap();
     }
 
@@ -574,6 +1042,117 @@ mod tests {
         assert_eq!(psbt.inputs[1].partial_sigs.len(), 1);
     }
 
+    #[test]
+    fn hot_signer_sign_taproot() {
+        let secp = secp256k1::Secp256k1::new();
+        let network = bitcoin::Network::Bitcoin;
+
+        // A Taproot Liana descriptor with a single key as primary path (the internal key) and a
+        // single key as recovery path (in a tapleaf).
+        let (prim_signer, recov_signer) = (
+            HotSigner::generate(network).unwrap(),
+            HotSigner::generate(network).unwrap(),
+        );
+        let desc_key = |signer: &HotSigner| {
+            let origin_der = bip32::DerivationPath::from_str("m/48'/0'/0'/2'").unwrap();
+            let xkey = signer.xpub_at(&origin_der, &secp);
+            DescriptorPublicKey::MultiXPub(DescriptorMultiXKey {
+                origin: Some((signer.fingerprint(&secp), origin_der)),
+                xkey,
+                derivation_paths: DerivPaths::new(vec![
+                    bip32::DerivationPath::from_str("m/0").unwrap(),
+                    bip32::DerivationPath::from_str("m/1").unwrap(),
+                ])
+                .unwrap(),
+                wildcard: Wildcard::Unhardened,
+            })
+        };
+        let prim_keys = descriptors::PathInfo::Single(desc_key(&prim_signer));
+        let recov_keys = descriptors::PathInfo::Single(desc_key(&recov_signer));
+        let policy = descriptors::LianaPolicy::new_taproot(
+            prim_keys,
+            [(46, recov_keys)].iter().cloned().collect(),
+        )
+        .unwrap();
+        let desc = descriptors::LianaDescriptor::new(policy);
+        assert!(desc.is_taproot());
+
+        // Create a dummy PSBT spending a coin from this descriptor.
+        let spent_coin_desc = desc.receive_descriptor().derive(42.into(), &secp);
+        let mut psbt_in = PsbtIn {
+            witness_utxo: Some(bitcoin::TxOut {
+                value: 19_000,
+                script_pubkey: spent_coin_desc.script_pubkey(),
+            }),
+            ..PsbtIn::default()
+        };
+        spent_coin_desc.update_psbt_in(&mut psbt_in);
+        assert!(psbt_in.bip32_derivation.is_empty() && psbt_in.witness_script.is_none());
+        assert_eq!(psbt_in.tap_key_origins.len(), 2);
+        assert_eq!(psbt_in.tap_scripts.len(), 1);
+        let dummy_psbt = Psbt {
+            unsigned_tx: bitcoin::Transaction {
+                version: 2,
+                lock_time: absolute::LockTime::Blocks(absolute::Height::ZERO),
+                input: vec![bitcoin::TxIn {
+                    sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    previous_output: bitcoin::OutPoint::from_str(
+                        "4613e078e4cdbb0fce1bc6e44b028f0e11621a134a1605efdc456c32d155c922:19",
+                    )
+                    .unwrap(),
+                    ..bitcoin::TxIn::default()
+                }],
+                output: vec![bitcoin::TxOut {
+                    value: 18_420,
+                    script_pubkey: bitcoin::Address::from_str(
+                        "bc1qvklensptw5lk7d470ds60pcpsr0psdpgyvwepv",
+                    )
+                    .unwrap()
+                    .payload
+                    .script_pubkey(),
+                }],
+            },
+            version: 0,
+            xpub: BTreeMap::new(),
+            proprietary: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+            inputs: vec![psbt_in],
+            outputs: vec![Default::default()],
+        };
+
+        // The primary signer signs for the key path, the recovery signer for the tapleaf.
+        let psbt = prim_signer.sign_psbt(dummy_psbt.clone(), &secp).unwrap();
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+        assert!(psbt.inputs[0].tap_script_sigs.is_empty());
+        let info = desc.partial_spend_info(&psbt).unwrap();
+        assert_eq!(info.primary_path().sigs_count, 1);
+        let psbt = recov_signer.sign_psbt(psbt, &secp).unwrap();
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 1);
+
+        // The key path signature is valid and the transaction can be finalized.
+        let mut psbt = psbt;
+        psbt.inputs[0].tap_script_sigs.clear();
+        psbt.finalize_mut(&secp).unwrap();
+        assert_eq!(
+            psbt.inputs[0].final_script_witness.as_ref().unwrap().len(),
+            1
+        );
+
+        // When the recovery path is available, the recovery signature can be used to finalize.
+        let mut psbt = dummy_psbt;
+        psbt.unsigned_tx.input[0].sequence = bitcoin::Sequence::from_height(46);
+        let mut psbt = recov_signer.sign_psbt(psbt, &secp).unwrap();
+        let info = desc.partial_spend_info(&psbt).unwrap();
+        assert_eq!(info.primary_path().sigs_count, 0);
+        assert_eq!(info.recovery_paths().get(&46).unwrap().sigs_count, 1);
+        psbt.finalize_mut(&secp).unwrap();
+        // Signature, script and control block.
+        assert_eq!(
+            psbt.inputs[0].final_script_witness.as_ref().unwrap().len(),
+            3
+        );
+    }
+
     #[test]
     fn signer_set_net() {
         let secp = secp256k1::Secp256k1::signing_only();
@@ -1,11 +1,17 @@
 use crate::{
-    bitcoin::{BitcoinInterface, Block, BlockChainTip, SyncProgress, UTxO},
-    config::{BitcoinConfig, Config},
-    database::{BlockInfo, Coin, CoinStatus, DatabaseConnection, DatabaseInterface, LabelItem},
-    descriptors, DaemonHandle,
+    bitcoin::{BitcoinInterface, Block, BlockChainTip, MempoolEntry, SyncProgress, UTxO},
+    config::{BitcoinConfig, Config, SignerConfig},
+    database::{
+        BlockInfo, Coin, CoinStatus, DatabaseConnection, DatabaseInterface, LabelItem, SpendStatus,
+        SpendTx, Wallet,
+    },
+    descriptors,
+    signer::HotSigner,
+    DaemonHandle,
 };
 
 use std::{
+    cmp,
     collections::{HashMap, HashSet},
     env, fs, io, path, process,
     str::FromStr,
@@ -21,6 +27,7 @@ use miniscript::{
 
 pub struct DummyBitcoind {
     pub txs: HashMap<Txid, (Transaction, Option<Block>)>,
+    pub mempool: HashMap<Txid, MempoolEntry>,
 }
 
 impl DummyBitcoind {}
@@ -29,6 +36,7 @@ impl DummyBitcoind {
     pub fn new() -> Self {
         Self {
             txs: HashMap::new(),
+            mempool: HashMap::new(),
         }
     }
 }
@@ -91,17 +99,19 @@ impl BitcoinInterface for DummyBitcoind {
     }
 
     fn broadcast_tx(&self, _: &bitcoin::Transaction) -> Result<(), String> {
-        todo!()
+        Ok(())
     }
 
     fn start_rescan(&self, _: &descriptors::LianaDescriptor, _: u32) -> Result<(), String> {
-        todo!()
+        Ok(())
     }
 
     fn rescan_progress(&self) -> Option<f64> {
         None
     }
 
+    fn extend_watched_range(&self, _: &[descriptors::SinglePathLianaDesc], _: u32) {}
+
     fn block_before_date(&self, _: u32) -> Option<BlockChainTip> {
         todo!()
     }
@@ -116,6 +126,15 @@ impl BitcoinInterface for DummyBitcoind {
     ) -> Option<(bitcoin::Transaction, Option<Block>)> {
         self.txs.get(txid).cloned()
     }
+
+    fn mempool_entry(&self, txid: &bitcoin::Txid) -> Option<MempoolEntry> {
+        self.mempool.get(txid).cloned()
+    }
+
+    fn feerate_estimate(&self, conf_target: u16) -> Option<u64> {
+        // The sooner, the more expensive.
+        Some(if conf_target <= 2 { 10 } else { 1 })
+    }
 }
 
 struct DummyDbState {
@@ -123,9 +142,13 @@ struct DummyDbState {
     change_index: bip32::ChildNumber,
     curr_tip: Option<BlockChainTip>,
     coins: HashMap<bitcoin::OutPoint, Coin>,
-    spend_txs: HashMap<bitcoin::Txid, (Psbt, Option<u32>)>,
+    spend_txs: HashMap<bitcoin::Txid, SpendTx>,
+    addresses: HashMap<bitcoin::Address, (bip32::ChildNumber, bool)>,
+    labels: HashMap<String, String>,
+    wallets: Vec<Wallet>,
 }
 
+#[derive(Clone)]
 pub struct DummyDatabase {
     db: sync::Arc<sync::RwLock<DummyDbState>>,
 }
@@ -136,6 +159,11 @@ impl DatabaseInterface for DummyDatabase {
             db: self.db.clone(),
         })
     }
+
+    // The dummy database doesn't separate the data of each wallet.
+    fn wallet_connection(&self, _wallet_id: i64) -> Box<dyn DatabaseConnection> {
+        self.connection()
+    }
 }
 
 impl DummyDatabase {
@@ -147,6 +175,9 @@ impl DummyDatabase {
                 curr_tip: None,
                 coins: HashMap::new(),
                 spend_txs: HashMap::new(),
+                addresses: HashMap::new(),
+                labels: HashMap::new(),
+                wallets: Vec::new(),
             })),
         }
     }
@@ -156,6 +187,19 @@ impl DummyDatabase {
             self.db.write().unwrap().coins.insert(coin.outpoint, coin);
         }
     }
+
+    pub fn insert_address(
+        &mut self,
+        address: bitcoin::Address,
+        index: bip32::ChildNumber,
+        is_change: bool,
+    ) {
+        self.db
+            .write()
+            .unwrap()
+            .addresses
+            .insert(address, (index, is_change));
+    }
 }
 
 impl DatabaseConnection for DummyDatabase {
@@ -171,6 +215,29 @@ impl DatabaseConnection for DummyDatabase {
         self.db.write().unwrap().curr_tip = Some(*tip);
     }
 
+    fn wallets(&mut self) -> Vec<Wallet> {
+        self.db.read().unwrap().wallets.clone()
+    }
+
+    fn create_wallet(
+        &mut self,
+        name: &str,
+        main_descriptor: &descriptors::LianaDescriptor,
+        timestamp: u32,
+        _secp: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
+    ) -> i64 {
+        let mut db = self.db.write().unwrap();
+        let id = db.wallets.len() as i64 + 1;
+        db.wallets.push(Wallet {
+            id,
+            name: name.to_string(),
+            main_descriptor: main_descriptor.clone(),
+            timestamp,
+            rescan_timestamp: None,
+        });
+        id
+    }
+
     fn receive_index(&mut self) -> bip32::ChildNumber {
         self.db.read().unwrap().deposit_index
     }
@@ -184,7 +251,7 @@ impl DatabaseConnection for DummyDatabase {
     }
 
     fn change_index(&mut self) -> bip32::ChildNumber {
-        self.db.read().unwrap().deposit_index
+        self.db.read().unwrap().change_index
     }
 
     fn set_change_index(
@@ -294,9 +361,9 @@ impl DatabaseConnection for DummyDatabase {
 
     fn derivation_index_by_address(
         &mut self,
-        _: &bitcoin::Address,
+        address: &bitcoin::Address,
     ) -> Option<(bip32::ChildNumber, bool)> {
-        None
+        self.db.read().unwrap().addresses.get(address).cloned()
     }
 
     fn coins_by_outpoints(
@@ -316,11 +383,25 @@ impl DatabaseConnection for DummyDatabase {
 
     fn store_spend(&mut self, psbt: &Psbt) {
         let txid = psbt.unsigned_tx.txid();
+        let status = SpendStatus::unbroadcast(psbt);
         self.db
             .write()
             .unwrap()
             .spend_txs
-            .insert(txid, (psbt.clone(), None));
+            .entry(txid)
+            .and_modify(|spend| {
+                spend.psbt = psbt.clone();
+                if matches!(spend.status, SpendStatus::Draft | SpendStatus::Signing) {
+                    spend.status = status;
+                }
+            })
+            .or_insert_with(|| SpendTx {
+                psbt: psbt.clone(),
+                created_at: None,
+                updated_at: None,
+                note: None,
+                status,
+            });
     }
 
     fn spend_tx(&mut self, txid: &bitcoin::Txid) -> Option<Psbt> {
@@ -329,11 +410,10 @@ impl DatabaseConnection for DummyDatabase {
             .unwrap()
             .spend_txs
             .get(txid)
-            .cloned()
-            .map(|x| x.0)
+            .map(|spend| spend.psbt.clone())
     }
 
-    fn list_spend(&mut self) -> Vec<(Psbt, Option<u32>)> {
+    fn list_spend(&mut self) -> Vec<SpendTx> {
         self.db
             .read()
             .unwrap()
@@ -343,6 +423,18 @@ impl DatabaseConnection for DummyDatabase {
             .collect()
     }
 
+    fn set_spend_status(&mut self, txid: &bitcoin::Txid, status: SpendStatus) {
+        if let Some(spend) = self.db.write().unwrap().spend_txs.get_mut(txid) {
+            spend.status = status;
+        }
+    }
+
+    fn set_spend_note(&mut self, txid: &bitcoin::Txid, note: Option<&str>) {
+        if let Some(spend) = self.db.write().unwrap().spend_txs.get_mut(txid) {
+            spend.note = note.map(String::from);
+        }
+    }
+
     fn delete_spend(&mut self, txid: &bitcoin::Txid) {
         self.db.write().unwrap().spend_txs.remove(txid);
     }
@@ -363,12 +455,29 @@ impl DatabaseConnection for DummyDatabase {
         todo!()
     }
 
-    fn update_labels(&mut self, _items: &HashMap<LabelItem, Option<String>>) {
-        todo!()
+    fn update_labels(&mut self, items: &HashMap<LabelItem, Option<String>>) {
+        let labels = &mut self.db.write().unwrap().labels;
+        for (item, label) in items {
+            match label {
+                Some(label) => labels.insert(item.to_string(), label.clone()),
+                None => labels.remove(&item.to_string()),
+            };
+        }
     }
 
-    fn labels(&mut self, _items: &HashSet<LabelItem>) -> HashMap<String, String> {
-        todo!()
+    fn list_labels(&mut self) -> HashMap<String, String> {
+        self.db.read().unwrap().labels.clone()
+    }
+
+    fn labels(&mut self, items: &HashSet<LabelItem>) -> HashMap<String, String> {
+        let labels = &self.db.read().unwrap().labels;
+        items
+            .iter()
+            .filter_map(|item| {
+                let item = item.to_string();
+                labels.get(&item).map(|label| (item, label.clone()))
+            })
+            .collect()
     }
 
     fn list_txids(&mut self, start: u32, end: u32, limit: u64) -> Vec<bitcoin::Txid> {
@@ -399,6 +508,52 @@ impl DatabaseConnection for DummyDatabase {
         txids_and_time.truncate(limit as usize);
         txids_and_time.into_iter().map(|(txid, _)| txid).collect()
     }
+
+    fn list_recent_txids(&mut self, limit: u64, offset: u64) -> Vec<bitcoin::Txid> {
+        let mut txids_and_time: Vec<(bitcoin::Txid, Option<u32>)> = Vec::new();
+        let coins = &self.db.read().unwrap().coins;
+        for coin in coins.values() {
+            let mut rows = vec![(coin.outpoint.txid, coin.block_info.map(|b| b.time))];
+            if let Some(spend_txid) = coin.spend_txid {
+                rows.push((spend_txid, coin.spend_block.map(|b| b.time)));
+            }
+            for row in rows {
+                if !txids_and_time.iter().any(|(txid, _)| *txid == row.0) {
+                    txids_and_time.push(row);
+                }
+            }
+        }
+        // Unconfirmed first, then most recent first.
+        txids_and_time.sort_by(|(txid1, t1), (txid2, t2)| match (t1, t2) {
+            (None, None) => txid1.cmp(txid2),
+            (None, Some(_)) => cmp::Ordering::Less,
+            (Some(_), None) => cmp::Ordering::Greater,
+            (Some(t1), Some(t2)) => t2.cmp(t1).then(txid1.cmp(txid2)),
+        });
+        txids_and_time
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(txid, _)| txid)
+            .collect()
+    }
+
+    fn wallet(&mut self) -> Wallet {
+        // The dummy database only tracks the wallets created through it, and connections aren't
+        // scoped to any of them. Use the first one.
+        self.db
+            .read()
+            .unwrap()
+            .wallets
+            .first()
+            .cloned()
+            .expect("No wallet was created")
+    }
+
+    // There is no database file to copy, just create the backup file.
+    fn backup(&mut self, path: &path::Path) -> Result<(), String> {
+        fs::write(path, b"").map_err(|e| e.to_string())
+    }
 }
 
 pub struct DummyLiana {
@@ -429,6 +584,23 @@ impl DummyLiana {
     pub fn new(
         bitcoin_interface: impl BitcoinInterface + 'static,
         database: impl DatabaseInterface + 'static,
+    ) -> DummyLiana {
+        Self::_new(bitcoin_interface, database, None)
+    }
+
+    /// Creates a new DummyLiana interface which signs with this hot signer.
+    pub fn new_with_signer(
+        bitcoin_interface: impl BitcoinInterface + 'static,
+        database: impl DatabaseInterface + 'static,
+        signer: &HotSigner,
+    ) -> DummyLiana {
+        Self::_new(bitcoin_interface, database, Some(signer))
+    }
+
+    fn _new(
+        bitcoin_interface: impl BitcoinInterface + 'static,
+        database: impl DatabaseInterface + 'static,
+        signer: Option<&HotSigner>,
     ) -> DummyLiana {
         let tmp_dir = tmp_dir();
         fs::create_dir_all(&tmp_dir).unwrap();
@@ -439,6 +611,7 @@ impl DummyLiana {
         let bitcoin_config = BitcoinConfig {
             network,
             poll_interval_secs: time::Duration::from_secs(2),
+            gap_limit: 200,
         };
 
         let owner_key = descriptors::PathInfo::Single(descriptor::DescriptorPublicKey::from_str("[aabbccdd]xpub68JJTXc1MWK8KLW4HGLXZBJknja7kDUJuFHnM424LbziEXsfkh1WQCiEjjHw4zLqSUm4rvhgyGkkuRowE9tCJSgt3TQB5J3SKAbZ2SdcKST/<0;1>/*").unwrap());
@@ -449,9 +622,27 @@ impl DummyLiana {
         )
         .unwrap();
         let desc = descriptors::LianaDescriptor::new(policy);
+        let signer_config = signer.map(|signer| {
+            signer
+                .store(
+                    &data_dir,
+                    network,
+                    &secp256k1::Secp256k1::signing_only(),
+                    None,
+                )
+                .unwrap();
+            SignerConfig::default()
+        });
         let config = Config {
             bitcoin_config,
             bitcoind_config: None,
+            electrum_config: None,
+            cbf_config: None,
+            hooks_config: None,
+            http_rpc_config: None,
+            rpc_auth: Vec::new(),
+            signer_config,
+            guardrails_config: None,
             data_dir: Some(data_dir),
             #[cfg(unix)]
             daemon: false,
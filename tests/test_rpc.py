@@ -30,6 +30,24 @@ def test_getinfo(lianad):
     assert res["rescan_progress"] is None
 
 
+def test_getfeeestimates(lianad, bitcoind):
+    estimates = lianad.rpc.getfeeestimates()["estimates"]
+    assert [e["conf_target"] for e in estimates] == [1, 3, 6, 12, 24, 144, 1008]
+    # There isn't enough data on regtest, we fall back to the mempool minimum.
+    assert all(e["feerate"] == 1 for e in estimates)
+
+    # We can use a confirmation target instead of a feerate when creating a Spend.
+    addr = lianad.rpc.getnewaddress()["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.01)
+    bitcoind.generate_block(1, wait_for_mempool=txid)
+    wait_for(lambda: len(lianad.rpc.listcoins()["coins"]) == 1)
+    destinations = {bitcoind.rpc.getnewaddress(): 100_000}
+    res = lianad.rpc.createspend(destinations, [], None, 6)
+    assert "psbt" in res
+    with pytest.raises(RpcError, match="Invalid confirmation target: 0"):
+        lianad.rpc.createspend(destinations, [], None, 0)
+
+
 def test_getaddress(lianad):
     res = lianad.rpc.getnewaddress()
     assert "address" in res
@@ -385,6 +403,15 @@ def test_create_spend(lianad, bitcoind):
     with pytest.raises(RpcError, match=".*is from an immature coinbase transaction."):
         lianad.rpc.createspend(destinations, [imma_coin["outpoint"]], 1)
 
+    # If we don't specify any coin, they are selected automatically. The immature
+    # coinbase deposit is never selected.
+    res = lianad.rpc.createspend(destinations, [], 1)
+    assert imma_coin["outpoint"] not in res["coins"]
+    spend_psbt = PSBT.from_base64(res["psbt"])
+    assert sorted(
+        f"{txin.prevout.hash:064x}:{txin.prevout.n}" for txin in spend_psbt.tx.vin
+    ) == sorted(res["coins"])
+
 
 def test_list_spend(lianad, bitcoind):
     # Start by creating two conflicting Spend PSBTs. The first one will have a change
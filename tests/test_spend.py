@@ -219,3 +219,78 @@ def test_send_to_self(lianad, bitcoind):
         c for c in lianad.rpc.listcoins()["coins"] if c["spend_info"] is None
     )
     wait_for(lambda: len(list(unspent_coins())) == 1)
+
+
+def test_rbf_psbt(lianad, bitcoind):
+    """Test we can bump the fees of an unconfirmed Spend, or cancel it."""
+    addr = lianad.rpc.getnewaddress()["address"]
+    deposit_txid = bitcoind.rpc.sendtoaddress(addr, 0.01)
+    bitcoind.generate_block(1, wait_for_mempool=deposit_txid)
+    wait_for(lambda: len(lianad.rpc.listcoins()["coins"]) == 1)
+
+    # Broadcast a Spend with a change output.
+    outpoints = [c["outpoint"] for c in lianad.rpc.listcoins()["coins"]]
+    dest_addr = bitcoind.rpc.getnewaddress()
+    res = lianad.rpc.createspend({dest_addr: 100_000}, outpoints, 2)
+    signed_psbt = lianad.signer.sign_psbt(PSBT.from_base64(res["psbt"]))
+    lianad.rpc.updatespend(signed_psbt.to_base64())
+    spend_txid = signed_psbt.tx.txid().hex()
+    lianad.rpc.broadcastspend(spend_txid)
+
+    # The replacement must have a higher feerate.
+    with pytest.raises(RpcError, match="the feerate must be at least 3 sats/vb"):
+        lianad.rpc.rbfpsbt(spend_txid, False, 2)
+
+    # Bump its fees. The payment is kept.
+    res = lianad.rpc.rbfpsbt(spend_txid, False, 10)
+    assert res["coins"] == outpoints
+    rbf_psbt = PSBT.from_base64(res["psbt"])
+    assert len(rbf_psbt.tx.vout) == 2
+    signed_psbt = lianad.signer.sign_psbt(rbf_psbt)
+    lianad.rpc.updatespend(signed_psbt.to_base64())
+    rbf_txid = signed_psbt.tx.txid().hex()
+    lianad.rpc.broadcastspend(rbf_txid)
+    mempool = bitcoind.rpc.getrawmempool()
+    assert rbf_txid in mempool and spend_txid not in mempool
+
+    # Now cancel the replacement. All the funds are sent back to us.
+    res = lianad.rpc.rbfpsbt(rbf_txid, True)
+    cancel_psbt = PSBT.from_base64(res["psbt"])
+    assert len(cancel_psbt.tx.vout) == 1
+    signed_psbt = lianad.signer.sign_psbt(cancel_psbt)
+    lianad.rpc.updatespend(signed_psbt.to_base64())
+    cancel_txid = signed_psbt.tx.txid().hex()
+    lianad.rpc.broadcastspend(cancel_txid)
+    bitcoind.generate_block(1, wait_for_mempool=cancel_txid)
+
+    # We can't replace a confirmed transaction.
+    with pytest.raises(RpcError, match="the transaction is not in the mempool"):
+        lianad.rpc.rbfpsbt(cancel_txid, True)
+
+
+def test_create_cpfp(lianad, bitcoind):
+    """Test we can accelerate an unconfirmed deposit by spending its output."""
+    addr = lianad.rpc.getnewaddress()["address"]
+    deposit_txid = bitcoind.rpc.sendtoaddress(address=addr, amount=0.01, fee_rate=1)
+    wait_for(lambda: len(lianad.rpc.listcoins(["unconfirmed"])["coins"]) == 1)
+
+    # The deposit already pays 1sat/vb.
+    with pytest.raises(RpcError, match="the transaction already pays a feerate of 1 sats/vb"):
+        lianad.rpc.createcpfp(deposit_txid, 1)
+
+    # Create a child bringing the package to 20sat/vb.
+    res = lianad.rpc.createcpfp(deposit_txid, 20)
+    assert res["coins"] == [c["outpoint"] for c in lianad.rpc.listcoins()["coins"]]
+    cpfp_psbt = PSBT.from_base64(res["psbt"])
+    assert len(cpfp_psbt.tx.vout) == 1
+    signed_psbt = lianad.signer.sign_psbt(cpfp_psbt)
+    lianad.rpc.updatespend(signed_psbt.to_base64())
+    cpfp_txid = signed_psbt.tx.txid().hex()
+    lianad.rpc.broadcastspend(cpfp_txid)
+    entry = bitcoind.rpc.getmempoolentry(deposit_txid)
+    package_feerate = entry["fees"]["descendant"] * COIN / entry["descendantsize"]
+    assert package_feerate >= 20
+
+    # The parent's output is now being spent.
+    with pytest.raises(RpcError, match="the transaction has no unspent output of ours"):
+        lianad.rpc.createcpfp(deposit_txid, 30)